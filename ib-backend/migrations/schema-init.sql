@@ -4,11 +4,17 @@ CREATE TABLE IF NOT EXISTS users (
     id SERIAL PRIMARY KEY,
     email TEXT UNIQUE NOT NULL,
     username TEXT NOT NULL,
-    inkbucks INTEGER NOT NULL,
     uid VARCHAR UNIQUE NOT NULL,
     created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
 );
 
+-- Columns added after the first release go through ALTER so existing databases get them too
+ALTER TABLE users ADD COLUMN IF NOT EXISTS is_admin BOOLEAN NOT NULL DEFAULT FALSE;
+-- Set from the sign-in token, only verified addresses are trusted for invitations and sharing
+ALTER TABLE users ADD COLUMN IF NOT EXISTS email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+-- Addresses are looked up case-insensitively, they must be unique that way too
+CREATE UNIQUE INDEX IF NOT EXISTS users_email_lower_idx ON users (lower(email));
+
 -- Icon_packs table
 CREATE TABLE IF NOT EXISTS icon_packs (
     id SERIAL PRIMARY KEY,
@@ -16,6 +22,42 @@ CREATE TABLE IF NOT EXISTS icon_packs (
     name TEXT NOT NULL
 );
 
+-- Brand palette applied by pack-wide recolors, see RecolorParams
+ALTER TABLE icon_packs ADD COLUMN IF NOT EXISTS palette JSONB;
+
+-- Prompt templates, every row is one immutable version of a named template
+CREATE TABLE IF NOT EXISTS prompt_templates (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    body TEXT NOT NULL,
+    negative_prompt TEXT,
+    parameters JSONB NOT NULL DEFAULT '{}',
+    defaults JSONB NOT NULL DEFAULT '{}',
+    weight INTEGER NOT NULL DEFAULT 0 CHECK (weight >= 0),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (name, version)
+);
+
+INSERT INTO prompt_templates (name, version, body, defaults, weight)
+VALUES (
+    'ink-minimal',
+    1,
+    'Generate a minimalist icon for {{subject}}. Draw it with {{style}}. {{stroke_weight}}, simple, and {{palette}}.',
+    '{"style": "simple geometric shapes, flowy like sketched with an ink pen", "stroke_weight": "Bold", "palette": "monochrome"}',
+    1
+)
+ON CONFLICT (name, version) DO NOTHING;
+
+-- Search document for an icon: name first, then tags, then the prompt it was generated from.
+-- array_to_string is only STABLE, so the wrapper is declared IMMUTABLE to be usable in a generated column.
+CREATE OR REPLACE FUNCTION icon_search_vector(metadata TEXT, tags TEXT[], prompt TEXT)
+RETURNS TSVECTOR AS $$
+    SELECT setweight(to_tsvector('english', coalesce(metadata, '')), 'A')
+        || setweight(to_tsvector('english', array_to_string(tags, ' ')), 'B')
+        || setweight(to_tsvector('english', coalesce(prompt, '')), 'C')
+$$ LANGUAGE SQL IMMUTABLE;
+
 -- Icons table
 CREATE TABLE IF NOT EXISTS icons (
     id SERIAL PRIMARY KEY,
@@ -26,11 +68,587 @@ CREATE TABLE IF NOT EXISTS icons (
     created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
 );
 
--- Transactions table
-CREATE TABLE IF NOT EXISTS transactions (
+UPDATE icons SET created_at = CURRENT_TIMESTAMP WHERE created_at IS NULL;
+ALTER TABLE icons ALTER COLUMN created_at SET NOT NULL;
+ALTER TABLE icons ADD COLUMN IF NOT EXISTS prompt TEXT;
+ALTER TABLE icons ADD COLUMN IF NOT EXISTS tags TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE icons ADD COLUMN IF NOT EXISTS phash BIGINT;
+ALTER TABLE icons ADD COLUMN IF NOT EXISTS prompt_template_id INTEGER REFERENCES prompt_templates(id);
+-- Generation parameters, enough to reproduce the image exactly
+ALTER TABLE icons ADD COLUMN IF NOT EXISTS seed BIGINT;
+ALTER TABLE icons ADD COLUMN IF NOT EXISTS aspect_ratio TEXT;
+ALTER TABLE icons ADD COLUMN IF NOT EXISTS negative_prompt TEXT;
+ALTER TABLE icons ADD COLUMN IF NOT EXISTS model TEXT;
+ALTER TABLE icons ADD COLUMN IF NOT EXISTS output_format TEXT;
+-- Variations are candidates of their source icon until kept or discarded
+ALTER TABLE icons ADD COLUMN IF NOT EXISTS source_icon_id INTEGER REFERENCES icons(id) ON DELETE SET NULL;
+ALTER TABLE icons ADD COLUMN IF NOT EXISTS candidate_status TEXT CHECK(candidate_status IN ('candidate', 'kept'));
+-- Dominant line thickness in pixels, measured on demand
+ALTER TABLE icons ADD COLUMN IF NOT EXISTS stroke_width REAL;
+ALTER TABLE icons ADD COLUMN IF NOT EXISTS search_vector TSVECTOR
+    GENERATED ALWAYS AS (icon_search_vector(metadata, tags, prompt)) STORED;
+
+CREATE INDEX IF NOT EXISTS icons_search_vector_idx ON icons USING GIN (search_vector);
+CREATE INDEX IF NOT EXISTS icons_tags_idx ON icons USING GIN (tags);
+
+-- Inkbucks ledger, double-entry: every ledger transaction has entries summing to zero and
+-- an account's balance is the sum of its entries. ledger_accounts.balance caches that sum,
+-- the reconciliation job checks that the two agree.
+CREATE TABLE IF NOT EXISTS ledger_accounts (
+    id SERIAL PRIMARY KEY,
+    -- Either a user's wallet, a workspace wallet (organization_id, added with the workspace
+    -- tables) or one of the named system accounts on the other side of them
+    user_id INTEGER UNIQUE REFERENCES users(id),
+    system TEXT UNIQUE,
+    balance INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    -- Wallets never go negative, system accounts do by design
+    CONSTRAINT ledger_accounts_wallet_balance_check CHECK (system IS NOT NULL OR balance >= 0)
+);
+
+INSERT INTO ledger_accounts (system)
+VALUES ('revenue'), ('signup_bonus'), ('purchases'), ('grants'), ('opening_balance'), ('promotions'), ('referrals')
+ON CONFLICT (system) DO NOTHING;
+
+CREATE TABLE IF NOT EXISTS ledger_transactions (
+    id SERIAL PRIMARY KEY,
+    type TEXT NOT NULL,
+    icon_id INTEGER REFERENCES icons(id) ON DELETE SET NULL,
+    -- External id of whatever caused it, e.g. a payment
+    reference TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS ledger_transactions_icon_id_idx ON ledger_transactions (icon_id);
+
+-- Replaced on every run so new transaction types reach existing databases
+ALTER TABLE ledger_transactions DROP CONSTRAINT IF EXISTS ledger_transactions_type_check;
+ALTER TABLE ledger_transactions ADD CONSTRAINT ledger_transactions_type_check CHECK(type IN (
+    'signup_bonus', 'purchase', 'grant', 'refund', 'opening_balance', 'promo', 'referral',
+    'transfer', 'generate', 'style', 'edit', 'upscale', 'export'
+));
+
+CREATE TABLE IF NOT EXISTS ledger_entries (
+    id SERIAL PRIMARY KEY,
+    transaction_id INTEGER NOT NULL REFERENCES ledger_transactions(id),
+    account_id INTEGER NOT NULL REFERENCES ledger_accounts(id),
+    amount INTEGER NOT NULL CHECK(amount <> 0)
+);
+
+CREATE INDEX IF NOT EXISTS ledger_entries_transaction_id_idx ON ledger_entries (transaction_id);
+CREATE INDEX IF NOT EXISTS ledger_entries_account_id_idx ON ledger_entries (account_id, id);
+
+-- Entries are append-only, corrections are new transactions
+CREATE OR REPLACE FUNCTION ledger_entries_append_only() RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION 'ledger entries cannot be changed or deleted';
+END
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS ledger_entries_append_only ON ledger_entries;
+CREATE TRIGGER ledger_entries_append_only BEFORE UPDATE OR DELETE ON ledger_entries
+    FOR EACH ROW EXECUTE FUNCTION ledger_entries_append_only();
+
+-- Checked at commit so all entries of a transaction can be inserted first
+CREATE OR REPLACE FUNCTION ledger_transaction_balanced() RETURNS TRIGGER AS $$
+BEGIN
+    IF (SELECT SUM(amount) FROM ledger_entries WHERE transaction_id = NEW.transaction_id) <> 0 THEN
+        RAISE EXCEPTION 'ledger transaction % does not balance', NEW.transaction_id;
+    END IF;
+    RETURN NULL;
+END
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS ledger_transaction_balanced ON ledger_entries;
+CREATE CONSTRAINT TRIGGER ledger_transaction_balanced AFTER INSERT ON ledger_entries
+    DEFERRABLE INITIALLY DEFERRED
+    FOR EACH ROW EXECUTE FUNCTION ledger_transaction_balanced();
+
+-- Moves databases from the users.inkbucks column and transactions table onto the ledger.
+-- Old transactions are carried over as they are and whatever they do not explain about a
+-- balance (e.g. the unrecorded signup bonus) becomes an opening_balance transaction.
+DO $$
+DECLARE
+    t RECORD;
+    tx_id INTEGER;
+BEGIN
+    IF NOT EXISTS (
+        SELECT 1 FROM information_schema.columns WHERE table_name = 'users' AND column_name = 'inkbucks'
+    ) THEN
+        RETURN;
+    END IF;
+
+    INSERT INTO ledger_accounts (user_id) SELECT id FROM users ON CONFLICT (user_id) DO NOTHING;
+
+    IF EXISTS (SELECT 1 FROM information_schema.tables WHERE table_name = 'transactions') THEN
+        FOR t IN SELECT * FROM transactions WHERE amount <> 0 ORDER BY id LOOP
+            INSERT INTO ledger_transactions (type, icon_id) VALUES (t.type, t.icon_id) RETURNING id INTO tx_id;
+            INSERT INTO ledger_entries (transaction_id, account_id, amount) VALUES
+                (tx_id, (SELECT id FROM ledger_accounts WHERE user_id = t.user_id), t.amount),
+                (tx_id, (SELECT id FROM ledger_accounts WHERE system = 'revenue'), -t.amount);
+        END LOOP;
+    END IF;
+
+    FOR t IN
+        SELECT a.id AS account_id, u.inkbucks - COALESCE(SUM(e.amount), 0) AS difference
+        FROM users u
+        JOIN ledger_accounts a ON a.user_id = u.id
+        LEFT JOIN ledger_entries e ON e.account_id = a.id
+        GROUP BY a.id, u.inkbucks
+        HAVING u.inkbucks - COALESCE(SUM(e.amount), 0) <> 0
+    LOOP
+        INSERT INTO ledger_transactions (type) VALUES ('opening_balance') RETURNING id INTO tx_id;
+        INSERT INTO ledger_entries (transaction_id, account_id, amount) VALUES
+            (tx_id, t.account_id, t.difference),
+            (tx_id, (SELECT id FROM ledger_accounts WHERE system = 'opening_balance'), -t.difference);
+    END LOOP;
+
+    UPDATE ledger_accounts a
+    SET balance = (SELECT COALESCE(SUM(amount), 0) FROM ledger_entries WHERE account_id = a.id);
+
+    DROP TABLE IF EXISTS transactions;
+    ALTER TABLE users DROP COLUMN inkbucks;
+END
+$$;
+
+-- Ledgers created before wallets had to stay non-negative. Wallets the old check-then-debit
+-- race overdrew are written back up to zero with an opening_balance transaction first.
+DO $$
+DECLARE
+    t RECORD;
+    tx_id INTEGER;
+BEGIN
+    IF EXISTS (
+        SELECT 1 FROM pg_constraint WHERE conname = 'ledger_accounts_wallet_balance_check'
+    ) THEN
+        RETURN;
+    END IF;
+
+    FOR t IN SELECT id, balance FROM ledger_accounts WHERE user_id IS NOT NULL AND balance < 0 LOOP
+        INSERT INTO ledger_transactions (type) VALUES ('opening_balance') RETURNING id INTO tx_id;
+        INSERT INTO ledger_entries (transaction_id, account_id, amount) VALUES
+            (tx_id, t.id, -t.balance),
+            (tx_id, (SELECT id FROM ledger_accounts WHERE system = 'opening_balance'), t.balance);
+        UPDATE ledger_accounts SET balance = 0 WHERE id = t.id;
+        UPDATE ledger_accounts SET balance = balance + t.balance WHERE system = 'opening_balance';
+    END LOOP;
+
+    ALTER TABLE ledger_accounts ADD CONSTRAINT ledger_accounts_wallet_balance_check
+        CHECK (system IS NOT NULL OR balance >= 0);
+END
+$$;
+
+-- Derived renditions of an icon (upscales, recolors, ...), the original stays untouched
+CREATE TABLE IF NOT EXISTS icon_versions (
     id SERIAL PRIMARY KEY,
-    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
-    type TEXT NOT NULL CHECK(type IN ('generate', 'style', 'edit')),
     icon_id INTEGER NOT NULL REFERENCES icons(id) ON DELETE CASCADE,
-    amount INTEGER NOT NULL
+    operation TEXT NOT NULL,
+    parameters JSONB NOT NULL DEFAULT '{}',
+    image_data BYTEA NOT NULL,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
 );
+
+CREATE INDEX IF NOT EXISTS icon_versions_icon_id_idx ON icon_versions (icon_id);
+
+-- Batches, one row per subject generated into a pack
+CREATE TABLE IF NOT EXISTS batches (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    icon_pack_id INTEGER NOT NULL REFERENCES icon_packs(id) ON DELETE CASCADE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS batch_items (
+    id SERIAL PRIMARY KEY,
+    batch_id INTEGER NOT NULL REFERENCES batches(id) ON DELETE CASCADE,
+    subject TEXT NOT NULL,
+    icon_id INTEGER REFERENCES icons(id) ON DELETE SET NULL,
+    status TEXT NOT NULL DEFAULT 'pending' CHECK(status IN ('pending', 'running', 'succeeded', 'failed')),
+    error TEXT
+);
+
+CREATE INDEX IF NOT EXISTS batch_items_batch_id_idx ON batch_items (batch_id);
+
+-- Items are a persistent queue: what to generate, what it cost, and a lease on running items
+-- so work lost in a restart is picked up again or refunded
+ALTER TABLE batch_items ADD COLUMN IF NOT EXISTS params JSONB;
+ALTER TABLE batch_items ADD COLUMN IF NOT EXISTS cost INTEGER;
+ALTER TABLE batch_items ADD COLUMN IF NOT EXISTS attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE batch_items ADD COLUMN IF NOT EXISTS started_at TIMESTAMP WITH TIME ZONE;
+
+CREATE INDEX IF NOT EXISTS batch_items_queue_idx ON batch_items (id) WHERE status IN ('pending', 'running');
+
+-- Outbound webhooks, every event sent to an endpoint is kept as a delivery for the log
+CREATE TABLE IF NOT EXISTS webhook_endpoints (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    events TEXT[] NOT NULL,
+    active BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS webhook_endpoints_user_id_idx ON webhook_endpoints (user_id);
+
+CREATE TABLE IF NOT EXISTS webhook_deliveries (
+    id SERIAL PRIMARY KEY,
+    endpoint_id INTEGER NOT NULL REFERENCES webhook_endpoints(id) ON DELETE CASCADE,
+    event TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending' CHECK(status IN ('pending', 'succeeded', 'failed')),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    response_status INTEGER,
+    last_error TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    delivered_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX IF NOT EXISTS webhook_deliveries_endpoint_id_idx ON webhook_deliveries (endpoint_id);
+CREATE INDEX IF NOT EXISTS webhook_deliveries_due_idx ON webhook_deliveries (next_attempt_at) WHERE status = 'pending';
+
+-- Inkbuck purchases, credited to the ledger once the payment provider confirms them
+CREATE TABLE IF NOT EXISTS payments (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    product TEXT NOT NULL,
+    inkbucks INTEGER NOT NULL,
+    amount_cents INTEGER NOT NULL,
+    currency TEXT NOT NULL,
+    provider TEXT NOT NULL,
+    provider_session_id TEXT UNIQUE,
+    status TEXT NOT NULL DEFAULT 'pending' CHECK(status IN ('pending', 'paid', 'failed')),
+    ledger_transaction_id INTEGER REFERENCES ledger_transactions(id),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    paid_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX IF NOT EXISTS payments_user_id_idx ON payments (user_id);
+
+-- Second line of defence against crediting a payment or a subscription period twice
+CREATE UNIQUE INDEX IF NOT EXISTS ledger_transactions_reference_idx
+    ON ledger_transactions (reference) WHERE reference IS NOT NULL;
+
+-- Plans, entitlements are feature flags checked by handlers, e.g. export_svg
+CREATE TABLE IF NOT EXISTS plans (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    monthly_inkbucks INTEGER NOT NULL DEFAULT 0,
+    price_cents INTEGER NOT NULL DEFAULT 0,
+    entitlements TEXT[] NOT NULL DEFAULT '{}',
+    -- Icons a subscriber can generate per UTC day, NULL for no limit
+    daily_generations INTEGER CHECK(daily_generations >= 0)
+);
+
+-- Plans created before daily quotas existed
+DO $$
+BEGIN
+    IF NOT EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_name = 'plans' AND column_name = 'daily_generations'
+    ) THEN
+        ALTER TABLE plans ADD COLUMN daily_generations INTEGER CHECK(daily_generations >= 0);
+        UPDATE plans SET daily_generations = 50 WHERE id = 'free';
+        UPDATE plans SET daily_generations = 500 WHERE id = 'pro';
+    END IF;
+END $$;
+
+INSERT INTO plans (id, name, monthly_inkbucks, price_cents, entitlements, daily_generations)
+VALUES
+    ('free', 'Free', 0, 0, '{export_png}', 50),
+    ('pro', 'Pro', 200, 1500, '{export_png,export_svg}', 500)
+ON CONFLICT (id) DO NOTHING;
+
+-- Every generation started, what daily quotas count. Append-only, unlike icons the rows stay
+-- when an icon is discarded or deleted.
+CREATE TABLE IF NOT EXISTS generations (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS generations_user_id_created_at_idx ON generations (user_id, created_at);
+
+CREATE TABLE IF NOT EXISTS subscriptions (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    plan_id TEXT NOT NULL REFERENCES plans(id),
+    status TEXT NOT NULL CHECK(status IN ('trialing', 'active', 'past_due', 'cancelled')),
+    provider TEXT NOT NULL,
+    provider_subscription_id TEXT UNIQUE,
+    current_period_start TIMESTAMP WITH TIME ZONE NOT NULL,
+    current_period_end TIMESTAMP WITH TIME ZONE NOT NULL,
+    trial_end TIMESTAMP WITH TIME ZONE,
+    cancel_at_period_end BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- At most one live subscription per user
+CREATE UNIQUE INDEX IF NOT EXISTS subscriptions_live_user_idx ON subscriptions (user_id) WHERE status <> 'cancelled';
+
+-- Idempotency-Key snapshots, a row without response_status is a request still running.
+-- Keys are scoped to the user, unauthenticated requests are not deduplicated.
+CREATE TABLE IF NOT EXISTS idempotency_keys (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    idempotency_key TEXT NOT NULL,
+    request_hash TEXT NOT NULL,
+    response_status INTEGER,
+    response_content_type TEXT,
+    response_body BYTEA,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+DELETE FROM idempotency_keys WHERE user_id IS NULL;
+ALTER TABLE idempotency_keys ALTER COLUMN user_id SET NOT NULL;
+DROP INDEX IF EXISTS idempotency_keys_scope_idx;
+CREATE UNIQUE INDEX IF NOT EXISTS idempotency_keys_user_key_idx ON idempotency_keys (user_id, idempotency_key);
+CREATE INDEX IF NOT EXISTS idempotency_keys_expires_at_idx ON idempotency_keys (expires_at);
+
+-- Promo codes, redeemed for a fixed amount of inkbucks from the promotions account
+CREATE TABLE IF NOT EXISTS promo_codes (
+    id SERIAL PRIMARY KEY,
+    code TEXT NOT NULL UNIQUE,
+    amount INTEGER NOT NULL CHECK(amount > 0),
+    -- Across all users, NULL for unlimited
+    max_redemptions INTEGER CHECK(max_redemptions > 0),
+    per_user_limit INTEGER NOT NULL DEFAULT 1 CHECK(per_user_limit > 0),
+    redemptions INTEGER NOT NULL DEFAULT 0,
+    expires_at TIMESTAMP WITH TIME ZONE,
+    active BOOLEAN NOT NULL DEFAULT TRUE,
+    created_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS promo_redemptions (
+    id SERIAL PRIMARY KEY,
+    promo_code_id INTEGER NOT NULL REFERENCES promo_codes(id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    ledger_transaction_id INTEGER REFERENCES ledger_transactions(id),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS promo_redemptions_code_user_idx ON promo_redemptions (promo_code_id, user_id);
+
+-- Every user's referral link code, created on first use
+CREATE TABLE IF NOT EXISTS referral_codes (
+    user_id INTEGER PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    code TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Who referred whom, both sides are credited once the referee pays for the first time
+CREATE TABLE IF NOT EXISTS referrals (
+    id SERIAL PRIMARY KEY,
+    referrer_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    referee_id INTEGER NOT NULL UNIQUE REFERENCES users(id) ON DELETE CASCADE,
+    status TEXT NOT NULL DEFAULT 'pending' CHECK(status IN ('pending', 'rewarded')),
+    payment_id INTEGER REFERENCES payments(id),
+    referrer_transaction_id INTEGER REFERENCES ledger_transactions(id),
+    referee_transaction_id INTEGER REFERENCES ledger_transactions(id),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    rewarded_at TIMESTAMP WITH TIME ZONE,
+    CHECK(referrer_id <> referee_id)
+);
+
+CREATE INDEX IF NOT EXISTS referrals_referrer_id_idx ON referrals (referrer_id);
+
+-- Inkbucks per operation. Rows without a plan are the defaults, rows with one override them
+-- for that plan's subscribers. Generating operations are scaled by the model multiplier.
+CREATE TABLE IF NOT EXISTS operation_prices (
+    id SERIAL PRIMARY KEY,
+    operation TEXT NOT NULL CHECK(operation IN (
+        'generate', 'style', 'edit', 'upscale', 'upscale_provider', 'variations', 'batch', 'export'
+    )),
+    plan_id TEXT REFERENCES plans(id) ON DELETE CASCADE,
+    cost INTEGER NOT NULL CHECK(cost >= 0)
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS operation_prices_operation_plan_idx ON operation_prices (operation, COALESCE(plan_id, ''));
+
+INSERT INTO operation_prices (operation, cost)
+VALUES
+    ('generate', 1),
+    ('style', 1),
+    ('edit', 1),
+    ('upscale', 1),
+    ('upscale_provider', 2),
+    ('variations', 1),
+    ('batch', 1),
+    ('export', 0)
+ON CONFLICT DO NOTHING;
+
+CREATE TABLE IF NOT EXISTS model_multipliers (
+    model TEXT PRIMARY KEY,
+    multiplier REAL NOT NULL CHECK(multiplier > 0)
+);
+
+INSERT INTO model_multipliers (model, multiplier)
+VALUES ('sd3', 1), ('sd3-turbo', 1), ('core', 1), ('ultra', 2)
+ON CONFLICT (model) DO NOTHING;
+
+-- Team workspaces, members share packs, icons and an inkbucks wallet
+CREATE TABLE IF NOT EXISTS organizations (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    created_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS organization_members (
+    organization_id INTEGER NOT NULL REFERENCES organizations(id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    role TEXT NOT NULL CHECK(role IN ('owner', 'admin', 'editor', 'viewer')),
+    -- Inkbucks the member may spend from the workspace wallet per UTC calendar month, NULL for no cap
+    monthly_spend_cap INTEGER CHECK(monthly_spend_cap >= 0),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (organization_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS organization_members_user_id_idx ON organization_members (user_id);
+
+CREATE TABLE IF NOT EXISTS organization_invitations (
+    id SERIAL PRIMARY KEY,
+    organization_id INTEGER NOT NULL REFERENCES organizations(id) ON DELETE CASCADE,
+    email TEXT NOT NULL,
+    role TEXT NOT NULL CHECK(role IN ('admin', 'editor', 'viewer')),
+    token TEXT NOT NULL UNIQUE,
+    invited_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    accepted_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    accepted_at TIMESTAMP WITH TIME ZONE
+);
+
+-- One open invitation per address and workspace
+CREATE UNIQUE INDEX IF NOT EXISTS organization_invitations_open_idx
+    ON organization_invitations (organization_id, lower(email)) WHERE accepted_at IS NULL;
+
+-- Who changed what about a workspace: members, roles, caps, invitations and deposits.
+-- Spending is in the ledger, ledger_transactions.actor_id says which member spent.
+CREATE TABLE IF NOT EXISTS organization_audit_log (
+    id SERIAL PRIMARY KEY,
+    organization_id INTEGER NOT NULL REFERENCES organizations(id) ON DELETE CASCADE,
+    actor_id INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    action TEXT NOT NULL,
+    details JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS organization_audit_log_organization_id_idx ON organization_audit_log (organization_id, id);
+
+-- Workspace packs and icons, NULL for personal ones
+ALTER TABLE icon_packs ADD COLUMN IF NOT EXISTS organization_id INTEGER REFERENCES organizations(id) ON DELETE CASCADE;
+ALTER TABLE icons ADD COLUMN IF NOT EXISTS organization_id INTEGER REFERENCES organizations(id) ON DELETE CASCADE;
+
+CREATE INDEX IF NOT EXISTS icon_packs_organization_id_idx ON icon_packs (organization_id);
+CREATE INDEX IF NOT EXISTS icons_organization_id_idx ON icons (organization_id);
+
+-- Workspace wallets
+ALTER TABLE ledger_accounts ADD COLUMN IF NOT EXISTS organization_id INTEGER UNIQUE REFERENCES organizations(id);
+
+-- Replaces the two-owner check of databases created before workspace wallets
+ALTER TABLE ledger_accounts DROP CONSTRAINT IF EXISTS ledger_accounts_check;
+ALTER TABLE ledger_accounts DROP CONSTRAINT IF EXISTS ledger_accounts_owner_check;
+ALTER TABLE ledger_accounts ADD CONSTRAINT ledger_accounts_owner_check
+    CHECK (num_nonnulls(user_id, organization_id, system) = 1);
+
+-- Workspace wallets may not go negative either. Databases from before workspaces carry the
+-- user-only version of this check under the same name, so it is always replaced.
+ALTER TABLE ledger_accounts DROP CONSTRAINT IF EXISTS ledger_accounts_wallet_balance_check;
+ALTER TABLE ledger_accounts ADD CONSTRAINT ledger_accounts_wallet_balance_check
+    CHECK (system IS NOT NULL OR balance >= 0);
+
+-- Who a ledger transaction was made by, the member spending from a workspace wallet
+ALTER TABLE ledger_transactions ADD COLUMN IF NOT EXISTS actor_id INTEGER REFERENCES users(id) ON DELETE SET NULL;
+
+-- Packs shared with individual users, 'edit' also lets them add and change icons
+CREATE TABLE IF NOT EXISTS pack_collaborators (
+    pack_id INTEGER NOT NULL REFERENCES icon_packs(id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    permission TEXT NOT NULL CHECK(permission IN ('view', 'edit')),
+    granted_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (pack_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS pack_collaborators_user_id_idx ON pack_collaborators (user_id);
+
+-- Read-only public links to a pack, optionally expiring and password protected (argon2 PHC string)
+CREATE TABLE IF NOT EXISTS pack_share_links (
+    id SERIAL PRIMARY KEY,
+    pack_id INTEGER NOT NULL REFERENCES icon_packs(id) ON DELETE CASCADE,
+    token TEXT NOT NULL UNIQUE,
+    password_hash TEXT,
+    expires_at TIMESTAMP WITH TIME ZONE,
+    created_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    revoked_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX IF NOT EXISTS pack_share_links_pack_id_idx ON pack_share_links (pack_id);
+
+-- Prompt settings a pack's icons are generated with, the defaults when generating into it
+ALTER TABLE icon_packs ADD COLUMN IF NOT EXISTS style JSONB;
+-- Gallery pack a pack was remixed from
+ALTER TABLE icon_packs ADD COLUMN IF NOT EXISTS remixed_from INTEGER REFERENCES icon_packs(id) ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS icon_packs_remixed_from_idx ON icon_packs (remixed_from);
+
+-- Public gallery of packs their owners chose to publish
+CREATE TABLE IF NOT EXISTS gallery_categories (
+    slug TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    position INTEGER NOT NULL DEFAULT 0
+);
+
+INSERT INTO gallery_categories (slug, name, position)
+VALUES
+    ('minimalist', 'Minimalist', 1),
+    ('bold', 'Bold', 2),
+    ('hand-drawn', 'Hand-Drawn', 3),
+    ('tech', 'Tech', 4),
+    ('animal', 'Animal', 5),
+    ('tools', 'Tools', 6)
+ON CONFLICT (slug) DO NOTHING;
+
+CREATE TABLE IF NOT EXISTS gallery_packs (
+    pack_id INTEGER PRIMARY KEY REFERENCES icon_packs(id) ON DELETE CASCADE,
+    category TEXT NOT NULL REFERENCES gallery_categories(slug),
+    description TEXT,
+    published_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    published_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS gallery_packs_category_idx ON gallery_packs (category, published_at);
+
+-- Unpublishing a pack drops its likes
+CREATE TABLE IF NOT EXISTS gallery_likes (
+    pack_id INTEGER NOT NULL REFERENCES gallery_packs(pack_id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (pack_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS gallery_likes_created_at_idx ON gallery_likes (created_at);
+
+-- Personal API tokens for programmatic access, stored as a SHA-256 of the secret
+CREATE TABLE IF NOT EXISTS api_tokens (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    prefix TEXT NOT NULL,
+    scopes TEXT[] NOT NULL CHECK (scopes <@ ARRAY['icons:read', 'icons:write', 'packs:export', 'billing:read']::TEXT[]),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP WITH TIME ZONE,
+    last_used_at TIMESTAMP WITH TIME ZONE,
+    revoked_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX IF NOT EXISTS api_tokens_user_id_idx ON api_tokens (user_id);
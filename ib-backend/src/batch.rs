@@ -0,0 +1,522 @@
+use std::collections::HashMap;
+use std::env;
+use std::time::Duration;
+
+use actix_web::{get, post, web, HttpResponse};
+use chrono::Utc;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    billing::{self, Wallet},
+    events,
+    handlers::{AppError, AuthenticatedUser},
+    jobs,
+    model::{normalize_tags, AppState},
+    organizations::Role,
+    packs::{find_pack, PackStyle},
+    pricing::{self, Operation, Pricing},
+    prompts::{self, PromptVariables},
+    provider::{GenerationParams, Model, RequestedParams},
+    ratelimit,
+    schema::{batch_items, batches, icon_packs, icons},
+};
+
+const MAX_BATCH_SIZE: usize = 100;
+/// Items the worker claims per round.
+const CLAIM_LIMIT: i64 = 100;
+/// Runs an item may start before it is given up on and refunded.
+const MAX_ATTEMPTS: i32 = 2;
+/// How often the worker looks for work and expired leases when nothing wakes it up.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+struct CreateBatch {
+    subjects: Vec<String>,
+    tags: Option<Vec<String>>,
+    template: Option<String>,
+    style: Option<String>,
+    stroke_weight: Option<String>,
+    palette: Option<String>,
+    #[serde(flatten)]
+    params: RequestedParams,
+}
+
+#[derive(Debug, Queryable, Serialize)]
+struct BatchItem {
+    id: i32,
+    subject: String,
+    icon_id: Option<i32>,
+    status: String,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct BatchProgress {
+    id: i32,
+    icon_pack_id: i32,
+    status: &'static str,
+    total: usize,
+    pending: usize,
+    running: usize,
+    succeeded: usize,
+    failed: usize,
+    items: Vec<BatchItem>,
+}
+
+/// How many items of one batch are in flight at once. The job runner bounds provider
+/// calls globally, this keeps a single large batch from taking every slot.
+fn batch_concurrency() -> usize {
+    env::var("BATCH_CONCURRENCY")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(2)
+}
+
+/// How long an item may run before it is considered lost, `BATCH_ITEM_LEASE_SECS`.
+fn item_lease() -> chrono::Duration {
+    chrono::Duration::seconds(
+        env::var("BATCH_ITEM_LEASE_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(600),
+    )
+}
+
+fn load_progress(
+    conn: &mut PgConnection,
+    user_id: i32,
+    batch_id: i32,
+) -> Result<BatchProgress, AppError> {
+    let (id, icon_pack_id): (i32, i32) = batches::table
+        .filter(batches::id.eq(batch_id))
+        .filter(batches::user_id.eq(user_id))
+        .select((batches::id, batches::icon_pack_id))
+        .first(conn)
+        .optional()?
+        .ok_or_else(|| AppError::NotFound("Batch not found".to_string()))?;
+
+    let items: Vec<BatchItem> = batch_items::table
+        .filter(batch_items::batch_id.eq(id))
+        .select((
+            batch_items::id,
+            batch_items::subject,
+            batch_items::icon_id,
+            batch_items::status,
+            batch_items::error,
+        ))
+        .order(batch_items::id.asc())
+        .load(conn)?;
+
+    let count = |status: &str| items.iter().filter(|item| item.status == status).count();
+    let (pending, running, succeeded, failed) =
+        (count("pending"), count("running"), count("succeeded"), count("failed"));
+    let status = if pending + running > 0 {
+        "running"
+    } else if failed > 0 {
+        "completed_with_errors"
+    } else {
+        "completed"
+    };
+
+    Ok(BatchProgress {
+        id,
+        icon_pack_id,
+        status,
+        total: items.len(),
+        pending,
+        running,
+        succeeded,
+        failed,
+        items,
+    })
+}
+
+/// Queues one icon per subject into a pack. The whole batch is paid for up front in a
+/// single transaction, the worker generates the items and refunds those that fail one by one.
+#[post("/packs/{id}/batch")]
+pub async fn create_batch(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<CreateBatch>,
+) -> Result<HttpResponse, AppError> {
+    let pack_id = path.into_inner();
+    let mut body = body.into_inner();
+
+    let subjects: Vec<String> = body.subjects
+        .iter()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+    if subjects.is_empty() || subjects.len() > MAX_BATCH_SIZE {
+        return Err(AppError::BadRequest(format!(
+            "A batch needs between 1 and {} subjects",
+            MAX_BATCH_SIZE
+        )));
+    }
+    let tags = normalize_tags(body.tags.as_deref().unwrap_or_default());
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let pack = find_pack(&mut conn, user.id, pack_id, Role::Editor)?;
+    let wallet = Wallet::of(user.id, pack.organization_id);
+    let pack_style = PackStyle::of(&pack)?;
+    body.template = body.template.or(pack_style.template);
+    body.style = body.style.or(pack_style.style);
+    body.stroke_weight = body.stroke_weight.or(pack_style.stroke_weight);
+    body.palette = body.palette.or(pack_style.palette);
+
+    let template_name = body.template.as_deref().unwrap_or(prompts::DEFAULT_TEMPLATE);
+    let template = prompts::select_template(&mut conn, template_name, user.id)?;
+
+    let mut planned = Vec::with_capacity(subjects.len());
+    for subject in subjects {
+        let prompt = template.render(&PromptVariables {
+            subject: subject.clone(),
+            style: body.style.clone(),
+            stroke_weight: body.stroke_weight.clone(),
+            palette: body.palette.clone(),
+        });
+        data.moderation.check_request(&prompt, &body.params).await?;
+        let params = GenerationParams::resolve(
+            prompt,
+            template.negative_prompt.as_deref(),
+            &template.parameters,
+            &body.params,
+        )?;
+        data.image_provider.validate(&params)?;
+        planned.push((subject, params));
+    }
+
+    let pricing = Pricing::for_wallet(&mut conn, wallet)?;
+    let costs = planned
+        .iter()
+        .map(|(_, params)| pricing.cost(Operation::Batch, Some(params.model)))
+        .collect::<Result<Vec<i32>, _>>()?;
+    let total_cost = costs.iter().sum();
+    let batch_id = conn.transaction(|conn| {
+        ratelimit::reserve_generations(conn, user.id, planned.len() as i64)?;
+        billing::charge(conn, wallet, Operation::Batch.transaction_type(), total_cost, None)?;
+
+        let batch_id: i32 = diesel::insert_into(batches::table)
+            .values((batches::user_id.eq(user.id), batches::icon_pack_id.eq(pack_id)))
+            .returning(batches::id)
+            .get_result(conn)?;
+
+        for ((subject, params), cost) in planned.into_iter().zip(costs) {
+            let icon_id: i32 = diesel::insert_into(icons::table)
+                .values((
+                    icons::user_id.eq(user.id),
+                    icons::icon_pack_id.eq(pack_id),
+                    icons::metadata.eq(&subject),
+                    icons::image_data.eq(Vec::<u8>::new()),
+                    icons::prompt.eq(&params.prompt),
+                    icons::tags.eq(&tags),
+                    icons::prompt_template_id.eq(template.id),
+                    icons::seed.eq(params.seed.map(i64::from)),
+                    icons::aspect_ratio.eq(&params.aspect_ratio),
+                    icons::negative_prompt.eq(&params.negative_prompt),
+                    icons::model.eq(params.model.as_str()),
+                    icons::output_format.eq(params.output_format.as_str()),
+                    icons::organization_id.eq(pack.organization_id),
+                ))
+                .returning(icons::id)
+                .get_result(conn)?;
+
+            let params = serde_json::to_value(&params)
+                .map_err(|e| AppError::InternalServerError(e.to_string()))?;
+            diesel::insert_into(batch_items::table)
+                .values((
+                    batch_items::batch_id.eq(batch_id),
+                    batch_items::subject.eq(&subject),
+                    batch_items::icon_id.eq(icon_id),
+                    batch_items::params.eq(params),
+                    batch_items::cost.eq(cost),
+                ))
+                .execute(conn)?;
+        }
+        Ok::<_, AppError>(batch_id)
+    })?;
+    events::balance_changed(&data, user.id);
+    data.jobs.batches.notify_one();
+
+    let progress = load_progress(&mut conn, user.id, batch_id)?;
+    Ok(HttpResponse::Accepted().json(serde_json::json!({
+        "status": "success",
+        "data": { "batch": progress }
+    })))
+}
+
+#[derive(Queryable)]
+struct QueuedItem {
+    id: i32,
+    batch_id: i32,
+    user_id: i32,
+    organization_id: Option<i32>,
+    icon_id: Option<i32>,
+}
+
+#[derive(Queryable)]
+struct StaleItem {
+    id: i32,
+    attempts: i32,
+    user_id: i32,
+    organization_id: Option<i32>,
+    icon_id: Option<i32>,
+    cost: Option<i32>,
+    has_params: bool,
+}
+
+/// A claimed item, `attempt` is the lease: whatever the run records afterwards only lands
+/// while the item is still running under that attempt.
+struct ClaimedItem {
+    id: i32,
+    attempt: i32,
+    icon_id: i32,
+    params: GenerationParams,
+    cost: i32,
+    wallet: Wallet,
+}
+
+/// Works the batch queue: claims pending items, at most `batch_concurrency()` of a batch at
+/// once, and generates them. Items survive restarts in `batch_items`, running items whose
+/// lease ran out are picked up again on startup and every round after.
+pub async fn run_worker(state: AppState) {
+    loop {
+        if let Err(e) = recover_stale(&state) {
+            println!("Batch recovery failed: {}", e);
+        }
+        match claim(&state) {
+            Ok(claimed) => {
+                for item in claimed {
+                    let state = state.clone();
+                    actix_web::rt::spawn(async move { run_item(&state, item).await });
+                }
+            }
+            Err(e) => println!("Claiming batch items failed: {}", e),
+        }
+        let _ = tokio::time::timeout(POLL_INTERVAL, state.jobs.batches.notified()).await;
+    }
+}
+
+fn claim(state: &AppState) -> Result<Vec<ClaimedItem>, AppError> {
+    let mut conn = state.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let mut running: HashMap<i32, i64> = batch_items::table
+        .filter(batch_items::status.eq("running"))
+        .group_by(batch_items::batch_id)
+        .select((batch_items::batch_id, diesel::dsl::count_star()))
+        .load::<(i32, i64)>(&mut conn)?
+        .into_iter()
+        .collect();
+    let pending: Vec<QueuedItem> = batch_items::table
+        .inner_join(batches::table.inner_join(icon_packs::table))
+        .filter(batch_items::status.eq("pending"))
+        .filter(batch_items::params.is_not_null())
+        .select((
+            batch_items::id,
+            batch_items::batch_id,
+            batches::user_id,
+            icon_packs::organization_id,
+            batch_items::icon_id,
+        ))
+        .order(batch_items::id.asc())
+        .limit(CLAIM_LIMIT)
+        .load(&mut conn)?;
+
+    let mut claimed = Vec::new();
+    for QueuedItem { id, batch_id, user_id, organization_id, icon_id } in pending {
+        let in_flight = running.entry(batch_id).or_default();
+        if *in_flight >= batch_concurrency() as i64 {
+            continue;
+        }
+        // Another instance may have claimed it in the meantime.
+        let row: Option<(i32, Option<serde_json::Value>, Option<i32>)> = diesel::update(
+            batch_items::table
+                .filter(batch_items::id.eq(id))
+                .filter(batch_items::status.eq("pending")),
+        )
+        .set((
+            batch_items::status.eq("running"),
+            batch_items::attempts.eq(batch_items::attempts + 1),
+            batch_items::started_at.eq(Utc::now()),
+        ))
+        .returning((batch_items::attempts, batch_items::params, batch_items::cost))
+        .get_result(&mut conn)
+        .optional()?;
+        let Some((attempt, Some(params), cost)) = row else {
+            continue;
+        };
+        *in_flight += 1;
+        let wallet = Wallet::of(user_id, organization_id);
+        let cost = cost.unwrap_or(0);
+        let params = serde_json::from_value(params);
+        let refunded = match (icon_id, params) {
+            (Some(icon_id), Ok(params)) => {
+                claimed.push(ClaimedItem { id, attempt, icon_id, params, cost, wallet });
+                continue;
+            }
+            (_, Err(e)) => refund_item(state, wallet, id, attempt, icon_id, cost, &e.to_string()),
+            (None, _) => refund_item(state, wallet, id, attempt, None, cost, "The icon was deleted"),
+        };
+        // The items claimed so far must still start, `recover_stale` retries this one.
+        if let Err(e) = refunded {
+            println!("Refunding batch item {} failed: {}", id, e);
+        }
+    }
+    Ok(claimed)
+}
+
+/// Running items whose lease ran out, e.g. because the instance running them restarted,
+/// go back to the queue until they used up their attempts and are refunded. Items queued
+/// before the queue was persisted carry no parameters and are refunded straight away.
+fn recover_stale(state: &AppState) -> Result<(), AppError> {
+    let mut conn = state.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let lease_expired = Utc::now() - item_lease();
+    let stale: Vec<StaleItem> = batch_items::table
+        .inner_join(batches::table.inner_join(icon_packs::table))
+        .filter(
+            batch_items::status.eq("running").and(batch_items::started_at.lt(lease_expired))
+                .or(batch_items::status.eq_any(["pending", "running"]).and(batch_items::params.is_null())),
+        )
+        .select((
+            batch_items::id,
+            batch_items::attempts,
+            batches::user_id,
+            icon_packs::organization_id,
+            batch_items::icon_id,
+            batch_items::cost,
+            batch_items::params.is_not_null(),
+        ))
+        .load(&mut conn)?;
+
+    for StaleItem { id, attempts, user_id, organization_id, icon_id, cost, has_params } in stale {
+        let wallet = Wallet::of(user_id, organization_id);
+        if has_params && attempts < MAX_ATTEMPTS {
+            diesel::update(
+                batch_items::table
+                    .filter(batch_items::id.eq(id))
+                    .filter(batch_items::attempts.eq(attempts)),
+            )
+            .set(batch_items::status.eq("pending"))
+            .execute(&mut conn)?;
+            continue;
+        }
+        let cost = match cost {
+            Some(cost) => cost,
+            None => legacy_cost(&mut conn, wallet, icon_id)?,
+        };
+        if let Err(e) = refund_item(state, wallet, id, attempts, icon_id, cost, "Generation was interrupted") {
+            println!("Refunding batch item {} failed: {}", id, e);
+        }
+    }
+    Ok(())
+}
+
+/// What an item queued before costs were stored was charged, at today's batch price.
+fn legacy_cost(conn: &mut PgConnection, wallet: Wallet, icon_id: Option<i32>) -> Result<i32, AppError> {
+    let model: Option<String> = match icon_id {
+        Some(icon_id) => icons::table
+            .filter(icons::id.eq(icon_id))
+            .select(icons::model)
+            .first::<Option<String>>(conn)
+            .optional()?
+            .flatten(),
+        None => None,
+    };
+    pricing::cost(conn, wallet, Operation::Batch, model.as_deref().and_then(Model::parse))
+}
+
+async fn run_item(state: &AppState, item: ClaimedItem) {
+    let result = jobs::generate_icon(state, item.wallet.actor(), item.icon_id, &item.params).await;
+    let outcome = match result {
+        Ok(()) => finish_item(state, item.id, item.attempt),
+        Err(e) => refund_item(
+            state,
+            item.wallet,
+            item.id,
+            item.attempt,
+            Some(item.icon_id),
+            item.cost,
+            &e.to_string(),
+        ),
+    };
+    if let Err(e) = outcome {
+        println!("Failed to record batch item {}: {}", item.id, e);
+    }
+    state.jobs.batches.notify_one();
+}
+
+/// Only the current attempt of an item may record its outcome.
+fn leased(item_id: i32, attempt: i32) -> batch_items::BoxedQuery<'static, Pg> {
+    batch_items::table
+        .filter(batch_items::id.eq(item_id))
+        .filter(batch_items::attempts.eq(attempt))
+        .filter(batch_items::status.eq_any(["pending", "running"]))
+        .into_boxed()
+}
+
+fn finish_item(state: &AppState, item_id: i32, attempt: i32) -> Result<(), AppError> {
+    let mut conn = state.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    diesel::update(batch_items::table)
+        .filter(batch_items::id.eq_any(leased(item_id, attempt).select(batch_items::id)))
+        .set(batch_items::status.eq("succeeded"))
+        .execute(&mut conn)?;
+    Ok(())
+}
+
+/// Marks an item failed, drops its placeholder icon and gives its share of the batch back,
+/// unless another attempt took the item over in the meantime.
+fn refund_item(
+    state: &AppState,
+    wallet: Wallet,
+    item_id: i32,
+    attempt: i32,
+    icon_id: Option<i32>,
+    cost: i32,
+    error: &str,
+) -> Result<(), AppError> {
+    let mut conn = state.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let failed = conn.transaction(|conn| {
+        let updated = diesel::update(batch_items::table)
+            .filter(batch_items::id.eq_any(leased(item_id, attempt).select(batch_items::id)))
+            .set((batch_items::status.eq("failed"), batch_items::error.eq(error)))
+            .execute(conn)?;
+        if updated == 0 {
+            return Ok(false);
+        }
+        if let Some(icon_id) = icon_id {
+            diesel::delete(icons::table.filter(icons::id.eq(icon_id))).execute(conn)?;
+        }
+        billing::refund(conn, wallet, cost, None)?;
+        Ok::<_, AppError>(true)
+    })?;
+    if failed {
+        if let Some(icon_id) = icon_id {
+            state.phash_index.remove(icon_id);
+        }
+        events::balance_changed(state, wallet.actor());
+    }
+    Ok(())
+}
+
+#[get("/batches/{id}")]
+pub async fn get_batch(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let progress = load_progress(&mut conn, user.id, path.into_inner())?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "batch": progress }
+    })))
+}
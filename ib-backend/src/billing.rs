@@ -0,0 +1,226 @@
+use diesel::prelude::*;
+
+use crate::{
+    handlers::AppError,
+    ledger,
+    model::TransactionType,
+    organizations,
+    schema::{ledger_accounts, ledger_entries, ledger_transactions},
+};
+
+/// Inkbucks every new account starts with.
+pub const SIGNUP_BONUS: i32 = 5;
+
+/// Wallet an operation is paid from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wallet {
+    User(i32),
+    /// A workspace's shared wallet, drawn on by one of its members.
+    Organization { id: i32, member_id: i32 },
+}
+
+impl Wallet {
+    /// The workspace wallet for workspace icons and packs, the user's own otherwise.
+    pub fn of(user_id: i32, organization_id: Option<i32>) -> Wallet {
+        match organization_id {
+            Some(id) => Wallet::Organization { id, member_id: user_id },
+            None => Wallet::User(user_id),
+        }
+    }
+
+    /// The user spending from the wallet.
+    pub fn actor(&self) -> i32 {
+        match self {
+            Wallet::User(user_id) => *user_id,
+            Wallet::Organization { member_id, .. } => *member_id,
+        }
+    }
+
+    fn account(&self, conn: &mut PgConnection) -> Result<i32, AppError> {
+        match self {
+            Wallet::User(user_id) => ledger::user_account(conn, *user_id),
+            Wallet::Organization { id, .. } => ledger::organization_account(conn, *id),
+        }
+    }
+}
+
+/// Takes `amount` inkbucks from a wallet, failing with `InsufficientInkbucks` when the
+/// wallet does not cover it. The balance check and the debit are one statement in
+/// `ledger::post`, so parallel charges cannot both spend the same inkbucks. Workspace
+/// members also have to be allowed to spend and stay within their monthly cap. Must run
+/// inside the caller's database transaction so the charge rolls back with whatever it pays
+/// for. Returns the ledger transaction id, `None` for free operations which are not recorded.
+pub fn charge(
+    conn: &mut PgConnection,
+    wallet: Wallet,
+    kind: TransactionType,
+    amount: i32,
+    icon_id: Option<i32>,
+) -> Result<Option<i32>, AppError> {
+    if amount == 0 {
+        return Ok(None);
+    }
+    if let Wallet::Organization { id, member_id } = wallet {
+        organizations::check_spend(conn, id, member_id, amount)?;
+    }
+    let account = wallet.account(conn)?;
+    let revenue = ledger::counterparty_account(conn, kind)?;
+    ledger::post(conn, kind, icon_id, None, Some(wallet.actor()), &[(account, -amount), (revenue, amount)])
+        .map(Some)
+}
+
+/// Fails like `charge` would if the wallet cannot pay `amount` right now, without taking
+/// anything. For work that has to happen before the charge can be made, e.g. a provider call
+/// whose result is paid for together with storing it. A parallel spend can still get in
+/// between, the charge afterwards remains the real check.
+pub fn ensure_affordable(conn: &mut PgConnection, wallet: Wallet, amount: i32) -> Result<(), AppError> {
+    if amount == 0 {
+        return Ok(());
+    }
+    let balance = match wallet {
+        Wallet::User(user_id) => ledger::balance(conn, user_id)?,
+        Wallet::Organization { id, member_id } => {
+            conn.transaction(|conn| organizations::check_spend(conn, id, member_id, amount))?;
+            ledger::organization_balance(conn, id)?
+        }
+    };
+    if balance < amount {
+        return Err(AppError::InsufficientInkbucks);
+    }
+    Ok(())
+}
+
+/// Gives `amount` inkbucks back to the wallet that paid, e.g. for a generation that failed
+/// after it was paid for. Nothing to give back for free operations, `None` then.
+pub fn refund(
+    conn: &mut PgConnection,
+    wallet: Wallet,
+    amount: i32,
+    icon_id: Option<i32>,
+) -> Result<Option<i32>, AppError> {
+    if amount == 0 {
+        return Ok(None);
+    }
+    let account = wallet.account(conn)?;
+    let revenue = ledger::counterparty_account(conn, TransactionType::Refund)?;
+    ledger::post(
+        conn,
+        TransactionType::Refund,
+        icon_id,
+        None,
+        Some(wallet.actor()),
+        &[(revenue, -amount), (account, amount)],
+    )
+    .map(Some)
+}
+
+/// Adds inkbucks to a user's wallet from the system account that matches `kind`, e.g. the
+/// signup bonus or a purchase identified by `reference`.
+pub fn credit(
+    conn: &mut PgConnection,
+    user_id: i32,
+    kind: TransactionType,
+    amount: i32,
+    icon_id: Option<i32>,
+    reference: Option<&str>,
+) -> Result<i32, AppError> {
+    let wallet = ledger::user_account(conn, user_id)?;
+    let source = ledger::counterparty_account(conn, kind)?;
+    ledger::post(conn, kind, icon_id, reference, None, &[(source, -amount), (wallet, amount)])
+}
+
+/// What generating an icon was charged, as a positive amount. Recolors, upscales and other
+/// edits paid for on the icon afterwards are not included. Generation refunds delete the icon
+/// together with the refund, so there is never an earlier refund to net out.
+pub fn charged_for_generation(conn: &mut PgConnection, icon_id: i32) -> Result<i32, AppError> {
+    let amount: Option<i64> = ledger_entries::table
+        .inner_join(ledger_transactions::table)
+        .inner_join(ledger_accounts::table)
+        .filter(ledger_transactions::icon_id.eq(icon_id))
+        .filter(ledger_transactions::type_.eq(TransactionType::Generate.as_str()))
+        .filter(ledger_accounts::system.is_null())
+        .select(diesel::dsl::sum(ledger_entries::amount))
+        .first(conn)?;
+    let net = amount.unwrap_or(0) as i32;
+    Ok((-net).max(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    use super::*;
+    use crate::testing::{self, pool};
+
+    /// A new user granted `inkbucks`.
+    fn user_with(conn: &mut PgConnection, inkbucks: i32) -> i32 {
+        let user_id = testing::user(conn);
+        conn.transaction(|conn| credit(conn, user_id, TransactionType::Grant, inkbucks, None, None))
+            .unwrap();
+        user_id
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn parallel_charges_and_refunds_do_not_deadlock() {
+        const THREADS: usize = 16;
+        const ROUNDS: usize = 25;
+        let pool = pool(THREADS as u32);
+        let user_id = user_with(&mut pool.get().unwrap(), 100);
+        let wallet = Wallet::User(user_id);
+
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let pool = pool.clone();
+                thread::spawn(move || {
+                    let mut conn = pool.get().unwrap();
+                    for _ in 0..ROUNDS {
+                        conn.transaction(|conn| charge(conn, wallet, TransactionType::Generate, 1, None))?;
+                        conn.transaction(|conn| refund(conn, wallet, 1, None))?;
+                    }
+                    Ok::<_, AppError>(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+
+        assert_eq!(ledger::balance(&mut pool.get().unwrap(), user_id).unwrap(), 100);
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn parallel_charges_never_overdraw() {
+        const THREADS: usize = 32;
+        const BALANCE: i32 = 10;
+        let pool = pool(THREADS as u32);
+        let user_id = user_with(&mut pool.get().unwrap(), BALANCE);
+        let wallet = Wallet::User(user_id);
+        let start = Arc::new(Barrier::new(THREADS));
+
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let pool = pool.clone();
+                let start = start.clone();
+                thread::spawn(move || {
+                    let mut conn = pool.get().unwrap();
+                    start.wait();
+                    conn.transaction(|conn| charge(conn, wallet, TransactionType::Generate, 1, None))
+                })
+            })
+            .collect();
+        let mut charged = 0;
+        for handle in handles {
+            match handle.join().unwrap() {
+                Ok(_) => charged += 1,
+                Err(AppError::InsufficientInkbucks) => {}
+                Err(e) => panic!("charge failed: {}", e),
+            }
+        }
+
+        assert_eq!(charged, BALANCE);
+        assert_eq!(ledger::balance(&mut pool.get().unwrap(), user_id).unwrap(), 0);
+    }
+}
@@ -0,0 +1,287 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use futures::StreamExt;
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+use crate::{
+    handlers::{AppError, AuthenticatedUser},
+    ledger,
+    model::AppState,
+    webhooks,
+};
+
+/// Events kept for `Last-Event-ID` replay across all users.
+const HISTORY_SIZE: usize = 1024;
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Debug)]
+pub struct Event {
+    pub id: u64,
+    pub user_id: i32,
+    pub kind: &'static str,
+    pub data: Value,
+}
+
+impl Event {
+    fn to_sse(&self) -> String {
+        format!("id: {}\nevent: {}\ndata: {}\n\n", self.id, self.kind, self.data)
+    }
+}
+
+/// In-process fan-out of per-user events with a short replay buffer.
+pub struct EventBus {
+    next_id: AtomicU64,
+    sender: broadcast::Sender<Arc<Event>>,
+    history: Mutex<VecDeque<Arc<Event>>>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(HISTORY_SIZE);
+        // Ids start at the current time so they keep increasing across restarts and a
+        // stale Last-Event-ID never hides new events.
+        let start = chrono::Utc::now().timestamp_millis() as u64 * 1000;
+        EventBus {
+            next_id: AtomicU64::new(start),
+            sender,
+            history: Mutex::new(VecDeque::with_capacity(HISTORY_SIZE)),
+        }
+    }
+}
+
+impl EventBus {
+    pub fn publish(&self, user_id: i32, kind: &'static str, data: Value) {
+        let event = Arc::new(Event {
+            id: self.next_id.fetch_add(1, Ordering::SeqCst),
+            user_id,
+            kind,
+            data,
+        });
+        {
+            let mut history = self.history.lock().unwrap();
+            if history.len() == HISTORY_SIZE {
+                history.pop_front();
+            }
+            history.push_back(event.clone());
+        }
+        // No receivers just means nobody is listening right now.
+        let _ = self.sender.send(event);
+    }
+
+    fn replay(&self, user_id: i32, after: u64) -> Vec<Arc<Event>> {
+        self.history
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| event.user_id == user_id && event.id > after)
+            .cloned()
+            .collect()
+    }
+
+    /// The user's events after `last_event_id` followed by live ones. Ends when the
+    /// receiver falls behind the channel, the client then reconnects with `Last-Event-ID`
+    /// and gets what it missed from the history.
+    fn stream(
+        &self,
+        user_id: i32,
+        last_event_id: Option<u64>,
+    ) -> impl futures::Stream<Item = Result<web::Bytes, actix_web::Error>> {
+        // Subscribe before reading the history so nothing published in between is lost.
+        let receiver = self.sender.subscribe();
+        let missed = match last_event_id {
+            Some(after) => self.replay(user_id, after),
+            None => Vec::new(),
+        };
+        let last_sent = missed.last().map(|event| event.id).or(last_event_id).unwrap_or(0);
+
+        let replay = futures::stream::iter(
+            missed.into_iter().map(|event| Ok::<_, actix_web::Error>(web::Bytes::from(event.to_sse()))),
+        );
+
+        let live = futures::stream::unfold((receiver, last_sent), move |(mut receiver, mut last_sent)| async move {
+            loop {
+                match tokio::time::timeout(KEEP_ALIVE, receiver.recv()).await {
+                    Err(_) => {
+                        let chunk = web::Bytes::from_static(b": keep-alive\n\n");
+                        return Some((Ok::<_, actix_web::Error>(chunk), (receiver, last_sent)));
+                    }
+                    // Events already sent during replay may arrive again live, skip them by id.
+                    Ok(Ok(event)) if event.user_id == user_id && event.id > last_sent => {
+                        last_sent = event.id;
+                        return Some((Ok(web::Bytes::from(event.to_sse())), (receiver, last_sent)));
+                    }
+                    Ok(Ok(_)) => continue,
+                    // Fell behind the channel. Carrying on would silently drop events, ending the
+                    // stream makes the client reconnect and replay them through Last-Event-ID.
+                    Ok(Err(broadcast::error::RecvError::Lagged(_)))
+                    | Ok(Err(broadcast::error::RecvError::Closed)) => return None,
+                }
+            }
+        });
+
+        replay.chain(live)
+    }
+}
+
+/// Publishes the user's current balance. Call after the transaction that changed it has
+/// committed, so listeners never see a balance that was rolled back.
+pub fn balance_changed(state: &AppState, user_id: i32) {
+    let balance = state.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))
+        .and_then(|mut conn| ledger::balance(&mut conn, user_id));
+    match balance {
+        Ok(inkbucks) => {
+            state.events.publish(user_id, "balance.changed", serde_json::json!({ "inkbucks": inkbucks }));
+            webhooks::check_balance(state, user_id, inkbucks);
+        }
+        Err(e) => println!("Failed to publish balance of user {}: {}", user_id, e),
+    }
+}
+
+/// Server-Sent Events stream of the user's job progress and balance changes. Reconnecting
+/// clients send `Last-Event-ID` and first receive whatever they missed.
+#[get("/events")]
+pub async fn stream_events(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let last_event_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(data.events.stream(user.id, last_event_id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::prelude::*;
+
+    use crate::{
+        jobs,
+        provider::{GenerationParams, MockProvider, Model, OutputFormat},
+        schema::{icons, users},
+        testing,
+    };
+
+    /// Id and kind of every event in the chunks, keep-alives skipped.
+    fn parse(chunks: &[web::Bytes]) -> Vec<(u64, String)> {
+        chunks
+            .iter()
+            .filter_map(|chunk| {
+                let text = std::str::from_utf8(chunk).unwrap();
+                let mut lines = text.lines();
+                let id = lines.next()?.strip_prefix("id: ")?.parse().ok()?;
+                let kind = lines.next()?.strip_prefix("event: ")?.to_string();
+                Some((id, kind))
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn lagging_stream_ends_and_replays_after_reconnect() {
+        let bus = EventBus::default();
+        let stream = bus.stream(1, None);
+        for n in 0..=HISTORY_SIZE {
+            bus.publish(1, "test", serde_json::json!({ "n": n }));
+        }
+        let chunks: Vec<_> = stream.map(Result::unwrap).collect().await;
+        assert!(chunks.is_empty(), "a lagging stream must end instead of skipping events");
+
+        // The oldest event fell out of the history, everything after the next one replays.
+        let kept = bus.replay(1, 0);
+        assert_eq!(kept.len(), HISTORY_SIZE);
+        let chunks: Vec<_> = bus
+            .stream(1, Some(kept[0].id))
+            .take(HISTORY_SIZE - 1)
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        let ids: Vec<u64> = parse(&chunks).into_iter().map(|(id, _)| id).collect();
+        let expected: Vec<u64> = kept[1..].iter().map(|event| event.id).collect();
+        assert_eq!(ids, expected);
+    }
+
+    #[tokio::test]
+    async fn streams_only_the_users_own_events() {
+        let bus = EventBus::default();
+        let stream = bus.stream(1, None);
+        bus.publish(2, "job.queued", serde_json::json!({}));
+        bus.publish(1, "balance.changed", serde_json::json!({}));
+        let chunks: Vec<_> = stream.take(1).map(Result::unwrap).collect().await;
+        assert_eq!(parse(&chunks)[0].1, "balance.changed");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    async fn mock_generation_reports_progress() {
+        let state = AppState {
+            image_provider: Arc::new(MockProvider::from_env()),
+            ..AppState::init().await
+        };
+        let mut conn = state.db_pool.get().unwrap();
+        let user_id = testing::user(&mut conn);
+        let icon_id: i32 = diesel::insert_into(icons::table)
+            .values((
+                icons::user_id.eq(user_id),
+                icons::metadata.eq("cat"),
+                icons::image_data.eq(Vec::<u8>::new()),
+            ))
+            .returning(icons::id)
+            .get_result(&mut conn)
+            .unwrap();
+
+        let params = GenerationParams {
+            prompt: "cat".to_string(),
+            negative_prompt: None,
+            seed: Some(7),
+            aspect_ratio: "1:1".to_string(),
+            model: Model::Sd3,
+            output_format: OutputFormat::Png,
+            extra: serde_json::Map::new(),
+            init_image: None,
+            strength: None,
+        };
+        let stream = state.events.stream(user_id, None);
+        let result = jobs::generate_icon(&state, user_id, icon_id, &params).await;
+
+        let chunks: Vec<_> = stream.take(6).map(Result::unwrap).collect().await;
+        let events = parse(&chunks);
+        // Reconnecting after the third event only replays the rest.
+        let replayed: Vec<_> = state
+            .events
+            .stream(user_id, Some(events[2].0))
+            .take(3)
+            .map(Result::unwrap)
+            .collect()
+            .await;
+
+        diesel::delete(users::table.filter(users::id.eq(user_id)))
+            .execute(&mut conn)
+            .unwrap();
+        result.unwrap();
+
+        let kinds: Vec<&str> = events.iter().map(|(_, kind)| kind.as_str()).collect();
+        assert_eq!(kinds, [
+            "job.queued",
+            "job.started",
+            "job.provider_responded",
+            "job.postprocess",
+            "job.postprocess",
+            "job.finished",
+        ]);
+        assert_eq!(parse(&replayed), events[3..]);
+    }
+}
@@ -0,0 +1,685 @@
+use std::collections::HashMap;
+use std::env;
+
+use actix_web::{get, post, web, HttpResponse};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::{Array, BigInt, Bool, Int4, Nullable, Text, Timestamptz};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    handlers::{AppError, AuthenticatedUser},
+    model::{AppState, IconPack},
+    organizations::{self, Role},
+    packs::{self, find_pack, PackStyle},
+    provider::OutputFormat,
+    schema::{gallery_categories, gallery_likes, gallery_packs, icon_packs, icons},
+};
+
+const DEFAULT_LIMIT: i64 = 24;
+const MAX_LIMIT: i64 = 100;
+const PREVIEW_ICONS: i64 = 4;
+const MAX_DESCRIPTION_LENGTH: usize = 500;
+
+/// Finished icons of the gallery pack `g`, kept variations included, what the gallery shows
+/// of a pack.
+const PUBLISHED_ICONS: &str = "\
+    i.icon_pack_id = g.pack_id AND i.candidate_status IS DISTINCT FROM 'candidate' AND octet_length(i.image_data) > 0";
+
+/// $1 category (NULL for all), $2 trending window in days, $3 viewer (NULL when signed out),
+/// $4 pack id (NULL for all).
+fn entries_query() -> String {
+    format!(
+        "SELECT g.pack_id, p.name, g.category, g.description, u.username AS author, g.published_at, \
+            (SELECT COUNT(*) FROM gallery_likes l WHERE l.pack_id = g.pack_id) AS likes, \
+            (SELECT COUNT(*) FROM gallery_likes l WHERE l.pack_id = g.pack_id \
+                AND l.created_at > NOW() - make_interval(days => $2)) AS recent_likes, \
+            (SELECT COUNT(*) FROM icon_packs r WHERE r.remixed_from = g.pack_id) AS remixes, \
+            (SELECT COUNT(*) FROM icons i WHERE {icons}) AS icon_count, \
+            ARRAY(SELECT i.id FROM icons i WHERE {icons} ORDER BY i.id LIMIT {preview}) AS preview_ids, \
+            EXISTS (SELECT 1 FROM gallery_likes l WHERE l.pack_id = g.pack_id AND l.user_id = $3) AS liked \
+         FROM gallery_packs g \
+         JOIN icon_packs p ON p.id = g.pack_id \
+         LEFT JOIN users u ON u.id = g.published_by \
+         WHERE ($1::TEXT IS NULL OR g.category = $1) AND ($4::INT IS NULL OR g.pack_id = $4)",
+        icons = PUBLISHED_ICONS,
+        preview = PREVIEW_ICONS,
+    )
+}
+
+/// Days of likes that count towards trending, `GALLERY_TRENDING_DAYS` (7 by default).
+fn trending_days() -> i32 {
+    env::var("GALLERY_TRENDING_DAYS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(7)
+        .max(1)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Sort {
+    /// Most liked recently.
+    Trending,
+    New,
+    /// Most liked ever.
+    Top,
+}
+
+impl Sort {
+    fn parse(s: &str) -> Option<Sort> {
+        match s {
+            "trending" => Some(Sort::Trending),
+            "new" => Some(Sort::New),
+            "top" => Some(Sort::Top),
+            _ => None,
+        }
+    }
+
+    fn order_by(&self) -> &'static str {
+        match self {
+            Sort::Trending => "recent_likes DESC, likes DESC, g.published_at DESC",
+            Sort::New => "g.published_at DESC",
+            Sort::Top => "likes DESC, g.published_at DESC",
+        }
+    }
+}
+
+#[derive(Debug, QueryableByName)]
+struct EntryRow {
+    #[diesel(sql_type = Int4)]
+    pack_id: i32,
+    #[diesel(sql_type = Text)]
+    name: String,
+    #[diesel(sql_type = Text)]
+    category: String,
+    #[diesel(sql_type = Nullable<Text>)]
+    description: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    author: Option<String>,
+    #[diesel(sql_type = Timestamptz)]
+    published_at: DateTime<Utc>,
+    #[diesel(sql_type = BigInt)]
+    likes: i64,
+    #[diesel(sql_type = BigInt)]
+    remixes: i64,
+    #[diesel(sql_type = BigInt)]
+    icon_count: i64,
+    #[diesel(sql_type = Array<Int4>)]
+    preview_ids: Vec<i32>,
+    #[diesel(sql_type = Bool)]
+    liked: bool,
+}
+
+#[derive(QueryableByName)]
+struct Total {
+    #[diesel(sql_type = BigInt)]
+    total: i64,
+}
+
+fn image_url(pack_id: i32, icon_id: i32) -> String {
+    format!("/api/gallery/{}/icons/{}/image", pack_id, icon_id)
+}
+
+#[derive(Serialize)]
+struct GalleryEntry {
+    pack_id: i32,
+    name: String,
+    category: String,
+    description: Option<String>,
+    author: Option<String>,
+    published_at: DateTime<Utc>,
+    likes: i64,
+    remixes: i64,
+    icon_count: i64,
+    preview_urls: Vec<String>,
+    /// Whether the signed in viewer liked the pack, always false when signed out.
+    liked: bool,
+}
+
+impl From<EntryRow> for GalleryEntry {
+    fn from(row: EntryRow) -> Self {
+        GalleryEntry {
+            preview_urls: row.preview_ids.iter().map(|id| image_url(row.pack_id, *id)).collect(),
+            pack_id: row.pack_id,
+            name: row.name,
+            category: row.category,
+            description: row.description,
+            author: row.author,
+            published_at: row.published_at,
+            likes: row.likes,
+            remixes: row.remixes,
+            icon_count: row.icon_count,
+            liked: row.liked,
+        }
+    }
+}
+
+fn load_entry(conn: &mut PgConnection, pack_id: i32, viewer: Option<i32>) -> Result<GalleryEntry, AppError> {
+    diesel::sql_query(entries_query())
+        .bind::<Nullable<Text>, _>(None::<String>)
+        .bind::<Int4, _>(trending_days())
+        .bind::<Nullable<Int4>, _>(viewer)
+        .bind::<Nullable<Int4>, _>(Some(pack_id))
+        .get_result::<EntryRow>(conn)
+        .optional()?
+        .map(GalleryEntry::from)
+        .ok_or_else(|| AppError::NotFound("Pack is not in the gallery".to_string()))
+}
+
+/// Gallery categories in display order, with how many packs each has.
+#[get("/gallery/categories")]
+pub async fn list_categories(data: web::Data<AppState>) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let categories: Vec<(String, String)> = gallery_categories::table
+        .select((gallery_categories::slug, gallery_categories::name))
+        .order(gallery_categories::position.asc())
+        .load(&mut conn)?;
+    let counts: HashMap<String, i64> = gallery_packs::table
+        .group_by(gallery_packs::category)
+        .select((gallery_packs::category, diesel::dsl::count_star()))
+        .load::<(String, i64)>(&mut conn)?
+        .into_iter()
+        .collect();
+    let categories: Vec<serde_json::Value> = categories
+        .into_iter()
+        .map(|(slug, name)| {
+            let packs = counts.get(&slug).copied().unwrap_or(0);
+            serde_json::json!({ "slug": slug, "name": name, "packs": packs })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "categories": categories }
+    })))
+}
+
+/// A page of published packs, of one category or all.
+fn list_entries(
+    conn: &mut PgConnection,
+    category: Option<&str>,
+    sort: Sort,
+    viewer: Option<i32>,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<GalleryEntry>, AppError> {
+    Ok(diesel::sql_query(format!(
+        "{} ORDER BY {} LIMIT $5 OFFSET $6",
+        entries_query(),
+        sort.order_by()
+    ))
+    .bind::<Nullable<Text>, _>(category)
+    .bind::<Int4, _>(trending_days())
+    .bind::<Nullable<Int4>, _>(viewer)
+    .bind::<Nullable<Int4>, _>(None::<i32>)
+    .bind::<BigInt, _>(limit)
+    .bind::<BigInt, _>(offset)
+    .load::<EntryRow>(conn)?
+    .into_iter()
+    .map(GalleryEntry::from)
+    .collect())
+}
+
+#[derive(Deserialize)]
+struct GalleryQuery {
+    /// Category slug, every category when omitted.
+    category: Option<String>,
+    /// `trending` (the default), `new` or `top`.
+    sort: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+/// Published packs, no login needed.
+#[get("/gallery")]
+pub async fn list_gallery(
+    data: web::Data<AppState>,
+    user: Option<AuthenticatedUser>,
+    query: web::Query<GalleryQuery>,
+) -> Result<HttpResponse, AppError> {
+    let sort = match query.sort.as_deref() {
+        None => Sort::Trending,
+        Some(sort) => Sort::parse(sort).ok_or_else(|| AppError::BadRequest(format!("Unknown sort '{}'", sort)))?,
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let offset = query.offset.unwrap_or(0).max(0);
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let entries = list_entries(
+        &mut conn,
+        query.category.as_deref(),
+        sort,
+        user.map(|user| user.id),
+        limit,
+        offset,
+    )?;
+    let total = diesel::sql_query("SELECT COUNT(*) AS total FROM gallery_packs WHERE ($1::TEXT IS NULL OR category = $1)")
+        .bind::<Nullable<Text>, _>(&query.category)
+        .get_result::<Total>(&mut conn)?
+        .total;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "total": total, "packs": entries }
+    })))
+}
+
+/// A published pack with all its icons and the style a remix copies.
+#[get("/gallery/{id}")]
+pub async fn get_gallery_pack(
+    data: web::Data<AppState>,
+    user: Option<AuthenticatedUser>,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let pack_id = path.into_inner();
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let entry = load_entry(&mut conn, pack_id, user.map(|user| user.id))?;
+    let pack: IconPack = icon_packs::table.find(pack_id).first(&mut conn)?;
+    let pack_icons: Vec<(i32, Option<String>, Vec<String>)> = icons::table
+        .filter(icons::icon_pack_id.eq(pack_id))
+        .filter(icons::candidate_status.is_distinct_from("candidate"))
+        .filter(icons::image_data.ne(Vec::<u8>::new()))
+        .select((icons::id, icons::metadata, icons::tags))
+        .order(icons::id.asc())
+        .load(&mut conn)?;
+    let pack_icons: Vec<serde_json::Value> = pack_icons
+        .into_iter()
+        .map(|(id, metadata, tags)| {
+            serde_json::json!({
+                "id": id,
+                "metadata": metadata,
+                "tags": tags,
+                "image_url": image_url(pack_id, id),
+            })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": {
+            "pack": entry,
+            "style": PackStyle::of(&pack)?,
+            "palette": pack.palette,
+            "icons": pack_icons,
+        }
+    })))
+}
+
+/// An icon image of a published pack, no login needed.
+#[get("/gallery/{id}/icons/{icon_id}/image")]
+pub async fn gallery_icon_image(
+    data: web::Data<AppState>,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, AppError> {
+    let (pack_id, icon_id) = path.into_inner();
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let (image_data, output_format): (Vec<u8>, Option<String>) = icons::table
+        .inner_join(gallery_packs::table.on(gallery_packs::pack_id.nullable().eq(icons::icon_pack_id)))
+        .filter(icons::id.eq(icon_id))
+        .filter(icons::icon_pack_id.eq(pack_id))
+        .filter(icons::candidate_status.is_distinct_from("candidate"))
+        .filter(icons::image_data.ne(Vec::<u8>::new()))
+        .select((icons::image_data, icons::output_format))
+        .first(&mut conn)
+        .optional()?
+        .ok_or(AppError::IconNotFound)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(
+            output_format
+                .as_deref()
+                .and_then(OutputFormat::parse)
+                .unwrap_or(OutputFormat::Jpeg)
+                .content_type(),
+        )
+        .insert_header(("Cache-Control", "public, max-age=3600"))
+        .body(image_data))
+}
+
+#[derive(Deserialize)]
+struct Publish {
+    /// Category slug, see `/gallery/categories`.
+    category: String,
+    description: Option<String>,
+}
+
+/// Publishes a pack to the gallery, or changes its category and description when it is
+/// already there. Anyone can then see its finished icons.
+#[post("/packs/{id}/publish")]
+pub async fn publish_pack(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<Publish>,
+) -> Result<HttpResponse, AppError> {
+    let body = body.into_inner();
+    let description = body.description.map(|d| d.trim().to_string()).filter(|d| !d.is_empty());
+    if description.as_ref().is_some_and(|d| d.chars().count() > MAX_DESCRIPTION_LENGTH) {
+        return Err(AppError::BadRequest(format!(
+            "Descriptions are at most {} characters",
+            MAX_DESCRIPTION_LENGTH
+        )));
+    }
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let pack = find_pack(&mut conn, user.id, path.into_inner(), Role::Admin)?;
+    let category_exists: bool = diesel::select(diesel::dsl::exists(
+        gallery_categories::table.filter(gallery_categories::slug.eq(&body.category)),
+    ))
+    .get_result(&mut conn)?;
+    if !category_exists {
+        return Err(AppError::BadRequest(format!("Unknown category '{}'", body.category)));
+    }
+    let has_icons: bool = diesel::select(diesel::dsl::exists(
+        icons::table
+            .filter(icons::icon_pack_id.eq(pack.id))
+            .filter(icons::candidate_status.is_distinct_from("candidate"))
+            .filter(icons::image_data.ne(Vec::<u8>::new())),
+    ))
+    .get_result(&mut conn)?;
+    if !has_icons {
+        return Err(AppError::BadRequest("Only packs with finished icons can be published".to_string()));
+    }
+
+    diesel::insert_into(gallery_packs::table)
+        .values((
+            gallery_packs::pack_id.eq(pack.id),
+            gallery_packs::category.eq(&body.category),
+            gallery_packs::description.eq(&description),
+            gallery_packs::published_by.eq(user.id),
+        ))
+        .on_conflict(gallery_packs::pack_id)
+        .do_update()
+        .set((
+            gallery_packs::category.eq(&body.category),
+            gallery_packs::description.eq(&description),
+        ))
+        .execute(&mut conn)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "pack": load_entry(&mut conn, pack.id, Some(user.id))? }
+    })))
+}
+
+/// Takes a pack out of the gallery, its likes go with it. Remixes already made stay.
+#[post("/packs/{id}/unpublish")]
+pub async fn unpublish_pack(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let pack = find_pack(&mut conn, user.id, path.into_inner(), Role::Admin)?;
+    let removed = diesel::delete(gallery_packs::table.filter(gallery_packs::pack_id.eq(pack.id)))
+        .execute(&mut conn)?;
+    if removed == 0 {
+        return Err(AppError::NotFound("Pack is not in the gallery".to_string()));
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "pack_id": pack.id }
+    })))
+}
+
+/// Loads a published pack for a signed in viewer.
+fn published_pack(conn: &mut PgConnection, pack_id: i32) -> Result<IconPack, AppError> {
+    icon_packs::table
+        .inner_join(gallery_packs::table)
+        .filter(icon_packs::id.eq(pack_id))
+        .select(icon_packs::all_columns)
+        .first(conn)
+        .optional()?
+        .ok_or_else(|| AppError::NotFound("Pack is not in the gallery".to_string()))
+}
+
+#[post("/gallery/{id}/like")]
+pub async fn like_pack(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let pack_id = like(&mut conn, user.id, path.into_inner())?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "pack": load_entry(&mut conn, pack_id, Some(user.id))? }
+    })))
+}
+
+/// Likes a published pack of someone else's, liking it again changes nothing.
+fn like(conn: &mut PgConnection, user_id: i32, pack_id: i32) -> Result<i32, AppError> {
+    let pack = published_pack(conn, pack_id)?;
+    if pack.user_id == user_id {
+        return Err(AppError::BadRequest("You cannot like your own pack".to_string()));
+    }
+    diesel::insert_into(gallery_likes::table)
+        .values((gallery_likes::pack_id.eq(pack.id), gallery_likes::user_id.eq(user_id)))
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    Ok(pack.id)
+}
+
+#[post("/gallery/{id}/unlike")]
+pub async fn unlike_pack(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let pack = published_pack(&mut conn, path.into_inner())?;
+    diesel::delete(
+        gallery_likes::table
+            .filter(gallery_likes::pack_id.eq(pack.id))
+            .filter(gallery_likes::user_id.eq(user.id)),
+    )
+    .execute(&mut conn)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "pack": load_entry(&mut conn, pack.id, Some(user.id))? }
+    })))
+}
+
+#[derive(Deserialize)]
+struct Remix {
+    /// Defaults to the gallery pack's name with " remix" appended.
+    name: Option<String>,
+    /// Workspace to create the pack in, a personal pack when omitted.
+    organization_id: Option<i32>,
+}
+
+/// "Remix this pack": a new, empty pack of the viewer's with the gallery pack's style and
+/// brand palette, ready to generate their own icons in the same look.
+#[post("/gallery/{id}/remix")]
+pub async fn remix_pack(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<Remix>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let pack = remix(&mut conn, user.id, path.into_inner(), &body)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "pack": pack }
+    })))
+}
+
+/// The default name is shortened to fit when the gallery pack's name is long already.
+fn remix(conn: &mut PgConnection, user_id: i32, pack_id: i32, body: &Remix) -> Result<IconPack, AppError> {
+    let source = published_pack(conn, pack_id)?;
+    let name = match body.name.as_deref().filter(|name| !name.trim().is_empty()) {
+        Some(name) => packs::pack_name(name)?.to_string(),
+        None => {
+            const SUFFIX: &str = " remix";
+            let base: String = source.name.chars().take(packs::MAX_NAME_LENGTH - SUFFIX.len()).collect();
+            format!("{}{}", base.trim_end(), SUFFIX)
+        }
+    };
+    if let Some(organization_id) = body.organization_id {
+        organizations::require_role(conn, organization_id, user_id, Role::Editor)?;
+    }
+
+    Ok(diesel::insert_into(icon_packs::table)
+        .values((
+            icon_packs::user_id.eq(user_id),
+            icon_packs::name.eq(&name),
+            icon_packs::organization_id.eq(body.organization_id),
+            icon_packs::palette.eq(&source.palette),
+            icon_packs::style.eq(&source.style),
+            icon_packs::remixed_from.eq(source.id),
+        ))
+        .get_result(conn)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+    use crate::testing;
+
+    /// A new category, so listings only see the packs of one test.
+    fn category(conn: &mut PgConnection) -> String {
+        let slug = format!("test-{:016x}", rand::random::<u64>());
+        diesel::insert_into(gallery_categories::table)
+            .values((gallery_categories::slug.eq(&slug), gallery_categories::name.eq("Test")))
+            .execute(conn)
+            .unwrap();
+        slug
+    }
+
+    /// A pack of `owner` named `name`, published `days_ago` in `category`.
+    fn published(conn: &mut PgConnection, owner: i32, name: &str, category: &str, days_ago: i64) -> i32 {
+        let pack_id = diesel::insert_into(icon_packs::table)
+            .values((
+                icon_packs::user_id.eq(owner),
+                icon_packs::name.eq(name),
+                icon_packs::palette.eq(serde_json::json!(["#112233"])),
+                icon_packs::style.eq(serde_json::json!({ "style": "flat" })),
+            ))
+            .returning(icon_packs::id)
+            .get_result(conn)
+            .unwrap();
+        diesel::insert_into(gallery_packs::table)
+            .values((
+                gallery_packs::pack_id.eq(pack_id),
+                gallery_packs::category.eq(category),
+                gallery_packs::published_by.eq(owner),
+                gallery_packs::published_at.eq(Utc::now() - Duration::days(days_ago)),
+            ))
+            .execute(conn)
+            .unwrap();
+        pack_id
+    }
+
+    /// `count` likes of new users, given `days_ago`.
+    fn liked(conn: &mut PgConnection, pack_id: i32, count: usize, days_ago: i64) {
+        for _ in 0..count {
+            let user_id = testing::user(conn);
+            diesel::insert_into(gallery_likes::table)
+                .values((
+                    gallery_likes::pack_id.eq(pack_id),
+                    gallery_likes::user_id.eq(user_id),
+                    gallery_likes::created_at.eq(Utc::now() - Duration::days(days_ago)),
+                ))
+                .execute(conn)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn sorts_by_trending_new_and_top() {
+        let mut conn = testing::pool(1).get().unwrap();
+        let owner = testing::user(&mut conn);
+        let category = category(&mut conn);
+        let classic = published(&mut conn, owner, "classic", &category, 30);
+        let rising = published(&mut conn, owner, "rising", &category, 2);
+        let fresh = published(&mut conn, owner, "fresh", &category, 0);
+        liked(&mut conn, classic, 3, trending_days() as i64 + 1);
+        liked(&mut conn, rising, 2, 0);
+
+        let mut order = |sort| {
+            list_entries(&mut conn, Some(&category), sort, None, 10, 0)
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.pack_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(order(Sort::New), vec![fresh, rising, classic]);
+        assert_eq!(order(Sort::Top), vec![classic, rising, fresh]);
+        // Recent likes first, all-time likes break the tie.
+        assert_eq!(order(Sort::Trending), vec![rising, classic, fresh]);
+
+        let page = list_entries(&mut conn, Some(&category), Sort::New, None, 1, 1).unwrap();
+        assert_eq!(page.iter().map(|entry| entry.pack_id).collect::<Vec<_>>(), vec![rising]);
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn likes_count_once_and_not_from_the_owner() {
+        let mut conn = testing::pool(1).get().unwrap();
+        let owner = testing::user(&mut conn);
+        let viewer = testing::user(&mut conn);
+        let category = category(&mut conn);
+        let pack_id = published(&mut conn, owner, "liked", &category, 0);
+
+        assert!(matches!(like(&mut conn, owner, pack_id), Err(AppError::BadRequest(_))));
+        like(&mut conn, viewer, pack_id).unwrap();
+        like(&mut conn, viewer, pack_id).unwrap();
+
+        let entry = load_entry(&mut conn, pack_id, Some(viewer)).unwrap();
+        assert_eq!(entry.likes, 1);
+        assert!(entry.liked);
+        assert!(!load_entry(&mut conn, pack_id, Some(owner)).unwrap().liked);
+        assert!(!load_entry(&mut conn, pack_id, None).unwrap().liked);
+
+        diesel::delete(gallery_packs::table.find(pack_id)).execute(&mut conn).unwrap();
+        assert!(matches!(like(&mut conn, viewer, pack_id), Err(AppError::NotFound(_))));
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn remixes_copy_the_style_under_a_valid_name() {
+        let mut conn = testing::pool(1).get().unwrap();
+        let owner = testing::user(&mut conn);
+        let viewer = testing::user(&mut conn);
+        let category = category(&mut conn);
+        let long_name = "x".repeat(packs::MAX_NAME_LENGTH);
+        let source_id = published(&mut conn, owner, &long_name, &category, 0);
+        let remix_of = |name: Option<&str>| Remix { name: name.map(str::to_string), organization_id: None };
+
+        let pack = remix(&mut conn, viewer, source_id, &remix_of(None)).unwrap();
+        assert_eq!(pack.user_id, viewer);
+        assert_eq!(pack.remixed_from, Some(source_id));
+        assert_eq!(pack.style, Some(serde_json::json!({ "style": "flat" })));
+        assert_eq!(pack.palette, Some(serde_json::json!(["#112233"])));
+        assert_eq!(pack.name.chars().count(), packs::MAX_NAME_LENGTH);
+        assert!(pack.name.ends_with(" remix"));
+
+        let named = remix(&mut conn, viewer, source_id, &remix_of(Some("  Mine  "))).unwrap();
+        assert_eq!(named.name, "Mine");
+        let too_long = "y".repeat(packs::MAX_NAME_LENGTH + 1);
+        let rejected = remix(&mut conn, viewer, source_id, &remix_of(Some(&too_long)));
+        assert!(matches!(rejected, Err(AppError::BadRequest(_))));
+        assert_eq!(load_entry(&mut conn, source_id, None).unwrap().remixes, 2);
+
+        // Only published packs can be remixed.
+        let unpublished = remix(&mut conn, viewer, pack.id, &remix_of(None));
+        assert!(matches!(unpublished, Err(AppError::NotFound(_))));
+    }
+}
@@ -1,12 +1,19 @@
-use actix_web::{dev::Payload, get, post, web, FromRequest, HttpRequest, HttpResponse, Responder};
+use actix_web::{dev::Payload, get, post, put, web, FromRequest, HttpMessage, HttpRequest, HttpResponse, Responder};
 use diesel::prelude::*;
 use crate::{
-    model::{AppState, CreateIcon, CreateUser, Icon, TransactionType, User},
-    schema::{icons, transactions, users},
+    model::{normalize_tags, AppState, CreateIcon, CreateUser, Icon, TransactionType, User},
+    schema::{icons, users},
     auth::verify_id_token,
+    batch, billing, events, gallery, history, idempotency, jobs, ledger, organizations, packs, payments, phash,
+    pricing, prompts, promos, ratelimit, recolor, referrals, search, sharing, strokes, subscriptions,
+    tokens, variations, versions, webhooks,
+    billing::Wallet,
+    organizations::Role,
+    packs::PackStyle,
+    pricing::Operation,
+    provider::{GenerationParams, OutputFormat},
 };
 use std::env;
-use reqwest::Client;
 use serde::{Serialize, Deserialize};
 use std::pin::Pin;
 use futures::Future;
@@ -22,8 +29,24 @@ pub enum AppError {
     ImageGeneration(String),
     #[error("User not found")]
     UserNotFound,
+    #[error("Icon not found")]
+    IconNotFound,
+    #[error("Not found: {0}")]
+    NotFound(String),
+    #[error("Invalid request: {0}")]
+    BadRequest(String),
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    #[error("Unprocessable request: {0}")]
+    Unprocessable(String),
+    #[error("Content policy violation: {0}")]
+    ContentPolicy(String),
     #[error("Insufficient inkbucks")]
     InsufficientInkbucks,
+    #[error("Rate limited: {message}")]
+    RateLimited { message: String, retry_after: u64 },
     #[error("Internal server error: {0}")]
     InternalServerError(String),
 }
@@ -35,13 +58,25 @@ impl actix_web::ResponseError for AppError {
             AppError::DbOperation(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
             AppError::ImageGeneration(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
             AppError::UserNotFound => actix_web::http::StatusCode::BAD_REQUEST,
+            AppError::IconNotFound => actix_web::http::StatusCode::NOT_FOUND,
+            AppError::NotFound(_) => actix_web::http::StatusCode::NOT_FOUND,
+            AppError::BadRequest(_) => actix_web::http::StatusCode::BAD_REQUEST,
+            AppError::Forbidden(_) => actix_web::http::StatusCode::FORBIDDEN,
+            AppError::Conflict(_) => actix_web::http::StatusCode::CONFLICT,
+            AppError::Unprocessable(_) => actix_web::http::StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::ContentPolicy(_) => actix_web::http::StatusCode::UNPROCESSABLE_ENTITY,
             AppError::InsufficientInkbucks => actix_web::http::StatusCode::PAYMENT_REQUIRED,
+            AppError::RateLimited { .. } => actix_web::http::StatusCode::TOO_MANY_REQUESTS,
             AppError::InternalServerError(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code())
+        let mut response = HttpResponse::build(self.status_code());
+        if let AppError::RateLimited { retry_after, .. } = self {
+            response.insert_header((actix_web::http::header::RETRY_AFTER, *retry_after));
+        }
+        response
             .json(serde_json::json!({"status": "fail", "message": self.to_string()}))
     }
 }
@@ -58,11 +93,32 @@ struct IconData {
 }
 
 #[derive(Serialize)]
-struct FilteredIcon {
+pub(crate) struct FilteredIcon {
     id: i32,
     user_id: i32,
     icon_pack_id: Option<i32>,
+    organization_id: Option<i32>,
     metadata: Option<String>,
+    prompt: Option<String>,
+    tags: Vec<String>,
+    source_icon_id: Option<i32>,
+    candidate_status: Option<String>,
+}
+
+impl From<Icon> for FilteredIcon {
+    fn from(icon: Icon) -> Self {
+        FilteredIcon {
+            id: icon.id,
+            user_id: icon.user_id,
+            icon_pack_id: icon.icon_pack_id,
+            organization_id: icon.organization_id,
+            metadata: icon.metadata,
+            prompt: icon.prompt,
+            tags: icon.tags,
+            source_icon_id: icon.source_icon_id,
+            candidate_status: icon.candidate_status,
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -74,6 +130,9 @@ struct UserResponse {
 #[derive(Serialize)]
 struct UserData {
     user: FilteredUser,
+    /// Why the referral code given at signup was not applied.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    referral_error: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -84,11 +143,24 @@ struct FilteredUser {
     inkbucks: i32,
 }
 
+/// The email address comes from the verified token, one sent alongside it is ignored.
 #[derive(Deserialize)]
 struct AuthRequest {
     token: String,
-    email: String,
     username: String,
+    referral_code: Option<String>,
+}
+
+/// Addresses are unique regardless of case, a second account can't take one over.
+fn email_taken(e: diesel::result::Error) -> AppError {
+    match e {
+        diesel::result::Error::DatabaseError(diesel::result::DatabaseErrorKind::UniqueViolation, ref info)
+            if matches!(info.constraint_name(), Some("users_email_key" | "users_email_lower_idx")) =>
+        {
+            AppError::Conflict("Another account already uses this email address".to_string())
+        }
+        e => AppError::DbOperation(e),
+    }
 }
 
 #[post("/auth")]
@@ -100,17 +172,20 @@ async fn auth(
     let claims = verify_id_token(&auth_req.token, &project_id).await
         .map_err(|e| {
             println!("Error verify_id_token: {}", e);
-            return AppError::InternalServerError(e.to_string())
+            AppError::InternalServerError(e.to_string())
         })?;
 
     let uid = claims["sub"].as_str().unwrap_or("").to_string();
-    let email = auth_req.email.clone();
+    let email = claims["email"].as_str()
+        .map(str::to_string)
+        .ok_or_else(|| AppError::BadRequest("Sign in with an account that has an email address".to_string()))?;
+    let email_verified = claims["email_verified"].as_bool().unwrap_or(false);
     let username = auth_req.username.clone();
 
     let mut conn = data.db_pool.get()
         .map_err(|e| {
             println!("Error conn: {}", e);
-            return AppError::DbConnection(e.to_string())
+            AppError::DbConnection(e.to_string())
         })?;
 
     let user: Option<User> = users::table
@@ -119,26 +194,41 @@ async fn auth(
         .optional()
         .map_err(|e| {
             println!("Error filter eq uid: {}: {}", &uid, e);
-            return AppError::DbOperation(e)
+            AppError::DbOperation(e)
         })?;
 
+    let mut referral_error = None;
     let user = if let Some(user) = user {
-        user
+        if user.email == email && user.email_verified == email_verified {
+            user
+        } else {
+            // The address changed or was verified since the last sign in.
+            diesel::update(users::table.filter(users::id.eq(user.id)))
+                .set((users::email.eq(&email), users::email_verified.eq(email_verified)))
+                .get_result(&mut conn)
+                .map_err(email_taken)?
+        }
     } else {
         let new_user = (
             users::uid.eq(&uid),
             users::email.eq(&email),
+            users::email_verified.eq(email_verified),
             users::username.eq(&username),
-            users::inkbucks.eq(5),
         );
-        diesel::insert_into(users::table)
-            .values(new_user)
-            .get_result(&mut conn)
-            .map_err(|e| {
-                println!("Error insert_into: {}", e);
-                return AppError::DbOperation(e)
-            })?
+        conn.transaction(|conn| {
+            let user: User = diesel::insert_into(users::table)
+                .values(new_user)
+                .get_result(conn)
+                .map_err(|e| {
+                    println!("Error insert_into: {}", e);
+                    email_taken(e)
+                })?;
+            billing::credit(conn, user.id, TransactionType::SignupBonus, billing::SIGNUP_BONUS, None, None)?;
+            referral_error = referrals::attach_at_signup(conn, user.id, auth_req.referral_code.as_deref())?;
+            Ok::<_, AppError>(user)
+        })?
     };
+    let inkbucks = ledger::balance(&mut conn, user.id)?;
 
     let response = UserResponse {
         status: "success".to_string(),
@@ -147,17 +237,18 @@ async fn auth(
                 id: user.id,
                 email: user.email,
                 username: user.username,
-                inkbucks: user.inkbucks,
+                inkbucks,
             },
+            referral_error,
         },
     };
 
     Ok(HttpResponse::Ok().json(response))
 }
 
-struct AuthenticatedUser {
-    id: i32,
-    uid: String,
+#[derive(Clone, Copy)]
+pub(crate) struct AuthenticatedUser {
+    pub id: i32,
 }
 
 impl FromRequest for AuthenticatedUser {
@@ -165,6 +256,10 @@ impl FromRequest for AuthenticatedUser {
     type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
 
     fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        // Middleware that already authenticated the request leaves the user behind.
+        if let Some(user) = req.extensions().get::<AuthenticatedUser>().copied() {
+            return Box::pin(async move { Ok(user) });
+        }
         let app_data = req.app_data::<web::Data<AppState>>().expect("AppState not found");
         let auth_header = match req.headers().get("Authorization") {
             Some(header) => header.to_str().unwrap_or(""),
@@ -172,6 +267,16 @@ impl FromRequest for AuthenticatedUser {
         };
 
         let token = auth_header.strip_prefix("Bearer ").unwrap_or("").to_string();
+        if token.starts_with(tokens::PREFIX) {
+            let required = tokens::Scope::required(req.method(), req.path());
+            let app_data = app_data.clone();
+            return Box::pin(async move {
+                let mut conn = app_data.db_pool.get()
+                    .map_err(actix_web::error::ErrorInternalServerError)?;
+                let id = tokens::authenticate(&mut conn, &token, required)?;
+                Ok(AuthenticatedUser { id })
+            });
+        }
         let project_id = env::var("FIREBASE_PROJECT_ID").unwrap_or_else(|_| "inkblink-5d5fa".to_string());
 
         let app_data = app_data.clone();
@@ -180,67 +285,31 @@ impl FromRequest for AuthenticatedUser {
                 .map_err(|e| actix_web::error::ErrorUnauthorized(e.to_string()))?;
             let uid = claims["sub"].as_str().unwrap_or("").to_string();
             let mut conn = app_data.db_pool.get()
-                .map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
+                .map_err(actix_web::error::ErrorInternalServerError)?;
             let user: User = users::table
                 .filter(users::uid.eq(&uid))
                 .first(&mut conn)
                 .map_err(|_| actix_web::error::ErrorUnauthorized("User not found"))?;
-            Ok(AuthenticatedUser {
-                id: user.id,
-                uid: user.uid,
-            })
+            Ok(AuthenticatedUser { id: user.id })
         })
     }
 }
 
-fn generate_prompt(name: &str) -> String {
-    format!(
-        "Generate a minimalist icon for {}. Draw it with simple geometric shapes, flowy like sketched with an ink pen. Bold, simple, and monochrome.",
-        name
-    )
-}
-
-async fn generate_image(prompt: &str) -> Result<Vec<u8>, AppError> {
-    let api_key = env::var("STABILITY_API_KEY").unwrap_or_else(|_| {
-        "sk-K8H8bsXkAbdnnOZDlZGMjICh1FHG6RNuR52BYjElCV4b8gOs".to_string()
-    });
-    let url = "https://api.stability.ai/v2beta/stable-image/generate/sd3";
-
-    let client = Client::new();
-    let form = reqwest::multipart::Form::new()
-        .text("prompt", prompt.to_string())
-        .text("output_format", "jpeg");
-
-    let response = client
-        .post(url)
-        .header("Authorization", format!("Bearer {}", api_key))
-        .header("Accept", "image/*")
-        .multipart(form)
-        .send()
-        .await
-        .map_err(|e| AppError::ImageGeneration(format!("Request failed: {}", e)))?;
-
-    if response.status().is_success() {
-        let image_data = response
-            .bytes()
-            .await
-            .map_err(|e| AppError::ImageGeneration(format!("Failed to read response bytes: {}", e)))?;
-        Ok(image_data.to_vec())
-    } else {
-        Err(AppError::ImageGeneration(format!(
-            "Failed to generate image. HTTP Status: {}",
-            response.status()
-        )))
-    }
-}
-
 #[post("/icons")]
 async fn create_icon(
     data: web::Data<AppState>,
+    user: AuthenticatedUser,
     icon: web::Json<CreateIcon>,
 ) -> impl Responder {
-    let icon = icon.into_inner();
-    let prompt = generate_prompt(&icon.metadata.as_ref().unwrap_or(&"default".to_string()));
+    let mut icon = icon.into_inner();
+    let user_id = user.id;
+    let tags = normalize_tags(icon.tags.as_deref().unwrap_or_default());
+    if tags.len() > MAX_TAGS {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "status": "fail",
+            "message": format!("at most {} tags per icon", MAX_TAGS)
+        }));
+    }
 
     let mut conn = match data.db_pool.get() {
         Ok(conn) => conn,
@@ -250,68 +319,92 @@ async fn create_icon(
         })),
     };
 
-    let icon_id = match conn.transaction(|conn| {
-        let inkbucks: i32 = match users::table
-            .filter(users::id.eq(icon.user_id))
-            .select(users::inkbucks)
-            .first(conn) {
-            Ok(inkbucks) => inkbucks,
-            Err(diesel::result::Error::NotFound) => return Err(AppError::UserNotFound),
-            Err(e) => return Err(AppError::DbOperation(e)),
+    let organization_id = match organizations::icon_workspace(&mut conn, user_id, icon.icon_pack_id, icon.organization_id) {
+        Ok(organization_id) => organization_id,
+        Err(e) => return actix_web::ResponseError::error_response(&e),
+    };
+    let wallet = Wallet::of(user_id, organization_id);
+    if let Some(pack_id) = icon.icon_pack_id {
+        let pack_style = match packs::find_pack(&mut conn, user_id, pack_id, Role::Viewer)
+            .and_then(|pack| PackStyle::of(&pack)) {
+            Ok(style) => style,
+            Err(e) => return actix_web::ResponseError::error_response(&e),
         };
+        icon.template = icon.template.or(pack_style.template);
+        icon.style = icon.style.or(pack_style.style);
+        icon.stroke_weight = icon.stroke_weight.or(pack_style.stroke_weight);
+        icon.palette = icon.palette.or(pack_style.palette);
+    }
 
-        let transaction_amount = -1;
-        if inkbucks + transaction_amount < 0 {
-            return Err(AppError::InsufficientInkbucks);
-        }
+    let template_name = icon.template.as_deref().unwrap_or(prompts::DEFAULT_TEMPLATE);
+    let template = match prompts::select_template(&mut conn, template_name, user_id) {
+        Ok(template) => template,
+        Err(e) => return actix_web::ResponseError::error_response(&e),
+    };
+    let prompt = template.render(&prompts::PromptVariables {
+        subject: icon.metadata.clone().unwrap_or_else(|| "default".to_string()),
+        style: icon.style.clone(),
+        stroke_weight: icon.stroke_weight.clone(),
+        palette: icon.palette.clone(),
+    });
 
-        let new_transaction = (
-            transactions::user_id.eq(icon.user_id),
-            transactions::type_.eq(match TransactionType::Generate {
-                TransactionType::Generate => "generate",
-                TransactionType::Style => "style",
-                TransactionType::Edit => "edit",
-            }),
-            transactions::icon_id.eq(0),
-            transactions::amount.eq(transaction_amount),
-        );
+    let params = match GenerationParams::resolve(
+        prompt.clone(),
+        template.negative_prompt.as_deref(),
+        &template.parameters,
+        &icon.params,
+    ).and_then(|params| data.image_provider.validate(&params).map(|_| params)) {
+        Ok(params) => params,
+        Err(e) => return actix_web::ResponseError::error_response(&e),
+    };
+
+    if let Err(e) = data.moderation.check_request(&prompt, &icon.params).await {
+        return actix_web::ResponseError::error_response(&e);
+    }
 
-        let transaction_id: i32 = match diesel::insert_into(transactions::table)
-            .values(new_transaction)
-            .returning(transactions::id)
-            .get_result(conn) {
-            Ok(id) => id,
-            Err(e) => return Err(AppError::DbOperation(e)),
+    if icon.warn_on_duplicate.unwrap_or(false) {
+        let duplicates = match phash::likely_duplicates(&mut conn, user_id, &prompt, params.seed.map(i64::from)) {
+            Ok(ids) => ids,
+            Err(e) => return HttpResponse::InternalServerError().json(serde_json::json!({
+                "status": "fail",
+                "message": e.to_string()
+            })),
         };
 
+        if !duplicates.is_empty() {
+            return HttpResponse::Conflict().json(serde_json::json!({
+                "status": "warning",
+                "message": "This prompt keeps producing the same icon, generating again will likely duplicate it",
+                "data": { "duplicates": duplicates }
+            }));
+        }
+    }
+
+    let icon_id = match conn.transaction(|conn| {
+        ratelimit::reserve_generations(conn, user_id, 1)?;
         let new_icon = (
-            icons::user_id.eq(icon.user_id),
+            icons::user_id.eq(user_id),
             icons::icon_pack_id.eq(icon.icon_pack_id),
             icons::metadata.eq(icon.metadata.clone()),
             icons::image_data.eq(Vec::<u8>::new()),
+            icons::prompt.eq(&prompt),
+            icons::tags.eq(&tags),
+            icons::prompt_template_id.eq(template.id),
+            icons::seed.eq(params.seed.map(i64::from)),
+            icons::aspect_ratio.eq(&params.aspect_ratio),
+            icons::negative_prompt.eq(&params.negative_prompt),
+            icons::model.eq(params.model.as_str()),
+            icons::output_format.eq(params.output_format.as_str()),
+            icons::organization_id.eq(organization_id),
         );
 
-        let icon_id: i32 = match diesel::insert_into(icons::table)
+        let icon_id: i32 = diesel::insert_into(icons::table)
             .values(new_icon)
             .returning(icons::id)
-            .get_result(conn) {
-            Ok(id) => id,
-            Err(e) => return Err(AppError::DbOperation(e)),
-        };
+            .get_result(conn)?;
 
-        match diesel::update(transactions::table.filter(transactions::id.eq(transaction_id)))
-            .set(transactions::icon_id.eq(icon_id))
-            .execute(conn) {
-            Ok(_) => (),
-            Err(e) => return Err(AppError::DbOperation(e)),
-        };
-
-        match diesel::update(users::table.filter(users::id.eq(icon.user_id)))
-            .set(users::inkbucks.eq(users::inkbucks + transaction_amount))
-            .execute(conn) {
-            Ok(_) => (),
-            Err(e) => return Err(AppError::DbOperation(e)),
-        };
+        let cost = pricing::cost(conn, wallet, Operation::Generate, Some(params.model))?;
+        billing::charge(conn, wallet, Operation::Generate.transaction_type(), cost, Some(icon_id))?;
 
         Ok(icon_id)
     }) {
@@ -324,29 +417,48 @@ async fn create_icon(
             "status": "fail",
             "message": "Insufficient inkbucks"
         })),
+        Err(e @ (AppError::RateLimited { .. } | AppError::Forbidden(_))) => {
+            return actix_web::ResponseError::error_response(&e)
+        }
         Err(e) => return HttpResponse::InternalServerError().json(serde_json::json!({
             "status": "fail",
             "message": e.to_string()
         })),
     };
+    events::balance_changed(&data, user_id);
 
-    let image_data = match generate_image(&prompt).await {
-        Ok(data) => data,
+    // Generations queue for a provider slot, they must not hold a pooled connection meanwhile.
+    drop(conn);
+    let generated = jobs::generate_icon(&data, user_id, icon_id, &params).await;
+    let mut conn = match data.db_pool.get() {
+        Ok(conn) => conn,
         Err(e) => return HttpResponse::InternalServerError().json(serde_json::json!({
             "status": "fail",
-            "message": e.to_string()
+            "message": format!("Failed to get DB connection: {}", e)
         })),
     };
-
-    match diesel::update(icons::table.filter(icons::id.eq(icon_id)))
-        .set(icons::image_data.eq(image_data))
-        .execute(&mut conn) {
-        Ok(_) => (),
-        Err(e) => return HttpResponse::InternalServerError().json(serde_json::json!({
+    if let Err(e) = generated {
+        // A provider-side content filter is the same outcome as our own policy check, so
+        // the user gets their inkbucks back just as if we had rejected it up front.
+        if let AppError::ContentPolicy(_) = e {
+            let refunded = conn.transaction(|conn| {
+                let charged = billing::charged_for_generation(conn, icon_id)?;
+                billing::refund(conn, wallet, charged, Some(icon_id))?;
+                diesel::delete(icons::table.filter(icons::id.eq(icon_id))).execute(conn)?;
+                Ok::<_, AppError>(())
+            });
+            data.phash_index.remove(icon_id);
+            match refunded {
+                Ok(()) => events::balance_changed(&data, user_id),
+                Err(refund_error) => println!("Failed to refund filtered icon {}: {}", icon_id, refund_error),
+            }
+            return actix_web::ResponseError::error_response(&e);
+        }
+        return HttpResponse::InternalServerError().json(serde_json::json!({
             "status": "fail",
-            "message": format!("Failed to update icon: {}", e)
-        })),
-    };
+            "message": e.to_string()
+        }));
+    }
 
     let inserted_icon: Icon = match icons::table
         .filter(icons::id.eq(icon_id))
@@ -361,53 +473,181 @@ async fn create_icon(
     let response = IconResponse {
         status: "success".to_string(),
         data: IconData {
-            icon: FilteredIcon {
-                id: inserted_icon.id,
-                user_id: inserted_icon.user_id,
-                icon_pack_id: inserted_icon.icon_pack_id,
-                metadata: inserted_icon.metadata,
-            },
+            icon: inserted_icon.into(),
         },
     };
 
     HttpResponse::Ok().json(response)
 }
 
+const MAX_TAGS: usize = 32;
+
+#[derive(Deserialize)]
+struct UpdateTags {
+    tags: Vec<String>,
+}
+
+#[put("/icons/{id}/tags")]
+async fn update_icon_tags(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<UpdateTags>,
+) -> Result<HttpResponse, AppError> {
+    let icon_id = path.into_inner();
+    let tags = normalize_tags(&body.tags);
+    if tags.len() > MAX_TAGS {
+        return Err(AppError::BadRequest(format!("at most {} tags per icon", MAX_TAGS)));
+    }
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+
+    let icon: Icon = diesel::update(
+        icons::table
+            .filter(icons::id.eq(icon_id))
+            .filter(organizations::icon_access(user.id, Role::Editor)),
+    )
+    .set(icons::tags.eq(&tags))
+    .get_result(&mut conn)
+    .optional()?
+    .ok_or(AppError::IconNotFound)?;
+
+    let response = IconResponse {
+        status: "success".to_string(),
+        data: IconData {
+            icon: icon.into(),
+        },
+    };
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[derive(Deserialize)]
+struct IconImageQuery {
+    expires: Option<i64>,
+    signature: Option<String>,
+}
+
+/// An icon's image, for anyone who can view the icon or holds a signed URL to it
+/// (`UrlSigner::icon_image_url`).
 #[get("/icons/{id}/image")]
 async fn get_icon_image(
     data: web::Data<AppState>,
+    user: Result<AuthenticatedUser, actix_web::Error>,
     path: web::Path<i32>,
-) -> impl Responder {
+    query: web::Query<IconImageQuery>,
+) -> Result<HttpResponse, AppError> {
     let icon_id = path.into_inner();
-    let mut conn = match data.db_pool.get() {
-        Ok(conn) => conn,
-        Err(e) => return HttpResponse::InternalServerError().json(serde_json::json!({
-            "status": "fail",
-            "message": format!("Failed to get DB connection: {}", e)
-        })),
+    let signed = match (query.expires, query.signature.as_deref()) {
+        (Some(expires), Some(signature)) => data.url_signer.verify_icon(icon_id, expires, signature),
+        _ => false,
     };
+    let user = match user {
+        _ if signed => None,
+        Ok(user) => Some(user),
+        Err(_) if query.signature.is_some() => {
+            return Err(AppError::Forbidden("Invalid or expired signature".to_string()));
+        }
+        Err(e) => return Ok(e.error_response()),
+    };
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
 
-    let image_data: Option<Vec<u8>> = match icons::table
+    let mut image = icons::table
         .filter(icons::id.eq(icon_id))
-        .select(icons::image_data)
+        .select((icons::image_data, icons::output_format))
+        .into_boxed();
+    if let Some(user) = user {
+        image = image.filter(organizations::icon_access(user.id, Role::Viewer));
+    }
+    let (image_data, output_format): (Vec<u8>, Option<String>) = image
         .first(&mut conn)
-        .optional() {
-        Ok(data) => data,
-        Err(e) => return HttpResponse::InternalServerError().json(serde_json::json!({
-            "status": "fail",
-            "message": format!("Failed to retrieve image: {}", e)
-        })),
+        .optional()?
+        .ok_or(AppError::IconNotFound)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(
+            output_format
+                .as_deref()
+                .and_then(OutputFormat::parse)
+                .unwrap_or(OutputFormat::Jpeg)
+                .content_type(),
+        )
+        .body(image_data))
+}
+
+#[derive(Deserialize)]
+struct SimilarQuery {
+    max_distance: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct SimilarIcon {
+    id: i32,
+    metadata: Option<String>,
+    distance: u32,
+}
+
+#[get("/icons/{id}/similar")]
+async fn get_similar_icons(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    query: web::Query<SimilarQuery>,
+) -> Result<HttpResponse, AppError> {
+    let icon_id = path.into_inner();
+    let max_distance = query.max_distance.unwrap_or(phash::DEFAULT_MAX_DISTANCE).min(64);
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+
+    let icon: Icon = icons::table
+        .filter(icons::id.eq(icon_id))
+        .filter(organizations::icon_access(user.id, Role::Viewer))
+        .first(&mut conn)
+        .optional()?
+        .ok_or(AppError::IconNotFound)?;
+
+    let hash = match icon.phash {
+        Some(hash) => phash::from_db(hash),
+        None if icon.image_data.is_empty() => {
+            return Err(AppError::BadRequest("Icon has no image yet".to_string()));
+        }
+        None => phash::dhash(&icon.image_data)?,
     };
 
-    match image_data {
-        Some(data) => HttpResponse::Ok()
-            .content_type("image/jpeg")
-            .body(data),
-        None => HttpResponse::NotFound().json(serde_json::json!({
-            "status": "fail",
-            "message": "Image not found"
-        })),
-    }
+    let matches: Vec<(i32, u32)> = data.phash_index
+        .find(hash, max_distance)
+        .into_iter()
+        .filter(|(id, _)| *id != icon_id)
+        .collect();
+    let ids: Vec<i32> = matches.iter().map(|(id, _)| *id).collect();
+    // The index covers everyone's icons, only the ones the user can still open are shown.
+    let metadata: std::collections::HashMap<i32, Option<String>> = icons::table
+        .filter(icons::id.eq_any(&ids))
+        .filter(organizations::icon_access(user.id, Role::Viewer))
+        .select((icons::id, icons::metadata))
+        .load::<(i32, Option<String>)>(&mut conn)?
+        .into_iter()
+        .collect();
+
+    let similar: Vec<SimilarIcon> = matches
+        .into_iter()
+        .filter_map(|(id, distance)| {
+            metadata.get(&id).map(|metadata| SimilarIcon {
+                id,
+                metadata: metadata.clone(),
+                distance,
+            })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "icons": similar }
+    })))
 }
 
 #[post("/users")]
@@ -427,14 +667,20 @@ async fn create_user(
     let new_user = (
         users::email.eq(user.email.clone()),
         users::username.eq(user.username.clone()),
-        users::inkbucks.eq(5),
     );
 
-    let user_id: i32 = match diesel::insert_into(users::table)
-        .values(new_user)
-        .returning(users::id)
-        .get_result(&mut conn) {
+    let mut referral_error = None;
+    let user_id: i32 = match conn.transaction(|conn| {
+        let user_id: i32 = diesel::insert_into(users::table)
+            .values(new_user)
+            .returning(users::id)
+            .get_result(conn)?;
+        billing::credit(conn, user_id, TransactionType::SignupBonus, billing::SIGNUP_BONUS, None, None)?;
+        referral_error = referrals::attach_at_signup(conn, user_id, user.referral_code.as_deref())?;
+        Ok::<_, AppError>(user_id)
+    }) {
         Ok(id) => id,
+        Err(e @ AppError::BadRequest(_)) => return actix_web::ResponseError::error_response(&e),
         Err(e) => return HttpResponse::InternalServerError().json(serde_json::json!({
             "status": "fail",
             "message": format!("Failed to insert user: {}", e)
@@ -451,6 +697,14 @@ async fn create_user(
         })),
     };
 
+    let inkbucks = match ledger::balance(&mut conn, user_id) {
+        Ok(inkbucks) => inkbucks,
+        Err(e) => return HttpResponse::InternalServerError().json(serde_json::json!({
+            "status": "fail",
+            "message": format!("Failed to retrieve balance: {}", e)
+        })),
+    };
+
     let response = UserResponse {
         status: "success".to_string(),
         data: UserData {
@@ -458,8 +712,9 @@ async fn create_user(
                 id: inserted_user.id,
                 email: inserted_user.email,
                 username: inserted_user.username,
-                inkbucks: inserted_user.inkbucks,
+                inkbucks,
             },
+            referral_error,
         },
     };
 
@@ -468,10 +723,95 @@ async fn create_user(
 
 pub fn config(conf: &mut web::ServiceConfig) {
     let scope = web::scope("/api")
+        .wrap(actix_web::middleware::from_fn(idempotency::middleware))
+        .wrap(actix_web::middleware::from_fn(ratelimit::middleware))
         .service(auth)
+        .service(search::search_icons)
         .service(create_icon)
+        .service(update_icon_tags)
         .service(get_icon_image)
-        .service(create_user);
+        .service(get_similar_icons)
+        .service(create_user)
+        .service(packs::create_pack)
+        .service(packs::list_packs)
+        .service(packs::export_pack)
+        .service(packs::set_pack_style)
+        .service(sharing::list_collaborators)
+        .service(sharing::share_pack)
+        .service(sharing::unshare_pack)
+        .service(sharing::create_link)
+        .service(sharing::list_links)
+        .service(sharing::revoke_link)
+        .service(sharing::public_pack)
+        .service(sharing::public_icon_image)
+        .service(gallery::list_categories)
+        .service(gallery::list_gallery)
+        .service(gallery::get_gallery_pack)
+        .service(gallery::gallery_icon_image)
+        .service(gallery::publish_pack)
+        .service(gallery::unpublish_pack)
+        .service(gallery::like_pack)
+        .service(gallery::unlike_pack)
+        .service(gallery::remix_pack)
+        .service(tokens::create_token)
+        .service(tokens::list_tokens)
+        .service(tokens::revoke_token)
+        .service(batch::create_batch)
+        .service(batch::get_batch)
+        .service(variations::create_variations)
+        .service(variations::list_variations)
+        .service(variations::keep_candidate)
+        .service(variations::discard_candidate)
+        .service(versions::upscale_icon)
+        .service(versions::list_versions)
+        .service(versions::get_version_image)
+        .service(recolor::recolor_icon)
+        .service(recolor::render_icon)
+        .service(recolor::set_pack_palette)
+        .service(recolor::recolor_pack)
+        .service(strokes::measure_stroke)
+        .service(strokes::normalize_icon_stroke)
+        .service(strokes::normalize_pack_strokes)
+        .service(events::stream_events)
+        .service(webhooks::create_endpoint)
+        .service(webhooks::list_endpoints)
+        .service(webhooks::update_endpoint)
+        .service(webhooks::list_deliveries)
+        .service(webhooks::replay_delivery)
+        .service(history::list_transactions)
+        .service(history::list_organization_transactions)
+        .service(subscriptions::list_plans)
+        .service(subscriptions::get_subscription)
+        .service(subscriptions::cancel_subscription)
+        .service(promos::redeem_code)
+        .service(promos::list_codes)
+        .service(promos::create_code)
+        .service(promos::update_code)
+        .service(referrals::get_referral)
+        .service(referrals::apply_referral)
+        .service(pricing::get_pricing)
+        .service(organizations::create_organization)
+        .service(organizations::list_organizations)
+        .service(organizations::get_organization)
+        .service(organizations::update_member)
+        .service(organizations::remove_member)
+        .service(organizations::create_invitation)
+        .service(organizations::list_invitations)
+        .service(organizations::revoke_invitation)
+        .service(organizations::my_invitations)
+        .service(organizations::accept_invitation)
+        .service(organizations::deposit)
+        .service(organizations::list_audit_log)
+        .service(payments::list_products)
+        .service(payments::create_checkout)
+        .service(payments::get_payment)
+        .service(payments::payment_webhook)
+        .service(payments::fake_checkout)
+        .service(history::export_transactions)
+        .service(ledger::reconcile_ledger)
+        .service(prompts::list_templates)
+        .service(prompts::create_template)
+        .service(prompts::update_template_weight);
 
     conf.service(scope);
 }
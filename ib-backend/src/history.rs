@@ -0,0 +1,316 @@
+use actix_web::{get, web, HttpResponse};
+use chrono::{DateTime, NaiveDate, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::{Array, BigInt, Int4, Nullable, Text, Timestamptz};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    handlers::{AppError, AuthenticatedUser},
+    ledger,
+    model::{AppState, TransactionType},
+    organizations::{self, Role},
+    sharing::UrlSigner,
+};
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 200;
+
+/// A wallet's entries with the balance after each one. The running balance is taken
+/// over the whole history before any filter applies, so it stays correct on every page.
+const HISTORY: &str = "\
+    WITH history AS ( \
+        SELECT t.id, t.type, e.amount, \
+               SUM(e.amount) OVER (ORDER BY e.id)::BIGINT AS balance, \
+               t.icon_id, i.metadata AS icon_name, t.reference, t.actor_id, t.created_at \
+        FROM ledger_entries e \
+        JOIN ledger_transactions t ON t.id = e.transaction_id \
+        LEFT JOIN icons i ON i.id = t.icon_id \
+        WHERE e.account_id = $1 \
+    ), filtered AS ( \
+        SELECT * FROM history \
+        WHERE ($2::TEXT[] IS NULL OR type = ANY($2)) \
+          AND ($3::TIMESTAMPTZ IS NULL OR created_at >= $3) \
+          AND ($4::TIMESTAMPTZ IS NULL OR created_at < $4) \
+    )";
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    /// Comma separated transaction types, e.g. `generate,refund`.
+    #[serde(rename = "type")]
+    type_: Option<String>,
+    /// Inclusive start, RFC 3339 or `YYYY-MM-DD`.
+    from: Option<String>,
+    /// Exclusive end, RFC 3339 or `YYYY-MM-DD` (which includes that whole day).
+    to: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Debug, QueryableByName)]
+struct HistoryRow {
+    #[diesel(sql_type = Int4)]
+    id: i32,
+    #[diesel(sql_type = Text)]
+    type_: String,
+    #[diesel(sql_type = Int4)]
+    amount: i32,
+    #[diesel(sql_type = BigInt)]
+    balance: i64,
+    #[diesel(sql_type = Nullable<Int4>)]
+    icon_id: Option<i32>,
+    #[diesel(sql_type = Nullable<Text>)]
+    icon_name: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    reference: Option<String>,
+    #[diesel(sql_type = Nullable<Int4>)]
+    actor_id: Option<i32>,
+    #[diesel(sql_type = Timestamptz)]
+    created_at: DateTime<Utc>,
+}
+
+#[derive(QueryableByName)]
+struct Total {
+    #[diesel(sql_type = BigInt)]
+    total: i64,
+}
+
+#[derive(Serialize)]
+struct LinkedIcon {
+    id: i32,
+    name: Option<String>,
+    /// Signed, so it loads in an `<img>` tag without an Authorization header.
+    thumbnail_url: String,
+}
+
+#[derive(Serialize)]
+struct HistoryEntry {
+    id: i32,
+    #[serde(rename = "type")]
+    type_: String,
+    amount: i32,
+    balance: i64,
+    reference: Option<String>,
+    /// Member who spent or moved the inkbucks, for workspace wallets.
+    actor_id: Option<i32>,
+    created_at: DateTime<Utc>,
+    icon: Option<LinkedIcon>,
+}
+
+impl HistoryEntry {
+    fn new(row: HistoryRow, url_signer: &UrlSigner) -> Self {
+        HistoryEntry {
+            id: row.id,
+            type_: row.type_,
+            amount: row.amount,
+            balance: row.balance,
+            reference: row.reference,
+            actor_id: row.actor_id,
+            created_at: row.created_at,
+            icon: row.icon_id.map(|id| LinkedIcon {
+                id,
+                name: row.icon_name,
+                thumbnail_url: url_signer.icon_image_url(id),
+            }),
+        }
+    }
+}
+
+struct Filters {
+    types: Option<Vec<String>>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+}
+
+fn parse_time(value: &str, end: bool) -> Result<DateTime<Utc>, AppError> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| AppError::BadRequest(format!("Invalid date '{}'", value)))?;
+    let date = if end { date.succ_opt().unwrap_or(date) } else { date };
+    Ok(date.and_hms_opt(0, 0, 0).unwrap().and_utc())
+}
+
+fn parse_filters(query: &HistoryQuery) -> Result<Filters, AppError> {
+    let types = match query.type_.as_deref() {
+        Some(types) => {
+            let types = types
+                .split(',')
+                .map(str::trim)
+                .filter(|t| !t.is_empty())
+                .map(|t| {
+                    TransactionType::parse(t)
+                        .map(|kind| kind.as_str().to_string())
+                        .ok_or_else(|| AppError::BadRequest(format!("Unknown transaction type '{}'", t)))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Some(types).filter(|types| !types.is_empty())
+        }
+        None => None,
+    };
+    Ok(Filters {
+        types,
+        from: query.from.as_deref().map(|v| parse_time(v, false)).transpose()?,
+        to: query.to.as_deref().map(|v| parse_time(v, true)).transpose()?,
+    })
+}
+
+fn load_history(
+    conn: &mut PgConnection,
+    account_id: i32,
+    filters: &Filters,
+    limit: Option<i64>,
+    offset: i64,
+) -> Result<Vec<HistoryRow>, AppError> {
+    Ok(diesel::sql_query(format!(
+        "{} SELECT id, type AS type_, amount, balance, icon_id, icon_name, reference, actor_id, created_at \
+         FROM filtered ORDER BY id DESC LIMIT $5 OFFSET $6",
+        HISTORY
+    ))
+    .bind::<Int4, _>(account_id)
+    .bind::<Nullable<Array<Text>>, _>(&filters.types)
+    .bind::<Nullable<Timestamptz>, _>(filters.from)
+    .bind::<Nullable<Timestamptz>, _>(filters.to)
+    .bind::<Nullable<BigInt>, _>(limit)
+    .bind::<BigInt, _>(offset)
+    .load(conn)?)
+}
+
+/// One page of an account's history and the number of entries matching the filters.
+fn history_page(
+    conn: &mut PgConnection,
+    account_id: i32,
+    query: &HistoryQuery,
+    url_signer: &UrlSigner,
+) -> Result<(i64, Vec<HistoryEntry>), AppError> {
+    let filters = parse_filters(query)?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let offset = query.offset.unwrap_or(0).max(0);
+
+    let total = diesel::sql_query(format!("{} SELECT COUNT(*) AS total FROM filtered", HISTORY))
+        .bind::<Int4, _>(account_id)
+        .bind::<Nullable<Array<Text>>, _>(&filters.types)
+        .bind::<Nullable<Timestamptz>, _>(filters.from)
+        .bind::<Nullable<Timestamptz>, _>(filters.to)
+        .get_result::<Total>(conn)?
+        .total;
+    let transactions = load_history(conn, account_id, &filters, Some(limit), offset)?
+        .into_iter()
+        .map(|row| HistoryEntry::new(row, url_signer))
+        .collect();
+    Ok((total, transactions))
+}
+
+/// The user's inkbucks history, newest first, with the balance after every transaction.
+#[get("/me/transactions")]
+pub async fn list_transactions(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    query: web::Query<HistoryQuery>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let account_id = ledger::user_account(&mut conn, user.id)?;
+    let (total, transactions) = history_page(&mut conn, account_id, &query, &data.url_signer)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": {
+            "total": total,
+            "balance": ledger::balance(&mut conn, user.id)?,
+            "transactions": transactions,
+        }
+    })))
+}
+
+/// The workspace wallet's history with the member behind every entry, for admins.
+#[get("/organizations/{id}/transactions")]
+pub async fn list_organization_transactions(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    query: web::Query<HistoryQuery>,
+) -> Result<HttpResponse, AppError> {
+    let organization_id = path.into_inner();
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    organizations::require_role(&mut conn, organization_id, user.id, Role::Admin)?;
+    let account_id = ledger::organization_account(&mut conn, organization_id)?;
+    let (total, transactions) = history_page(&mut conn, account_id, &query, &data.url_signer)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": {
+            "total": total,
+            "balance": ledger::organization_balance(&mut conn, organization_id)?,
+            "transactions": transactions,
+        }
+    })))
+}
+
+/// Quotes a free-text cell when needed. Cells that spreadsheets would evaluate as a formula
+/// get a leading `'`, icon names and references are user input.
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+/// Same data as `/me/transactions` with the same filters, unpaginated, as CSV.
+#[get("/me/transactions.csv")]
+pub async fn export_transactions(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    query: web::Query<HistoryQuery>,
+) -> Result<HttpResponse, AppError> {
+    let filters = parse_filters(&query)?;
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let account_id = ledger::user_account(&mut conn, user.id)?;
+    let rows = load_history(&mut conn, account_id, &filters, None, 0)?;
+
+    let mut csv = String::from("id,date,type,amount,balance,icon_id,icon_name,reference\r\n");
+    for row in rows {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{},{}\r\n",
+            row.id,
+            row.created_at.to_rfc3339(),
+            row.type_,
+            row.amount,
+            row.balance,
+            row.icon_id.map(|id| id.to_string()).unwrap_or_default(),
+            csv_field(row.icon_name.as_deref().unwrap_or("")),
+            csv_field(row.reference.as_deref().unwrap_or("")),
+        ));
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header(("Content-Disposition", "attachment; filename=\"inkbucks-transactions.csv\""))
+        .body(csv))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_field_quotes_and_neutralizes_formulas() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("=HYPERLINK(\"http://x\")"), "\"'=HYPERLINK(\"\"http://x\"\")\"");
+        assert_eq!(csv_field("+1"), "'+1");
+        assert_eq!(csv_field("-1"), "'-1");
+        assert_eq!(csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_field("a=b"), "a=b");
+    }
+}
@@ -0,0 +1,422 @@
+use std::env;
+
+use actix_web::{
+    body::{self, BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    web, HttpMessage, HttpResponse,
+};
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    handlers::{AppError, AuthenticatedUser},
+    model::AppState,
+    schema::idempotency_keys,
+};
+
+pub const HEADER: &str = "Idempotency-Key";
+
+/// Set on responses that were served from a stored snapshot.
+const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+
+const MAX_KEY_LENGTH: usize = 255;
+
+#[derive(Queryable)]
+struct StoredKey {
+    request_hash: String,
+    response_status: Option<i32>,
+    response_content_type: Option<String>,
+    response_body: Option<Vec<u8>>,
+}
+
+/// How long a completed response is replayed, `IDEMPOTENCY_TTL_HOURS` (24 by default).
+fn ttl() -> Duration {
+    Duration::hours(
+        env::var("IDEMPOTENCY_TTL_HOURS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(24),
+    )
+}
+
+/// After `IDEMPOTENCY_LOCK_SECS` (10 minutes by default) a request that never finished,
+/// e.g. because the server went down mid generation, no longer blocks its key.
+fn lock_timeout() -> Duration {
+    Duration::seconds(
+        env::var("IDEMPOTENCY_LOCK_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(600),
+    )
+}
+
+/// Fingerprint of what a key was first used for. Reusing the key for anything else is a
+/// client bug, not a retry.
+fn request_hash(req: &ServiceRequest, payload: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(req.method().as_str());
+    hasher.update(b"\n");
+    hasher.update(req.uri().to_string());
+    hasher.update(b"\n");
+    hasher.update(payload);
+    hex::encode(hasher.finalize())
+}
+
+/// Claims the key for this request. Returns `None` when the key is ours to run with, or
+/// the response to send instead: the stored snapshot, 409 while the first request is still
+/// running and 422 when the key was used for a different request.
+fn claim(
+    conn: &mut PgConnection,
+    user_id: i32,
+    key: &str,
+    hash: &str,
+) -> Result<Option<HttpResponse>, AppError> {
+    let now = Utc::now();
+    diesel::delete(
+        idempotency_keys::table.filter(
+            idempotency_keys::expires_at.le(now).or(idempotency_keys::response_status
+                .is_null()
+                .and(idempotency_keys::created_at.le(now - lock_timeout()))),
+        ),
+    )
+    .execute(conn)?;
+
+    let claimed = diesel::insert_into(idempotency_keys::table)
+        .values((
+            idempotency_keys::user_id.eq(user_id),
+            idempotency_keys::idempotency_key.eq(key),
+            idempotency_keys::request_hash.eq(hash),
+            idempotency_keys::expires_at.eq(now + ttl()),
+        ))
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    if claimed == 1 {
+        return Ok(None);
+    }
+
+    let stored: Option<StoredKey> = idempotency_keys::table
+        .filter(idempotency_keys::user_id.eq(user_id))
+        .filter(idempotency_keys::idempotency_key.eq(key))
+        .select((
+            idempotency_keys::request_hash,
+            idempotency_keys::response_status,
+            idempotency_keys::response_content_type,
+            idempotency_keys::response_body,
+        ))
+        .first(conn)
+        .optional()?;
+    // Released between our insert and this read, the client can retry right away.
+    let stored = stored.ok_or_else(|| AppError::Conflict("Request with this Idempotency-Key was retried too quickly".to_string()))?;
+    if stored.request_hash != hash {
+        return Err(AppError::Unprocessable(
+            "Idempotency-Key was already used for a different request".to_string(),
+        ));
+    }
+    let Some(status) = stored.response_status else {
+        return Err(AppError::Conflict(
+            "A request with this Idempotency-Key is still being processed".to_string(),
+        ));
+    };
+
+    let Some(body) = stored.response_body else {
+        return Err(AppError::Conflict(
+            "The request with this Idempotency-Key already completed, its response held a secret that is not kept"
+                .to_string(),
+        ));
+    };
+
+    let status = StatusCode::from_u16(status as u16).unwrap_or(StatusCode::OK);
+    let mut response = HttpResponse::build(status);
+    response.insert_header((REPLAYED_HEADER, "true"));
+    if let Some(content_type) = stored.response_content_type {
+        response.content_type(content_type);
+    }
+    Ok(Some(response.body(body)))
+}
+
+/// Keeps the response for replays, server errors included: a handler can fail after
+/// committing a charge, and running it again would charge twice. `body` is `None` for
+/// responses that must not be kept.
+fn store(
+    conn: &mut PgConnection,
+    user_id: i32,
+    key: &str,
+    status: StatusCode,
+    content_type: Option<&str>,
+    body: Option<&[u8]>,
+) -> Result<(), AppError> {
+    diesel::update(
+        idempotency_keys::table
+            .filter(idempotency_keys::user_id.eq(user_id))
+            .filter(idempotency_keys::idempotency_key.eq(key)),
+    )
+    .set((
+        idempotency_keys::response_status.eq(i32::from(status.as_u16())),
+        idempotency_keys::response_content_type.eq(content_type),
+        idempotency_keys::response_body.eq(body),
+    ))
+    .execute(conn)?;
+    Ok(())
+}
+
+/// Responses handing out a secret, like a new API token, are marked `Cache-Control:
+/// no-store` and never written to the key table in plain text.
+fn holds_secret(headers: &header::HeaderMap) -> bool {
+    headers
+        .get(header::CACHE_CONTROL)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(',').any(|directive| directive.trim() == "no-store"))
+}
+
+/// Makes mutating requests that carry an `Idempotency-Key` header safe to retry. The
+/// first request with a key runs and its response is kept for the TTL, retries get that
+/// response back without running the handler, so a timed out `POST /api/icons` is not
+/// charged or generated twice. Failed requests are replayed too, retrying one takes a new
+/// key. Keys are scoped to the authenticated user, anonymous requests are never replayed.
+pub async fn middleware(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let mutating = matches!(*req.method(), Method::POST | Method::PUT | Method::PATCH | Method::DELETE);
+    let key = req.headers().get(HEADER).map(|value| value.to_str().map(str::to_string));
+    let key = match key {
+        Some(Ok(key)) if mutating => key,
+        Some(Err(_)) if mutating => {
+            return Err(AppError::BadRequest("Idempotency-Key must be visible ASCII".to_string()).into())
+        }
+        _ => return next.call(req).await.map(ServiceResponse::map_into_boxed_body),
+    };
+    if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+        return Err(AppError::BadRequest(format!(
+            "Idempotency-Key must be 1 to {} characters",
+            MAX_KEY_LENGTH
+        ))
+        .into());
+    }
+
+    // Keys are only kept for a user, anonymous clients could otherwise replay or block
+    // each other's requests. Without one the handler turns the request away or has
+    // nothing to charge, there is nothing to deduplicate.
+    if !req.headers().contains_key(header::AUTHORIZATION) {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    }
+    let user_id = match req.extract::<AuthenticatedUser>().await {
+        Ok(user) => {
+            req.extensions_mut().insert(user);
+            user.id
+        }
+        Err(_) => return next.call(req).await.map(ServiceResponse::map_into_boxed_body),
+    };
+
+    let payload = req.extract::<web::Bytes>().await?;
+    let hash = request_hash(&req, &payload);
+    req.set_payload(payload.into());
+
+    let state = req
+        .app_data::<web::Data<AppState>>()
+        .expect("AppState not found")
+        .clone();
+    let mut conn = state.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    if let Some(replay) = claim(&mut conn, user_id, &key, &hash)? {
+        return Ok(req.into_response(replay));
+    }
+    drop(conn);
+
+    let response = match next.call(req).await {
+        Ok(response) => response,
+        Err(e) => {
+            let error = e.error_response();
+            let content_type = error
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
+            let status = error.status();
+            let body = body::to_bytes(error.into_body()).await.unwrap_or_default();
+            let mut conn = state.db_pool.get()
+                .map_err(|e| AppError::DbConnection(e.to_string()))?;
+            store(&mut conn, user_id, &key, status, content_type.as_deref(), Some(&body))?;
+            return Err(e);
+        }
+    };
+
+    let (req, response) = response.into_parts();
+    let (response, body) = response.into_parts();
+    let body = body::to_bytes(body)
+        .await
+        .map_err(|e| AppError::InternalServerError(format!("Failed to read response: {}", e.into())))?;
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
+    let mut conn = state.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let kept = (!holds_secret(response.headers())).then_some(&body[..]);
+    store(&mut conn, user_id, &key, response.status(), content_type.as_deref(), kept)?;
+
+    Ok(ServiceResponse::new(req, response.set_body(body)).map_into_boxed_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use actix_web::{
+        dev::{Service, ServiceResponse},
+        http::header::{CacheControl, CacheDirective},
+        middleware::from_fn,
+        test::{call_service, init_service, read_body, TestRequest},
+        App,
+    };
+    use tokio::sync::Notify;
+
+    use super::*;
+    use crate::{schema::api_tokens, testing, tokens};
+
+    #[test]
+    fn keeps_responses_unless_marked_no_store() {
+        let mut headers = header::HeaderMap::new();
+        assert!(!holds_secret(&headers));
+        headers.insert(header::CACHE_CONTROL, "private, max-age=0".parse().unwrap());
+        assert!(!holds_secret(&headers));
+        headers.insert(header::CACHE_CONTROL, "private, no-store".parse().unwrap());
+        assert!(holds_secret(&headers));
+    }
+
+    /// Counts how often the handlers behind the middleware actually ran.
+    #[derive(Default)]
+    struct Calls {
+        count: AtomicUsize,
+        release: Notify,
+    }
+
+    async fn charge(calls: web::Data<Calls>, body: web::Bytes) -> HttpResponse {
+        let call = calls.count.fetch_add(1, Ordering::SeqCst) + 1;
+        HttpResponse::Created().json(serde_json::json!({ "call": call, "body": body.len() }))
+    }
+
+    async fn slow(calls: web::Data<Calls>) -> HttpResponse {
+        calls.count.fetch_add(1, Ordering::SeqCst);
+        calls.release.notified().await;
+        HttpResponse::Created().finish()
+    }
+
+    async fn secret(calls: web::Data<Calls>) -> HttpResponse {
+        calls.count.fetch_add(1, Ordering::SeqCst);
+        HttpResponse::Created()
+            .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+            .json(serde_json::json!({ "token": "ib_secret" }))
+    }
+
+    /// An API token that may write icons, for a new user.
+    fn api_token(conn: &mut PgConnection) -> String {
+        let user_id = testing::user(conn);
+        let token = format!("{}test_{:016x}", tokens::PREFIX, rand::random::<u64>());
+        diesel::insert_into(api_tokens::table)
+            .values((
+                api_tokens::user_id.eq(user_id),
+                api_tokens::name.eq("idempotency test"),
+                api_tokens::token_hash.eq(hex::encode(Sha256::digest(token.as_bytes()))),
+                api_tokens::prefix.eq(&token[..8]),
+                api_tokens::scopes.eq(vec!["icons:write"]),
+            ))
+            .execute(conn)
+            .unwrap();
+        token
+    }
+
+    fn post(path: &str, token: Option<&str>, key: &str, body: &'static str) -> TestRequest {
+        let mut req = TestRequest::post()
+            .uri(path)
+            .insert_header((HEADER, key))
+            .set_payload(body);
+        if let Some(token) = token {
+            req = req.insert_header((header::AUTHORIZATION, format!("Bearer {}", token)));
+        }
+        req
+    }
+
+    /// The status the client sees, errors included.
+    fn status(result: Result<ServiceResponse, actix_web::Error>) -> StatusCode {
+        match result {
+            Ok(response) => response.status(),
+            Err(e) => e.as_response_error().status_code(),
+        }
+    }
+
+    fn replayed(response: &ServiceResponse) -> bool {
+        response.headers().contains_key(REPLAYED_HEADER)
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    async fn replays_conflicts_and_rejects_reused_keys() {
+        let state = AppState::init().await;
+        let mut conn = state.db_pool.get().unwrap();
+        let token = api_token(&mut conn);
+        let stranger = api_token(&mut conn);
+        drop(conn);
+        let calls = web::Data::new(Calls::default());
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .app_data(calls.clone())
+                .wrap(from_fn(middleware))
+                .route("/api/icons", web::post().to(charge))
+                .route("/api/icons/slow", web::post().to(slow))
+                .route("/api/icons/secret", web::post().to(secret)),
+        )
+        .await;
+
+        // A retry gets the first response back without running the handler again.
+        let first = call_service(&app, post("/api/icons", Some(&token), "a", "{}").to_request()).await;
+        assert_eq!(first.status(), StatusCode::CREATED);
+        assert!(!replayed(&first));
+        let first = read_body(first).await;
+        let retry = call_service(&app, post("/api/icons", Some(&token), "a", "{}").to_request()).await;
+        assert_eq!(retry.status(), StatusCode::CREATED);
+        assert!(replayed(&retry));
+        assert_eq!(read_body(retry).await, first);
+        assert_eq!(calls.count.load(Ordering::SeqCst), 1);
+
+        // The same key for another body is a client bug.
+        let other = app.call(post("/api/icons", Some(&token), "a", "{\"x\":1}").to_request()).await;
+        assert_eq!(status(other), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(calls.count.load(Ordering::SeqCst), 1);
+
+        // A retry while the first request still runs is turned away.
+        let (running, retry) = futures::join!(
+            app.call(post("/api/icons/slow", Some(&token), "b", "{}").to_request()),
+            async {
+                let retry = app.call(post("/api/icons/slow", Some(&token), "b", "{}").to_request()).await;
+                calls.release.notify_one();
+                retry
+            }
+        );
+        assert_eq!(status(running), StatusCode::CREATED);
+        assert_eq!(status(retry), StatusCode::CONFLICT);
+        assert_eq!(calls.count.load(Ordering::SeqCst), 2);
+
+        // A response holding a secret is not kept, its retry can't be answered.
+        let created = call_service(&app, post("/api/icons/secret", Some(&token), "c", "{}").to_request()).await;
+        assert_eq!(created.status(), StatusCode::CREATED);
+        let retry = app.call(post("/api/icons/secret", Some(&token), "c", "{}").to_request()).await;
+        assert_eq!(status(retry), StatusCode::CONFLICT);
+        assert_eq!(calls.count.load(Ordering::SeqCst), 3);
+
+        // Keys are per user and anonymous requests are never deduplicated.
+        let theirs = call_service(&app, post("/api/icons", Some(&stranger), "a", "{}").to_request()).await;
+        assert!(!replayed(&theirs));
+        for _ in 0..2 {
+            let anonymous = call_service(&app, post("/api/icons", None, "a", "{}").to_request()).await;
+            assert!(!replayed(&anonymous));
+        }
+        assert_eq!(calls.count.load(Ordering::SeqCst), 6);
+    }
+}
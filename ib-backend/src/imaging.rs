@@ -0,0 +1,311 @@
+use std::io::Cursor;
+
+use image::{imageops::FilterType, DynamicImage, ImageFormat};
+
+use crate::handlers::AppError;
+
+/// Longest side we render to, larger exports are better served by the SVG path.
+pub const MAX_DIMENSION: u32 = 8192;
+
+pub fn decode(data: &[u8]) -> Result<DynamicImage, AppError> {
+    image::load_from_memory(data)
+        .map_err(|e| AppError::InternalServerError(format!("Failed to decode image: {}", e)))
+}
+
+/// Width and height from the image header, without decoding the pixels.
+pub fn dimensions(data: &[u8]) -> Result<(u32, u32), AppError> {
+    image::ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|e| AppError::InternalServerError(format!("Failed to read image: {}", e)))?
+        .into_dimensions()
+        .map_err(|e| AppError::InternalServerError(format!("Failed to read image: {}", e)))
+}
+
+pub fn encode_png(img: &DynamicImage) -> Result<Vec<u8>, AppError> {
+    let mut out = Cursor::new(Vec::new());
+    img.write_to(&mut out, ImageFormat::Png)
+        .map_err(|e| AppError::InternalServerError(format!("Failed to encode image: {}", e)))?;
+    Ok(out.into_inner())
+}
+
+/// Scales an image so its longest side is `long_side` pixels. Lanczos keeps ink edges
+/// smooth, the unsharp mask afterwards restores the contrast resampling softens, which
+/// matters most for thin line art printed large.
+pub fn upscale(img: &DynamicImage, long_side: u32) -> DynamicImage {
+    let (width, height) = (img.width(), img.height());
+    let scale = long_side as f32 / width.max(height) as f32;
+    let target_w = ((width as f32 * scale).round() as u32).max(1);
+    let target_h = ((height as f32 * scale).round() as u32).max(1);
+
+    let resized = img.resize_exact(target_w, target_h, FilterType::Lanczos3);
+    // Sharpen radius grows with the scale factor so edges stay crisp at any size.
+    let sigma = (scale / 2.0).clamp(0.5, 3.0);
+    resized.unsharpen(sigma, 2)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Color(pub [u8; 4]);
+
+impl Color {
+    pub const TRANSPARENT: Color = Color([0, 0, 0, 0]);
+
+    /// Parses `#rgb`, `#rrggbb`, `#rrggbbaa` (the `#` is optional) or `transparent`.
+    pub fn parse(s: &str) -> Result<Color, AppError> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("transparent") {
+            return Ok(Color::TRANSPARENT);
+        }
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        let hex = match hex.len() {
+            3 => hex.chars().flat_map(|c| [c, c]).collect::<String>(),
+            6 | 8 => hex.to_string(),
+            _ => return Err(AppError::BadRequest(format!("Invalid colour '{}'", s))),
+        };
+        let channel = |i: usize| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| AppError::BadRequest(format!("Invalid colour '{}'", s)))
+        };
+        let alpha = if hex.len() == 8 { channel(6)? } else { 255 };
+        Ok(Color([channel(0)?, channel(2)?, channel(4)?, alpha]))
+    }
+}
+
+/// How ink pixels are painted.
+#[derive(Debug, Clone, Copy)]
+pub enum InkFill {
+    Solid(Color),
+    /// Linear gradient from `from` to `to`, `angle` in degrees clockwise from left-to-right.
+    Gradient { from: Color, to: Color, angle: f32 },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Recolor {
+    pub ink: InkFill,
+    pub background: Color,
+    /// Treat light pixels as ink, for icons drawn light on dark.
+    pub swap: bool,
+}
+
+/// Mixes two colours in premultiplied alpha, so fading ink into a transparent background
+/// keeps the ink's hue instead of darkening the edges towards black.
+fn lerp(a: Color, b: Color, t: f32) -> Color {
+    let alpha = a.0[3] as f32 + (b.0[3] as f32 - a.0[3] as f32) * t;
+    if alpha <= 0.0 {
+        return Color::TRANSPARENT;
+    }
+    let mut out = [0u8; 4];
+    for (i, channel) in out.iter_mut().take(3).enumerate() {
+        let pa = a.0[i] as f32 * a.0[3] as f32;
+        let pb = b.0[i] as f32 * b.0[3] as f32;
+        *channel = ((pa + (pb - pa) * t) / alpha).round().clamp(0.0, 255.0) as u8;
+    }
+    out[3] = alpha.round() as u8;
+    Color(out)
+}
+
+/// Maps a monochrome icon onto new colours. Each pixel's darkness is read as ink coverage,
+/// so anti-aliased edges blend between background and ink instead of turning jagged.
+pub fn recolor(img: &DynamicImage, recolor: &Recolor) -> DynamicImage {
+    let luma = img.to_luma8();
+    let (width, height) = luma.dimensions();
+    let (dx, dy) = match recolor.ink {
+        InkFill::Gradient { angle, .. } => (angle.to_radians().cos(), angle.to_radians().sin()),
+        InkFill::Solid(_) => (1.0, 0.0),
+    };
+    // Project the corners onto the gradient axis to normalise positions to 0..1.
+    let corners = [(0.0, 0.0), (width as f32, 0.0), (0.0, height as f32), (width as f32, height as f32)];
+    let projections = corners.map(|(x, y)| x * dx + y * dy);
+    let min = projections.iter().cloned().fold(f32::INFINITY, f32::min);
+    let max = projections.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let span = (max - min).max(1.0);
+
+    let out = image::RgbaImage::from_fn(width, height, |x, y| {
+        let value = luma.get_pixel(x, y)[0] as f32 / 255.0;
+        let coverage = if recolor.swap { value } else { 1.0 - value };
+        let ink = match recolor.ink {
+            InkFill::Solid(color) => color,
+            InkFill::Gradient { from, to, .. } => {
+                let t = ((x as f32 * dx + y as f32 * dy) - min) / span;
+                lerp(from, to, t)
+            }
+        };
+        image::Rgba(lerp(recolor.background, ink, coverage).0)
+    });
+    DynamicImage::ImageRgba8(out)
+}
+
+/// Pixels darker than this are ink.
+const INK_THRESHOLD: u8 = 128;
+
+const FAR: f32 = 1e20;
+
+/// 1D squared Euclidean distance transform (Felzenszwalb & Huttenlocher) over `f`, where
+/// `f[i]` is 0 at feature pixels and `FAR` elsewhere.
+fn edt_1d(f: &[f32]) -> Vec<f32> {
+    let n = f.len();
+    let mut v = vec![0usize; n];
+    let mut z = vec![0.0f32; n + 1];
+    let intersect = |q: usize, p: usize| {
+        ((f[q] + (q * q) as f32) - (f[p] + (p * p) as f32)) / (2.0 * (q as f32 - p as f32))
+    };
+
+    let mut k = 0;
+    z[0] = f32::NEG_INFINITY;
+    z[1] = f32::INFINITY;
+    for q in 1..n {
+        let mut s = intersect(q, v[k]);
+        while s <= z[k] {
+            k -= 1;
+            s = intersect(q, v[k]);
+        }
+        k += 1;
+        v[k] = q;
+        z[k] = s;
+        z[k + 1] = f32::INFINITY;
+    }
+
+    k = 0;
+    (0..n)
+        .map(|q| {
+            while z[k + 1] < q as f32 {
+                k += 1;
+            }
+            let p = v[k];
+            (q as f32 - p as f32).powi(2) + f[p]
+        })
+        .collect()
+}
+
+/// Euclidean distance from every pixel to the nearest pixel where `feature` is true.
+pub fn distance_transform(feature: &[bool], width: u32, height: u32) -> Vec<f32> {
+    let (w, h) = (width as usize, height as usize);
+    let mut grid: Vec<f32> = feature.iter().map(|&f| if f { 0.0 } else { FAR }).collect();
+
+    let mut column = vec![0.0; h];
+    for x in 0..w {
+        for y in 0..h {
+            column[y] = grid[y * w + x];
+        }
+        for (y, value) in edt_1d(&column).into_iter().enumerate() {
+            grid[y * w + x] = value;
+        }
+    }
+    for y in 0..h {
+        let row = edt_1d(&grid[y * w..(y + 1) * w]);
+        grid[y * w..(y + 1) * w].copy_from_slice(&row);
+    }
+    grid.into_iter().map(f32::sqrt).collect()
+}
+
+pub fn ink_mask(img: &DynamicImage) -> (Vec<bool>, u32, u32) {
+    let luma = img.to_luma8();
+    let (width, height) = luma.dimensions();
+    (luma.pixels().map(|p| p[0] < INK_THRESHOLD).collect(), width, height)
+}
+
+/// Dominant stroke width in pixels, `None` for an image without ink. Inside a stroke the
+/// distance to the nearest background pixel peaks along its centre line at half the
+/// stroke width, so the median of those ridge values measures the typical line.
+pub fn stroke_width(img: &DynamicImage) -> Option<f32> {
+    let (ink, width, height) = ink_mask(img);
+    let background: Vec<bool> = ink.iter().map(|i| !i).collect();
+    let dist = distance_transform(&background, width, height);
+    let (w, h) = (width as i64, height as i64);
+
+    let mut ridge: Vec<f32> = Vec::new();
+    for y in 0..h {
+        for x in 0..w {
+            let d = dist[(y * w + x) as usize];
+            if !ink[(y * w + x) as usize] {
+                continue;
+            }
+            let is_peak = (-1..=1).all(|dy| {
+                (-1..=1).all(|dx| {
+                    let (nx, ny) = (x + dx, y + dy);
+                    nx < 0 || ny < 0 || nx >= w || ny >= h || dist[(ny * w + nx) as usize] <= d
+                })
+            });
+            if is_peak {
+                ridge.push(d);
+            }
+        }
+    }
+
+    if ridge.is_empty() {
+        return None;
+    }
+    ridge.sort_by(|a, b| a.total_cmp(b));
+    // Distances are measured centre to centre, so a one pixel line already reads as 1.
+    Some((2.0 * ridge[ridge.len() / 2] - 1.0).max(1.0))
+}
+
+/// Grows or erodes ink so strokes of width `current` end up `target` wide. Edges get a
+/// one pixel ramp to stay anti-aliased.
+pub fn normalize_stroke(img: &DynamicImage, current: f32, target: f32) -> DynamicImage {
+    let (ink, width, height) = ink_mask(img);
+    let delta = (target - current) / 2.0;
+
+    let coverage: Vec<f32> = if delta >= 0.0 {
+        let to_ink = distance_transform(&ink, width, height);
+        to_ink.iter().map(|d| (delta + 1.0 - d).clamp(0.0, 1.0)).collect()
+    } else {
+        let background: Vec<bool> = ink.iter().map(|i| !i).collect();
+        let to_background = distance_transform(&background, width, height);
+        to_background.iter().map(|d| (d + delta).clamp(0.0, 1.0)).collect()
+    };
+
+    let out = image::GrayImage::from_fn(width, height, |x, y| {
+        let c = coverage[(y * width + x) as usize];
+        image::Luma([(255.0 * (1.0 - c)).round() as u8])
+    });
+    DynamicImage::ImageLuma8(out)
+}
+
+/// Vectorises the ink of an icon into a single SVG path. Each row is split into runs of
+/// ink and runs that line up with the run below are merged into one rectangle, which keeps
+/// the path small for the flat line art icons are drawn as.
+pub fn trace_svg(img: &DynamicImage) -> String {
+    let (ink, width, height) = ink_mask(img);
+    let mut path = String::new();
+    // Open rectangles keyed by (start, end) column, with the row they started on.
+    let mut open: Vec<(u32, u32, u32)> = Vec::new();
+    for y in 0..=height {
+        let mut runs = Vec::new();
+        if y < height {
+            let row = &ink[(y * width) as usize..((y + 1) * width) as usize];
+            let mut x = 0;
+            while x < width {
+                if row[x as usize] {
+                    let start = x;
+                    while x < width && row[x as usize] {
+                        x += 1;
+                    }
+                    runs.push((start, x));
+                } else {
+                    x += 1;
+                }
+            }
+        }
+        let mut still_open = Vec::new();
+        for (start, end, top) in open {
+            if runs.contains(&(start, end)) {
+                still_open.push((start, end, top));
+            } else {
+                path.push_str(&format!("M{} {}h{}v{}h-{}z", start, top, end - start, y - top, end - start));
+            }
+        }
+        for run in runs {
+            if !still_open.iter().any(|&(s, e, _)| (s, e) == run) {
+                still_open.push((run.0, run.1, y));
+            }
+        }
+        open = still_open;
+    }
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {w} {h}\" width=\"{w}\" height=\"{h}\">\
+         <path fill=\"#000\" d=\"{d}\"/></svg>",
+        w = width,
+        h = height,
+        d = path
+    )
+}
@@ -0,0 +1,104 @@
+use std::env;
+
+use diesel::prelude::*;
+use serde_json::json;
+use tokio::sync::{Notify, Semaphore};
+
+use crate::{
+    handlers::AppError,
+    model::AppState,
+    phash,
+    provider::GenerationParams,
+    schema::icons,
+    webhooks,
+};
+
+/// Bounds how many provider calls run at once across all requests and batches.
+pub struct JobRunner {
+    permits: Semaphore,
+    /// Wakes the batch worker when items are queued or finish.
+    pub batches: Notify,
+}
+
+impl JobRunner {
+    pub fn from_env() -> JobRunner {
+        let concurrency = env::var("GENERATION_CONCURRENCY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(4);
+        JobRunner { permits: Semaphore::new(concurrency), batches: Notify::new() }
+    }
+}
+
+/// Generates the image for an already paid-for icon row and stores it together with its
+/// perceptual hash and the seed the provider used. Progress is published on the user's
+/// event stream, ending in `job.finished` or `job.failed`.
+pub async fn generate_icon(
+    state: &AppState,
+    user_id: i32,
+    icon_id: i32,
+    params: &GenerationParams,
+) -> Result<(), AppError> {
+    state.events.publish(user_id, "job.queued", json!({ "icon_id": icon_id }));
+    match run(state, user_id, icon_id, params).await {
+        Ok(()) => {
+            state.events.publish(user_id, "job.finished", json!({ "icon_id": icon_id }));
+            webhooks::emit(state, user_id, "icon.created", json!({
+                "icon_id": icon_id,
+                "prompt": params.prompt,
+                "model": params.model.as_str(),
+                "image_url": state.url_signer.icon_image_url(icon_id),
+            }));
+            Ok(())
+        }
+        Err(e) => {
+            state.events.publish(user_id, "job.failed", json!({ "icon_id": icon_id, "error": e.to_string() }));
+            webhooks::emit(state, user_id, "icon.failed", json!({ "icon_id": icon_id, "error": e.to_string() }));
+            Err(e)
+        }
+    }
+}
+
+async fn run(
+    state: &AppState,
+    user_id: i32,
+    icon_id: i32,
+    params: &GenerationParams,
+) -> Result<(), AppError> {
+    let generated = {
+        let _permit = state.jobs.permits.acquire().await
+            .map_err(|e| AppError::InternalServerError(e.to_string()))?;
+        state.events.publish(user_id, "job.started", json!({ "icon_id": icon_id }));
+        state.image_provider.generate(params).await?
+    };
+    state.events.publish(user_id, "job.provider_responded", json!({
+        "icon_id": icon_id,
+        "provider": state.image_provider.name(),
+        "bytes": generated.data.len(),
+    }));
+
+    state.events.publish(user_id, "job.postprocess", json!({ "icon_id": icon_id, "step": "phash" }));
+    let hash = match phash::dhash(&generated.data) {
+        Ok(hash) => Some(hash),
+        Err(e) => {
+            println!("Failed to hash icon {}: {}", icon_id, e);
+            None
+        }
+    };
+
+    state.events.publish(user_id, "job.postprocess", json!({ "icon_id": icon_id, "step": "store" }));
+    let mut conn = state.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    diesel::update(icons::table.filter(icons::id.eq(icon_id)))
+        .set((
+            icons::image_data.eq(generated.data),
+            icons::phash.eq(hash.map(phash::to_db)),
+            icons::seed.eq(generated.seed.map(i64::from)),
+        ))
+        .execute(&mut conn)?;
+
+    if let Some(hash) = hash {
+        state.phash_index.insert(icon_id, hash);
+    }
+    Ok(())
+}
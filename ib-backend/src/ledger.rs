@@ -0,0 +1,262 @@
+use std::env;
+use std::time::Duration;
+
+use actix_web::{get, web, HttpResponse};
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Integer, Nullable, Text};
+use serde::Serialize;
+
+use crate::{
+    handlers::{AppError, AuthenticatedUser},
+    model::{AppState, TransactionType},
+    prompts::require_admin,
+    schema::{ledger_accounts, ledger_entries, ledger_transactions, users},
+};
+
+/// Returns the id of a user's wallet account, opening it on first use.
+pub fn user_account(conn: &mut PgConnection, user_id: i32) -> Result<i32, AppError> {
+    let existing: Option<i32> = ledger_accounts::table
+        .filter(ledger_accounts::user_id.eq(user_id))
+        .select(ledger_accounts::id)
+        .first(conn)
+        .optional()?;
+    if let Some(id) = existing {
+        return Ok(id);
+    }
+
+    let user_exists: bool = diesel::select(diesel::dsl::exists(users::table.filter(users::id.eq(user_id))))
+        .get_result(conn)?;
+    if !user_exists {
+        return Err(AppError::UserNotFound);
+    }
+    diesel::insert_into(ledger_accounts::table)
+        .values(ledger_accounts::user_id.eq(user_id))
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    Ok(ledger_accounts::table
+        .filter(ledger_accounts::user_id.eq(user_id))
+        .select(ledger_accounts::id)
+        .first(conn)?)
+}
+
+/// Returns the id of a workspace wallet, opening it on first use.
+pub fn organization_account(conn: &mut PgConnection, organization_id: i32) -> Result<i32, AppError> {
+    let existing: Option<i32> = ledger_accounts::table
+        .filter(ledger_accounts::organization_id.eq(organization_id))
+        .select(ledger_accounts::id)
+        .first(conn)
+        .optional()?;
+    if let Some(id) = existing {
+        return Ok(id);
+    }
+
+    diesel::insert_into(ledger_accounts::table)
+        .values(ledger_accounts::organization_id.eq(organization_id))
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    Ok(ledger_accounts::table
+        .filter(ledger_accounts::organization_id.eq(organization_id))
+        .select(ledger_accounts::id)
+        .first(conn)?)
+}
+
+pub fn system_account(conn: &mut PgConnection, name: &str) -> Result<i32, AppError> {
+    ledger_accounts::table
+        .filter(ledger_accounts::system.eq(name))
+        .select(ledger_accounts::id)
+        .first(conn)
+        .optional()?
+        .ok_or_else(|| AppError::InternalServerError(format!("Missing ledger account '{}'", name)))
+}
+
+/// The system account on the other side of a `kind` transaction.
+pub fn counterparty_account(conn: &mut PgConnection, kind: TransactionType) -> Result<i32, AppError> {
+    let name = kind.counterparty().ok_or_else(|| {
+        AppError::InternalServerError(format!("No system account for {} transactions", kind.as_str()))
+    })?;
+    system_account(conn, name)
+}
+
+/// A user's spendable inkbucks, 0 for users that never had an account.
+pub fn balance(conn: &mut PgConnection, user_id: i32) -> Result<i32, AppError> {
+    Ok(ledger_accounts::table
+        .filter(ledger_accounts::user_id.eq(user_id))
+        .select(ledger_accounts::balance)
+        .first(conn)
+        .optional()?
+        .unwrap_or(0))
+}
+
+/// A workspace's spendable inkbucks, 0 before anything was paid in.
+pub fn organization_balance(conn: &mut PgConnection, organization_id: i32) -> Result<i32, AppError> {
+    Ok(ledger_accounts::table
+        .filter(ledger_accounts::organization_id.eq(organization_id))
+        .select(ledger_accounts::balance)
+        .first(conn)
+        .optional()?
+        .unwrap_or(0))
+}
+
+/// Writes one ledger transaction with its entries and updates the cached balances. The
+/// entries have to sum to zero, the database refuses to commit otherwise. A wallet is only
+/// debited if it covers the amount, checked and applied in the same update so concurrent
+/// debits serialise on the row, else this fails with `InsufficientInkbucks`. `actor_id` is
+/// the user who spent or moved the inkbucks. Must run inside the caller's database transaction.
+pub fn post(
+    conn: &mut PgConnection,
+    kind: TransactionType,
+    icon_id: Option<i32>,
+    reference: Option<&str>,
+    actor_id: Option<i32>,
+    entries: &[(i32, i32)],
+) -> Result<i32, AppError> {
+    if entries.iter().map(|(_, amount)| amount).sum::<i32>() != 0 {
+        return Err(AppError::InternalServerError(format!(
+            "Unbalanced {} transaction",
+            kind.as_str()
+        )));
+    }
+
+    let transaction_id: i32 = diesel::insert_into(ledger_transactions::table)
+        .values((
+            ledger_transactions::type_.eq(kind.as_str()),
+            ledger_transactions::icon_id.eq(icon_id),
+            ledger_transactions::reference.eq(reference),
+            ledger_transactions::actor_id.eq(actor_id),
+        ))
+        .returning(ledger_transactions::id)
+        .get_result(conn)?;
+
+    // Rows are locked in account order whatever order the caller lists them in, otherwise
+    // a charge (wallet, revenue) and a refund (revenue, wallet) can deadlock each other.
+    let mut entries = entries.to_vec();
+    entries.sort_by_key(|&(account_id, _)| account_id);
+    for (account_id, amount) in entries.into_iter().filter(|(_, amount)| *amount != 0) {
+        diesel::insert_into(ledger_entries::table)
+            .values((
+                ledger_entries::transaction_id.eq(transaction_id),
+                ledger_entries::account_id.eq(account_id),
+                ledger_entries::amount.eq(amount),
+            ))
+            .execute(conn)?;
+        let updated = diesel::update(
+            ledger_accounts::table
+                .filter(ledger_accounts::id.eq(account_id))
+                .filter(ledger_accounts::system.is_not_null().or(ledger_accounts::balance.ge(-amount))),
+        )
+        .set(ledger_accounts::balance.eq(ledger_accounts::balance + amount))
+        .execute(conn)?;
+        if updated == 0 {
+            return Err(AppError::InsufficientInkbucks);
+        }
+    }
+
+    Ok(transaction_id)
+}
+
+#[derive(Debug, QueryableByName, Serialize)]
+pub struct AccountMismatch {
+    #[diesel(sql_type = Integer)]
+    pub account_id: i32,
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub user_id: Option<i32>,
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub organization_id: Option<i32>,
+    #[diesel(sql_type = Nullable<Text>)]
+    pub system: Option<String>,
+    /// Cached `ledger_accounts.balance`.
+    #[diesel(sql_type = Integer)]
+    pub balance: i32,
+    /// Sum of the account's entries.
+    #[diesel(sql_type = BigInt)]
+    pub derived: i64,
+}
+
+#[derive(Debug, QueryableByName, Serialize)]
+pub struct UnbalancedTransaction {
+    #[diesel(sql_type = Integer)]
+    pub transaction_id: i32,
+    #[diesel(sql_type = BigInt)]
+    pub total: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Reconciliation {
+    pub accounts: Vec<AccountMismatch>,
+    pub transactions: Vec<UnbalancedTransaction>,
+}
+
+impl Reconciliation {
+    pub fn is_clean(&self) -> bool {
+        self.accounts.is_empty() && self.transactions.is_empty()
+    }
+}
+
+/// Compares every cached balance with the sum of its entries and looks for transactions
+/// that do not net to zero. Only reports, fixing a balance is a decision for a human.
+pub fn reconcile(conn: &mut PgConnection) -> Result<Reconciliation, AppError> {
+    let accounts = diesel::sql_query(
+        "SELECT a.id AS account_id, a.user_id, a.organization_id, a.system, a.balance, \
+                COALESCE(SUM(e.amount), 0)::BIGINT AS derived \
+         FROM ledger_accounts a \
+         LEFT JOIN ledger_entries e ON e.account_id = a.id \
+         GROUP BY a.id \
+         HAVING a.balance <> COALESCE(SUM(e.amount), 0) \
+         ORDER BY a.id",
+    )
+    .load(conn)?;
+    let transactions = diesel::sql_query(
+        "SELECT transaction_id, SUM(amount)::BIGINT AS total \
+         FROM ledger_entries \
+         GROUP BY transaction_id \
+         HAVING SUM(amount) <> 0 \
+         ORDER BY transaction_id",
+    )
+    .load(conn)?;
+    Ok(Reconciliation { accounts, transactions })
+}
+
+/// Runs `reconcile` every `LEDGER_RECONCILE_SECS` (hourly by default) and logs mismatches.
+pub async fn run_reconciliation(state: AppState) {
+    let interval = env::var("LEDGER_RECONCILE_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(3600);
+    let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+    loop {
+        ticker.tick().await;
+        let pool = state.db_pool.clone();
+        let result = web::block(move || {
+            let mut conn = pool.get().map_err(|e| AppError::DbConnection(e.to_string()))?;
+            reconcile(&mut conn)
+        })
+        .await;
+        match result {
+            Ok(Ok(report)) if report.is_clean() => {}
+            Ok(Ok(report)) => println!(
+                "Ledger reconciliation found {} account and {} transaction mismatches: {:?}",
+                report.accounts.len(),
+                report.transactions.len(),
+                report
+            ),
+            Ok(Err(e)) => println!("Ledger reconciliation failed: {}", e),
+            Err(e) => println!("Ledger reconciliation failed: {}", e),
+        }
+    }
+}
+
+#[get("/admin/ledger/reconcile")]
+pub async fn reconcile_ledger(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    require_admin(&mut conn, user.id)?;
+    let report = reconcile(&mut conn)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "clean": report.is_clean(), "reconciliation": report }
+    })))
+}
@@ -6,10 +6,46 @@ mod model;
 mod schema;
 mod handlers;
 mod auth;
+mod search;
+mod phash;
+mod prompts;
+mod provider;
+mod billing;
+mod jobs;
+mod packs;
+mod batch;
+mod variations;
+mod imaging;
+mod versions;
+mod recolor;
+mod strokes;
+mod moderation;
+mod events;
+mod webhooks;
+mod ledger;
+mod history;
+mod payments;
+mod subscriptions;
+mod idempotency;
+mod promos;
+mod referrals;
+mod pricing;
+mod ratelimit;
+mod organizations;
+mod sharing;
+mod gallery;
+mod tokens;
+#[cfg(test)]
+mod testing;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let app_state = AppState::init().await;
+    actix_web::rt::spawn(phash::run_loader(app_state.clone()));
+    actix_web::rt::spawn(webhooks::run_dispatcher(app_state.clone()));
+    actix_web::rt::spawn(ledger::run_reconciliation(app_state.clone()));
+    actix_web::rt::spawn(subscriptions::run_scheduler(app_state.clone()));
+    actix_web::rt::spawn(batch::run_worker(app_state.clone()));
     let app_data = web::Data::new(app_state);
 
     HttpServer::new(move || {
@@ -17,11 +53,20 @@ async fn main() -> std::io::Result<()> {
             .wrap(
                 Cors::default()
                     .allowed_origin("http://localhost:8000") // Match Traefik frontend URL
-                    .allowed_methods(vec!["GET", "POST"])
+                    .allowed_methods(vec!["GET", "POST", "PUT"])
                     .allowed_headers(vec![
                         actix_web::http::header::CONTENT_TYPE,
                         actix_web::http::header::ACCEPT,
                         actix_web::http::header::AUTHORIZATION,
+                        actix_web::http::header::HeaderName::from_static("last-event-id"),
+                        actix_web::http::header::HeaderName::from_static("idempotency-key"),
+                        actix_web::http::header::HeaderName::from_static("x-share-password"),
+                    ])
+                    .expose_headers(vec![
+                        actix_web::http::header::RETRY_AFTER,
+                        actix_web::http::header::HeaderName::from_static("ratelimit-limit"),
+                        actix_web::http::header::HeaderName::from_static("ratelimit-remaining"),
+                        actix_web::http::header::HeaderName::from_static("ratelimit-reset"),
                     ])
                     .max_age(3600),
             )
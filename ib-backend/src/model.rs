@@ -1,64 +1,224 @@
 use serde::{Deserialize, Serialize};
 use diesel::prelude::*;
 use diesel::r2d2::{ConnectionManager, Pool};
-use crate::schema::{icons, users};
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use crate::events::EventBus;
+use crate::jobs::JobRunner;
+use crate::moderation::PromptPolicy;
+use crate::payments::{self, PaymentProvider};
+use crate::ratelimit::RateLimiter;
+use crate::sharing::UrlSigner;
+use crate::webhooks::Webhooks;
+use crate::phash::PhashIndex;
+use crate::provider::{self, ImageProvider, RequestedParams};
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Kind of a ledger transaction. Credits move inkbucks from a system account into a user's
+/// wallet, debits move them from the wallet to revenue.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum TransactionType {
+    SignupBonus,
+    Purchase,
+    Grant,
+    Refund,
+    /// Balance carried over from before the ledger existed.
+    OpeningBalance,
+    /// Redeemed promo code.
+    Promo,
+    /// Bonus for referring someone or being referred.
+    Referral,
+    /// Inkbucks a member moved from their wallet into a workspace wallet.
+    Transfer,
     Generate,
     Style,
     Edit,
+    Upscale,
+    Export,
+}
+
+impl TransactionType {
+    pub const ALL: [TransactionType; 13] = [
+        TransactionType::SignupBonus,
+        TransactionType::Purchase,
+        TransactionType::Grant,
+        TransactionType::Refund,
+        TransactionType::OpeningBalance,
+        TransactionType::Promo,
+        TransactionType::Referral,
+        TransactionType::Transfer,
+        TransactionType::Generate,
+        TransactionType::Style,
+        TransactionType::Edit,
+        TransactionType::Upscale,
+        TransactionType::Export,
+    ];
+
+    pub fn parse(s: &str) -> Option<TransactionType> {
+        TransactionType::ALL.into_iter().find(|kind| kind.as_str() == s)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionType::SignupBonus => "signup_bonus",
+            TransactionType::Purchase => "purchase",
+            TransactionType::Grant => "grant",
+            TransactionType::Refund => "refund",
+            TransactionType::OpeningBalance => "opening_balance",
+            TransactionType::Promo => "promo",
+            TransactionType::Referral => "referral",
+            TransactionType::Transfer => "transfer",
+            TransactionType::Generate => "generate",
+            TransactionType::Style => "style",
+            TransactionType::Edit => "edit",
+            TransactionType::Upscale => "upscale",
+            TransactionType::Export => "export",
+        }
+    }
+
+    /// System account on the other side of the wallet, `None` for transfers which move
+    /// inkbucks between two wallets.
+    pub fn counterparty(&self) -> Option<&'static str> {
+        match self {
+            TransactionType::SignupBonus => Some("signup_bonus"),
+            TransactionType::Purchase => Some("purchases"),
+            TransactionType::Grant => Some("grants"),
+            TransactionType::OpeningBalance => Some("opening_balance"),
+            TransactionType::Promo => Some("promotions"),
+            TransactionType::Referral => Some("referrals"),
+            TransactionType::Transfer => None,
+            TransactionType::Refund
+            | TransactionType::Generate
+            | TransactionType::Style
+            | TransactionType::Edit
+            | TransactionType::Upscale
+            | TransactionType::Export => Some("revenue"),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CreateUser {
     pub email: String,
     pub username: String,
+    /// Code from a referral link the user signed up through.
+    pub referral_code: Option<String>,
 }
 
 #[derive(Debug, Queryable, Serialize)]
-#[diesel(table_name = users)]
+#[diesel(table_name = crate::schema::users)]
 pub struct User {
     pub id: i32,
     pub email: String,
     pub username: String,
-    pub inkbucks: i32,
     pub uid: String,
+    pub is_admin: bool,
+    /// Whether the sign-in provider confirmed the user owns `email`.
+    pub email_verified: bool,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CreateIcon {
-    pub user_id: i32,
     pub icon_pack_id: Option<i32>,
+    /// Workspace the icon belongs to, taken from the pack when there is one.
+    pub organization_id: Option<i32>,
     pub metadata: Option<String>,
+    pub tags: Option<Vec<String>>,
+    /// Ask for a warning instead of a charge when the prompt already produced an icon.
+    pub warn_on_duplicate: Option<bool>,
+    /// Prompt template name, the default ink style when omitted.
+    pub template: Option<String>,
+    pub style: Option<String>,
+    pub stroke_weight: Option<String>,
+    pub palette: Option<String>,
+    #[serde(flatten)]
+    pub params: RequestedParams,
 }
 
 #[derive(Debug, Queryable, Serialize)]
-#[diesel(table_name = icons)]
+#[diesel(table_name = crate::schema::icons)]
 pub struct Icon {
     pub id: i32,
     pub user_id: i32,
     pub icon_pack_id: Option<i32>,
     pub metadata: Option<String>,
     pub image_data: Vec<u8>,
+    pub created_at: DateTime<Utc>,
+    pub prompt: Option<String>,
+    pub tags: Vec<String>,
+    pub phash: Option<i64>,
+    pub prompt_template_id: Option<i32>,
+    pub seed: Option<i64>,
+    pub aspect_ratio: Option<String>,
+    pub negative_prompt: Option<String>,
+    pub model: Option<String>,
+    pub output_format: Option<String>,
+    pub source_icon_id: Option<i32>,
+    pub candidate_status: Option<String>,
+    pub stroke_width: Option<f32>,
+    /// Workspace the icon belongs to, `None` for personal icons.
+    pub organization_id: Option<i32>,
+}
+
+/// Lowercases, trims and de-duplicates user supplied tags, dropping empty ones.
+pub fn normalize_tags(tags: &[String]) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        if !tag.is_empty() && !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    normalized
+}
+
+#[derive(Debug, Queryable, Serialize)]
+#[diesel(table_name = crate::schema::icon_packs)]
+pub struct IconPack {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    pub palette: Option<serde_json::Value>,
+    pub organization_id: Option<i32>,
+    /// `packs::PackStyle` the pack's icons are generated with.
+    pub style: Option<serde_json::Value>,
+    pub remixed_from: Option<i32>,
 }
 
 #[derive(Clone)]
 pub struct AppState {
     pub db_pool: Pool<ConnectionManager<PgConnection>>,
+    pub phash_index: Arc<PhashIndex>,
+    pub image_provider: Arc<dyn ImageProvider>,
+    pub jobs: Arc<JobRunner>,
+    pub moderation: Arc<PromptPolicy>,
+    pub events: Arc<EventBus>,
+    pub webhooks: Arc<Webhooks>,
+    pub payments: Arc<dyn PaymentProvider>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub url_signer: Arc<UrlSigner>,
 }
 
 impl AppState {
     pub async fn init() -> AppState {
         let database_url = std::env::var("DATABASE_URL")
             .unwrap_or_else(|_| "postgres://ib_usr:ib_pwd@db:5432/ib_db".to_string());
-        let database_url = String::from("postgres://ib_usr:ib_pwd@db:5432/ib_db");
 
         println!("database_url: {}", &database_url);
         let manager = ConnectionManager::<PgConnection>::new(database_url);
         let pool = Pool::builder()
             .build(manager)
             .expect("Failed to create pool");
-        AppState { db_pool: pool }
+        AppState {
+            db_pool: pool,
+            phash_index: Arc::new(PhashIndex::default()),
+            image_provider: provider::from_env(),
+            jobs: Arc::new(JobRunner::from_env()),
+            moderation: Arc::new(PromptPolicy::from_env()),
+            events: Arc::new(EventBus::default()),
+            webhooks: Arc::new(Webhooks::from_env()),
+            payments: payments::from_env(),
+            rate_limiter: Arc::new(RateLimiter::from_env()),
+            url_signer: Arc::new(UrlSigner::from_env()),
+        }
     }
 }
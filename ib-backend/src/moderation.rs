@@ -0,0 +1,206 @@
+use std::env;
+use std::fs;
+
+use futures::future::BoxFuture;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{handlers::AppError, provider::RequestedParams};
+
+/// Terms rejected when no `MODERATION_CONFIG` is given.
+const DEFAULT_BLOCKLIST: [&str; 8] = [
+    "nude", "naked", "nsfw", "porn", "gore", "swastika", "beheading", "child abuse",
+];
+
+#[derive(Debug, Default, Deserialize)]
+struct RuleConfig {
+    pattern: String,
+    reason: String,
+}
+
+/// Shape of the JSON file `MODERATION_CONFIG` points at.
+#[derive(Debug, Default, Deserialize)]
+struct PolicyConfig {
+    #[serde(default)]
+    blocklist: Vec<String>,
+    /// Phrases that are fine even though they contain a blocked term or match a rule,
+    /// e.g. "shooting star".
+    #[serde(default)]
+    allowlist: Vec<String>,
+    #[serde(default)]
+    rules: Vec<RuleConfig>,
+}
+
+struct Rule {
+    pattern: Regex,
+    reason: String,
+}
+
+pub struct Classification {
+    pub flagged: bool,
+    pub category: Option<String>,
+}
+
+/// External moderation model, consulted after the local rules pass.
+pub trait ContentClassifier: Send + Sync {
+    fn classify<'a>(&'a self, text: &'a str) -> BoxFuture<'a, Result<Classification, AppError>>;
+}
+
+/// Stand-in until a hosted classifier is wired up, flags nothing.
+pub struct LocalClassifier;
+
+impl ContentClassifier for LocalClassifier {
+    fn classify<'a>(&'a self, _text: &'a str) -> BoxFuture<'a, Result<Classification, AppError>> {
+        Box::pin(async { Ok(Classification { flagged: false, category: None }) })
+    }
+}
+
+pub struct PromptPolicy {
+    blocklist: Vec<Regex>,
+    allowlist: Vec<String>,
+    rules: Vec<Rule>,
+    classifier: Box<dyn ContentClassifier>,
+}
+
+impl PromptPolicy {
+    /// Loads the policy from the JSON file in `MODERATION_CONFIG`, falling back to the
+    /// built-in blocklist. A broken config aborts startup rather than silently allowing
+    /// everything.
+    pub fn from_env() -> PromptPolicy {
+        let config = match env::var("MODERATION_CONFIG") {
+            Ok(path) => {
+                let raw = fs::read_to_string(&path)
+                    .unwrap_or_else(|e| panic!("Failed to read moderation config {}: {}", path, e));
+                serde_json::from_str(&raw)
+                    .unwrap_or_else(|e| panic!("Invalid moderation config {}: {}", path, e))
+            }
+            Err(_) => PolicyConfig {
+                blocklist: DEFAULT_BLOCKLIST.iter().map(|t| t.to_string()).collect(),
+                ..PolicyConfig::default()
+            },
+        };
+        PromptPolicy::new(config, Box::new(LocalClassifier))
+    }
+
+    fn new(config: PolicyConfig, classifier: Box<dyn ContentClassifier>) -> PromptPolicy {
+        let blocklist = config.blocklist
+            .iter()
+            .map(|term| Regex::new(&format!(r"\b{}\b", regex::escape(&term.to_lowercase()))).unwrap())
+            .collect();
+        let rules = config.rules
+            .into_iter()
+            .map(|rule| Rule {
+                pattern: Regex::new(&format!("(?i){}", rule.pattern))
+                    .unwrap_or_else(|e| panic!("Invalid moderation rule '{}': {}", rule.pattern, e)),
+                reason: rule.reason,
+            })
+            .collect();
+        let allowlist = config.allowlist.iter().map(|phrase| phrase.to_lowercase()).collect();
+        PromptPolicy { blocklist, allowlist, rules, classifier }
+    }
+
+    /// Rejects text that breaks the policy with `AppError::ContentPolicy`. Runs before
+    /// anything is charged.
+    pub async fn check(&self, text: &str) -> Result<(), AppError> {
+        let mut normalized = text.to_lowercase();
+        for phrase in &self.allowlist {
+            normalized = normalized.replace(phrase.as_str(), " ");
+        }
+
+        if let Some(term) = self.blocklist.iter().find_map(|re| re.find(&normalized)) {
+            return Err(AppError::ContentPolicy(format!("'{}' is not allowed", term.as_str())));
+        }
+        if let Some(rule) = self.rules.iter().find(|rule| rule.pattern.is_match(&normalized)) {
+            return Err(AppError::ContentPolicy(rule.reason.clone()));
+        }
+
+        let classification = self.classifier.classify(text).await?;
+        if classification.flagged {
+            return Err(AppError::ContentPolicy(format!(
+                "Prompt flagged as {}",
+                classification.category.as_deref().unwrap_or("unsafe")
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks every text of a generation request that reaches the provider: the rendered
+    /// prompt and the negative prompt the client may set. A template's own negative prompt
+    /// is left alone, it lists exactly the terms it keeps out.
+    pub async fn check_request(&self, prompt: &str, params: &RequestedParams) -> Result<(), AppError> {
+        self.check(prompt).await?;
+        if let Some(negative_prompt) = &params.negative_prompt {
+            self.check(negative_prompt).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FlagEverything;
+
+    impl ContentClassifier for FlagEverything {
+        fn classify<'a>(&'a self, _text: &'a str) -> BoxFuture<'a, Result<Classification, AppError>> {
+            Box::pin(async { Ok(Classification { flagged: true, category: Some("violence".to_string()) }) })
+        }
+    }
+
+    fn policy(blocklist: &[&str], allowlist: &[&str], rules: &[(&str, &str)]) -> PromptPolicy {
+        let config = PolicyConfig {
+            blocklist: blocklist.iter().map(|t| t.to_string()).collect(),
+            allowlist: allowlist.iter().map(|t| t.to_string()).collect(),
+            rules: rules
+                .iter()
+                .map(|(pattern, reason)| RuleConfig { pattern: pattern.to_string(), reason: reason.to_string() })
+                .collect(),
+        };
+        PromptPolicy::new(config, Box::new(LocalClassifier))
+    }
+
+    fn rejected(result: Result<(), AppError>) -> String {
+        match result {
+            Err(AppError::ContentPolicy(reason)) => reason,
+            other => panic!("expected a policy violation, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn blocklist_matches_whole_words_in_any_case() {
+        let policy = policy(&["gore", "child abuse"], &[], &[]);
+        assert_eq!(rejected(policy.check("A GORE icon").await), "'gore' is not allowed");
+        assert_eq!(rejected(policy.check("stop child abuse").await), "'child abuse' is not allowed");
+        assert!(policy.check("a gorgeous, Gorey-style gorge").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn allowlisted_phrases_pass_the_blocklist_and_rules() {
+        let policy = policy(&["shooting"], &["Shooting Star"], &[(r"\bstar\b", "No stars")]);
+        assert!(policy.check("a SHOOTING STAR over the hills").await.is_ok());
+        assert_eq!(rejected(policy.check("a shooting gallery").await), "'shooting' is not allowed");
+        assert_eq!(rejected(policy.check("a star").await), "No stars");
+    }
+
+    #[tokio::test]
+    async fn rules_are_case_insensitive_regexes() {
+        let policy = policy(&[], &[], &[(r"\b(buy|order) now\b", "No advertising")]);
+        assert_eq!(rejected(policy.check("ORDER NOW sign").await), "No advertising");
+        assert!(policy.check("an order form").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn classifier_runs_after_the_local_rules() {
+        let policy = PromptPolicy::new(PolicyConfig::default(), Box::new(FlagEverything));
+        assert_eq!(rejected(policy.check("a cat").await), "Prompt flagged as violence");
+    }
+
+    #[tokio::test]
+    async fn checks_the_requested_negative_prompt() {
+        let policy = policy(&["gore"], &[], &[]);
+        let params = RequestedParams { negative_prompt: Some("gore".to_string()), ..RequestedParams::default() };
+        assert!(policy.check_request("a cat", &params).await.is_err());
+        assert!(policy.check_request("a cat", &RequestedParams::default()).await.is_ok());
+    }
+}
@@ -0,0 +1,1063 @@
+use std::collections::HashMap;
+use std::env;
+
+use actix_web::{
+    get,
+    http::header::{CacheControl, CacheDirective},
+    post, put, web, HttpResponse,
+};
+use chrono::{DateTime, Datelike, Duration, Utc};
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::sql_types::{Bool, Integer, Nullable, Text};
+use rand::Rng;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{
+    events,
+    handlers::{AppError, AuthenticatedUser},
+    ledger,
+    model::{AppState, TransactionType},
+    packs::find_pack,
+    schema::{
+        icon_packs, icons, ledger_accounts, ledger_entries, ledger_transactions, organization_audit_log,
+        organization_invitations, organization_members, organizations, users,
+    },
+    sharing,
+};
+
+const MAX_NAME_LENGTH: usize = 100;
+
+define_sql_function!(fn lower(x: Text) -> Text);
+
+/// What a member may do in a workspace, each role includes the ones below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// Sees the workspace's packs and icons.
+    Viewer,
+    /// Creates and edits icons, spending from the workspace wallet.
+    Editor,
+    /// Manages editors and viewers, invitations and spend caps.
+    Admin,
+    Owner,
+}
+
+impl Role {
+    const ALL: [Role; 4] = [Role::Viewer, Role::Editor, Role::Admin, Role::Owner];
+
+    pub fn parse(s: &str) -> Option<Role> {
+        Role::ALL.into_iter().find(|role| role.as_str() == s)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Admin => "admin",
+            Role::Owner => "owner",
+        }
+    }
+
+    fn and_above(self) -> Vec<&'static str> {
+        Role::ALL.into_iter().filter(|role| *role >= self).map(|role| role.as_str()).collect()
+    }
+
+    /// Owners manage everyone, admins manage editors and viewers.
+    fn can_manage(self, other: Role) -> bool {
+        self == Role::Owner || (self == Role::Admin && other < Role::Admin)
+    }
+}
+
+fn parse_role(s: &str) -> Result<Role, AppError> {
+    Role::parse(s).ok_or_else(|| AppError::BadRequest(format!("Unknown role '{}'", s)))
+}
+
+#[derive(Debug, Queryable, Serialize)]
+#[diesel(table_name = organizations)]
+pub struct Organization {
+    pub id: i32,
+    pub name: String,
+    pub created_by: Option<i32>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Queryable, Serialize)]
+#[diesel(table_name = organization_members)]
+pub struct Membership {
+    pub organization_id: i32,
+    pub user_id: i32,
+    pub role: String,
+    pub monthly_spend_cap: Option<i32>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Membership {
+    fn role(&self) -> Role {
+        Role::parse(&self.role).unwrap_or(Role::Viewer)
+    }
+}
+
+#[derive(Debug, Queryable, Serialize)]
+#[diesel(table_name = organization_invitations)]
+pub struct Invitation {
+    pub id: i32,
+    pub organization_id: i32,
+    pub email: String,
+    pub role: String,
+    /// Only handed out once, in the link returned when the invitation is created.
+    #[serde(skip_serializing)]
+    pub token: String,
+    pub invited_by: Option<i32>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub accepted_by: Option<i32>,
+    pub accepted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Queryable, Serialize)]
+#[diesel(table_name = organization_audit_log)]
+pub struct AuditEntry {
+    pub id: i32,
+    pub organization_id: i32,
+    pub actor_id: Option<i32>,
+    pub action: String,
+    pub details: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+/// How long an invitation can be accepted, `INVITATION_TTL_DAYS` (7 by default).
+fn invitation_ttl() -> Duration {
+    Duration::days(
+        env::var("INVITATION_TTL_DAYS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(7),
+    )
+}
+
+fn membership(
+    conn: &mut PgConnection,
+    organization_id: i32,
+    user_id: i32,
+) -> Result<Option<Membership>, AppError> {
+    Ok(organization_members::table
+        .filter(organization_members::organization_id.eq(organization_id))
+        .filter(organization_members::user_id.eq(user_id))
+        .first(conn)
+        .optional()?)
+}
+
+/// The user's role in a workspace if it is at least `min`. Workspaces the user is not a
+/// member of are reported as missing.
+pub fn require_role(
+    conn: &mut PgConnection,
+    organization_id: i32,
+    user_id: i32,
+    min: Role,
+) -> Result<Role, AppError> {
+    let role = membership(conn, organization_id, user_id)?
+        .ok_or_else(|| AppError::NotFound("Workspace not found".to_string()))?
+        .role();
+    if role < min {
+        return Err(AppError::Forbidden(format!("Requires the {} role", min.as_str())));
+    }
+    Ok(role)
+}
+
+/// Workspaces the user is a member of with at least `min`, as a subquery.
+fn member_of(user_id: i32, min: Role) -> organization_members::BoxedQuery<'static, Pg, Nullable<Integer>> {
+    organization_members::table
+        .filter(organization_members::user_id.eq(user_id))
+        .filter(organization_members::role.eq_any(min.and_above()))
+        .select(organization_members::organization_id.nullable())
+        .into_boxed()
+}
+
+/// Filter for the icons a user can work with at `min`: their personal icons, those of their
+/// workspaces and everything in a pack they can work with at `min`.
+pub fn icon_access(user_id: i32, min: Role) -> Box<dyn BoxableExpression<icons::table, Pg, SqlType = Bool>> {
+    let packs = icon_packs::table
+        .filter(pack_access(user_id, min))
+        .select(icon_packs::id.nullable())
+        .into_boxed();
+    Box::new(
+        icons::organization_id
+            .is_null()
+            .and(icons::user_id.eq(user_id))
+            .or(icons::organization_id.eq_any(member_of(user_id, min)).assume_not_null())
+            .or(icons::icon_pack_id.eq_any(packs).assume_not_null()),
+    )
+}
+
+/// Filter for the packs a user can work with at `min`: their personal packs, those of their
+/// workspaces and packs shared with them with a permission that covers `min`.
+pub fn pack_access(user_id: i32, min: Role) -> Box<dyn BoxableExpression<icon_packs::table, Pg, SqlType = Bool>> {
+    Box::new(
+        icon_packs::organization_id
+            .is_null()
+            .and(icon_packs::user_id.eq(user_id))
+            .or(icon_packs::organization_id.eq_any(member_of(user_id, min)).assume_not_null())
+            .or(icon_packs::id.eq_any(sharing::shared_packs(user_id, min))),
+    )
+}
+
+/// Workspace a new icon belongs to: the pack's when it goes into one, else the requested
+/// workspace, else none. The user has to be an editor there.
+pub fn icon_workspace(
+    conn: &mut PgConnection,
+    user_id: i32,
+    pack_id: Option<i32>,
+    organization_id: Option<i32>,
+) -> Result<Option<i32>, AppError> {
+    if let Some(pack_id) = pack_id {
+        let pack = find_pack(conn, user_id, pack_id, Role::Editor)?;
+        if organization_id.is_some() && organization_id != pack.organization_id {
+            return Err(AppError::BadRequest("The pack belongs to a different workspace".to_string()));
+        }
+        return Ok(pack.organization_id);
+    }
+    if let Some(organization_id) = organization_id {
+        require_role(conn, organization_id, user_id, Role::Editor)?;
+    }
+    Ok(organization_id)
+}
+
+fn month_start() -> DateTime<Utc> {
+    Utc::now().date_naive().with_day(1).unwrap().and_hms_opt(0, 0, 0).unwrap().and_utc()
+}
+
+/// Inkbucks each member spent from the workspace wallet this month, net of refunds.
+fn spent_this_month(conn: &mut PgConnection, organization_id: i32) -> Result<HashMap<i32, i64>, AppError> {
+    let rows: Vec<(Option<i32>, Option<i64>)> = ledger_entries::table
+        .inner_join(ledger_transactions::table)
+        .inner_join(ledger_accounts::table)
+        .filter(ledger_accounts::organization_id.eq(organization_id))
+        .filter(ledger_transactions::type_.ne(TransactionType::Transfer.as_str()))
+        .filter(ledger_transactions::created_at.ge(month_start()))
+        .group_by(ledger_transactions::actor_id)
+        .select((ledger_transactions::actor_id, diesel::dsl::sum(ledger_entries::amount)))
+        .load(conn)?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|(actor_id, amount)| Some((actor_id?, -amount.unwrap_or(0))))
+        .collect())
+}
+
+/// Checks that a member may spend `amount` from the workspace wallet: editors and above
+/// can, within their monthly cap. The membership row stays locked until the caller's
+/// transaction ends, so concurrent charges of one member cannot overrun the cap together.
+pub fn check_spend(
+    conn: &mut PgConnection,
+    organization_id: i32,
+    member_id: i32,
+    amount: i32,
+) -> Result<(), AppError> {
+    let member: Membership = organization_members::table
+        .filter(organization_members::organization_id.eq(organization_id))
+        .filter(organization_members::user_id.eq(member_id))
+        .for_update()
+        .first(conn)
+        .optional()?
+        .ok_or_else(|| AppError::Forbidden("Not a member of this workspace".to_string()))?;
+    if member.role() < Role::Editor {
+        return Err(AppError::Forbidden("Viewers cannot spend from the workspace wallet".to_string()));
+    }
+    if let Some(cap) = member.monthly_spend_cap {
+        let spent = spent_this_month(conn, organization_id)?.get(&member_id).copied().unwrap_or(0);
+        if spent + amount as i64 > cap as i64 {
+            return Err(AppError::Forbidden(format!(
+                "Monthly workspace spend cap of {} inkbucks reached, {} left",
+                cap,
+                (cap as i64 - spent).max(0)
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn audit(
+    conn: &mut PgConnection,
+    organization_id: i32,
+    actor_id: i32,
+    action: &str,
+    details: serde_json::Value,
+) -> Result<(), AppError> {
+    diesel::insert_into(organization_audit_log::table)
+        .values((
+            organization_audit_log::organization_id.eq(organization_id),
+            organization_audit_log::actor_id.eq(actor_id),
+            organization_audit_log::action.eq(action),
+            organization_audit_log::details.eq(details),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Owners of the workspace. Locks the workspace row until the caller's transaction ends,
+/// so two owners stepping down at once can't each count on the other to stay.
+fn owner_count(conn: &mut PgConnection, organization_id: i32) -> Result<i64, AppError> {
+    organizations::table
+        .filter(organizations::id.eq(organization_id))
+        .select(organizations::id)
+        .for_update()
+        .first::<i32>(conn)?;
+    Ok(organization_members::table
+        .filter(organization_members::organization_id.eq(organization_id))
+        .filter(organization_members::role.eq(Role::Owner.as_str()))
+        .count()
+        .get_result(conn)?)
+}
+
+#[derive(Deserialize)]
+struct CreateOrganization {
+    name: String,
+}
+
+#[post("/organizations")]
+pub async fn create_organization(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    body: web::Json<CreateOrganization>,
+) -> Result<HttpResponse, AppError> {
+    let name = body.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(AppError::BadRequest(format!(
+            "Workspace names are 1 to {} characters",
+            MAX_NAME_LENGTH
+        )));
+    }
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let organization: Organization = conn.transaction(|conn| {
+        let organization: Organization = diesel::insert_into(organizations::table)
+            .values((organizations::name.eq(name), organizations::created_by.eq(user.id)))
+            .get_result(conn)?;
+        diesel::insert_into(organization_members::table)
+            .values((
+                organization_members::organization_id.eq(organization.id),
+                organization_members::user_id.eq(user.id),
+                organization_members::role.eq(Role::Owner.as_str()),
+            ))
+            .execute(conn)?;
+        audit(conn, organization.id, user.id, "organization.created", serde_json::json!({ "name": name }))?;
+        Ok::<_, AppError>(organization)
+    })?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "organization": organization, "role": Role::Owner.as_str() }
+    })))
+}
+
+/// Workspaces the user is a member of, with their role and the wallet balance.
+#[get("/organizations")]
+pub async fn list_organizations(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let memberships: Vec<(Organization, String)> = organizations::table
+        .inner_join(organization_members::table)
+        .filter(organization_members::user_id.eq(user.id))
+        .select((organizations::all_columns, organization_members::role))
+        .order(organizations::id.asc())
+        .load(&mut conn)?;
+
+    let mut result = Vec::with_capacity(memberships.len());
+    for (organization, role) in memberships {
+        let balance = ledger::organization_balance(&mut conn, organization.id)?;
+        result.push(serde_json::json!({ "organization": organization, "role": role, "balance": balance }));
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "organizations": result }
+    })))
+}
+
+/// A workspace with its balance and members, including what each spent this month.
+#[get("/organizations/{id}")]
+pub async fn get_organization(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let organization_id = path.into_inner();
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let role = require_role(&mut conn, organization_id, user.id, Role::Viewer)?;
+    let organization: Organization = organizations::table
+        .filter(organizations::id.eq(organization_id))
+        .first(&mut conn)?;
+    let members: Vec<(Membership, String, String)> = organization_members::table
+        .inner_join(users::table)
+        .filter(organization_members::organization_id.eq(organization_id))
+        .select((organization_members::all_columns, users::email, users::username))
+        .order(organization_members::created_at.asc())
+        .load(&mut conn)?;
+    let spent = spent_this_month(&mut conn, organization_id)?;
+
+    let members: Vec<serde_json::Value> = members
+        .into_iter()
+        .map(|(member, email, username)| {
+            serde_json::json!({
+                "user_id": member.user_id,
+                "email": email,
+                "username": username,
+                "role": member.role,
+                "monthly_spend_cap": member.monthly_spend_cap,
+                "spent_this_month": spent.get(&member.user_id).copied().unwrap_or(0),
+                "joined_at": member.created_at,
+            })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": {
+            "organization": organization,
+            "role": role.as_str(),
+            "balance": ledger::organization_balance(&mut conn, organization_id)?,
+            "members": members,
+        }
+    })))
+}
+
+/// Tells a field that was sent as `null` apart from one that was left out.
+pub(crate) fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+#[derive(Deserialize)]
+struct UpdateMember {
+    role: Option<String>,
+    /// `null` lifts the cap, leaving it out keeps the current one.
+    #[serde(default, deserialize_with = "present")]
+    monthly_spend_cap: Option<Option<i32>>,
+}
+
+/// Changes a member's role or spend cap.
+#[put("/organizations/{id}/members/{user_id}")]
+pub async fn update_member(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, i32)>,
+    body: web::Json<UpdateMember>,
+) -> Result<HttpResponse, AppError> {
+    let (organization_id, member_id) = path.into_inner();
+    let new_role = body.role.as_deref().map(parse_role).transpose()?;
+    if body.monthly_spend_cap.flatten().is_some_and(|cap| cap < 0) {
+        return Err(AppError::BadRequest("Spend caps cannot be negative".to_string()));
+    }
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let member = change_member(&mut conn, organization_id, user.id, member_id, new_role, body.monthly_spend_cap)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "member": member }
+    })))
+}
+
+/// Changes a member's role and, unless `monthly_spend_cap` is `None`, their cap on behalf of
+/// `actor_id`. The last owner can't be demoted.
+fn change_member(
+    conn: &mut PgConnection,
+    organization_id: i32,
+    actor_id: i32,
+    member_id: i32,
+    new_role: Option<Role>,
+    monthly_spend_cap: Option<Option<i32>>,
+) -> Result<Membership, AppError> {
+    conn.transaction(|conn| {
+        let actor = require_role(conn, organization_id, actor_id, Role::Admin)?;
+        let current = membership(conn, organization_id, member_id)?
+            .ok_or_else(|| AppError::NotFound("Member not found".to_string()))?;
+        if !actor.can_manage(current.role()) || new_role.is_some_and(|role| !actor.can_manage(role)) {
+            return Err(AppError::Forbidden("Only owners can manage admins and owners".to_string()));
+        }
+        if current.role() == Role::Owner
+            && new_role.is_some_and(|role| role != Role::Owner)
+            && owner_count(conn, organization_id)? == 1
+        {
+            return Err(AppError::BadRequest("A workspace needs at least one owner".to_string()));
+        }
+
+        let member: Membership = diesel::update(
+            organization_members::table
+                .filter(organization_members::organization_id.eq(organization_id))
+                .filter(organization_members::user_id.eq(member_id)),
+        )
+        .set((
+            organization_members::role.eq(new_role.map_or(current.role.as_str(), |role| role.as_str())),
+            organization_members::monthly_spend_cap.eq(monthly_spend_cap.unwrap_or(current.monthly_spend_cap)),
+        ))
+        .get_result(conn)?;
+        audit(
+            conn,
+            organization_id,
+            actor_id,
+            "member.updated",
+            serde_json::json!({
+                "user_id": member_id,
+                "role": { "from": current.role, "to": member.role },
+                "monthly_spend_cap": { "from": current.monthly_spend_cap, "to": member.monthly_spend_cap },
+            }),
+        )?;
+        Ok(member)
+    })
+}
+
+/// Removes a member, or lets members leave. Their workspace icons stay in the workspace.
+#[post("/organizations/{id}/members/{user_id}/remove")]
+pub async fn remove_member(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, AppError> {
+    let (organization_id, member_id) = path.into_inner();
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    remove(&mut conn, organization_id, user.id, member_id)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "user_id": member_id }
+    })))
+}
+
+/// Removes `member_id` on behalf of `actor_id`, who may be the member leaving. The last
+/// owner can't leave.
+fn remove(conn: &mut PgConnection, organization_id: i32, actor_id: i32, member_id: i32) -> Result<(), AppError> {
+    conn.transaction(|conn| {
+        let actor = require_role(conn, organization_id, actor_id, Role::Viewer)?;
+        let member = membership(conn, organization_id, member_id)?
+            .ok_or_else(|| AppError::NotFound("Member not found".to_string()))?;
+        if member_id != actor_id && !actor.can_manage(member.role()) {
+            return Err(AppError::Forbidden("You cannot remove this member".to_string()));
+        }
+        if member.role() == Role::Owner && owner_count(conn, organization_id)? == 1 {
+            return Err(AppError::BadRequest("A workspace needs at least one owner".to_string()));
+        }
+
+        diesel::delete(
+            organization_members::table
+                .filter(organization_members::organization_id.eq(organization_id))
+                .filter(organization_members::user_id.eq(member_id)),
+        )
+        .execute(conn)?;
+        audit(
+            conn,
+            organization_id,
+            actor_id,
+            "member.removed",
+            serde_json::json!({ "user_id": member_id, "role": member.role }),
+        )
+    })
+}
+
+#[derive(Deserialize)]
+struct CreateInvitation {
+    email: String,
+    role: String,
+}
+
+/// Invites someone by email. The response carries the link to send them, whoever signs in
+/// with that address also finds the invitation under `/me/invitations`.
+#[post("/organizations/{id}/invitations")]
+pub async fn create_invitation(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<CreateInvitation>,
+) -> Result<HttpResponse, AppError> {
+    let organization_id = path.into_inner();
+    let email = body.email.trim().to_lowercase();
+    if !email.contains('@') {
+        return Err(AppError::BadRequest("A valid email address is required".to_string()));
+    }
+    let role = parse_role(&body.role)?;
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let invitation: Invitation = conn.transaction(|conn| {
+        let actor = require_role(conn, organization_id, user.id, Role::Admin)?;
+        if role == Role::Owner || !actor.can_manage(role) {
+            return Err(AppError::Forbidden(format!("You cannot invite {}s", role.as_str())));
+        }
+        let already_member: bool = diesel::select(diesel::dsl::exists(
+            organization_members::table
+                .inner_join(users::table)
+                .filter(organization_members::organization_id.eq(organization_id))
+                .filter(lower(users::email).eq(&email)),
+        ))
+        .get_result(conn)?;
+        if already_member {
+            return Err(AppError::BadRequest(format!("{} is already a member", email)));
+        }
+
+        // An expired invitation no longer blocks a new one for the same address.
+        diesel::delete(
+            organization_invitations::table
+                .filter(organization_invitations::organization_id.eq(organization_id))
+                .filter(organization_invitations::email.eq(&email))
+                .filter(organization_invitations::accepted_at.is_null())
+                .filter(organization_invitations::expires_at.le(Utc::now())),
+        )
+        .execute(conn)?;
+        let token = hex::encode(rand::thread_rng().gen::<[u8; 32]>());
+        let invitation: Invitation = diesel::insert_into(organization_invitations::table)
+            .values((
+                organization_invitations::organization_id.eq(organization_id),
+                organization_invitations::email.eq(&email),
+                organization_invitations::role.eq(role.as_str()),
+                organization_invitations::token.eq(&token),
+                organization_invitations::invited_by.eq(user.id),
+                organization_invitations::expires_at.eq(Utc::now() + invitation_ttl()),
+            ))
+            .on_conflict_do_nothing()
+            .get_result(conn)
+            .optional()?
+            .ok_or_else(|| AppError::BadRequest(format!("{} already has an open invitation", email)))?;
+        audit(
+            conn,
+            organization_id,
+            user.id,
+            "member.invited",
+            serde_json::json!({ "invitation_id": invitation.id, "email": email, "role": role.as_str() }),
+        )?;
+        Ok(invitation)
+    })?;
+
+    let frontend = env::var("FRONTEND_URL").unwrap_or_else(|_| "http://localhost:8000".to_string());
+    // The link lets anyone with the email join, it is not kept in idempotency snapshots.
+    Ok(HttpResponse::Ok()
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .json(serde_json::json!({
+            "status": "success",
+            "data": {
+                "invitation": invitation,
+                "link": format!("{}/invitations/{}", frontend, invitation.token),
+            }
+        })))
+}
+
+/// Open invitations of a workspace.
+#[get("/organizations/{id}/invitations")]
+pub async fn list_invitations(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let organization_id = path.into_inner();
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    require_role(&mut conn, organization_id, user.id, Role::Admin)?;
+    let invitations: Vec<Invitation> = organization_invitations::table
+        .filter(organization_invitations::organization_id.eq(organization_id))
+        .filter(organization_invitations::accepted_at.is_null())
+        .order(organization_invitations::id.desc())
+        .load(&mut conn)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "invitations": invitations }
+    })))
+}
+
+#[post("/organizations/{id}/invitations/{invitation_id}/revoke")]
+pub async fn revoke_invitation(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, AppError> {
+    let (organization_id, invitation_id) = path.into_inner();
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    conn.transaction(|conn| {
+        require_role(conn, organization_id, user.id, Role::Admin)?;
+        let invitation: Invitation = diesel::delete(
+            organization_invitations::table
+                .filter(organization_invitations::id.eq(invitation_id))
+                .filter(organization_invitations::organization_id.eq(organization_id))
+                .filter(organization_invitations::accepted_at.is_null()),
+        )
+        .get_result(conn)
+        .optional()?
+        .ok_or_else(|| AppError::NotFound("Invitation not found".to_string()))?;
+        audit(
+            conn,
+            organization_id,
+            user.id,
+            "invitation.revoked",
+            serde_json::json!({ "invitation_id": invitation.id, "email": invitation.email }),
+        )
+    })?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "invitation_id": invitation_id }
+    })))
+}
+
+/// The user's address once the sign-in provider verified it, until then anyone could have
+/// typed in the address an invitation was sent to.
+fn verified_email(conn: &mut PgConnection, user_id: i32) -> Result<Option<String>, AppError> {
+    let email: Option<String> = users::table
+        .filter(users::id.eq(user_id))
+        .filter(users::email_verified)
+        .select(users::email)
+        .first(conn)
+        .optional()?;
+    Ok(email.map(|email| email.to_lowercase()))
+}
+
+/// Open invitations for the user's verified email address.
+#[get("/me/invitations")]
+pub async fn my_invitations(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let email = verified_email(&mut conn, user.id)?;
+    let invitations: Vec<(Invitation, String)> = organization_invitations::table
+        .inner_join(organizations::table)
+        .filter(organization_invitations::email.nullable().eq(&email))
+        .filter(organization_invitations::accepted_at.is_null())
+        .filter(organization_invitations::expires_at.gt(Utc::now()))
+        .select((organization_invitations::all_columns, organizations::name))
+        .load(&mut conn)?;
+    let invitations: Vec<serde_json::Value> = invitations
+        .into_iter()
+        .map(|(invitation, name)| {
+            serde_json::json!({
+                "invitation": invitation,
+                "organization_name": name,
+                "token": invitation.token,
+            })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "invitations": invitations }
+    })))
+}
+
+/// Joins the workspace with the invited role. Only the invited address can accept, once
+/// it is verified.
+#[post("/invitations/{token}/accept")]
+pub async fn accept_invitation(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let token = path.into_inner();
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let member: Membership = conn.transaction(|conn| {
+        let invitation: Invitation = organization_invitations::table
+            .filter(organization_invitations::token.eq(&token))
+            .for_update()
+            .first(conn)
+            .optional()?
+            .ok_or_else(|| AppError::NotFound("Invitation not found".to_string()))?;
+        if invitation.accepted_at.is_some() || invitation.expires_at <= Utc::now() {
+            return Err(AppError::BadRequest("This invitation is no longer valid".to_string()));
+        }
+        match verified_email(conn, user.id)? {
+            Some(email) if email == invitation.email => {}
+            Some(_) => {
+                return Err(AppError::Forbidden("This invitation is for a different email address".to_string()));
+            }
+            None => {
+                return Err(AppError::Forbidden("Verify your email address to accept invitations".to_string()));
+            }
+        }
+
+        let member: Membership = diesel::insert_into(organization_members::table)
+            .values((
+                organization_members::organization_id.eq(invitation.organization_id),
+                organization_members::user_id.eq(user.id),
+                organization_members::role.eq(&invitation.role),
+            ))
+            .on_conflict_do_nothing()
+            .get_result(conn)
+            .optional()?
+            .ok_or_else(|| AppError::BadRequest("You are already a member of this workspace".to_string()))?;
+        diesel::update(organization_invitations::table.filter(organization_invitations::id.eq(invitation.id)))
+            .set((
+                organization_invitations::accepted_by.eq(user.id),
+                organization_invitations::accepted_at.eq(Utc::now()),
+            ))
+            .execute(conn)?;
+        audit(
+            conn,
+            invitation.organization_id,
+            user.id,
+            "member.joined",
+            serde_json::json!({ "invitation_id": invitation.id, "role": invitation.role }),
+        )?;
+        Ok(member)
+    })?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "member": member }
+    })))
+}
+
+#[derive(Deserialize)]
+struct Deposit {
+    amount: i32,
+}
+
+/// Moves inkbucks from the member's own wallet into the workspace wallet.
+#[post("/organizations/{id}/wallet/deposit")]
+pub async fn deposit(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<Deposit>,
+) -> Result<HttpResponse, AppError> {
+    let organization_id = path.into_inner();
+    if body.amount < 1 {
+        return Err(AppError::BadRequest("Amount must be at least 1 inkbuck".to_string()));
+    }
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let transaction_id = conn.transaction(|conn| {
+        require_role(conn, organization_id, user.id, Role::Viewer)?;
+        let from = ledger::user_account(conn, user.id)?;
+        let to = ledger::organization_account(conn, organization_id)?;
+        let transaction_id = ledger::post(
+            conn,
+            TransactionType::Transfer,
+            None,
+            Some(&format!("organization:{}", organization_id)),
+            Some(user.id),
+            &[(from, -body.amount), (to, body.amount)],
+        )?;
+        audit(
+            conn,
+            organization_id,
+            user.id,
+            "wallet.deposit",
+            serde_json::json!({ "amount": body.amount, "transaction_id": transaction_id }),
+        )?;
+        Ok::<_, AppError>(transaction_id)
+    })?;
+    events::balance_changed(&data, user.id);
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": {
+            "transaction_id": transaction_id,
+            "balance": ledger::organization_balance(&mut conn, organization_id)?,
+        }
+    })))
+}
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+/// Membership, invitation and wallet changes, newest first. Spending is listed under
+/// `/organizations/{id}/transactions`.
+#[get("/organizations/{id}/audit")]
+pub async fn list_audit_log(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    query: web::Query<AuditQuery>,
+) -> Result<HttpResponse, AppError> {
+    let organization_id = path.into_inner();
+    let limit = query.limit.unwrap_or(50).clamp(1, 200);
+    let offset = query.offset.unwrap_or(0).max(0);
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    require_role(&mut conn, organization_id, user.id, Role::Admin)?;
+    let entries: Vec<AuditEntry> = organization_audit_log::table
+        .filter(organization_audit_log::organization_id.eq(organization_id))
+        .order(organization_audit_log::id.desc())
+        .limit(limit)
+        .offset(offset)
+        .load(&mut conn)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "entries": entries }
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    use super::*;
+    use crate::{
+        billing::{self, Wallet},
+        testing,
+    };
+
+    #[test]
+    fn owners_manage_everyone_and_admins_those_below_them() {
+        for role in Role::ALL {
+            assert!(Role::Owner.can_manage(role));
+            assert!(!Role::Editor.can_manage(role));
+            assert!(!Role::Viewer.can_manage(role));
+        }
+        assert!(Role::Admin.can_manage(Role::Viewer));
+        assert!(Role::Admin.can_manage(Role::Editor));
+        assert!(!Role::Admin.can_manage(Role::Admin));
+        assert!(!Role::Admin.can_manage(Role::Owner));
+    }
+
+    /// A new workspace with the given members.
+    fn workspace(conn: &mut PgConnection, members: &[(i32, Role)]) -> i32 {
+        let organization_id = diesel::insert_into(organizations::table)
+            .values(organizations::name.eq("Test workspace"))
+            .returning(organizations::id)
+            .get_result(conn)
+            .unwrap();
+        for (user_id, role) in members {
+            diesel::insert_into(organization_members::table)
+                .values((
+                    organization_members::organization_id.eq(organization_id),
+                    organization_members::user_id.eq(user_id),
+                    organization_members::role.eq(role.as_str()),
+                ))
+                .execute(conn)
+                .unwrap();
+        }
+        organization_id
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn the_last_owner_cannot_step_down() {
+        let pool = testing::pool(2);
+        let mut conn = pool.get().unwrap();
+        let owner = testing::user(&mut conn);
+        let admin = testing::user(&mut conn);
+        let organization_id = workspace(&mut conn, &[(owner, Role::Owner), (admin, Role::Admin)]);
+
+        let demoted = change_member(&mut conn, organization_id, owner, owner, Some(Role::Admin), None);
+        assert!(matches!(demoted, Err(AppError::BadRequest(_))));
+        assert!(matches!(remove(&mut conn, organization_id, owner, owner), Err(AppError::BadRequest(_))));
+        let promoted = change_member(&mut conn, organization_id, admin, admin, Some(Role::Owner), None);
+        assert!(matches!(promoted, Err(AppError::Forbidden(_))));
+        assert!(matches!(remove(&mut conn, organization_id, admin, owner), Err(AppError::Forbidden(_))));
+
+        // With two owners stepping down at once, one of them has to stay.
+        drop(conn);
+        for _ in 0..20 {
+            let mut conn = pool.get().unwrap();
+            diesel::update(organization_members::table.filter(organization_members::organization_id.eq(organization_id)))
+                .set(organization_members::role.eq(Role::Owner.as_str()))
+                .execute(&mut conn)
+                .unwrap();
+            drop(conn);
+
+            let barrier = Arc::new(Barrier::new(2));
+            let handles: Vec<_> = [owner, admin]
+                .into_iter()
+                .map(|member_id| {
+                    let (pool, barrier) = (pool.clone(), barrier.clone());
+                    thread::spawn(move || {
+                        let mut conn = pool.get().unwrap();
+                        barrier.wait();
+                        change_member(&mut conn, organization_id, member_id, member_id, Some(Role::Editor), None)
+                    })
+                })
+                .collect();
+            let stepped_down = handles.into_iter().filter_map(|handle| handle.join().unwrap().ok()).count();
+            assert_eq!(stepped_down, 1);
+            assert_eq!(owner_count(&mut pool.get().unwrap(), organization_id).unwrap(), 1);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn parallel_charges_stay_within_the_spend_cap() {
+        const THREADS: usize = 10;
+        let pool = testing::pool(THREADS as u32);
+        let mut conn = pool.get().unwrap();
+        let owner = testing::user(&mut conn);
+        let editor = testing::user(&mut conn);
+        let organization_id = workspace(&mut conn, &[(owner, Role::Owner), (editor, Role::Editor)]);
+        change_member(&mut conn, organization_id, owner, editor, None, Some(Some(5))).unwrap();
+        conn.transaction(|conn| {
+            let grants = ledger::counterparty_account(conn, TransactionType::Grant)?;
+            let wallet = ledger::organization_account(conn, organization_id)?;
+            ledger::post(conn, TransactionType::Grant, None, None, None, &[(grants, -100), (wallet, 100)])
+        })
+        .unwrap();
+        drop(conn);
+
+        let wallet = Wallet::Organization { id: organization_id, member_id: editor };
+        let barrier = Arc::new(Barrier::new(THREADS));
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let (pool, barrier) = (pool.clone(), barrier.clone());
+                thread::spawn(move || {
+                    let mut conn = pool.get().unwrap();
+                    barrier.wait();
+                    conn.transaction(|conn| billing::charge(conn, wallet, TransactionType::Generate, 1, None))
+                })
+            })
+            .collect();
+        let charged = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .filter(|result| match result {
+                Ok(_) => true,
+                Err(AppError::Forbidden(_)) => false,
+                Err(e) => panic!("unexpected error {}", e),
+            })
+            .count();
+
+        assert_eq!(charged, 5);
+        let mut conn = pool.get().unwrap();
+        assert_eq!(ledger::organization_balance(&mut conn, organization_id).unwrap(), 95);
+        assert_eq!(spent_this_month(&mut conn, organization_id).unwrap().get(&editor), Some(&5));
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn invitations_only_go_to_verified_addresses() {
+        let mut conn = testing::pool(1).get().unwrap();
+        let user_id = testing::user(&mut conn);
+        assert_eq!(verified_email(&mut conn, user_id).unwrap(), None);
+
+        let email: String = diesel::update(users::table.filter(users::id.eq(user_id)))
+            .set((users::email.eq(lower(users::email)), users::email_verified.eq(true)))
+            .returning(users::email)
+            .get_result(&mut conn)
+            .unwrap();
+        assert_eq!(verified_email(&mut conn, user_id).unwrap(), Some(email.clone()));
+
+        // Addresses are unique regardless of case.
+        let taken = diesel::insert_into(users::table)
+            .values((
+                users::email.eq(email.to_uppercase()),
+                users::username.eq("copycat"),
+                users::uid.eq(format!("copy-{}", email)),
+            ))
+            .execute(&mut conn);
+        assert!(taken.is_err());
+    }
+}
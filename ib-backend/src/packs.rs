@@ -0,0 +1,277 @@
+use std::io::{Cursor, Write};
+
+use actix_web::{get, post, put, web, HttpResponse};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use zip::{write::SimpleFileOptions, ZipWriter};
+
+use crate::{
+    billing::{self, Wallet},
+    events,
+    handlers::{AppError, AuthenticatedUser},
+    imaging,
+    model::{AppState, IconPack},
+    organizations::{self, Role},
+    pricing::{self, Operation},
+    prompts,
+    provider::OutputFormat,
+    schema::{icon_packs, icons},
+    subscriptions, webhooks,
+};
+
+pub const MAX_NAME_LENGTH: usize = 100;
+
+/// A pack name as it is stored, trimmed.
+pub fn pack_name(name: &str) -> Result<&str, AppError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(AppError::BadRequest(format!("Pack names are 1 to {} characters", MAX_NAME_LENGTH)));
+    }
+    Ok(name)
+}
+
+/// Loads a pack the user can work with at `role`, a personal pack of theirs or one of a
+/// workspace they are a member of. Anything else is treated as missing.
+pub fn find_pack(
+    conn: &mut PgConnection,
+    user_id: i32,
+    pack_id: i32,
+    role: Role,
+) -> Result<IconPack, AppError> {
+    icon_packs::table
+        .filter(icon_packs::id.eq(pack_id))
+        .filter(organizations::pack_access(user_id, role))
+        .first(conn)
+        .optional()?
+        .ok_or_else(|| AppError::NotFound("Icon pack not found".to_string()))
+}
+
+/// Prompt settings a pack's icons are generated with. Generating into the pack uses them
+/// for whatever the request leaves out, remixing a gallery pack copies them.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct PackStyle {
+    /// Prompt template name.
+    pub template: Option<String>,
+    pub style: Option<String>,
+    pub stroke_weight: Option<String>,
+    pub palette: Option<String>,
+}
+
+impl PackStyle {
+    pub fn of(pack: &IconPack) -> Result<PackStyle, AppError> {
+        pack.style
+            .clone()
+            .map(serde_json::from_value)
+            .transpose()
+            .map(Option::unwrap_or_default)
+            .map_err(|e| AppError::InternalServerError(e.to_string()))
+    }
+}
+
+#[derive(Deserialize)]
+struct CreatePack {
+    name: String,
+    /// Workspace to create the pack in, a personal pack when omitted.
+    organization_id: Option<i32>,
+}
+
+#[post("/packs")]
+pub async fn create_pack(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    body: web::Json<CreatePack>,
+) -> Result<HttpResponse, AppError> {
+    let name = pack_name(&body.name)?;
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    if let Some(organization_id) = body.organization_id {
+        organizations::require_role(&mut conn, organization_id, user.id, Role::Editor)?;
+    }
+    let pack: IconPack = diesel::insert_into(icon_packs::table)
+        .values((
+            icon_packs::user_id.eq(user.id),
+            icon_packs::name.eq(name),
+            icon_packs::organization_id.eq(body.organization_id),
+        ))
+        .get_result(&mut conn)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "pack": pack }
+    })))
+}
+
+#[get("/packs")]
+pub async fn list_packs(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let packs: Vec<IconPack> = icon_packs::table
+        .filter(organizations::pack_access(user.id, Role::Viewer))
+        .order(icon_packs::id.asc())
+        .load(&mut conn)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "packs": packs }
+    })))
+}
+
+/// Replaces the pack's style.
+#[put("/packs/{id}/style")]
+pub async fn set_pack_style(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<PackStyle>,
+) -> Result<HttpResponse, AppError> {
+    let pack_id = path.into_inner();
+    let style = body.into_inner();
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    find_pack(&mut conn, user.id, pack_id, Role::Editor)?;
+    if let Some(template) = &style.template {
+        prompts::select_template(&mut conn, template, user.id)?;
+    }
+    let style = serde_json::to_value(&style)
+        .map_err(|e| AppError::InternalServerError(e.to_string()))?;
+    diesel::update(icon_packs::table.filter(icon_packs::id.eq(pack_id)))
+        .set(icon_packs::style.eq(&style))
+        .execute(&mut conn)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "style": style }
+    })))
+}
+
+/// Icon id, image and output format.
+type ExportFile = (i32, Vec<u8>, Option<String>);
+
+/// The icons an export contains: every generated icon of the pack, kept variations
+/// included, but no candidates.
+fn export_files(conn: &mut PgConnection, pack_id: i32) -> Result<Vec<ExportFile>, AppError> {
+    Ok(icons::table
+        .filter(icons::icon_pack_id.eq(pack_id))
+        .filter(icons::candidate_status.is_distinct_from("candidate"))
+        .filter(icons::image_data.ne(Vec::<u8>::new()))
+        .order(icons::id.asc())
+        .select((icons::id, icons::image_data, icons::output_format))
+        .load(conn)?)
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    /// `png` (the stored images, the default) or `svg`, which needs the `export_svg`
+    /// entitlement.
+    format: Option<String>,
+}
+
+/// Downloads the finished icons of a pack as a zip archive. Candidates and icons still
+/// being generated are left out. A POST since exports are paid for, which also makes them
+/// safe to retry with an `Idempotency-Key`.
+#[post("/packs/{id}/export")]
+pub async fn export_pack(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    query: web::Query<ExportQuery>,
+) -> Result<HttpResponse, AppError> {
+    let svg = match query.format.as_deref() {
+        None | Some("png") => false,
+        Some("svg") => true,
+        Some(other) => return Err(AppError::BadRequest(format!("Unknown export format '{}'", other))),
+    };
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let pack = find_pack(&mut conn, user.id, path.into_inner(), Role::Viewer)?;
+    if svg {
+        subscriptions::require_entitlement(&mut conn, user.id, subscriptions::EXPORT_SVG)?;
+    }
+    // Checked up front so nobody waits for an archive they can't pay for.
+    let wallet = Wallet::of(user.id, pack.organization_id);
+    let cost = pricing::cost(&mut conn, wallet, Operation::Export, None)?;
+    billing::ensure_affordable(&mut conn, wallet, cost)?;
+    let files = export_files(&mut conn, pack.id)?;
+    let count = files.len();
+
+    let archive = web::block(move || {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (id, image, format) in files {
+            if svg {
+                let Ok(img) = imaging::decode(&image) else { continue };
+                zip.start_file(format!("{}.svg", id), SimpleFileOptions::default())?;
+                zip.write_all(imaging::trace_svg(&img).as_bytes())?;
+            } else {
+                let format = format.as_deref().and_then(OutputFormat::parse).unwrap_or(OutputFormat::Jpeg);
+                zip.start_file(format!("{}.{}", id, format.as_str()), SimpleFileOptions::default())?;
+                zip.write_all(&image)?;
+            }
+        }
+        Ok::<_, zip::result::ZipError>(zip.finish()?.into_inner())
+    })
+    .await
+    .map_err(|e| AppError::InternalServerError(e.to_string()))?
+    .map_err(|e| AppError::InternalServerError(e.to_string()))?;
+
+    if cost > 0 {
+        conn.transaction(|conn| billing::charge(conn, wallet, Operation::Export.transaction_type(), cost, None))?;
+        events::balance_changed(&data, user.id);
+    }
+
+    webhooks::emit(&data, user.id, "pack.exported", serde_json::json!({
+        "pack_id": pack.id,
+        "name": pack.name,
+        "icons": count,
+    }));
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"pack-{}.zip\"", pack.id),
+        ))
+        .body(archive))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn exports_kept_variations_but_not_candidates() {
+        let mut conn = testing::pool(1).get().unwrap();
+        let user_id = testing::user(&mut conn);
+        let pack_id: i32 = diesel::insert_into(icon_packs::table)
+            .values((icon_packs::user_id.eq(user_id), icon_packs::name.eq("Test")))
+            .returning(icon_packs::id)
+            .get_result(&mut conn)
+            .unwrap();
+        let mut icon = |candidate_status: Option<&str>, image_data: &[u8]| -> i32 {
+            diesel::insert_into(icons::table)
+                .values((
+                    icons::user_id.eq(user_id),
+                    icons::icon_pack_id.eq(pack_id),
+                    icons::image_data.eq(image_data),
+                    icons::candidate_status.eq(candidate_status),
+                ))
+                .returning(icons::id)
+                .get_result(&mut conn)
+                .unwrap()
+        };
+        let generated = icon(None, b"png");
+        let kept = icon(Some("kept"), b"png");
+        icon(Some("candidate"), b"png");
+        icon(None, b"");
+
+        let exported: Vec<i32> = export_files(&mut conn, pack_id).unwrap().into_iter().map(|(id, _, _)| id).collect();
+        assert_eq!(exported, vec![generated, kept]);
+    }
+}
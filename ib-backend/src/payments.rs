@@ -0,0 +1,911 @@
+use std::env;
+use std::sync::Arc;
+
+use actix_web::{get, http::header::HeaderMap, post, web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use futures::future::BoxFuture;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::{
+    billing, events,
+    handlers::{AppError, AuthenticatedUser},
+    model::{AppState, TransactionType},
+    referrals,
+    schema::payments,
+    subscriptions::{self, ProviderUpdate, SubscriptionStatus},
+};
+
+/// How old a signed webhook may be before it is rejected as a possible replay.
+const SIGNATURE_TOLERANCE_SECS: i64 = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProductKind {
+    OneTime,
+    Subscription,
+}
+
+/// Something the pricing page sells.
+#[derive(Debug, Serialize)]
+pub struct Product {
+    pub id: &'static str,
+    pub kind: ProductKind,
+    pub inkbucks: i32,
+    pub amount_cents: i32,
+    pub currency: &'static str,
+    /// Plan a subscription product signs up for, its inkbucks come as monthly grants.
+    pub plan: Option<&'static str>,
+    /// Entitlements a one-time purchase unlocks for good.
+    pub entitlements: &'static [&'static str],
+}
+
+pub const PRODUCTS: [Product; 4] = [
+    Product {
+        id: "ib-50",
+        kind: ProductKind::OneTime,
+        inkbucks: 50,
+        amount_cents: 500,
+        currency: "usd",
+        plan: None,
+        entitlements: &[],
+    },
+    Product {
+        id: "ib-120",
+        kind: ProductKind::OneTime,
+        inkbucks: 120,
+        amount_cents: 1000,
+        currency: "usd",
+        plan: None,
+        entitlements: &[subscriptions::EXPORT_SVG],
+    },
+    Product {
+        id: "ib-250",
+        kind: ProductKind::OneTime,
+        inkbucks: 250,
+        amount_cents: 2000,
+        currency: "usd",
+        plan: None,
+        entitlements: &[subscriptions::EXPORT_SVG],
+    },
+    Product {
+        id: "ib-200-monthly",
+        kind: ProductKind::Subscription,
+        inkbucks: 200,
+        amount_cents: 1500,
+        currency: "usd",
+        plan: Some("pro"),
+        entitlements: &[],
+    },
+];
+
+pub fn find_product(id: &str) -> Option<&'static Product> {
+    PRODUCTS.iter().find(|product| product.id == id)
+}
+
+#[derive(Debug, Queryable, Serialize)]
+#[diesel(table_name = payments)]
+pub struct Payment {
+    pub id: i32,
+    pub user_id: i32,
+    pub product: String,
+    pub inkbucks: i32,
+    pub amount_cents: i32,
+    pub currency: String,
+    pub provider: String,
+    pub provider_session_id: Option<String>,
+    pub status: String,
+    pub ledger_transaction_id: Option<i32>,
+    pub created_at: DateTime<Utc>,
+    pub paid_at: Option<DateTime<Utc>>,
+}
+
+pub struct CheckoutRequest<'a> {
+    pub payment_id: i32,
+    pub user_id: i32,
+    pub product: &'a Product,
+    pub success_url: &'a str,
+    pub cancel_url: &'a str,
+}
+
+pub struct CheckoutSession {
+    pub id: String,
+    /// Page the user is sent to for paying.
+    pub url: String,
+}
+
+/// What a verified provider webhook means for a payment.
+#[derive(Debug)]
+pub enum PaymentEvent {
+    Succeeded {
+        session_id: String,
+        amount_cents: Option<i64>,
+        /// Provider id of the subscription a subscription checkout started.
+        subscription_id: Option<String>,
+    },
+    Failed { session_id: String },
+    SubscriptionChanged(ProviderUpdate),
+    /// Valid, but nothing we act on.
+    Ignored,
+}
+
+pub trait PaymentProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn create_checkout<'a>(&'a self, request: &'a CheckoutRequest<'a>) -> BoxFuture<'a, Result<CheckoutSession, AppError>>;
+
+    /// Verifies the signature of a webhook call and interprets it. Anything unsigned or
+    /// wrongly signed must be rejected, it is the only proof that a payment happened.
+    fn parse_webhook(&self, headers: &HeaderMap, payload: &[u8]) -> Result<PaymentEvent, AppError>;
+
+    /// The provider's current state of one of its subscriptions.
+    fn fetch_subscription<'a>(&'a self, provider_subscription_id: &'a str) -> BoxFuture<'a, Result<ProviderUpdate, AppError>>;
+
+    /// Stops renewing a subscription after its current period.
+    fn cancel_subscription<'a>(&'a self, provider_subscription_id: &'a str) -> BoxFuture<'a, Result<(), AppError>>;
+}
+
+/// Selects the provider from `PAYMENT_PROVIDER`, `stripe` unless set to `fake`.
+pub fn from_env() -> Arc<dyn PaymentProvider> {
+    match env::var("PAYMENT_PROVIDER").as_deref() {
+        Ok("fake") => Arc::new(FakeProvider::from_env()),
+        _ => Arc::new(StripeProvider::from_env()),
+    }
+}
+
+/// Checks a `t=<unix seconds>,v1=<hex HMAC-SHA256 of "<t>.<payload>">` header. Stripe may
+/// send several `v1` values while a secret is being rolled, any of them may match.
+fn verify_signature(secret: &str, header: Option<&str>, payload: &[u8]) -> Result<(), AppError> {
+    let invalid = || AppError::BadRequest("Invalid webhook signature".to_string());
+    let header = header.ok_or_else(invalid)?;
+    let mut timestamp = None;
+    let mut signatures = Vec::new();
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", value)) => timestamp = value.parse::<i64>().ok(),
+            Some(("v1", value)) => signatures.extend(hex::decode(value).ok()),
+            _ => {}
+        }
+    }
+    let timestamp = timestamp.ok_or_else(invalid)?;
+    if (Utc::now().timestamp() - timestamp).abs() > SIGNATURE_TOLERANCE_SECS {
+        return Err(invalid());
+    }
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(payload);
+    let valid = signatures.iter().any(|signature| mac.clone().verify_slice(signature).is_ok());
+    if valid {
+        Ok(())
+    } else {
+        Err(invalid())
+    }
+}
+
+fn sign(secret: &str, timestamp: i64, payload: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(payload);
+    format!("t={},v1={}", timestamp, hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Reads a Stripe subscription object, `None` while it has not been paid for yet.
+fn stripe_subscription(object: &Value) -> Option<ProviderUpdate> {
+    let status = match object["status"].as_str() {
+        Some("trialing") => SubscriptionStatus::Trialing,
+        Some("active") => SubscriptionStatus::Active,
+        Some("past_due") | Some("unpaid") => SubscriptionStatus::PastDue,
+        Some("canceled") | Some("incomplete_expired") => SubscriptionStatus::Cancelled,
+        // incomplete and paused subscriptions have not been paid for yet
+        _ => return None,
+    };
+    // Newer API versions moved the period onto the subscription items.
+    let period = |field: &str| {
+        object[field]
+            .as_i64()
+            .or_else(|| object["items"]["data"][0][field].as_i64())
+            .and_then(|t| DateTime::from_timestamp(t, 0))
+    };
+    Some(ProviderUpdate {
+        provider_subscription_id: object["id"].as_str().unwrap_or_default().to_string(),
+        status,
+        current_period_start: period("current_period_start"),
+        current_period_end: period("current_period_end"),
+        trial_end: object["trial_end"].as_i64().and_then(|t| DateTime::from_timestamp(t, 0)),
+        cancel_at_period_end: object["cancel_at_period_end"].as_bool().unwrap_or(false),
+    })
+}
+
+/// Stripe Checkout, configured by `STRIPE_SECRET_KEY` and `STRIPE_WEBHOOK_SECRET`.
+pub struct StripeProvider {
+    secret_key: Option<String>,
+    webhook_secret: Option<String>,
+    client: Client,
+}
+
+impl StripeProvider {
+    pub fn from_env() -> StripeProvider {
+        let secret_key = env::var("STRIPE_SECRET_KEY").ok();
+        let webhook_secret = env::var("STRIPE_WEBHOOK_SECRET").ok();
+        if secret_key.is_none() || webhook_secret.is_none() {
+            println!("STRIPE_SECRET_KEY or STRIPE_WEBHOOK_SECRET not set, purchases are disabled");
+        }
+        StripeProvider { secret_key, webhook_secret, client: Client::new() }
+    }
+}
+
+impl PaymentProvider for StripeProvider {
+    fn name(&self) -> &'static str {
+        "stripe"
+    }
+
+    fn create_checkout<'a>(&'a self, request: &'a CheckoutRequest<'a>) -> BoxFuture<'a, Result<CheckoutSession, AppError>> {
+        Box::pin(async move {
+            let secret_key = self.secret_key.as_deref()
+                .ok_or_else(|| AppError::InternalServerError("Payments are not configured".to_string()))?;
+            let product = request.product;
+            let mut form = vec![
+                ("success_url", request.success_url.to_string()),
+                ("cancel_url", request.cancel_url.to_string()),
+                ("client_reference_id", request.payment_id.to_string()),
+                ("metadata[payment_id]", request.payment_id.to_string()),
+                ("metadata[user_id]", request.user_id.to_string()),
+                ("line_items[0][quantity]", "1".to_string()),
+                ("line_items[0][price_data][currency]", product.currency.to_string()),
+                ("line_items[0][price_data][unit_amount]", product.amount_cents.to_string()),
+                ("line_items[0][price_data][product_data][name]", format!("{} InkBucks", product.inkbucks)),
+            ];
+            match product.kind {
+                ProductKind::OneTime => form.push(("mode", "payment".to_string())),
+                ProductKind::Subscription => {
+                    form.push(("mode", "subscription".to_string()));
+                    form.push(("line_items[0][price_data][recurring][interval]", "month".to_string()));
+                }
+            }
+
+            let response = self.client
+                .post("https://api.stripe.com/v1/checkout/sessions")
+                .bearer_auth(secret_key)
+                .form(&form)
+                .send()
+                .await
+                .map_err(|e| AppError::InternalServerError(format!("Stripe request failed: {}", e)))?;
+            let status = response.status();
+            let body: Value = response.json().await
+                .map_err(|e| AppError::InternalServerError(format!("Invalid Stripe response: {}", e)))?;
+            if !status.is_success() {
+                return Err(AppError::InternalServerError(format!(
+                    "Stripe rejected the checkout: {}",
+                    body["error"]["message"].as_str().unwrap_or("unknown error")
+                )));
+            }
+
+            match (body["id"].as_str(), body["url"].as_str()) {
+                (Some(id), Some(url)) => Ok(CheckoutSession { id: id.to_string(), url: url.to_string() }),
+                _ => Err(AppError::InternalServerError("Stripe returned no checkout session".to_string())),
+            }
+        })
+    }
+
+    fn parse_webhook(&self, headers: &HeaderMap, payload: &[u8]) -> Result<PaymentEvent, AppError> {
+        let secret = self.webhook_secret.as_deref()
+            .ok_or_else(|| AppError::InternalServerError("Payments are not configured".to_string()))?;
+        let header = headers.get("Stripe-Signature").and_then(|v| v.to_str().ok());
+        verify_signature(secret, header, payload)?;
+
+        let event: Value = serde_json::from_slice(payload)
+            .map_err(|e| AppError::BadRequest(format!("Invalid webhook payload: {}", e)))?;
+        let object = &event["data"]["object"];
+        let id = object["id"].as_str().unwrap_or_default().to_string();
+        let succeeded = || PaymentEvent::Succeeded {
+            session_id: id.clone(),
+            amount_cents: object["amount_total"].as_i64(),
+            subscription_id: object["subscription"].as_str().map(str::to_string),
+        };
+        Ok(match event["type"].as_str() {
+            // Card payments are paid on completion, delayed methods follow up with
+            // async_payment_succeeded.
+            Some("checkout.session.completed") if object["payment_status"] == "paid" => succeeded(),
+            Some("checkout.session.async_payment_succeeded") => succeeded(),
+            Some("checkout.session.expired") | Some("checkout.session.async_payment_failed") => {
+                PaymentEvent::Failed { session_id: id }
+            }
+            Some("customer.subscription.created")
+            | Some("customer.subscription.updated")
+            | Some("customer.subscription.deleted") => match stripe_subscription(object) {
+                Some(update) => PaymentEvent::SubscriptionChanged(update),
+                None => PaymentEvent::Ignored,
+            },
+            _ => PaymentEvent::Ignored,
+        })
+    }
+
+    fn fetch_subscription<'a>(&'a self, provider_subscription_id: &'a str) -> BoxFuture<'a, Result<ProviderUpdate, AppError>> {
+        Box::pin(async move {
+            let secret_key = self.secret_key.as_deref()
+                .ok_or_else(|| AppError::InternalServerError("Payments are not configured".to_string()))?;
+            let response = self.client
+                .get(format!("https://api.stripe.com/v1/subscriptions/{}", provider_subscription_id))
+                .bearer_auth(secret_key)
+                .send()
+                .await
+                .map_err(|e| AppError::InternalServerError(format!("Stripe request failed: {}", e)))?;
+            let status = response.status();
+            let body: Value = response.json().await
+                .map_err(|e| AppError::InternalServerError(format!("Invalid Stripe response: {}", e)))?;
+            if !status.is_success() {
+                return Err(AppError::InternalServerError(format!(
+                    "Stripe refused to return the subscription: {}",
+                    body["error"]["message"].as_str().unwrap_or("unknown error")
+                )));
+            }
+            stripe_subscription(&body).ok_or_else(|| {
+                AppError::InternalServerError(format!("Subscription {} is not paid for yet", provider_subscription_id))
+            })
+        })
+    }
+
+    fn cancel_subscription<'a>(&'a self, provider_subscription_id: &'a str) -> BoxFuture<'a, Result<(), AppError>> {
+        Box::pin(async move {
+            let secret_key = self.secret_key.as_deref()
+                .ok_or_else(|| AppError::InternalServerError("Payments are not configured".to_string()))?;
+            let response = self.client
+                .post(format!("https://api.stripe.com/v1/subscriptions/{}", provider_subscription_id))
+                .bearer_auth(secret_key)
+                .form(&[("cancel_at_period_end", "true")])
+                .send()
+                .await
+                .map_err(|e| AppError::InternalServerError(format!("Stripe request failed: {}", e)))?;
+            if !response.status().is_success() {
+                return Err(AppError::InternalServerError(format!(
+                    "Stripe refused to cancel the subscription. HTTP Status: {}",
+                    response.status()
+                )));
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Local stand-in for Stripe. Its checkout page is `GET /api/payments/fake/{session}`,
+/// which pays immediately by sending itself a webhook signed with `FAKE_PAYMENT_SECRET`.
+pub struct FakeProvider {
+    secret: String,
+    public_url: String,
+}
+
+fn fake_secret() -> String {
+    env::var("FAKE_PAYMENT_SECRET").unwrap_or_else(|_| "whsec_fake".to_string())
+}
+
+impl FakeProvider {
+    pub fn from_env() -> FakeProvider {
+        FakeProvider {
+            secret: fake_secret(),
+            public_url: env::var("API_PUBLIC_URL").unwrap_or_else(|_| "http://localhost:8080".to_string()),
+        }
+    }
+}
+
+impl PaymentProvider for FakeProvider {
+    fn name(&self) -> &'static str {
+        "fake"
+    }
+
+    fn create_checkout<'a>(&'a self, _request: &'a CheckoutRequest<'a>) -> BoxFuture<'a, Result<CheckoutSession, AppError>> {
+        Box::pin(async move {
+            let mut id = [0u8; 12];
+            rand::thread_rng().fill_bytes(&mut id);
+            let id = format!("fake_{}", hex::encode(id));
+            let url = format!("{}/api/payments/fake/{}", self.public_url, id);
+            Ok(CheckoutSession { id, url })
+        })
+    }
+
+    fn parse_webhook(&self, headers: &HeaderMap, payload: &[u8]) -> Result<PaymentEvent, AppError> {
+        let header = headers.get("Fake-Signature").and_then(|v| v.to_str().ok());
+        verify_signature(&self.secret, header, payload)?;
+
+        let event: Value = serde_json::from_slice(payload)
+            .map_err(|e| AppError::BadRequest(format!("Invalid webhook payload: {}", e)))?;
+        let session_id = event["session_id"].as_str().unwrap_or_default().to_string();
+        Ok(match event["type"].as_str() {
+            Some("paid") => PaymentEvent::Succeeded {
+                session_id,
+                amount_cents: event["amount_cents"].as_i64(),
+                subscription_id: None,
+            },
+            Some("failed") => PaymentEvent::Failed { session_id },
+            _ => PaymentEvent::Ignored,
+        })
+    }
+
+    /// Fake checkouts never hand out subscription ids, their periods start at activation.
+    fn fetch_subscription<'a>(&'a self, provider_subscription_id: &'a str) -> BoxFuture<'a, Result<ProviderUpdate, AppError>> {
+        Box::pin(async move {
+            Err(AppError::NotFound(format!("Subscription {} not found", provider_subscription_id)))
+        })
+    }
+
+    fn cancel_subscription<'a>(&'a self, _provider_subscription_id: &'a str) -> BoxFuture<'a, Result<(), AppError>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Marks the payment paid and credits its inkbucks, or for a subscription starts it and
+/// grants its first month, then pays out a referral if this was the user's first purchase.
+/// The status guard in the update lets exactly one of several deliveries of the same event
+/// through, the others are no-ops. A payment for a different amount than was charged is
+/// failed instead. Returns the payment and the rewarded referrer.
+fn settle(
+    conn: &mut PgConnection,
+    session_id: &str,
+    amount_cents: Option<i64>,
+    subscription: Option<&ProviderUpdate>,
+) -> Result<Option<(Payment, Option<i32>)>, AppError> {
+    conn.transaction(|conn| {
+        let payment: Option<Payment> = diesel::update(
+            payments::table
+                .filter(payments::provider_session_id.eq(session_id))
+                .filter(payments::status.eq("pending")),
+        )
+        .set((payments::status.eq("paid"), payments::paid_at.eq(Utc::now())))
+        .get_result(conn)
+        .optional()?;
+        let Some(payment) = payment else {
+            return Ok(None);
+        };
+        if amount_cents.is_some_and(|amount| amount != payment.amount_cents as i64) {
+            // Redelivering the event won't change the amount. Credit nothing, fail the payment
+            // for support to look into and acknowledge the event.
+            println!(
+                "Payment {} was for {:?} cents, expected {}, marking it failed",
+                payment.id, amount_cents, payment.amount_cents
+            );
+            diesel::update(payments::table.filter(payments::id.eq(payment.id)))
+                .set((payments::status.eq("failed"), payments::paid_at.eq(None::<DateTime<Utc>>)))
+                .execute(conn)?;
+            return Ok(None);
+        }
+
+        let plan = find_product(&payment.product).and_then(|product| product.plan);
+        let transaction_id = match plan {
+            Some(plan) => {
+                let subscription = subscriptions::activate(
+                    conn,
+                    payment.user_id,
+                    plan,
+                    &payment.provider,
+                    subscription,
+                )?;
+                subscriptions::grant_current_period(conn, &subscription)?
+            }
+            None => {
+                let reference = format!("payment:{}", payment.id);
+                Some(billing::credit(
+                    conn,
+                    payment.user_id,
+                    TransactionType::Purchase,
+                    payment.inkbucks,
+                    None,
+                    Some(&reference),
+                )?)
+            }
+        };
+        let payment: Payment = diesel::update(payments::table.filter(payments::id.eq(payment.id)))
+            .set(payments::ledger_transaction_id.eq(transaction_id))
+            .get_result(conn)?;
+        let referrer = referrals::reward_first_purchase(conn, &payment)?;
+        Ok(Some((payment, referrer)))
+    })
+}
+
+/// Applies a verified provider event.
+async fn handle_event(state: &AppState, event: PaymentEvent) -> Result<(), AppError> {
+    // The checkout event doesn't say which billing period a subscription starts with.
+    let subscription = match &event {
+        PaymentEvent::Succeeded { subscription_id: Some(id), .. } => Some(state.payments.fetch_subscription(id).await?),
+        _ => None,
+    };
+    let mut conn = state.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    match event {
+        PaymentEvent::Succeeded { session_id, amount_cents, .. } => {
+            let settled = settle(&mut conn, &session_id, amount_cents, subscription.as_ref())?;
+            if let Some((payment, referrer)) = settled {
+                events::balance_changed(state, payment.user_id);
+                if let Some(referrer) = referrer {
+                    events::balance_changed(state, referrer);
+                }
+            }
+        }
+        PaymentEvent::SubscriptionChanged(update) => {
+            if let Some(subscription) = subscriptions::apply_provider_update(&mut conn, &update)? {
+                events::balance_changed(state, subscription.user_id);
+            }
+        }
+        PaymentEvent::Failed { session_id } => {
+            diesel::update(
+                payments::table
+                    .filter(payments::provider_session_id.eq(&session_id))
+                    .filter(payments::status.eq("pending")),
+            )
+            .set(payments::status.eq("failed"))
+            .execute(&mut conn)?;
+        }
+        PaymentEvent::Ignored => {}
+    }
+    Ok(())
+}
+
+#[get("/payments/products")]
+pub async fn list_products() -> Result<HttpResponse, AppError> {
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "products": PRODUCTS }
+    })))
+}
+
+#[derive(Deserialize)]
+struct CreateCheckout {
+    product: String,
+    success_url: Option<String>,
+    cancel_url: Option<String>,
+}
+
+/// Where the provider sends the user back to, `default_path` on the frontend unless the
+/// client asked for a page. Only pages of the frontend itself are accepted, the provider
+/// would otherwise redirect anywhere on our behalf.
+fn redirect_url(frontend: &str, requested: Option<&str>, default_path: &str) -> Result<String, AppError> {
+    let Some(requested) = requested else {
+        return Ok(format!("{}{}", frontend.trim_end_matches('/'), default_path));
+    };
+    let same_origin = match (reqwest::Url::parse(frontend), reqwest::Url::parse(requested)) {
+        (Ok(frontend), Ok(url)) => url.origin() == frontend.origin(),
+        _ => false,
+    };
+    if !same_origin {
+        return Err(AppError::BadRequest(format!("Redirect URLs must be on {}", frontend)));
+    }
+    Ok(requested.to_string())
+}
+
+/// Starts a purchase and returns the provider page to send the user to. Inkbucks are only
+/// credited once the provider's webhook confirms the payment.
+#[post("/payments/checkout")]
+pub async fn create_checkout(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    body: web::Json<CreateCheckout>,
+) -> Result<HttpResponse, AppError> {
+    let product = find_product(&body.product)
+        .ok_or_else(|| AppError::BadRequest(format!("Unknown product '{}'", body.product)))?;
+    let frontend = env::var("FRONTEND_URL").unwrap_or_else(|_| "http://localhost:8000".to_string());
+    let success_url = redirect_url(&frontend, body.success_url.as_deref(), "/pricing?checkout=success")?;
+    let cancel_url = redirect_url(&frontend, body.cancel_url.as_deref(), "/pricing?checkout=cancelled")?;
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    if product.plan.is_some() && subscriptions::live_subscription(&mut conn, user.id)?.is_some() {
+        return Err(AppError::BadRequest("You already have a subscription".to_string()));
+    }
+    let payment_id: i32 = diesel::insert_into(payments::table)
+        .values((
+            payments::user_id.eq(user.id),
+            payments::product.eq(product.id),
+            payments::inkbucks.eq(product.inkbucks),
+            payments::amount_cents.eq(product.amount_cents),
+            payments::currency.eq(product.currency),
+            payments::provider.eq(data.payments.name()),
+        ))
+        .returning(payments::id)
+        .get_result(&mut conn)?;
+
+    let request = CheckoutRequest {
+        payment_id,
+        user_id: user.id,
+        product,
+        success_url: &success_url,
+        cancel_url: &cancel_url,
+    };
+    let session = match data.payments.create_checkout(&request).await {
+        Ok(session) => session,
+        Err(e) => {
+            diesel::update(payments::table.filter(payments::id.eq(payment_id)))
+                .set(payments::status.eq("failed"))
+                .execute(&mut conn)?;
+            return Err(e);
+        }
+    };
+    let payment: Payment = diesel::update(payments::table.filter(payments::id.eq(payment_id)))
+        .set(payments::provider_session_id.eq(&session.id))
+        .get_result(&mut conn)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "payment": payment, "checkout_url": session.url }
+    })))
+}
+
+#[get("/payments/{id}")]
+pub async fn get_payment(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let payment: Payment = payments::table
+        .filter(payments::id.eq(path.into_inner()))
+        .filter(payments::user_id.eq(user.id))
+        .first(&mut conn)
+        .optional()?
+        .ok_or_else(|| AppError::NotFound("Payment not found".to_string()))?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "payment": payment }
+    })))
+}
+
+/// Called by the payment provider, authenticated by the payload signature alone.
+#[post("/payments/webhook")]
+pub async fn payment_webhook(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    payload: web::Bytes,
+) -> Result<HttpResponse, AppError> {
+    let event = data.payments.parse_webhook(req.headers(), &payload)?;
+    handle_event(&data, event).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
+}
+
+/// Checkout page of the fake provider: pays the session right away through the same
+/// signed webhook path Stripe uses, then redirects like Stripe would.
+#[get("/payments/fake/{session}")]
+pub async fn fake_checkout(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    if data.payments.name() != "fake" {
+        return Err(AppError::NotFound("Not found".to_string()));
+    }
+    let session_id = path.into_inner();
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let payment: Payment = payments::table
+        .filter(payments::provider_session_id.eq(&session_id))
+        .first(&mut conn)
+        .optional()?
+        .ok_or_else(|| AppError::NotFound("Checkout session not found".to_string()))?;
+
+    let payload = serde_json::json!({
+        "type": "paid",
+        "session_id": session_id,
+        "amount_cents": payment.amount_cents,
+    })
+    .to_string();
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        actix_web::http::header::HeaderName::from_static("fake-signature"),
+        sign(&fake_secret(), Utc::now().timestamp(), payload.as_bytes())
+            .parse()
+            .map_err(|_| AppError::InternalServerError("Invalid signature header".to_string()))?,
+    );
+    let event = data.payments.parse_webhook(&headers, payload.as_bytes())?;
+    handle_event(&data, event).await?;
+
+    let frontend = env::var("FRONTEND_URL").unwrap_or_else(|_| "http://localhost:8000".to_string());
+    Ok(HttpResponse::SeeOther()
+        .insert_header(("Location", format!("{}/pricing?checkout=success&payment={}", frontend, payment.id)))
+        .finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Barrier;
+    use std::thread;
+
+    use super::*;
+    use crate::{
+        ledger, referrals,
+        schema::{ledger_transactions, referrals as referrals_table},
+        testing,
+    };
+
+    const SECRET: &str = "whsec_test";
+
+    fn fake_provider() -> FakeProvider {
+        FakeProvider { secret: SECRET.to_string(), public_url: "http://localhost:8080".to_string() }
+    }
+
+    fn signed_headers(header: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            actix_web::http::header::HeaderName::from_static("fake-signature"),
+            header.parse().unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn accepts_a_fresh_signature() {
+        let header = sign(SECRET, Utc::now().timestamp(), b"{}");
+        assert!(verify_signature(SECRET, Some(&header), b"{}").is_ok());
+    }
+
+    #[test]
+    fn rejects_missing_and_stale_signatures() {
+        assert!(verify_signature(SECRET, None, b"{}").is_err());
+        let old = Utc::now().timestamp() - SIGNATURE_TOLERANCE_SECS - 1;
+        assert!(verify_signature(SECRET, Some(&sign(SECRET, old, b"{}")), b"{}").is_err());
+        let future = Utc::now().timestamp() + SIGNATURE_TOLERANCE_SECS + 1;
+        assert!(verify_signature(SECRET, Some(&sign(SECRET, future, b"{}")), b"{}").is_err());
+    }
+
+    #[test]
+    fn rejects_a_tampered_payload_or_timestamp() {
+        let now = Utc::now().timestamp();
+        let header = sign(SECRET, now, br#"{"amount_cents":500}"#);
+        assert!(verify_signature(SECRET, Some(&header), br#"{"amount_cents":5}"#).is_err());
+        assert!(verify_signature("whsec_other", Some(&header), br#"{"amount_cents":500}"#).is_err());
+
+        let signature = header.split_once(",v1=").unwrap().1;
+        let moved = format!("t={},v1={}", now - 1, signature);
+        assert!(verify_signature(SECRET, Some(&moved), br#"{"amount_cents":500}"#).is_err());
+    }
+
+    #[test]
+    fn accepts_any_v1_while_a_secret_is_rolled() {
+        let now = Utc::now().timestamp();
+        let old = sign("whsec_old", now, b"{}");
+        let new = sign(SECRET, now, b"{}");
+        let old_signature = old.split_once(",v1=").unwrap().1;
+        let new_signature = new.split_once(",v1=").unwrap().1;
+
+        let header = format!("t={},v1={},v1={}", now, old_signature, new_signature);
+        assert!(verify_signature(SECRET, Some(&header), b"{}").is_ok());
+        assert!(verify_signature("whsec_old", Some(&header), b"{}").is_ok());
+        assert!(verify_signature("whsec_other", Some(&header), b"{}").is_err());
+
+        let garbled = format!("t={}, v1=not-hex, v1={}", now, new_signature);
+        assert!(verify_signature(SECRET, Some(&garbled), b"{}").is_ok());
+    }
+
+    #[test]
+    fn redirects_only_to_the_frontend() {
+        let frontend = "https://inkbucket.example";
+        assert_eq!(
+            redirect_url(frontend, None, "/pricing?checkout=success").unwrap(),
+            "https://inkbucket.example/pricing?checkout=success"
+        );
+        assert_eq!(
+            redirect_url(frontend, Some("https://inkbucket.example/packs/3?done=1"), "/").unwrap(),
+            "https://inkbucket.example/packs/3?done=1"
+        );
+        for url in [
+            "https://evil.example/pricing",
+            "https://inkbucket.example.evil.example/",
+            "http://inkbucket.example/pricing",
+            "https://inkbucket.example:8443/pricing",
+            "https://user@evil.example/",
+            "//evil.example/",
+            "/pricing",
+            "javascript:alert(1)",
+        ] {
+            assert!(redirect_url(frontend, Some(url), "/").is_err(), "{} was accepted", url);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    async fn credits_a_redelivered_payment_once() {
+        let state = AppState { payments: Arc::new(fake_provider()), ..AppState::init().await };
+        let mut conn = state.db_pool.get().unwrap();
+        let user_id = testing::user(&mut conn);
+        let session_id = format!("fake_test_{}", user_id);
+        let payment_id: i32 = diesel::insert_into(payments::table)
+            .values((
+                payments::user_id.eq(user_id),
+                payments::product.eq("ib-50"),
+                payments::inkbucks.eq(50),
+                payments::amount_cents.eq(500),
+                payments::currency.eq("usd"),
+                payments::provider.eq("fake"),
+                payments::provider_session_id.eq(&session_id),
+            ))
+            .returning(payments::id)
+            .get_result(&mut conn)
+            .unwrap();
+        drop(conn);
+
+        let payload = serde_json::json!({ "type": "paid", "session_id": session_id, "amount_cents": 500 })
+            .to_string();
+        let headers = signed_headers(&sign(SECRET, Utc::now().timestamp(), payload.as_bytes()));
+        for _ in 0..2 {
+            let event = state.payments.parse_webhook(&headers, payload.as_bytes()).unwrap();
+            handle_event(&state, event).await.unwrap();
+        }
+
+        let mut conn = state.db_pool.get().unwrap();
+        let payment: Payment = payments::table.find(payment_id).first(&mut conn).unwrap();
+        assert_eq!(payment.status, "paid");
+        assert!(payment.ledger_transaction_id.is_some());
+        assert_eq!(ledger::balance(&mut conn, user_id).unwrap(), 50);
+    }
+
+    /// A pending checkout of `ib-50` for the user, returns its session id.
+    fn pending_payment(conn: &mut PgConnection, user_id: i32) -> String {
+        let session_id = format!("fake_test_{:016x}", rand::random::<u64>());
+        diesel::insert_into(payments::table)
+            .values((
+                payments::user_id.eq(user_id),
+                payments::product.eq("ib-50"),
+                payments::inkbucks.eq(50),
+                payments::amount_cents.eq(500),
+                payments::currency.eq("usd"),
+                payments::provider.eq("fake"),
+                payments::provider_session_id.eq(&session_id),
+            ))
+            .execute(conn)
+            .unwrap();
+        session_id
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn pays_a_referral_out_once() {
+        let pool = testing::pool(4);
+        let mut conn = pool.get().unwrap();
+        let referrer_id = testing::user(&mut conn);
+        let referee_id = testing::user(&mut conn);
+        let code = referrals::code_for(&mut conn, referrer_id).unwrap();
+        let referral = referrals::attach(&mut conn, referee_id, &code).unwrap();
+        // Two purchases settling at the same time, each delivered twice.
+        let sessions = [pending_payment(&mut conn, referee_id), pending_payment(&mut conn, referee_id)];
+        drop(conn);
+
+        let barrier = Arc::new(Barrier::new(4));
+        let handles: Vec<_> = sessions
+            .iter()
+            .chain(sessions.iter())
+            .map(|session_id| {
+                let pool = pool.clone();
+                let barrier = barrier.clone();
+                let session_id = session_id.clone();
+                thread::spawn(move || {
+                    let mut conn = pool.get().unwrap();
+                    barrier.wait();
+                    settle(&mut conn, &session_id, Some(500), None).map(|settled| settled.and_then(|(_, referrer)| referrer))
+                })
+            })
+            .collect();
+        let rewarded: Vec<i32> = handles.into_iter().filter_map(|handle| handle.join().unwrap().unwrap()).collect();
+        // Whichever purchase settles first is the first one, it alone pays the referral.
+        assert_eq!(rewarded, vec![referrer_id]);
+
+        let mut conn = pool.get().unwrap();
+        let status: String = referrals_table::table
+            .find(referral.id)
+            .select(referrals_table::status)
+            .first(&mut conn)
+            .unwrap();
+        assert_eq!(status, "rewarded");
+        let payouts: i64 = ledger_transactions::table
+            .filter(ledger_transactions::reference.like(format!("referral:{}:%", referral.id)))
+            .count()
+            .get_result(&mut conn)
+            .unwrap();
+        assert_eq!(payouts, 2);
+        assert!(ledger::balance(&mut conn, referrer_id).unwrap() > 0);
+        assert!(ledger::balance(&mut conn, referee_id).unwrap() > 100);
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use actix_web::web;
+use diesel::prelude::*;
+use image::imageops::FilterType;
+
+use crate::{
+    handlers::AppError,
+    model::AppState,
+    organizations::{icon_access, Role},
+    schema::icons,
+};
+
+/// Icons whose hashes differ by at most this many bits are treated as near-duplicates.
+pub const DEFAULT_MAX_DISTANCE: u32 = 10;
+
+/// 64 bit difference hash: the image is shrunk to 9x8 grey pixels and every bit records
+/// whether a pixel is brighter than its right-hand neighbour. Robust to rescaling, JPEG
+/// artefacts and small shifts, which is what separates regenerations of the same concept.
+pub fn dhash(image_data: &[u8]) -> Result<u64, AppError> {
+    let img = image::load_from_memory(image_data)
+        .map_err(|e| AppError::InternalServerError(format!("Failed to decode image: {}", e)))?;
+    let small = img.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    Ok(hash)
+}
+
+pub fn hamming(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Postgres has no unsigned 64 bit type, hashes are stored bit-for-bit in a BIGINT.
+pub fn to_db(hash: u64) -> i64 {
+    hash as i64
+}
+
+pub fn from_db(hash: i64) -> u64 {
+    hash as u64
+}
+
+/// Icons a new generation of `prompt` would likely duplicate, among those the user can see.
+/// Earlier results of the prompt are compared with each other: when some of them came out as
+/// near-duplicates the prompt keeps drawing the same picture and those are returned, when they
+/// all differ a new run will most likely differ too. A single earlier result, or one made with
+/// the requested seed, is all there is to go on and counts as a duplicate.
+pub fn likely_duplicates(
+    conn: &mut PgConnection,
+    user_id: i32,
+    prompt: &str,
+    seed: Option<i64>,
+) -> Result<Vec<i32>, AppError> {
+    let earlier: Vec<(i32, Option<i64>, Option<i64>)> = icons::table
+        .filter(icons::prompt.eq(prompt))
+        .filter(icons::phash.is_not_null())
+        .filter(icon_access(user_id, Role::Viewer))
+        .select((icons::id, icons::phash, icons::seed))
+        .order(icons::id)
+        .load(conn)?;
+
+    let same_seed: Vec<i32> = earlier
+        .iter()
+        .filter(|(_, _, earlier_seed)| seed.is_some() && *earlier_seed == seed)
+        .map(|(id, _, _)| *id)
+        .collect();
+    if !same_seed.is_empty() {
+        return Ok(same_seed);
+    }
+    if earlier.len() == 1 {
+        return Ok(vec![earlier[0].0]);
+    }
+
+    let hashes: Vec<(i32, u64)> = earlier
+        .iter()
+        .filter_map(|(id, hash, _)| hash.map(|hash| (*id, from_db(hash))))
+        .collect();
+    Ok(hashes
+        .iter()
+        .filter(|(id, hash)| {
+            hashes
+                .iter()
+                .any(|(other, other_hash)| other != id && hamming(*hash, *other_hash) <= DEFAULT_MAX_DISTANCE)
+        })
+        .map(|(id, _)| *id)
+        .collect())
+}
+
+struct BkNode {
+    hash: u64,
+    icon_ids: Vec<i32>,
+    children: HashMap<u32, usize>,
+}
+
+/// Burkhard-Keller tree over Hamming distance. Lookups only descend into children whose
+/// edge distance is within `max_distance` of the query's distance to the node.
+#[derive(Default)]
+pub struct BkTree {
+    nodes: Vec<BkNode>,
+    /// Node every icon sits in, so removals don't need the icon's hash. Emptied nodes stay
+    /// in the tree to route lookups to their children.
+    positions: HashMap<i32, usize>,
+}
+
+impl BkTree {
+    pub fn insert(&mut self, hash: u64, icon_id: i32) {
+        self.remove(icon_id);
+        if self.nodes.is_empty() {
+            self.nodes.push(BkNode { hash, icon_ids: vec![icon_id], children: HashMap::new() });
+            self.positions.insert(icon_id, 0);
+            return;
+        }
+
+        let mut current = 0;
+        loop {
+            let distance = hamming(self.nodes[current].hash, hash);
+            if distance == 0 {
+                self.nodes[current].icon_ids.push(icon_id);
+                self.positions.insert(icon_id, current);
+                return;
+            }
+            match self.nodes[current].children.get(&distance) {
+                Some(&child) => current = child,
+                None => {
+                    let index = self.nodes.len();
+                    self.nodes.push(BkNode { hash, icon_ids: vec![icon_id], children: HashMap::new() });
+                    self.nodes[current].children.insert(distance, index);
+                    self.positions.insert(icon_id, index);
+                    return;
+                }
+            }
+        }
+    }
+
+    pub fn remove(&mut self, icon_id: i32) {
+        if let Some(index) = self.positions.remove(&icon_id) {
+            self.nodes[index].icon_ids.retain(|id| *id != icon_id);
+        }
+    }
+    /// Returns `(icon_id, distance)` pairs within `max_distance`, closest first.
+    pub fn find(&self, hash: u64, max_distance: u32) -> Vec<(i32, u32)> {
+        let mut found = Vec::new();
+        if self.nodes.is_empty() {
+            return found;
+        }
+
+        let mut pending = vec![0];
+        while let Some(index) = pending.pop() {
+            let node = &self.nodes[index];
+            let distance = hamming(node.hash, hash);
+            if distance <= max_distance {
+                found.extend(node.icon_ids.iter().map(|id| (*id, distance)));
+            }
+            let low = distance.saturating_sub(max_distance);
+            let high = distance + max_distance;
+            pending.extend(
+                node.children
+                    .iter()
+                    .filter(|(edge, _)| (low..=high).contains(*edge))
+                    .map(|(_, child)| *child),
+            );
+        }
+
+        found.sort_by_key(|(id, distance)| (*distance, *id));
+        found
+    }
+}
+
+/// One BK-tree over every stored icon, filled in the background at startup by `run_loader`
+/// and kept current as icons are generated and deleted. Who may see a match is up to the
+/// caller, the tree knows nothing about owners or workspaces.
+#[derive(Default)]
+pub struct PhashIndex {
+    state: RwLock<IndexState>,
+}
+
+#[derive(Default)]
+struct IndexState {
+    tree: BkTree,
+    loaded: bool,
+    /// Icons inserted or removed while the loader runs, its rows for them may be stale.
+    changed: HashSet<i32>,
+}
+
+impl PhashIndex {
+    pub fn insert(&self, icon_id: i32, hash: u64) {
+        let mut state = self.state.write().unwrap();
+        if !state.loaded {
+            state.changed.insert(icon_id);
+        }
+        state.tree.insert(hash, icon_id);
+    }
+
+    pub fn remove(&self, icon_id: i32) {
+        let mut state = self.state.write().unwrap();
+        if !state.loaded {
+            state.changed.insert(icon_id);
+        }
+        state.tree.remove(icon_id);
+    }
+
+    /// Candidate `(icon_id, distance)` pairs, closest first. Filter them through
+    /// `organizations::icon_access` before showing them to anyone. Until the startup load
+    /// finishes, only the icons loaded so far are searched.
+    pub fn find(&self, hash: u64, max_distance: u32) -> Vec<(i32, u32)> {
+        self.state.read().unwrap().tree.find(hash, max_distance)
+    }
+
+    /// Adds a page of stored hashes, except for icons changed since the page was read.
+    fn load(&self, rows: &[(i32, u64)]) {
+        let mut state = self.state.write().unwrap();
+        for (icon_id, hash) in rows {
+            if !state.changed.contains(icon_id) {
+                state.tree.insert(*hash, *icon_id);
+            }
+        }
+    }
+
+    fn finish_loading(&self) {
+        let mut state = self.state.write().unwrap();
+        state.loaded = true;
+        state.changed = HashSet::new();
+    }
+}
+
+const LOAD_PAGE_SIZE: i64 = 5000;
+/// Pages of unhashed icons carry their images, so they are kept small.
+const BACKFILL_PAGE_SIZE: i64 = 50;
+const LOAD_RETRY: Duration = Duration::from_secs(30);
+
+/// Loads the index once at startup, retrying until the database is reachable.
+pub async fn run_loader(state: AppState) {
+    loop {
+        let pool = state.db_pool.clone();
+        let index = state.phash_index.clone();
+        let result = web::block(move || {
+            let mut conn = pool.get().map_err(|e| AppError::DbConnection(e.to_string()))?;
+            load_index(&mut conn, &index)
+        })
+        .await;
+        match result {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => println!("Loading the phash index failed: {}", e),
+            Err(e) => println!("Loading the phash index failed: {}", e),
+        }
+        tokio::time::sleep(LOAD_RETRY).await;
+    }
+}
+
+/// Hashes the stored images that predate perceptual hashing, then reads every hash into the
+/// index, a page at a time.
+fn load_index(conn: &mut PgConnection, index: &PhashIndex) -> Result<(), AppError> {
+    let mut after = 0;
+    loop {
+        let page: Vec<(i32, Vec<u8>)> = icons::table
+            .filter(icons::id.gt(after))
+            .filter(icons::phash.is_null())
+            .filter(icons::image_data.ne(Vec::<u8>::new()))
+            .select((icons::id, icons::image_data))
+            .order(icons::id)
+            .limit(BACKFILL_PAGE_SIZE)
+            .load(conn)?;
+        let Some((last, _)) = page.last() else { break };
+        after = *last;
+        for (icon_id, image_data) in page {
+            match dhash(&image_data) {
+                Ok(hash) => {
+                    diesel::update(icons::table.filter(icons::id.eq(icon_id)))
+                        .set(icons::phash.eq(to_db(hash)))
+                        .execute(conn)?;
+                }
+                Err(e) => println!("Skipping phash for icon {}: {}", icon_id, e),
+            }
+        }
+    }
+
+    let mut after = 0;
+    loop {
+        let page: Vec<(i32, Option<i64>)> = icons::table
+            .filter(icons::id.gt(after))
+            .filter(icons::phash.is_not_null())
+            .select((icons::id, icons::phash))
+            .order(icons::id)
+            .limit(LOAD_PAGE_SIZE)
+            .load(conn)?;
+        let Some((last, _)) = page.last() else { break };
+        after = *last;
+        let rows: Vec<(i32, u64)> = page
+            .into_iter()
+            .filter_map(|(icon_id, hash)| hash.map(|hash| (icon_id, from_db(hash))))
+            .collect();
+        index.load(&rows);
+    }
+    index.finish_loading();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{ImageFormat, Rgb, RgbImage};
+
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn loading_keeps_changes_made_meanwhile() {
+        let index = PhashIndex::default();
+        index.insert(1, 0b1111);
+        index.remove(2);
+        // The loader read both rows before those changes landed.
+        index.load(&[(1, 0), (2, 0), (3, 0)]);
+        index.finish_loading();
+
+        assert_eq!(index.find(0, 0), vec![(3, 0)]);
+        assert_eq!(index.find(0b1111, 0), vec![(1, 0)]);
+        // After the load, changes apply directly.
+        index.remove(3);
+        assert!(index.find(0, 0).is_empty());
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn load_hashes_old_images_and_indexes_them() {
+        let mut conn = testing::pool(1).get().unwrap();
+        let user_id = testing::user(&mut conn);
+        let image = RgbImage::from_fn(32, 32, |x, _| Rgb([(x * 8) as u8, 0, 0]));
+        let mut png = Vec::new();
+        image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        let icon_id: i32 = diesel::insert_into(icons::table)
+            .values((icons::user_id.eq(user_id), icons::image_data.eq(&png)))
+            .returning(icons::id)
+            .get_result(&mut conn)
+            .unwrap();
+
+        let index = PhashIndex::default();
+        load_index(&mut conn, &index).unwrap();
+
+        let stored: Option<i64> = icons::table.find(icon_id).select(icons::phash).first(&mut conn).unwrap();
+        let hash = dhash(&png).unwrap();
+        assert_eq!(stored, Some(to_db(hash)));
+        assert!(index.find(hash, 0).contains(&(icon_id, 0)));
+    }
+}
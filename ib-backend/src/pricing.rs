@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+
+use actix_web::{get, web, HttpResponse};
+use diesel::prelude::*;
+
+use crate::{
+    billing::Wallet,
+    handlers::{AppError, AuthenticatedUser},
+    model::{AppState, TransactionType},
+    provider::Model,
+    schema::{model_multipliers, operation_prices},
+    subscriptions,
+};
+
+/// Plan workspace wallets are priced at. Workspaces have no subscription of their own, so
+/// which member spends from the shared wallet must not change what it pays.
+pub const WORKSPACE_PLAN: &str = subscriptions::FREE_PLAN;
+
+/// Everything that costs inkbucks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Generate,
+    /// Recolouring, per icon.
+    Style,
+    /// Stroke normalization, per icon.
+    Edit,
+    /// Local resampling.
+    Upscale,
+    /// Upscale through the image provider.
+    UpscaleProvider,
+    /// Per candidate.
+    Variations,
+    /// Per subject.
+    Batch,
+    /// Per pack export.
+    Export,
+}
+
+impl Operation {
+    pub const ALL: [Operation; 8] = [
+        Operation::Generate,
+        Operation::Style,
+        Operation::Edit,
+        Operation::Upscale,
+        Operation::UpscaleProvider,
+        Operation::Variations,
+        Operation::Batch,
+        Operation::Export,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Generate => "generate",
+            Operation::Style => "style",
+            Operation::Edit => "edit",
+            Operation::Upscale => "upscale",
+            Operation::UpscaleProvider => "upscale_provider",
+            Operation::Variations => "variations",
+            Operation::Batch => "batch",
+            Operation::Export => "export",
+        }
+    }
+
+    pub fn transaction_type(&self) -> TransactionType {
+        match self {
+            Operation::Generate | Operation::Variations | Operation::Batch => TransactionType::Generate,
+            Operation::Style => TransactionType::Style,
+            Operation::Edit => TransactionType::Edit,
+            Operation::Upscale | Operation::UpscaleProvider => TransactionType::Upscale,
+            Operation::Export => TransactionType::Export,
+        }
+    }
+
+    /// Operations that run the model, their cost scales with its multiplier.
+    fn uses_model(&self) -> bool {
+        matches!(self, Operation::Generate | Operation::Variations | Operation::Batch)
+    }
+}
+
+/// Prices as they apply to one user, loaded once per request.
+pub struct Pricing {
+    pub plan: String,
+    costs: HashMap<String, i32>,
+    multipliers: HashMap<String, f32>,
+}
+
+impl Pricing {
+    pub fn for_plan(conn: &mut PgConnection, plan: &str) -> Result<Pricing, AppError> {
+        let rows: Vec<(String, Option<String>, i32)> = operation_prices::table
+            .filter(operation_prices::plan_id.is_null().or(operation_prices::plan_id.eq(plan)))
+            .select((operation_prices::operation, operation_prices::plan_id, operation_prices::cost))
+            .load(conn)?;
+        let mut costs = HashMap::new();
+        // Defaults first so the plan's own rows overwrite them.
+        let (overrides, defaults): (Vec<_>, Vec<_>) = rows.into_iter().partition(|row| row.1.is_some());
+        for (operation, _, cost) in defaults.into_iter().chain(overrides) {
+            costs.insert(operation, cost);
+        }
+        let multipliers = model_multipliers::table
+            .select((model_multipliers::model, model_multipliers::multiplier))
+            .load::<(String, f32)>(conn)?
+            .into_iter()
+            .collect();
+        Ok(Pricing { plan: plan.to_string(), costs, multipliers })
+    }
+
+    pub fn for_user(conn: &mut PgConnection, user_id: i32) -> Result<Pricing, AppError> {
+        let plan = subscriptions::current_plan(conn, user_id)?;
+        Pricing::for_plan(conn, &plan)
+    }
+
+    /// Prices for spending from `wallet`, those of the user's plan for their own wallet and
+    /// `WORKSPACE_PLAN` for a workspace's.
+    pub fn for_wallet(conn: &mut PgConnection, wallet: Wallet) -> Result<Pricing, AppError> {
+        match wallet {
+            Wallet::User(user_id) => Pricing::for_user(conn, user_id),
+            Wallet::Organization { .. } => Pricing::for_plan(conn, WORKSPACE_PLAN),
+        }
+    }
+
+    /// Inkbucks for one unit of `operation`, rounded up after the model multiplier.
+    pub fn cost(&self, operation: Operation, model: Option<Model>) -> Result<i32, AppError> {
+        let base = *self.costs.get(operation.as_str()).ok_or_else(|| {
+            AppError::InternalServerError(format!("No price for {}", operation.as_str()))
+        })?;
+        let multiplier = match model {
+            Some(model) if operation.uses_model() => {
+                self.multipliers.get(model.as_str()).copied().unwrap_or(1.0)
+            }
+            _ => 1.0,
+        };
+        Ok((base as f32 * multiplier).ceil() as i32)
+    }
+}
+
+/// Shortcut for handlers that price a single operation paid from `wallet`.
+pub fn cost(
+    conn: &mut PgConnection,
+    wallet: Wallet,
+    operation: Operation,
+    model: Option<Model>,
+) -> Result<i32, AppError> {
+    Pricing::for_wallet(conn, wallet)?.cost(operation, model)
+}
+
+/// What every operation costs, per model where the model matters. Signed in users see
+/// the prices of their plan, everyone else those of the free plan.
+#[get("/pricing")]
+pub async fn get_pricing(
+    data: web::Data<AppState>,
+    user: Option<AuthenticatedUser>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let pricing = match user {
+        Some(user) => Pricing::for_user(&mut conn, user.id)?,
+        None => Pricing::for_plan(&mut conn, subscriptions::FREE_PLAN)?,
+    };
+
+    let mut operations = serde_json::Map::new();
+    for operation in Operation::ALL {
+        let mut price = serde_json::json!({ "cost": pricing.cost(operation, None)? });
+        if operation.uses_model() {
+            let mut models = serde_json::Map::new();
+            for model in Model::ALL {
+                models.insert(model.as_str().to_string(), pricing.cost(operation, Some(model))?.into());
+            }
+            price["models"] = models.into();
+        }
+        operations.insert(operation.as_str().to_string(), price);
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": {
+            "plan": pricing.plan,
+            "operations": operations,
+            "model_multipliers": pricing.multipliers,
+        }
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn workspace_wallets_pay_workspace_prices() {
+        let mut conn = testing::pool(1).get().unwrap();
+        conn.test_transaction(|conn| {
+            let user_id = testing::user(conn);
+            subscriptions::activate(conn, user_id, "pro", "fake", None)?;
+            diesel::delete(
+                operation_prices::table
+                    .filter(operation_prices::operation.eq("export"))
+                    .filter(operation_prices::plan_id.eq("pro")),
+            )
+            .execute(conn)?;
+            diesel::insert_into(operation_prices::table)
+                .values((
+                    operation_prices::operation.eq("export"),
+                    operation_prices::plan_id.eq("pro"),
+                    operation_prices::cost.eq(7),
+                ))
+                .execute(conn)?;
+            let workspace_price = Pricing::for_plan(conn, WORKSPACE_PLAN)?.cost(Operation::Export, None)?;
+
+            assert_eq!(cost(conn, Wallet::User(user_id), Operation::Export, None)?, 7);
+            let workspace = Wallet::Organization { id: 0, member_id: user_id };
+            assert_eq!(cost(conn, workspace, Operation::Export, None)?, workspace_price);
+            Ok::<_, AppError>(())
+        });
+    }
+}
@@ -0,0 +1,333 @@
+use actix_web::{get, post, put, web, HttpResponse};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    billing, events,
+    handlers::{AppError, AuthenticatedUser},
+    model::{AppState, TransactionType},
+    organizations::present,
+    prompts::require_admin,
+    schema::{promo_codes, promo_redemptions},
+};
+
+const MAX_CODE_LENGTH: usize = 32;
+
+#[derive(Debug, Queryable, Serialize)]
+#[diesel(table_name = promo_codes)]
+pub struct PromoCode {
+    pub id: i32,
+    pub code: String,
+    pub amount: i32,
+    pub max_redemptions: Option<i32>,
+    pub per_user_limit: i32,
+    pub redemptions: i32,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub active: bool,
+    pub created_by: Option<i32>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Codes are matched case-insensitively, they are stored upper case.
+fn normalize_code(code: &str) -> Result<String, AppError> {
+    let code = code.trim().to_uppercase();
+    let valid = !code.is_empty()
+        && code.len() <= MAX_CODE_LENGTH
+        && code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(code)
+    } else {
+        Err(AppError::BadRequest(format!(
+            "Codes are 1 to {} letters, digits, '-' or '_'",
+            MAX_CODE_LENGTH
+        )))
+    }
+}
+
+/// Redeems a code for a user. The code row is locked for the duration so the global and
+/// per-user limits hold under concurrent redemptions. Returns the code and the ledger
+/// transaction of the credit.
+fn redeem(conn: &mut PgConnection, user_id: i32, code: &str) -> Result<(PromoCode, i32), AppError> {
+    conn.transaction(|conn| {
+        let promo: PromoCode = promo_codes::table
+            .filter(promo_codes::code.eq(code))
+            .for_update()
+            .first(conn)
+            .optional()?
+            .ok_or_else(|| AppError::NotFound("Promo code not found".to_string()))?;
+        if !promo.active || promo.expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+            return Err(AppError::BadRequest("This promo code has expired".to_string()));
+        }
+        if promo.max_redemptions.is_some_and(|max| promo.redemptions >= max) {
+            return Err(AppError::BadRequest("This promo code has been used up".to_string()));
+        }
+        let redeemed: i64 = promo_redemptions::table
+            .filter(promo_redemptions::promo_code_id.eq(promo.id))
+            .filter(promo_redemptions::user_id.eq(user_id))
+            .count()
+            .get_result(conn)?;
+        if redeemed >= promo.per_user_limit as i64 {
+            return Err(AppError::BadRequest("You already redeemed this promo code".to_string()));
+        }
+
+        let redemption_id: i32 = diesel::insert_into(promo_redemptions::table)
+            .values((
+                promo_redemptions::promo_code_id.eq(promo.id),
+                promo_redemptions::user_id.eq(user_id),
+            ))
+            .returning(promo_redemptions::id)
+            .get_result(conn)?;
+        let reference = format!("promo:{}:{}", promo.code, redemption_id);
+        let transaction_id = billing::credit(
+            conn,
+            user_id,
+            TransactionType::Promo,
+            promo.amount,
+            None,
+            Some(&reference),
+        )?;
+        diesel::update(promo_redemptions::table.filter(promo_redemptions::id.eq(redemption_id)))
+            .set(promo_redemptions::ledger_transaction_id.eq(transaction_id))
+            .execute(conn)?;
+        let promo = diesel::update(promo_codes::table.filter(promo_codes::id.eq(promo.id)))
+            .set(promo_codes::redemptions.eq(promo_codes::redemptions + 1))
+            .get_result(conn)?;
+        Ok((promo, transaction_id))
+    })
+}
+
+#[derive(Deserialize)]
+struct RedeemRequest {
+    code: String,
+}
+
+#[post("/promo-codes/redeem")]
+pub async fn redeem_code(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    body: web::Json<RedeemRequest>,
+) -> Result<HttpResponse, AppError> {
+    let code = normalize_code(&body.code)?;
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let (promo, transaction_id) = redeem(&mut conn, user.id, &code)?;
+    events::balance_changed(&data, user.id);
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": {
+            "code": promo.code,
+            "amount": promo.amount,
+            "transaction_id": transaction_id,
+        }
+    })))
+}
+
+#[derive(Deserialize)]
+struct CreatePromoCode {
+    code: String,
+    amount: i32,
+    max_redemptions: Option<i32>,
+    per_user_limit: Option<i32>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+struct UpdatePromoCode {
+    /// `null` lifts the limit, leaving it out keeps the current one.
+    #[serde(default, deserialize_with = "present")]
+    max_redemptions: Option<Option<i32>>,
+    per_user_limit: Option<i32>,
+    /// `null` makes the code never expire, leaving it out keeps the current expiry.
+    #[serde(default, deserialize_with = "present")]
+    expires_at: Option<Option<DateTime<Utc>>>,
+    active: Option<bool>,
+}
+
+fn validate_limits(max_redemptions: Option<i32>, per_user_limit: Option<i32>) -> Result<(), AppError> {
+    if max_redemptions.is_some_and(|max| max < 1) || per_user_limit.is_some_and(|limit| limit < 1) {
+        return Err(AppError::BadRequest("Redemption limits must be at least 1".to_string()));
+    }
+    Ok(())
+}
+
+#[get("/admin/promo-codes")]
+pub async fn list_codes(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    require_admin(&mut conn, user.id)?;
+    let codes: Vec<PromoCode> = promo_codes::table
+        .order(promo_codes::id.desc())
+        .load(&mut conn)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "promo_codes": codes }
+    })))
+}
+
+#[post("/admin/promo-codes")]
+pub async fn create_code(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    body: web::Json<CreatePromoCode>,
+) -> Result<HttpResponse, AppError> {
+    let code = normalize_code(&body.code)?;
+    if body.amount < 1 {
+        return Err(AppError::BadRequest("Amount must be at least 1 inkbuck".to_string()));
+    }
+    validate_limits(body.max_redemptions, body.per_user_limit)?;
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    require_admin(&mut conn, user.id)?;
+    let promo: Option<PromoCode> = diesel::insert_into(promo_codes::table)
+        .values((
+            promo_codes::code.eq(&code),
+            promo_codes::amount.eq(body.amount),
+            promo_codes::max_redemptions.eq(body.max_redemptions),
+            promo_codes::per_user_limit.eq(body.per_user_limit.unwrap_or(1)),
+            promo_codes::expires_at.eq(body.expires_at),
+            promo_codes::created_by.eq(user.id),
+        ))
+        .on_conflict_do_nothing()
+        .get_result(&mut conn)
+        .optional()?;
+    let promo = promo.ok_or_else(|| AppError::BadRequest(format!("Promo code '{}' already exists", code)))?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "promo_code": promo }
+    })))
+}
+
+/// Changes limits, expiry or disables a code. Amount and code are fixed once issued.
+#[put("/admin/promo-codes/{id}")]
+pub async fn update_code(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<UpdatePromoCode>,
+) -> Result<HttpResponse, AppError> {
+    validate_limits(body.max_redemptions.flatten(), body.per_user_limit)?;
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    require_admin(&mut conn, user.id)?;
+    let current: PromoCode = promo_codes::table
+        .filter(promo_codes::id.eq(path.into_inner()))
+        .first(&mut conn)
+        .optional()?
+        .ok_or_else(|| AppError::NotFound("Promo code not found".to_string()))?;
+
+    let promo: PromoCode = diesel::update(promo_codes::table.filter(promo_codes::id.eq(current.id)))
+        .set((
+            promo_codes::max_redemptions.eq(body.max_redemptions.unwrap_or(current.max_redemptions)),
+            promo_codes::per_user_limit.eq(body.per_user_limit.unwrap_or(current.per_user_limit)),
+            promo_codes::expires_at.eq(body.expires_at.unwrap_or(current.expires_at)),
+            promo_codes::active.eq(body.active.unwrap_or(current.active)),
+        ))
+        .get_result(&mut conn)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "promo_code": promo }
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    use super::*;
+    use crate::{ledger, testing};
+
+    #[test]
+    fn updates_tell_null_from_missing() {
+        let update: UpdatePromoCode = serde_json::from_str("{}").unwrap();
+        assert_eq!(update.max_redemptions, None);
+        assert_eq!(update.expires_at, None);
+
+        let update: UpdatePromoCode =
+            serde_json::from_str(r#"{"max_redemptions": null, "expires_at": null}"#).unwrap();
+        assert_eq!(update.max_redemptions, Some(None));
+        assert_eq!(update.expires_at, Some(None));
+
+        let update: UpdatePromoCode = serde_json::from_str(r#"{"max_redemptions": 5}"#).unwrap();
+        assert_eq!(update.max_redemptions, Some(Some(5)));
+    }
+
+    /// A new code worth 10 inkbucks.
+    fn promo(conn: &mut PgConnection, max_redemptions: Option<i32>, per_user_limit: i32) -> String {
+        let code = format!("TEST-{:012X}", rand::random::<u64>() >> 16);
+        diesel::insert_into(promo_codes::table)
+            .values((
+                promo_codes::code.eq(&code),
+                promo_codes::amount.eq(10),
+                promo_codes::max_redemptions.eq(max_redemptions),
+                promo_codes::per_user_limit.eq(per_user_limit),
+            ))
+            .execute(conn)
+            .unwrap();
+        code
+    }
+
+    /// Redeems `code` once for each of `users` at the same time, returns how many succeeded.
+    fn redeem_in_parallel(users: Vec<i32>, code: &str) -> usize {
+        let pool = testing::pool(users.len() as u32);
+        let barrier = Arc::new(Barrier::new(users.len()));
+        let handles: Vec<_> = users
+            .into_iter()
+            .map(|user_id| {
+                let pool = pool.clone();
+                let barrier = barrier.clone();
+                let code = code.to_string();
+                thread::spawn(move || {
+                    let mut conn = pool.get().unwrap();
+                    barrier.wait();
+                    redeem(&mut conn, user_id, &code)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .filter(|result| match result {
+                Ok(_) => true,
+                Err(AppError::BadRequest(_)) => false,
+                Err(e) => panic!("unexpected error {}", e),
+            })
+            .count()
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn parallel_redemptions_respect_the_global_limit() {
+        let mut conn = testing::pool(1).get().unwrap();
+        let code = promo(&mut conn, Some(3), 1);
+        let users: Vec<i32> = (0..8).map(|_| testing::user(&mut conn)).collect();
+
+        assert_eq!(redeem_in_parallel(users.clone(), &code), 3);
+        let promo: PromoCode = promo_codes::table.filter(promo_codes::code.eq(&code)).first(&mut conn).unwrap();
+        assert_eq!(promo.redemptions, 3);
+        let credited: i32 = users.iter().map(|&user_id| ledger::balance(&mut conn, user_id).unwrap()).sum();
+        assert_eq!(credited, 30);
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn parallel_redemptions_respect_the_per_user_limit() {
+        let mut conn = testing::pool(1).get().unwrap();
+        let code = promo(&mut conn, None, 2);
+        let user_id = testing::user(&mut conn);
+
+        assert_eq!(redeem_in_parallel(vec![user_id; 6], &code), 2);
+        assert_eq!(ledger::balance(&mut conn, user_id).unwrap(), 20);
+        assert!(matches!(redeem(&mut conn, user_id, &code), Err(AppError::BadRequest(_))));
+    }
+}
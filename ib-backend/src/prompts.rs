@@ -0,0 +1,320 @@
+use actix_web::{get, post, put, web, HttpResponse};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    handlers::{AppError, AuthenticatedUser},
+    model::AppState,
+    schema::{prompt_templates, users},
+};
+
+pub const DEFAULT_TEMPLATE: &str = "ink-minimal";
+
+/// Variables a template body may reference as `{{name}}`.
+const VARIABLES: [&str; 4] = ["subject", "style", "stroke_weight", "palette"];
+
+#[derive(Debug, Clone, Queryable, Serialize)]
+#[diesel(table_name = prompt_templates)]
+pub struct PromptTemplate {
+    pub id: i32,
+    pub name: String,
+    pub version: i32,
+    pub body: String,
+    pub negative_prompt: Option<String>,
+    /// Provider form fields sent along with the prompt, e.g. `{"aspect_ratio": "1:1"}`.
+    pub parameters: Value,
+    /// Fallback values for variables the request leaves empty.
+    pub defaults: Value,
+    /// Share of traffic this version gets among the versions of its name, 0 takes it out of rotation.
+    pub weight: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct PromptVariables {
+    pub subject: String,
+    pub style: Option<String>,
+    pub stroke_weight: Option<String>,
+    pub palette: Option<String>,
+}
+
+impl PromptTemplate {
+    /// Substitutes every `{{name}}` in one pass, so values are never scanned for
+    /// placeholders themselves.
+    pub fn render(&self, vars: &PromptVariables) -> String {
+        // Bodies are validated on the way in, one that still fails to parse is sent as is.
+        let tokens = parse_body(&self.body).unwrap_or_else(|_| vec![Token::Text(&self.body)]);
+        let mut prompt = String::with_capacity(self.body.len());
+        for token in tokens {
+            let name = match token {
+                Token::Text(text) => {
+                    prompt.push_str(text);
+                    continue;
+                }
+                Token::Variable(name) => name,
+            };
+            let given = match name {
+                "subject" => Some(vars.subject.as_str()),
+                "style" => vars.style.as_deref(),
+                "stroke_weight" => vars.stroke_weight.as_deref(),
+                _ => vars.palette.as_deref(),
+            };
+            let value = given
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .or_else(|| self.defaults.get(name).and_then(Value::as_str))
+                .unwrap_or("");
+            prompt.push_str(value);
+        }
+        prompt
+    }
+}
+
+enum Token<'a> {
+    Text(&'a str),
+    /// A known variable, with the whitespace inside the braces trimmed.
+    Variable(&'a str),
+}
+
+/// Splits a template body into literal text and `{{ name }}` placeholders.
+fn parse_body(body: &str) -> Result<Vec<Token<'_>>, AppError> {
+    let mut tokens = Vec::new();
+    let mut rest = body;
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..]
+            .find("}}")
+            .ok_or_else(|| AppError::BadRequest("Unclosed '{{' in template body".to_string()))?;
+        let name = rest[start + 2..start + end].trim();
+        if !VARIABLES.contains(&name) {
+            return Err(AppError::BadRequest(format!("Unknown template variable '{}'", name)));
+        }
+        if start > 0 {
+            tokens.push(Token::Text(&rest[..start]));
+        }
+        tokens.push(Token::Variable(name));
+        rest = &rest[start + end + 2..];
+    }
+    if !rest.is_empty() {
+        tokens.push(Token::Text(rest));
+    }
+    Ok(tokens)
+}
+
+/// Picks the version of `name` to use for a user. Versions split traffic by weight and a
+/// user always lands in the same bucket, so A/B comparisons are not skewed by one user
+/// seeing both wordings.
+pub fn select_template(
+    conn: &mut PgConnection,
+    name: &str,
+    user_id: i32,
+) -> Result<PromptTemplate, AppError> {
+    let candidates: Vec<PromptTemplate> = prompt_templates::table
+        .filter(prompt_templates::name.eq(name))
+        .filter(prompt_templates::weight.gt(0))
+        .order(prompt_templates::version.asc())
+        .load(conn)?;
+
+    let total: i64 = candidates.iter().map(|t| t.weight as i64).sum();
+    if total == 0 {
+        return Err(AppError::BadRequest(format!("Unknown prompt template '{}'", name)));
+    }
+
+    let mut bucket = (user_id as u32).wrapping_mul(2654435761) as i64 % total;
+    for template in candidates {
+        if bucket < template.weight as i64 {
+            return Ok(template);
+        }
+        bucket -= template.weight as i64;
+    }
+    unreachable!("bucket is always below the total weight")
+}
+
+/// Checks that a template body only uses known variables and mentions the subject.
+fn validate_body(body: &str) -> Result<(), AppError> {
+    let tokens = parse_body(body)?;
+    if !tokens.iter().any(|token| matches!(token, Token::Variable("subject"))) {
+        return Err(AppError::BadRequest("Template body must contain {{subject}}".to_string()));
+    }
+    Ok(())
+}
+
+pub fn require_admin(conn: &mut PgConnection, user_id: i32) -> Result<(), AppError> {
+    let is_admin: bool = users::table
+        .filter(users::id.eq(user_id))
+        .select(users::is_admin)
+        .first(conn)?;
+    if is_admin {
+        Ok(())
+    } else {
+        Err(AppError::Forbidden("Admin access required".to_string()))
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateTemplate {
+    name: String,
+    body: String,
+    negative_prompt: Option<String>,
+    parameters: Option<Value>,
+    defaults: Option<Value>,
+    weight: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct UpdateWeight {
+    weight: i32,
+}
+
+#[get("/admin/prompt-templates")]
+pub async fn list_templates(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    require_admin(&mut conn, user.id)?;
+
+    let templates: Vec<PromptTemplate> = prompt_templates::table
+        .order((prompt_templates::name.asc(), prompt_templates::version.desc()))
+        .load(&mut conn)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "templates": templates }
+    })))
+}
+
+/// Inserts the next version of `name`. Publishing is serialized per name, otherwise two
+/// admins saving at once would both pick the same version number.
+fn publish(
+    conn: &mut PgConnection,
+    name: &str,
+    body: &CreateTemplate,
+    parameters: &Value,
+    defaults: &Value,
+) -> Result<PromptTemplate, AppError> {
+    diesel::sql_query("SELECT pg_advisory_xact_lock(hashtext('prompt_templates:' || $1))")
+        .bind::<diesel::sql_types::Text, _>(name)
+        .execute(conn)?;
+    let latest: Option<i32> = prompt_templates::table
+        .filter(prompt_templates::name.eq(name))
+        .select(diesel::dsl::max(prompt_templates::version))
+        .first(conn)?;
+
+    Ok(diesel::insert_into(prompt_templates::table)
+        .values((
+            prompt_templates::name.eq(name),
+            prompt_templates::version.eq(latest.unwrap_or(0) + 1),
+            prompt_templates::body.eq(&body.body),
+            prompt_templates::negative_prompt.eq(&body.negative_prompt),
+            prompt_templates::parameters.eq(parameters),
+            prompt_templates::defaults.eq(defaults),
+            prompt_templates::weight.eq(body.weight.unwrap_or(0).max(0)),
+        ))
+        .get_result(conn)?)
+}
+
+/// Publishes a new version of a template. Existing versions are never edited so every
+/// icon can be traced back to the exact wording that produced it.
+#[post("/admin/prompt-templates")]
+pub async fn create_template(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    body: web::Json<CreateTemplate>,
+) -> Result<HttpResponse, AppError> {
+    let mut body = body.into_inner();
+    validate_body(&body.body)?;
+    let name = body.name.trim().to_string();
+    if name.is_empty() {
+        return Err(AppError::BadRequest("Template name is required".to_string()));
+    }
+    let parameters = body.parameters.take().unwrap_or_else(|| serde_json::json!({}));
+    let defaults = body.defaults.take().unwrap_or_else(|| serde_json::json!({}));
+    if !parameters.is_object() || !defaults.is_object() {
+        return Err(AppError::BadRequest("parameters and defaults must be JSON objects".to_string()));
+    }
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    require_admin(&mut conn, user.id)?;
+
+    let template = conn.transaction(|conn| publish(conn, &name, &body, &parameters, &defaults))?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "template": template }
+    })))
+}
+
+#[put("/admin/prompt-templates/{id}/weight")]
+pub async fn update_template_weight(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<UpdateWeight>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    require_admin(&mut conn, user.id)?;
+
+    let template: PromptTemplate = diesel::update(
+        prompt_templates::table.filter(prompt_templates::id.eq(path.into_inner())),
+    )
+    .set(prompt_templates::weight.eq(body.weight.max(0)))
+    .get_result(&mut conn)
+    .optional()?
+    .ok_or_else(|| AppError::NotFound("Prompt template not found".to_string()))?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "template": template }
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn parallel_publishes_get_consecutive_versions() {
+        const THREADS: usize = 8;
+        let pool = testing::pool(THREADS as u32);
+        let name = format!("test-{:016x}", rand::random::<u64>());
+        let start = Arc::new(Barrier::new(THREADS));
+
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let pool = pool.clone();
+                let name = name.clone();
+                let start = start.clone();
+                thread::spawn(move || {
+                    let body = CreateTemplate {
+                        name: name.clone(),
+                        body: "An icon of {{subject}}".to_string(),
+                        negative_prompt: None,
+                        parameters: None,
+                        defaults: None,
+                        weight: None,
+                    };
+                    let (parameters, defaults) = (serde_json::json!({}), serde_json::json!({}));
+                    let mut conn = pool.get().unwrap();
+                    start.wait();
+                    conn.transaction(|conn| publish(conn, &name, &body, &parameters, &defaults))
+                        .unwrap()
+                        .version
+                })
+            })
+            .collect();
+        let mut versions: Vec<i32> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        versions.sort();
+
+        assert_eq!(versions, (1..=THREADS as i32).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,477 @@
+use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        header::{self, HeaderName, HeaderValue},
+        Method,
+    },
+    middleware::Next,
+    web, HttpMessage,
+};
+use chrono::{Days, Utc};
+use diesel::prelude::*;
+
+use crate::{
+    handlers::{AppError, AuthenticatedUser},
+    model::AppState,
+    schema::{generations, plans, users},
+    subscriptions,
+};
+
+/// Buckets are dropped once they have been idle long enough to be full again, checked
+/// every this many requests.
+const PRUNE_EVERY: u64 = 1024;
+
+/// Routes grouped by what hammering them costs us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RouteClass {
+    /// Anything that calls the image provider.
+    Generate,
+    /// Other mutating requests.
+    Write,
+    Read,
+}
+
+impl RouteClass {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RouteClass::Generate => "generate",
+            RouteClass::Write => "write",
+            RouteClass::Read => "read",
+        }
+    }
+
+    /// `None` for routes that are not limited, e.g. payment provider callbacks.
+    fn of(method: &Method, path: &str) -> Option<RouteClass> {
+        let path = path.strip_prefix("/api").unwrap_or(path);
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        if *method == Method::GET || *method == Method::HEAD {
+            return Some(RouteClass::Read);
+        }
+        if *method == Method::OPTIONS {
+            return None;
+        }
+        match segments.as_slice() {
+            ["payments", "webhook"] => None,
+            ["icons"] | ["icons", _, "variations"] | ["icons", _, "upscale"] | ["packs", _, "batch"] => {
+                Some(RouteClass::Generate)
+            }
+            _ => Some(RouteClass::Write),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Limit {
+    /// Requests allowed in a burst.
+    burst: f64,
+    /// Tokens added back per second.
+    rate: f64,
+}
+
+impl Limit {
+    /// `RATE_LIMIT_<CLASS>_BURST` and `RATE_LIMIT_<CLASS>_PER_MINUTE`.
+    fn from_env(class: RouteClass, burst: u32, per_minute: u32) -> Limit {
+        let var = |suffix: &str, default: u32| {
+            env::var(format!("RATE_LIMIT_{}_{}", class.as_str().to_uppercase(), suffix))
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+                .max(1)
+        };
+        Limit {
+            burst: var("BURST", burst) as f64,
+            rate: var("PER_MINUTE", per_minute) as f64 / 60.0,
+        }
+    }
+
+    fn scaled(&self, factor: f64) -> Limit {
+        Limit { burst: self.burst * factor, rate: self.rate * factor }
+    }
+
+    /// Time for an empty bucket to fill up again.
+    fn refill_time(&self) -> Duration {
+        Duration::from_secs_f64(self.burst / self.rate)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Client {
+    User(i32),
+    Ip(String),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refilled(&self, limit: Limit, now: Instant) -> f64 {
+        (self.tokens + now.duration_since(self.updated).as_secs_f64() * limit.rate).min(limit.burst)
+    }
+}
+
+/// Outcome for the most constrained bucket a request drew from, what the headers report.
+struct Decision {
+    allowed: bool,
+    limit: u64,
+    remaining: u64,
+    /// Seconds until the bucket is full again.
+    reset: u64,
+    /// Seconds until the next request would be let through.
+    retry_after: u64,
+}
+
+/// An address range in CIDR notation, a bare address is a range of one.
+#[derive(Debug, Clone, Copy)]
+struct Network {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Network {
+    fn parse(s: &str) -> Option<Network> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr.parse().ok()?, Some(prefix.parse().ok()?)),
+            None => (s.parse().ok()?, None),
+        };
+        let bits = if matches!(addr, IpAddr::V4(_)) { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(bits);
+        (prefix <= bits).then_some(Network { addr, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// In-memory token buckets per client and route class. Each instance limits on its own,
+/// behind a load balancer the effective limit is that times the number of instances.
+pub struct RateLimiter {
+    limits: HashMap<RouteClass, Limit>,
+    /// IP buckets are this much larger than user buckets, many users can share an address.
+    ip_factor: f64,
+    buckets: Mutex<HashMap<(Client, RouteClass), Bucket>>,
+    requests: Mutex<u64>,
+    /// `TRUSTED_PROXIES`, comma separated addresses or ranges of the reverse proxies in front
+    /// of us. Only their `X-Forwarded-For` is believed.
+    trusted_proxies: Vec<Network>,
+}
+
+impl RateLimiter {
+    pub fn from_env() -> RateLimiter {
+        let limits = HashMap::from([
+            (RouteClass::Generate, Limit::from_env(RouteClass::Generate, 10, 10)),
+            (RouteClass::Write, Limit::from_env(RouteClass::Write, 60, 60)),
+            (RouteClass::Read, Limit::from_env(RouteClass::Read, 300, 300)),
+        ]);
+        RateLimiter {
+            limits,
+            ip_factor: env::var("RATE_LIMIT_IP_FACTOR")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|factor: &f64| *factor >= 1.0)
+                .unwrap_or(4.0),
+            buckets: Mutex::new(HashMap::new()),
+            requests: Mutex::new(0),
+            trusted_proxies: env::var("TRUSTED_PROXIES")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .filter_map(|s| {
+                    let network = Network::parse(s);
+                    if network.is_none() {
+                        println!("Ignoring invalid TRUSTED_PROXIES entry '{}'", s);
+                    }
+                    network
+                })
+                .collect(),
+        }
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|network| network.contains(ip))
+    }
+
+    /// The address a request is limited by. Anyone can send `X-Forwarded-For`, so it only
+    /// counts when the connection comes from a trusted proxy. Proxies append the address
+    /// they were connected from, the last one that isn't a proxy of ours is the client.
+    fn client_ip(&self, req: &ServiceRequest) -> String {
+        let Some(peer) = req.peer_addr().map(|addr| addr.ip()) else {
+            return "unknown".to_string();
+        };
+        if !self.is_trusted(peer) {
+            return peer.to_string();
+        }
+        let forwarded: Vec<&str> = req
+            .headers()
+            .get_all("x-forwarded-for")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+        forwarded
+            .into_iter()
+            .rev()
+            .find(|addr| addr.parse().map_or(true, |ip| !self.is_trusted(ip)))
+            .map_or_else(|| peer.to_string(), str::to_string)
+    }
+
+    fn limit_for(&self, client: &Client, class: RouteClass) -> Limit {
+        let limit = self.limits[&class];
+        match client {
+            Client::User(_) => limit,
+            Client::Ip(_) => limit.scaled(self.ip_factor),
+        }
+    }
+
+    /// Takes a token from every bucket of the request, or from none of them when any is
+    /// empty, so a request refused by its IP bucket does not also drain the user's.
+    fn check(&self, clients: &[Client], class: RouteClass) -> Decision {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let levels: Vec<(Limit, f64)> = clients
+            .iter()
+            .map(|client| {
+                let limit = self.limit_for(client, class);
+                let tokens = buckets
+                    .get(&(client.clone(), class))
+                    .map_or(limit.burst, |bucket| bucket.refilled(limit, now));
+                (limit, tokens)
+            })
+            .collect();
+        let allowed = levels.iter().all(|(_, tokens)| *tokens >= 1.0);
+        if allowed {
+            for (client, (_, tokens)) in clients.iter().zip(&levels) {
+                buckets.insert((client.clone(), class), Bucket { tokens: tokens - 1.0, updated: now });
+            }
+        }
+        drop(buckets);
+        self.prune(now);
+
+        let (limit, tokens) = levels
+            .into_iter()
+            .map(|(limit, tokens)| (limit, if allowed { tokens - 1.0 } else { tokens }))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .expect("every request has a client");
+        Decision {
+            allowed,
+            limit: limit.burst as u64,
+            remaining: tokens.max(0.0).floor() as u64,
+            reset: ((limit.burst - tokens) / limit.rate).ceil() as u64,
+            retry_after: ((1.0 - tokens).max(0.0) / limit.rate).ceil() as u64,
+        }
+    }
+
+    fn prune(&self, now: Instant) {
+        let mut requests = self.requests.lock().unwrap();
+        *requests += 1;
+        if !requests.is_multiple_of(PRUNE_EVERY) {
+            return;
+        }
+        drop(requests);
+        self.buckets.lock().unwrap().retain(|(client, class), bucket| {
+            now.duration_since(bucket.updated) < self.limit_for(client, *class).refill_time()
+        });
+    }
+}
+
+fn set_headers(headers: &mut header::HeaderMap, decision: &Decision) {
+    for (name, value) in [
+        ("ratelimit-limit", decision.limit),
+        ("ratelimit-remaining", decision.remaining),
+        ("ratelimit-reset", decision.reset),
+    ] {
+        headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+    }
+}
+
+/// Token bucket limits per route class, keyed by the authenticated user and by client IP.
+/// Every response carries `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset`
+/// for the tightest bucket, refused requests get a 429 with `Retry-After`.
+pub async fn middleware(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let Some(class) = RouteClass::of(req.method(), req.path()) else {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    };
+
+    let mut clients = Vec::with_capacity(2);
+    if req.headers().contains_key(header::AUTHORIZATION) {
+        if let Ok(user) = req.extract::<AuthenticatedUser>().await {
+            req.extensions_mut().insert(user);
+            clients.push(Client::User(user.id));
+        }
+    }
+    let state = req
+        .app_data::<web::Data<AppState>>()
+        .expect("AppState not found")
+        .clone();
+    clients.push(Client::Ip(state.rate_limiter.client_ip(&req)));
+
+    let decision = state.rate_limiter.check(&clients, class);
+    if !decision.allowed {
+        let error = AppError::RateLimited {
+            message: format!("Too many {} requests, slow down", class.as_str()),
+            retry_after: decision.retry_after,
+        };
+        let mut response = req.error_response(error);
+        set_headers(response.headers_mut(), &decision);
+        return Ok(response.map_into_boxed_body());
+    }
+
+    let mut response = next.call(req).await?.map_into_boxed_body();
+    set_headers(response.headers_mut(), &decision);
+    Ok(response)
+}
+
+/// Generations the user started since UTC midnight, including failed, discarded and deleted
+/// icons.
+pub fn generations_today(conn: &mut PgConnection, user_id: i32) -> Result<i64, AppError> {
+    let midnight = Utc::now().date_naive().and_hms_opt(0, 0, 0).unwrap().and_utc();
+    Ok(generations::table
+        .filter(generations::user_id.eq(user_id))
+        .filter(generations::created_at.ge(midnight))
+        .count()
+        .get_result(conn)?)
+}
+
+/// Records `count` generations for the user, or refuses them with a 429 that retries after
+/// UTC midnight when they would take the user past their plan's daily quota. The user's row
+/// stays locked until the caller's transaction ends, so parallel requests can't both fit in
+/// the last slot. Must run inside the transaction that starts the generations.
+pub fn reserve_generations(conn: &mut PgConnection, user_id: i32, count: i64) -> Result<(), AppError> {
+    users::table
+        .filter(users::id.eq(user_id))
+        .select(users::id)
+        .for_update()
+        .first::<i32>(conn)
+        .optional()?
+        .ok_or(AppError::UserNotFound)?;
+
+    let plan = subscriptions::current_plan(conn, user_id)?;
+    let quota: Option<i32> = plans::table
+        .filter(plans::id.eq(&plan))
+        .select(plans::daily_generations)
+        .first(conn)?;
+    if let Some(quota) = quota {
+        let used = generations_today(conn, user_id)?;
+        if used + count > quota as i64 {
+            let tomorrow = (Utc::now().date_naive() + Days::new(1)).and_hms_opt(0, 0, 0).unwrap().and_utc();
+            return Err(AppError::RateLimited {
+                message: format!(
+                    "Daily limit of {} generations reached, {} left today",
+                    quota,
+                    (quota as i64 - used).max(0)
+                ),
+                retry_after: (tomorrow - Utc::now()).num_seconds().max(1) as u64,
+            });
+        }
+    }
+
+    let rows = vec![generations::user_id.eq(user_id); count as usize];
+    diesel::insert_into(generations::table).values(&rows).execute(conn)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    use actix_web::test::TestRequest;
+
+    use super::*;
+    use crate::testing;
+
+    fn limiter(trusted_proxies: &str) -> RateLimiter {
+        RateLimiter {
+            trusted_proxies: trusted_proxies.split(',').filter_map(Network::parse).collect(),
+            ..RateLimiter::from_env()
+        }
+    }
+
+    fn request(peer: &str, forwarded: Option<&str>) -> ServiceRequest {
+        let mut req = TestRequest::default().peer_addr(peer.parse().unwrap());
+        if let Some(forwarded) = forwarded {
+            req = req.insert_header(("X-Forwarded-For", forwarded));
+        }
+        req.to_srv_request()
+    }
+
+    #[test]
+    fn ignores_forwarded_addresses_from_untrusted_peers() {
+        let limiter = limiter("");
+        let req = request("203.0.113.7:4000", Some("198.51.100.1"));
+        assert_eq!(limiter.client_ip(&req), "203.0.113.7");
+    }
+
+    #[test]
+    fn takes_the_last_untrusted_forwarded_address() {
+        let limiter = limiter("10.0.0.0/8,192.168.1.5");
+        // The client made up the first entry, the proxies appended the rest.
+        let req = request("10.1.2.3:4000", Some("198.51.100.1, 203.0.113.7, 192.168.1.5"));
+        assert_eq!(limiter.client_ip(&req), "203.0.113.7");
+        assert_eq!(limiter.client_ip(&request("10.1.2.3:4000", None)), "10.1.2.3");
+    }
+
+    #[test]
+    fn parses_networks() {
+        let network = Network::parse("172.16.0.0/12").unwrap();
+        assert!(network.contains("172.31.255.1".parse().unwrap()));
+        assert!(!network.contains("172.32.0.1".parse().unwrap()));
+        assert!(Network::parse("fd00::/8").unwrap().contains("fd12::1".parse().unwrap()));
+        assert!(Network::parse("0.0.0.0/0").unwrap().contains("8.8.8.8".parse().unwrap()));
+        assert!(Network::parse("10.0.0.0/33").is_none());
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn parallel_generations_stay_within_the_quota() {
+        const THREADS: usize = 64;
+        let pool = testing::pool(THREADS as u32);
+        let user_id = testing::user(&mut pool.get().unwrap());
+        let quota: i32 = plans::table
+            .filter(plans::id.eq("free"))
+            .select(plans::daily_generations.assume_not_null())
+            .first(&mut pool.get().unwrap())
+            .unwrap();
+        let start = Arc::new(Barrier::new(THREADS));
+
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let pool = pool.clone();
+                let start = start.clone();
+                thread::spawn(move || {
+                    let mut conn = pool.get().unwrap();
+                    start.wait();
+                    conn.transaction(|conn| reserve_generations(conn, user_id, 1)).is_ok()
+                })
+            })
+            .collect();
+        let reserved = handles.into_iter().map(|handle| handle.join().unwrap()).filter(|ok| *ok).count();
+
+        assert_eq!(reserved, quota as usize);
+        assert_eq!(generations_today(&mut pool.get().unwrap(), user_id).unwrap(), quota as i64);
+    }
+}
@@ -0,0 +1,288 @@
+use actix_web::{get, post, put, web, HttpResponse};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    billing::{self, Wallet},
+    events,
+    handlers::{AppError, AuthenticatedUser},
+    imaging::{self, Color, InkFill, Recolor},
+    model::AppState,
+    organizations::{self, Role},
+    packs::find_pack,
+    pricing::{self, Operation},
+    schema::{icon_packs, icon_versions, icons},
+    versions::{icon_image, store_version, IconVersion, Rendition, SkippedIcon},
+};
+
+/// Colour mapping as clients send it and as pack palettes are stored.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RecolorParams {
+    /// Ink colour, or the gradient start when `ink_to` is set. Defaults to black.
+    pub ink: Option<String>,
+    pub ink_to: Option<String>,
+    /// Gradient direction in degrees, 0 runs left to right.
+    pub angle: Option<f32>,
+    /// Defaults to transparent.
+    pub background: Option<String>,
+    /// Swap foreground and background before colouring.
+    pub swap: Option<bool>,
+}
+
+impl RecolorParams {
+    fn is_empty(&self) -> bool {
+        self.ink.is_none() && self.ink_to.is_none() && self.background.is_none() && self.swap.is_none()
+    }
+
+    pub fn to_recolor(&self) -> Result<Recolor, AppError> {
+        let ink = Color::parse(self.ink.as_deref().unwrap_or("#000000"))?;
+        let ink = match &self.ink_to {
+            Some(to) => InkFill::Gradient { from: ink, to: Color::parse(to)?, angle: self.angle.unwrap_or(0.0) },
+            None => InkFill::Solid(ink),
+        };
+        Ok(Recolor {
+            ink,
+            background: self.background.as_deref().map(Color::parse).transpose()?.unwrap_or(Color::TRANSPARENT),
+            swap: self.swap.unwrap_or(false),
+        })
+    }
+}
+
+async fn recolor_png(image_data: Vec<u8>, recolor: Recolor, size: Option<u32>) -> Result<Vec<u8>, AppError> {
+    web::block(move || {
+        let img = imaging::decode(&image_data)?;
+        let img = match size {
+            Some(size) if size != img.width().max(img.height()) => imaging::upscale(&img, size),
+            _ => img,
+        };
+        imaging::encode_png(&imaging::recolor(&img, &recolor))
+    })
+    .await
+    .map_err(|e| AppError::InternalServerError(e.to_string()))?
+}
+
+/// Charges `cost` for a recoloured icon and stores it as a new version. Run it in a
+/// transaction so it is both or neither.
+fn store_recolor(
+    conn: &mut PgConnection,
+    wallet: Wallet,
+    icon_id: i32,
+    parameters: &serde_json::Value,
+    rendition: Rendition,
+    cost: i32,
+) -> Result<IconVersion, AppError> {
+    billing::charge(conn, wallet, Operation::Style.transaction_type(), cost, Some(icon_id))?;
+    store_version(conn, icon_id, "recolor", parameters, rendition)
+}
+
+#[post("/icons/{id}/recolor")]
+pub async fn recolor_icon(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<RecolorParams>,
+) -> Result<HttpResponse, AppError> {
+    let icon_id = path.into_inner();
+    let params = body.into_inner();
+    let recolor = params.to_recolor()?;
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let (image_data, organization_id) = icon_image(&mut conn, user.id, icon_id, Role::Editor)?;
+    let rendition = Rendition::from_png(recolor_png(image_data, recolor, None).await?)?;
+    let parameters = serde_json::to_value(&params)
+        .map_err(|e| AppError::InternalServerError(e.to_string()))?;
+    let wallet = Wallet::of(user.id, organization_id);
+    let cost = pricing::cost(&mut conn, wallet, Operation::Style, None)?;
+    let version = conn.transaction(|conn| store_recolor(conn, wallet, icon_id, &parameters, rendition, cost))?;
+    events::balance_changed(&data, user.id);
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "version": version }
+    })))
+}
+
+#[derive(Deserialize)]
+struct RenderQuery {
+    /// Render a stored version instead of the original.
+    version: Option<i32>,
+    /// Longest side in pixels, resampled like a local upscale.
+    size: Option<u32>,
+    /// `pack` applies the pack's brand palette, explicit colours below override it.
+    palette: Option<String>,
+    // Spelled out rather than flattened, serde_urlencoded cannot parse numbers and bools
+    // through `#[serde(flatten)]`.
+    ink: Option<String>,
+    ink_to: Option<String>,
+    angle: Option<f32>,
+    background: Option<String>,
+    swap: Option<bool>,
+}
+
+/// Renders an icon on the fly without storing anything. With no colour parameters the
+/// image is only resized and re-encoded as PNG. Rendering is free and uncached, so it only
+/// ever shrinks: larger sizes are a paid upscale whose version can then be rendered.
+#[get("/icons/{id}/render")]
+pub async fn render_icon(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    query: web::Query<RenderQuery>,
+) -> Result<HttpResponse, AppError> {
+    let icon_id = path.into_inner();
+    let query = query.into_inner();
+    if query.size == Some(0) {
+        return Err(AppError::BadRequest("size must be at least 1".to_string()));
+    }
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let (mut image_data, _) = icon_image(&mut conn, user.id, icon_id, Role::Viewer)?;
+    if let Some(version_id) = query.version {
+        image_data = icon_versions::table
+            .filter(icon_versions::id.eq(version_id))
+            .filter(icon_versions::icon_id.eq(icon_id))
+            .select(icon_versions::image_data)
+            .first(&mut conn)
+            .optional()?
+            .ok_or_else(|| AppError::NotFound("Version not found".to_string()))?;
+    }
+    if let Some(size) = query.size {
+        let (width, height) = imaging::dimensions(&image_data)?;
+        if size > width.max(height) {
+            return Err(AppError::BadRequest(format!(
+                "size can be at most {}, upscale the icon for larger renders",
+                width.max(height)
+            )));
+        }
+    }
+
+    let mut colors = RecolorParams {
+        ink: query.ink,
+        ink_to: query.ink_to,
+        angle: query.angle,
+        background: query.background,
+        swap: query.swap,
+    };
+    if query.palette.as_deref() == Some("pack") {
+        let palette: Option<serde_json::Value> = icons::table
+            .inner_join(icon_packs::table)
+            .filter(icons::id.eq(icon_id))
+            .select(icon_packs::palette)
+            .first(&mut conn)
+            .optional()?
+            .flatten();
+        let pack: RecolorParams = palette
+            .map(serde_json::from_value)
+            .transpose()
+            .map_err(|e| AppError::InternalServerError(e.to_string()))?
+            .ok_or_else(|| AppError::BadRequest("Icon is not in a pack with a palette".to_string()))?;
+        colors = RecolorParams {
+            ink: colors.ink.or(pack.ink),
+            ink_to: colors.ink_to.or(pack.ink_to),
+            angle: colors.angle.or(pack.angle),
+            background: colors.background.or(pack.background),
+            swap: colors.swap.or(pack.swap),
+        };
+    }
+
+    let png = if colors.is_empty() {
+        web::block(move || {
+            let img = imaging::decode(&image_data)?;
+            let img = match query.size {
+                Some(size) => imaging::upscale(&img, size),
+                None => img,
+            };
+            imaging::encode_png(&img)
+        })
+        .await
+        .map_err(|e| AppError::InternalServerError(e.to_string()))??
+    } else {
+        recolor_png(image_data, colors.to_recolor()?, query.size).await?
+    };
+
+    Ok(HttpResponse::Ok().content_type("image/png").body(png))
+}
+
+#[put("/packs/{id}/palette")]
+pub async fn set_pack_palette(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<RecolorParams>,
+) -> Result<HttpResponse, AppError> {
+    let pack_id = path.into_inner();
+    let params = body.into_inner();
+    params.to_recolor()?;
+    let palette = serde_json::to_value(&params)
+        .map_err(|e| AppError::InternalServerError(e.to_string()))?;
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    find_pack(&mut conn, user.id, pack_id, Role::Editor)?;
+    diesel::update(icon_packs::table.filter(icon_packs::id.eq(pack_id)))
+        .set(icon_packs::palette.eq(&palette))
+        .execute(&mut conn)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "palette": palette }
+    })))
+}
+
+/// Applies the pack's brand palette to every finished icon in it, each result stored as a
+/// new version. All of them are charged and stored in one transaction, so a wallet that
+/// can't pay for the whole pack pays for none of it. Icons that fail to render are left
+/// out and listed under `skipped`.
+#[post("/packs/{id}/recolor")]
+pub async fn recolor_pack(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let pack_id = path.into_inner();
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let pack = find_pack(&mut conn, user.id, pack_id, Role::Editor)?;
+    let wallet = Wallet::of(user.id, pack.organization_id);
+    let palette = pack.palette
+        .ok_or_else(|| AppError::BadRequest("Pack has no palette".to_string()))?;
+    let recolor = serde_json::from_value::<RecolorParams>(palette.clone())
+        .map_err(|e| AppError::InternalServerError(e.to_string()))?
+        .to_recolor()?;
+
+    let pack_icons: Vec<(i32, Vec<u8>)> = icons::table
+        .filter(icons::icon_pack_id.eq(pack_id))
+        .filter(icons::candidate_status.is_distinct_from("candidate"))
+        .filter(icons::image_data.ne(Vec::<u8>::new()))
+        .filter(organizations::icon_access(user.id, Role::Editor))
+        .select((icons::id, icons::image_data))
+        .order(icons::id.asc())
+        .load(&mut conn)?;
+
+    let mut rendered = Vec::with_capacity(pack_icons.len());
+    let mut skipped = Vec::new();
+    for (icon_id, image_data) in pack_icons {
+        match recolor_png(image_data, recolor, None).await.and_then(Rendition::from_png) {
+            Ok(rendition) => rendered.push((icon_id, rendition)),
+            Err(e) => skipped.push(SkippedIcon { id: icon_id, error: e.to_string() }),
+        }
+    }
+
+    let cost = pricing::cost(&mut conn, wallet, Operation::Style, None)?;
+    let versions: Vec<IconVersion> = conn.transaction(|conn| {
+        rendered
+            .into_iter()
+            .map(|(icon_id, rendition)| store_recolor(conn, wallet, icon_id, &palette, rendition, cost))
+            .collect::<Result<_, AppError>>()
+    })?;
+    if !versions.is_empty() {
+        events::balance_changed(&data, user.id);
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "versions": versions, "skipped": skipped }
+    })))
+}
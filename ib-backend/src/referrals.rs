@@ -0,0 +1,254 @@
+use std::env;
+
+use actix_web::{get, post, web, HttpResponse};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    billing,
+    handlers::{AppError, AuthenticatedUser},
+    model::{AppState, TransactionType},
+    payments::Payment,
+    schema::{payments, referral_codes, referrals},
+};
+
+const CODE_LENGTH: usize = 8;
+/// No 0/O or 1/I, codes get read out and typed in.
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+#[derive(Debug, Queryable, Serialize)]
+#[diesel(table_name = referrals)]
+pub struct Referral {
+    pub id: i32,
+    pub referrer_id: i32,
+    pub referee_id: i32,
+    pub status: String,
+    pub payment_id: Option<i32>,
+    pub referrer_transaction_id: Option<i32>,
+    pub referee_transaction_id: Option<i32>,
+    pub created_at: DateTime<Utc>,
+    pub rewarded_at: Option<DateTime<Utc>>,
+}
+
+/// Inkbucks for the user who shared the link, `REFERRAL_REFERRER_BONUS` (25 by default).
+fn referrer_bonus() -> i32 {
+    env::var("REFERRAL_REFERRER_BONUS").ok().and_then(|v| v.parse().ok()).unwrap_or(25)
+}
+
+/// Inkbucks for the user who signed up with it, `REFERRAL_REFEREE_BONUS` (25 by default).
+fn referee_bonus() -> i32 {
+    env::var("REFERRAL_REFEREE_BONUS").ok().and_then(|v| v.parse().ok()).unwrap_or(25)
+}
+
+/// Returns a user's referral code, generating it on first use.
+pub fn code_for(conn: &mut PgConnection, user_id: i32) -> Result<String, AppError> {
+    loop {
+        let existing: Option<String> = referral_codes::table
+            .filter(referral_codes::user_id.eq(user_id))
+            .select(referral_codes::code)
+            .first(conn)
+            .optional()?;
+        if let Some(code) = existing {
+            return Ok(code);
+        }
+        let code: String = {
+            let mut rng = rand::thread_rng();
+            (0..CODE_LENGTH)
+                .map(|_| CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())] as char)
+                .collect()
+        };
+        // Either another request created the user's code or the code is taken, look again.
+        diesel::insert_into(referral_codes::table)
+            .values((referral_codes::user_id.eq(user_id), referral_codes::code.eq(&code)))
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+    }
+}
+
+/// Records that `referee_id` was referred by the owner of `code`. Only new customers can
+/// be referred, once they paid for something the referral would reward nothing.
+pub fn attach(conn: &mut PgConnection, referee_id: i32, code: &str) -> Result<Referral, AppError> {
+    let referrer_id: i32 = referral_codes::table
+        .filter(referral_codes::code.eq(code.trim().to_uppercase()))
+        .select(referral_codes::user_id)
+        .first(conn)
+        .optional()?
+        .ok_or_else(|| AppError::BadRequest("Unknown referral code".to_string()))?;
+    if referrer_id == referee_id {
+        return Err(AppError::BadRequest("You cannot refer yourself".to_string()));
+    }
+    let paid: bool = diesel::select(diesel::dsl::exists(
+        payments::table
+            .filter(payments::user_id.eq(referee_id))
+            .filter(payments::status.eq("paid")),
+    ))
+    .get_result(conn)?;
+    if paid {
+        return Err(AppError::BadRequest(
+            "Referral codes only apply before your first purchase".to_string(),
+        ));
+    }
+
+    diesel::insert_into(referrals::table)
+        .values((referrals::referrer_id.eq(referrer_id), referrals::referee_id.eq(referee_id)))
+        .on_conflict_do_nothing()
+        .get_result(conn)
+        .optional()?
+        .ok_or_else(|| AppError::BadRequest("You were already referred".to_string()))
+}
+
+/// Attaches the code a new user signed up with. A code that doesn't apply must not cost
+/// them the account, it is left out and the reason returned for the client to show.
+pub fn attach_at_signup(conn: &mut PgConnection, referee_id: i32, code: Option<&str>) -> Result<Option<String>, AppError> {
+    let Some(code) = code else {
+        return Ok(None);
+    };
+    match conn.transaction(|conn| attach(conn, referee_id, code)) {
+        Ok(_) => Ok(None),
+        Err(AppError::BadRequest(message)) => Ok(Some(message)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Credits both sides of a pending referral when `payment` is the referee's first paid
+/// one. Runs in the transaction that settles the payment, the status guard makes sure a
+/// referral pays out once. Returns the referrer's id when it did.
+pub fn reward_first_purchase(conn: &mut PgConnection, payment: &Payment) -> Result<Option<i32>, AppError> {
+    let paid: i64 = payments::table
+        .filter(payments::user_id.eq(payment.user_id))
+        .filter(payments::status.eq("paid"))
+        .count()
+        .get_result(conn)?;
+    if paid != 1 {
+        return Ok(None);
+    }
+
+    let referral: Option<Referral> = diesel::update(
+        referrals::table
+            .filter(referrals::referee_id.eq(payment.user_id))
+            .filter(referrals::status.eq("pending")),
+    )
+    .set((
+        referrals::status.eq("rewarded"),
+        referrals::payment_id.eq(payment.id),
+        referrals::rewarded_at.eq(Utc::now()),
+    ))
+    .get_result(conn)
+    .optional()?;
+    let Some(referral) = referral else {
+        return Ok(None);
+    };
+
+    let referrer_transaction_id = billing::credit(
+        conn,
+        referral.referrer_id,
+        TransactionType::Referral,
+        referrer_bonus(),
+        None,
+        Some(&format!("referral:{}:referrer", referral.id)),
+    )?;
+    let referee_transaction_id = billing::credit(
+        conn,
+        referral.referee_id,
+        TransactionType::Referral,
+        referee_bonus(),
+        None,
+        Some(&format!("referral:{}:referee", referral.id)),
+    )?;
+    diesel::update(referrals::table.filter(referrals::id.eq(referral.id)))
+        .set((
+            referrals::referrer_transaction_id.eq(referrer_transaction_id),
+            referrals::referee_transaction_id.eq(referee_transaction_id),
+        ))
+        .execute(conn)?;
+    Ok(Some(referral.referrer_id))
+}
+
+/// The user's referral link and how it did so far.
+#[get("/me/referral")]
+pub async fn get_referral(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let code = code_for(&mut conn, user.id)?;
+    let referred: Vec<Referral> = referrals::table
+        .filter(referrals::referrer_id.eq(user.id))
+        .load(&mut conn)?;
+    let rewarded = referred.iter().filter(|r| r.status == "rewarded").count();
+    let referred_by: Option<i32> = referrals::table
+        .filter(referrals::referee_id.eq(user.id))
+        .select(referrals::referrer_id)
+        .first(&mut conn)
+        .optional()?;
+
+    let frontend = env::var("FRONTEND_URL").unwrap_or_else(|_| "http://localhost:8000".to_string());
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": {
+            "code": code,
+            "link": format!("{}/signup?ref={}", frontend, code),
+            "referrer_bonus": referrer_bonus(),
+            "referee_bonus": referee_bonus(),
+            "referred": referred.len(),
+            "rewarded": rewarded,
+            "referred_by": referred_by,
+        }
+    })))
+}
+
+#[derive(Deserialize)]
+struct ApplyReferral {
+    code: String,
+}
+
+/// Applies a referral code after signup, for users who did not come in through the link.
+#[post("/me/referral")]
+pub async fn apply_referral(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    body: web::Json<ApplyReferral>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let referral = attach(&mut conn, user.id, &body.code)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "referral": referral }
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{schema::users, testing};
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn signup_survives_a_code_that_does_not_apply() {
+        let mut conn = testing::pool(1).get().unwrap();
+        let referrer_id = testing::user(&mut conn);
+        let code = code_for(&mut conn, referrer_id).unwrap();
+
+        let (user_id, error) = conn
+            .transaction(|conn| {
+                let user_id = testing::user(conn);
+                let error = attach_at_signup(conn, user_id, Some("NOSUCHCODE"))?;
+                Ok::<_, AppError>((user_id, error))
+            })
+            .unwrap();
+        assert_eq!(error.as_deref(), Some("Unknown referral code"));
+        let exists: bool = diesel::select(diesel::dsl::exists(users::table.filter(users::id.eq(user_id))))
+            .get_result(&mut conn)
+            .unwrap();
+        assert!(exists);
+
+        assert_eq!(attach_at_signup(&mut conn, user_id, Some(&code.to_lowercase())).unwrap(), None);
+        assert_eq!(attach_at_signup(&mut conn, referrer_id, Some(&code)).unwrap().as_deref(), Some("You cannot refer yourself"));
+        assert_eq!(attach_at_signup(&mut conn, user_id, None).unwrap(), None);
+    }
+}
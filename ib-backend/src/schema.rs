@@ -6,8 +6,9 @@ table! {
         id -> Int4,
         email -> Text,
         username -> Text,
-        inkbucks -> Int4,
         uid -> Varchar,
+        is_admin -> Bool,
+        email_verified -> Bool,
     }
 }
 
@@ -19,22 +20,464 @@ table! {
         icon_pack_id -> Nullable<Int4>,
         metadata -> Nullable<Text>,
         image_data -> Bytea,
+        created_at -> Timestamptz,
+        prompt -> Nullable<Text>,
+        tags -> Array<Text>,
+        phash -> Nullable<Int8>,
+        prompt_template_id -> Nullable<Int4>,
+        seed -> Nullable<Int8>,
+        aspect_ratio -> Nullable<Text>,
+        negative_prompt -> Nullable<Text>,
+        model -> Nullable<Text>,
+        output_format -> Nullable<Text>,
+        source_icon_id -> Nullable<Int4>,
+        candidate_status -> Nullable<Text>,
+        stroke_width -> Nullable<Float4>,
+        organization_id -> Nullable<Int4>,
+    }
+}
+
+// icon version table
+table! {
+    icon_versions (id) {
+        id -> Int4,
+        icon_id -> Int4,
+        operation -> Text,
+        parameters -> Jsonb,
+        image_data -> Bytea,
+        width -> Int4,
+        height -> Int4,
+        created_at -> Timestamptz,
+    }
+}
+
+// icon pack table
+table! {
+    icon_packs (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Text,
+        palette -> Nullable<Jsonb>,
+        organization_id -> Nullable<Int4>,
+        style -> Nullable<Jsonb>,
+        remixed_from -> Nullable<Int4>,
+    }
+}
+
+// batch tables
+table! {
+    batches (id) {
+        id -> Int4,
+        user_id -> Int4,
+        icon_pack_id -> Int4,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    batch_items (id) {
+        id -> Int4,
+        batch_id -> Int4,
+        subject -> Text,
+        icon_id -> Nullable<Int4>,
+        status -> Text,
+        error -> Nullable<Text>,
+        params -> Nullable<Jsonb>,
+        cost -> Nullable<Int4>,
+        attempts -> Int4,
+        started_at -> Nullable<Timestamptz>,
+    }
+}
+
+// prompt template table
+table! {
+    prompt_templates (id) {
+        id -> Int4,
+        name -> Text,
+        version -> Int4,
+        body -> Text,
+        negative_prompt -> Nullable<Text>,
+        parameters -> Jsonb,
+        defaults -> Jsonb,
+        weight -> Int4,
+        created_at -> Timestamptz,
+    }
+}
+
+// payment table
+table! {
+    payments (id) {
+        id -> Int4,
+        user_id -> Int4,
+        product -> Text,
+        inkbucks -> Int4,
+        amount_cents -> Int4,
+        currency -> Text,
+        provider -> Text,
+        provider_session_id -> Nullable<Text>,
+        status -> Text,
+        ledger_transaction_id -> Nullable<Int4>,
+        created_at -> Timestamptz,
+        paid_at -> Nullable<Timestamptz>,
+    }
+}
+
+// plan and subscription tables
+table! {
+    plans (id) {
+        id -> Text,
+        name -> Text,
+        monthly_inkbucks -> Int4,
+        price_cents -> Int4,
+        entitlements -> Array<Text>,
+        daily_generations -> Nullable<Int4>,
     }
 }
 
-// transaction table
 table! {
-    transactions (id) {
+    subscriptions (id) {
         id -> Int4,
         user_id -> Int4,
+        plan_id -> Text,
+        status -> Text,
+        provider -> Text,
+        provider_subscription_id -> Nullable<Text>,
+        current_period_start -> Timestamptz,
+        current_period_end -> Timestamptz,
+        trial_end -> Nullable<Timestamptz>,
+        cancel_at_period_end -> Bool,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+    }
+}
+
+// idempotency key table
+table! {
+    idempotency_keys (id) {
+        id -> Int4,
+        user_id -> Int4,
+        idempotency_key -> Text,
+        request_hash -> Text,
+        response_status -> Nullable<Int4>,
+        response_content_type -> Nullable<Text>,
+        response_body -> Nullable<Bytea>,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+    }
+}
+
+// promo code tables
+table! {
+    promo_codes (id) {
+        id -> Int4,
+        code -> Text,
+        amount -> Int4,
+        max_redemptions -> Nullable<Int4>,
+        per_user_limit -> Int4,
+        redemptions -> Int4,
+        expires_at -> Nullable<Timestamptz>,
+        active -> Bool,
+        created_by -> Nullable<Int4>,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    promo_redemptions (id) {
+        id -> Int4,
+        promo_code_id -> Int4,
+        user_id -> Int4,
+        ledger_transaction_id -> Nullable<Int4>,
+        created_at -> Timestamptz,
+    }
+}
+
+// referral tables
+table! {
+    referral_codes (user_id) {
+        user_id -> Int4,
+        code -> Text,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    referrals (id) {
+        id -> Int4,
+        referrer_id -> Int4,
+        referee_id -> Int4,
+        status -> Text,
+        payment_id -> Nullable<Int4>,
+        referrer_transaction_id -> Nullable<Int4>,
+        referee_transaction_id -> Nullable<Int4>,
+        created_at -> Timestamptz,
+        rewarded_at -> Nullable<Timestamptz>,
+    }
+}
+
+// pricing tables
+table! {
+    operation_prices (id) {
+        id -> Int4,
+        operation -> Text,
+        plan_id -> Nullable<Text>,
+        cost -> Int4,
+    }
+}
+
+table! {
+    model_multipliers (model) {
+        model -> Text,
+        multiplier -> Float4,
+    }
+}
+
+// workspace tables
+table! {
+    organizations (id) {
+        id -> Int4,
+        name -> Text,
+        created_by -> Nullable<Int4>,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    organization_members (organization_id, user_id) {
+        organization_id -> Int4,
+        user_id -> Int4,
+        role -> Text,
+        monthly_spend_cap -> Nullable<Int4>,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    organization_invitations (id) {
+        id -> Int4,
+        organization_id -> Int4,
+        email -> Text,
+        role -> Text,
+        token -> Text,
+        invited_by -> Nullable<Int4>,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        accepted_by -> Nullable<Int4>,
+        accepted_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    organization_audit_log (id) {
+        id -> Int4,
+        organization_id -> Int4,
+        actor_id -> Nullable<Int4>,
+        action -> Text,
+        details -> Jsonb,
+        created_at -> Timestamptz,
+    }
+}
+
+// pack sharing tables
+table! {
+    pack_collaborators (pack_id, user_id) {
+        pack_id -> Int4,
+        user_id -> Int4,
+        permission -> Text,
+        granted_by -> Nullable<Int4>,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    pack_share_links (id) {
+        id -> Int4,
+        pack_id -> Int4,
+        token -> Text,
+        password_hash -> Nullable<Text>,
+        expires_at -> Nullable<Timestamptz>,
+        created_by -> Nullable<Int4>,
+        created_at -> Timestamptz,
+        revoked_at -> Nullable<Timestamptz>,
+    }
+}
+
+// gallery tables
+table! {
+    gallery_categories (slug) {
+        slug -> Text,
+        name -> Text,
+        position -> Int4,
+    }
+}
+
+table! {
+    gallery_packs (pack_id) {
+        pack_id -> Int4,
+        category -> Text,
+        description -> Nullable<Text>,
+        published_by -> Nullable<Int4>,
+        published_at -> Timestamptz,
+    }
+}
+
+table! {
+    gallery_likes (pack_id, user_id) {
+        pack_id -> Int4,
+        user_id -> Int4,
+        created_at -> Timestamptz,
+    }
+}
+
+// API token table
+table! {
+    api_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Text,
+        token_hash -> Text,
+        prefix -> Text,
+        scopes -> Array<Text>,
+        created_at -> Timestamptz,
+        expires_at -> Nullable<Timestamptz>,
+        last_used_at -> Nullable<Timestamptz>,
+        revoked_at -> Nullable<Timestamptz>,
+    }
+}
+
+// generation log table, what daily quotas count
+table! {
+    generations (id) {
+        id -> Int4,
+        user_id -> Int4,
+        created_at -> Timestamptz,
+    }
+}
+
+// webhook tables
+table! {
+    webhook_endpoints (id) {
+        id -> Int4,
+        user_id -> Int4,
+        url -> Text,
+        secret -> Text,
+        events -> Array<Text>,
+        active -> Bool,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    webhook_deliveries (id) {
+        id -> Int4,
+        endpoint_id -> Int4,
+        event -> Text,
+        payload -> Jsonb,
+        status -> Text,
+        attempts -> Int4,
+        next_attempt_at -> Timestamptz,
+        response_status -> Nullable<Int4>,
+        last_error -> Nullable<Text>,
+        created_at -> Timestamptz,
+        delivered_at -> Nullable<Timestamptz>,
+    }
+}
+
+// ledger tables
+table! {
+    ledger_accounts (id) {
+        id -> Int4,
+        user_id -> Nullable<Int4>,
+        system -> Nullable<Text>,
+        balance -> Int4,
+        created_at -> Timestamptz,
+        organization_id -> Nullable<Int4>,
+    }
+}
+
+table! {
+    ledger_transactions (id) {
+        id -> Int4,
+        #[sql_name = "type"]
         type_ -> Text,
-        icon_id -> Int4,
+        icon_id -> Nullable<Int4>,
+        reference -> Nullable<Text>,
+        created_at -> Timestamptz,
+        actor_id -> Nullable<Int4>,
+    }
+}
+
+table! {
+    ledger_entries (id) {
+        id -> Int4,
+        transaction_id -> Int4,
+        account_id -> Int4,
         amount -> Int4,
     }
 }
 
 joinable!(icons -> users (user_id));
-joinable!(transactions -> users (user_id));
-joinable!(transactions -> icons (icon_id));
+joinable!(icons -> prompt_templates (prompt_template_id));
+joinable!(icons -> icon_packs (icon_pack_id));
+joinable!(icon_versions -> icons (icon_id));
+joinable!(icon_packs -> users (user_id));
+joinable!(batches -> icon_packs (icon_pack_id));
+joinable!(batch_items -> batches (batch_id));
+joinable!(batch_items -> icons (icon_id));
+joinable!(webhook_endpoints -> users (user_id));
+joinable!(webhook_deliveries -> webhook_endpoints (endpoint_id));
+joinable!(ledger_accounts -> users (user_id));
+joinable!(idempotency_keys -> users (user_id));
+joinable!(promo_redemptions -> promo_codes (promo_code_id));
+joinable!(promo_redemptions -> users (user_id));
+joinable!(referral_codes -> users (user_id));
+joinable!(payments -> users (user_id));
+joinable!(subscriptions -> users (user_id));
+joinable!(subscriptions -> plans (plan_id));
+joinable!(operation_prices -> plans (plan_id));
+joinable!(payments -> ledger_transactions (ledger_transaction_id));
+joinable!(ledger_transactions -> icons (icon_id));
+joinable!(ledger_entries -> ledger_transactions (transaction_id));
+joinable!(ledger_entries -> ledger_accounts (account_id));
+joinable!(ledger_transactions -> users (actor_id));
+joinable!(organization_members -> organizations (organization_id));
+joinable!(organization_members -> users (user_id));
+joinable!(organization_invitations -> organizations (organization_id));
+joinable!(organization_audit_log -> organizations (organization_id));
+joinable!(pack_collaborators -> icon_packs (pack_id));
+joinable!(pack_collaborators -> users (user_id));
+joinable!(pack_share_links -> icon_packs (pack_id));
+joinable!(gallery_packs -> icon_packs (pack_id));
+joinable!(gallery_packs -> gallery_categories (category));
+joinable!(gallery_likes -> gallery_packs (pack_id));
+joinable!(gallery_likes -> users (user_id));
+joinable!(api_tokens -> users (user_id));
+joinable!(generations -> users (user_id));
 
-allow_tables_to_appear_in_same_query!(users, icons, transactions);
+allow_tables_to_appear_in_same_query!(
+    users,
+    icons,
+    icon_versions,
+    icon_packs,
+    ledger_accounts,
+    ledger_transactions,
+    ledger_entries,
+    prompt_templates,
+    batches,
+    batch_items,
+    webhook_endpoints,
+    webhook_deliveries,
+    promo_codes,
+    promo_redemptions,
+    organizations,
+    organization_members,
+    organization_invitations,
+    organization_audit_log,
+    pack_collaborators,
+    pack_share_links,
+    gallery_categories,
+    gallery_packs,
+    gallery_likes,
+    api_tokens,
+    generations,
+);
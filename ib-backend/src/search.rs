@@ -0,0 +1,159 @@
+use actix_web::{get, web, HttpResponse};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::{Array, BigInt, Float4, Int4, Nullable, Text, Timestamptz};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    handlers::{AppError, AuthenticatedUser},
+    model::{normalize_tags, AppState},
+};
+
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 100;
+const FACET_LIMIT: i64 = 20;
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: Option<String>,
+    /// Comma separated list, every tag must be present on a result.
+    tags: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Debug, QueryableByName, Serialize)]
+struct SearchHit {
+    #[diesel(sql_type = Int4)]
+    id: i32,
+    #[diesel(sql_type = Nullable<Int4>)]
+    icon_pack_id: Option<i32>,
+    #[diesel(sql_type = Nullable<Text>)]
+    metadata: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    prompt: Option<String>,
+    #[diesel(sql_type = Array<Text>)]
+    tags: Vec<String>,
+    #[diesel(sql_type = Timestamptz)]
+    created_at: DateTime<Utc>,
+    #[diesel(sql_type = Float4)]
+    rank: f32,
+}
+
+#[derive(Debug, QueryableByName, Serialize)]
+struct TagFacet {
+    #[diesel(sql_type = Text)]
+    tag: String,
+    #[diesel(sql_type = BigInt)]
+    count: i64,
+}
+
+#[derive(QueryableByName)]
+struct Total {
+    #[diesel(sql_type = BigInt)]
+    total: i64,
+}
+
+#[derive(Serialize)]
+struct SearchResponse {
+    status: String,
+    data: SearchData,
+}
+
+#[derive(Serialize)]
+struct SearchData {
+    total: i64,
+    icons: Vec<SearchHit>,
+    facets: Vec<TagFacet>,
+}
+
+/// Turns free text into a prefix-matching tsquery, e.g. `rock ship` -> `rock:* & ship:*`.
+/// Anything that is not alphanumeric is treated as a separator so user input can never
+/// produce an invalid tsquery.
+fn to_prefix_tsquery(q: &str) -> String {
+    q.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(|term| format!("{}:*", term.to_lowercase()))
+        .collect::<Vec<_>>()
+        .join(" & ")
+}
+
+#[get("/icons/search")]
+pub async fn search_icons(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    query: web::Query<SearchQuery>,
+) -> Result<HttpResponse, AppError> {
+    let query = query.into_inner();
+    let tsquery = to_prefix_tsquery(query.q.as_deref().unwrap_or(""));
+    let tags: Vec<String> = normalize_tags(
+        &query
+            .tags
+            .as_deref()
+            .unwrap_or("")
+            .split(',')
+            .map(str::to_string)
+            .collect::<Vec<_>>(),
+    );
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let offset = query.offset.unwrap_or(0).max(0);
+
+    // $1 user id, $2 tsquery (may be empty), $3 required tags (may be empty). Same icons as
+    // `organizations::icon_access` for viewers.
+    let filter = "(i.organization_id IS NULL AND i.user_id = $1 \
+            OR i.organization_id IN (SELECT organization_id FROM organization_members WHERE user_id = $1) \
+            OR i.icon_pack_id IN (SELECT id FROM icon_packs WHERE organization_id IS NULL AND user_id = $1) \
+            OR i.icon_pack_id IN (SELECT pack_id FROM pack_collaborators WHERE user_id = $1)) \
+        AND ($2 = '' OR i.search_vector @@ to_tsquery('english', $2)) \
+        AND i.tags @> $3";
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+
+    let hits: Vec<SearchHit> = diesel::sql_query(format!(
+        "SELECT i.id, i.icon_pack_id, i.metadata, i.prompt, i.tags, i.created_at, \
+            CASE WHEN $2 = '' THEN 0::real \
+                 ELSE ts_rank_cd(i.search_vector, to_tsquery('english', $2)) END AS rank \
+         FROM icons i WHERE {} \
+         ORDER BY rank DESC, i.created_at DESC \
+         LIMIT $4 OFFSET $5",
+        filter
+    ))
+    .bind::<Int4, _>(user.id)
+    .bind::<Text, _>(&tsquery)
+    .bind::<Array<Text>, _>(&tags)
+    .bind::<BigInt, _>(limit)
+    .bind::<BigInt, _>(offset)
+    .load(&mut conn)?;
+
+    let total: Total = diesel::sql_query(format!(
+        "SELECT count(*) AS total FROM icons i WHERE {}",
+        filter
+    ))
+    .bind::<Int4, _>(user.id)
+    .bind::<Text, _>(&tsquery)
+    .bind::<Array<Text>, _>(&tags)
+    .get_result(&mut conn)?;
+
+    let facets: Vec<TagFacet> = diesel::sql_query(format!(
+        "SELECT t.tag, count(*) AS count \
+         FROM icons i, unnest(i.tags) AS t(tag) WHERE {} \
+         GROUP BY t.tag ORDER BY count DESC, t.tag \
+         LIMIT $4",
+        filter
+    ))
+    .bind::<Int4, _>(user.id)
+    .bind::<Text, _>(&tsquery)
+    .bind::<Array<Text>, _>(&tags)
+    .bind::<BigInt, _>(FACET_LIMIT)
+    .load(&mut conn)?;
+
+    Ok(HttpResponse::Ok().json(SearchResponse {
+        status: "success".to_string(),
+        data: SearchData {
+            total: total.total,
+            icons: hits,
+            facets,
+        },
+    }))
+}
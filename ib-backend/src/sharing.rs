@@ -0,0 +1,620 @@
+use std::env;
+
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use chrono::{DateTime, Duration, Utc};
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::sql_types::Integer;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    handlers::{AppError, AuthenticatedUser},
+    model::{AppState, IconPack},
+    organizations::{lower, Role},
+    packs::find_pack,
+    provider::OutputFormat,
+    schema::{icon_packs, icons, pack_collaborators, pack_share_links, users},
+};
+
+const PASSWORD_HEADER: &str = "X-Share-Password";
+const MAX_PASSWORD_LENGTH: usize = 128;
+
+/// What a collaborator may do with a pack shared with them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Sees and exports the pack's icons.
+    View,
+    /// Also adds icons and edits them, paying from their own wallet.
+    Edit,
+}
+
+impl Permission {
+    pub fn parse(s: &str) -> Option<Permission> {
+        match s {
+            "view" => Some(Permission::View),
+            "edit" => Some(Permission::Edit),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::View => "view",
+            Permission::Edit => "edit",
+        }
+    }
+
+    /// Permissions that let a collaborator work with a pack at `min`. Sharing never makes
+    /// anyone an admin of a pack.
+    fn covering(min: Role) -> Vec<&'static str> {
+        match min {
+            Role::Viewer => vec![Permission::View.as_str(), Permission::Edit.as_str()],
+            Role::Editor => vec![Permission::Edit.as_str()],
+            Role::Admin | Role::Owner => Vec::new(),
+        }
+    }
+}
+
+/// Packs shared with the user with a permission that covers `min`, as a subquery.
+pub fn shared_packs(user_id: i32, min: Role) -> pack_collaborators::BoxedQuery<'static, Pg, Integer> {
+    pack_collaborators::table
+        .filter(pack_collaborators::user_id.eq(user_id))
+        .filter(pack_collaborators::permission.eq_any(Permission::covering(min)))
+        .select(pack_collaborators::pack_id)
+        .into_boxed()
+}
+
+#[derive(Debug, Queryable, Serialize)]
+#[diesel(table_name = pack_collaborators)]
+pub struct Collaborator {
+    pub pack_id: i32,
+    pub user_id: i32,
+    pub permission: String,
+    pub granted_by: Option<i32>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Queryable, Serialize)]
+#[diesel(table_name = pack_share_links)]
+pub struct ShareLink {
+    pub id: i32,
+    pub pack_id: i32,
+    pub token: String,
+    #[serde(skip_serializing)]
+    pub password_hash: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_by: Option<i32>,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl ShareLink {
+    fn is_active(&self) -> bool {
+        self.revoked_at.is_none() && self.expires_at.is_none_or(|expires_at| expires_at > Utc::now())
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let frontend = env::var("FRONTEND_URL").unwrap_or_else(|_| "http://localhost:8000".to_string());
+        serde_json::json!({
+            "link": self,
+            "url": format!("{}/shared/{}", frontend, self.token),
+            "has_password": self.password_hash.is_some(),
+            "active": self.is_active(),
+        })
+    }
+}
+
+/// Signs image URLs so they load without a login for a while: those of public pack pages,
+/// which also stop loading as soon as their link is revoked, and single icon images handed
+/// to places that can't send credentials, like `<img>` tags and webhook receivers.
+pub struct UrlSigner {
+    secret: Vec<u8>,
+    ttl: Duration,
+}
+
+impl UrlSigner {
+    /// `SIGNED_URL_SECRET` shared by all instances, `SIGNED_URL_TTL_SECS` (an hour by default).
+    pub fn from_env() -> UrlSigner {
+        let secret = match env::var("SIGNED_URL_SECRET") {
+            Ok(secret) if !secret.is_empty() => secret.into_bytes(),
+            _ => {
+                println!("SIGNED_URL_SECRET not set, signed image URLs only work on this instance until it restarts");
+                rand::thread_rng().gen::<[u8; 32]>().to_vec()
+            }
+        };
+        let ttl = env::var("SIGNED_URL_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(3600)
+            .max(60);
+        UrlSigner { secret, ttl: Duration::seconds(ttl) }
+    }
+
+    fn mac(&self, link_id: i32, icon_id: i32, expires: i64) -> Hmac<Sha256> {
+        self.mac_of(&format!("{}:{}:{}", link_id, icon_id, expires))
+    }
+
+    fn mac_of(&self, message: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret)
+            .expect("HMAC accepts keys of any length");
+        mac.update(message.as_bytes());
+        mac
+    }
+
+    /// Prefixed so a signature for an icon can never pass as one for a link.
+    fn icon_mac(&self, icon_id: i32, expires: i64) -> Hmac<Sha256> {
+        self.mac_of(&format!("icon:{}:{}", icon_id, expires))
+    }
+
+    fn image_url(&self, link_id: i32, icon_id: i32) -> String {
+        let expires = (Utc::now() + self.ttl).timestamp();
+        let signature = hex::encode(self.mac(link_id, icon_id, expires).finalize().into_bytes());
+        format!(
+            "/api/public/icons/{}/image?link={}&expires={}&signature={}",
+            icon_id, link_id, expires, signature
+        )
+    }
+
+    fn verify(&self, link_id: i32, icon_id: i32, expires: i64, signature: &str) -> bool {
+        valid(self.mac(link_id, icon_id, expires), expires, signature)
+    }
+
+    /// `GET /api/icons/{id}/image` for callers without access to the icon.
+    pub fn icon_image_url(&self, icon_id: i32) -> String {
+        let expires = (Utc::now() + self.ttl).timestamp();
+        let signature = hex::encode(self.icon_mac(icon_id, expires).finalize().into_bytes());
+        format!("/api/icons/{}/image?expires={}&signature={}", icon_id, expires, signature)
+    }
+
+    pub fn verify_icon(&self, icon_id: i32, expires: i64, signature: &str) -> bool {
+        valid(self.icon_mac(icon_id, expires), expires, signature)
+    }
+}
+
+fn valid(mac: Hmac<Sha256>, expires: i64, signature: &str) -> bool {
+    if expires <= Utc::now().timestamp() {
+        return false;
+    }
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// Sharing is managed by whoever may administer the pack: its owner, or the workspace's
+/// admins for workspace packs.
+fn managed_pack(conn: &mut PgConnection, user_id: i32, pack_id: i32) -> Result<IconPack, AppError> {
+    find_pack(conn, user_id, pack_id, Role::Admin)
+}
+
+/// Users a pack is shared with.
+#[get("/packs/{id}/collaborators")]
+pub async fn list_collaborators(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let pack = managed_pack(&mut conn, user.id, path.into_inner())?;
+    let collaborators: Vec<(Collaborator, String, String)> = pack_collaborators::table
+        .inner_join(users::table)
+        .filter(pack_collaborators::pack_id.eq(pack.id))
+        .select((pack_collaborators::all_columns, users::email, users::username))
+        .order(pack_collaborators::created_at.asc())
+        .load(&mut conn)?;
+    let collaborators: Vec<serde_json::Value> = collaborators
+        .into_iter()
+        .map(|(collaborator, email, username)| {
+            serde_json::json!({
+                "user_id": collaborator.user_id,
+                "email": email,
+                "username": username,
+                "permission": collaborator.permission,
+                "granted_by": collaborator.granted_by,
+                "created_at": collaborator.created_at,
+            })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "collaborators": collaborators }
+    })))
+}
+
+/// The user whose verified address is `email`. Addresses are unique regardless of case, so
+/// there is at most one, and an unverified one could belong to anybody.
+fn verified_user(conn: &mut PgConnection, email: &str) -> Result<Option<i32>, AppError> {
+    Ok(users::table
+        .filter(lower(users::email).eq(email.trim().to_lowercase()))
+        .filter(users::email_verified)
+        .select(users::id)
+        .get_result(conn)
+        .optional()?)
+}
+
+#[derive(Deserialize)]
+struct ShareWithUser {
+    email: String,
+    permission: String,
+}
+
+/// Shares a pack with an existing user, found by their verified email address, or changes
+/// what they may do with it.
+#[post("/packs/{id}/collaborators")]
+pub async fn share_pack(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<ShareWithUser>,
+) -> Result<HttpResponse, AppError> {
+    let permission = Permission::parse(&body.permission)
+        .ok_or_else(|| AppError::BadRequest(format!("Unknown permission '{}'", body.permission)))?;
+    let email = body.email.trim().to_lowercase();
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let pack = managed_pack(&mut conn, user.id, path.into_inner())?;
+    // Collaborators pay for their edits themselves, which a workspace wallet cannot express.
+    if pack.organization_id.is_some() {
+        return Err(AppError::BadRequest(
+            "Workspace packs are shared by inviting people to the workspace".to_string(),
+        ));
+    }
+    let collaborator_id = verified_user(&mut conn, &email)?
+        .ok_or_else(|| AppError::NotFound(format!("No user with the verified email {}", email)))?;
+    if collaborator_id == pack.user_id {
+        return Err(AppError::BadRequest("The pack already belongs to this user".to_string()));
+    }
+
+    let collaborator: Collaborator = diesel::insert_into(pack_collaborators::table)
+        .values((
+            pack_collaborators::pack_id.eq(pack.id),
+            pack_collaborators::user_id.eq(collaborator_id),
+            pack_collaborators::permission.eq(permission.as_str()),
+            pack_collaborators::granted_by.eq(user.id),
+        ))
+        .on_conflict((pack_collaborators::pack_id, pack_collaborators::user_id))
+        .do_update()
+        .set((
+            pack_collaborators::permission.eq(permission.as_str()),
+            pack_collaborators::granted_by.eq(user.id),
+        ))
+        .get_result(&mut conn)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "collaborator": collaborator }
+    })))
+}
+
+/// Stops sharing a pack with a user. Collaborators can also remove themselves. Icons they
+/// added stay in the pack.
+#[post("/packs/{id}/collaborators/{user_id}/remove")]
+pub async fn unshare_pack(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, AppError> {
+    let (pack_id, collaborator_id) = path.into_inner();
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    if collaborator_id != user.id {
+        managed_pack(&mut conn, user.id, pack_id)?;
+    }
+    let removed = diesel::delete(
+        pack_collaborators::table
+            .filter(pack_collaborators::pack_id.eq(pack_id))
+            .filter(pack_collaborators::user_id.eq(collaborator_id)),
+    )
+    .execute(&mut conn)?;
+    if removed == 0 {
+        return Err(AppError::NotFound("Collaborator not found".to_string()));
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "pack_id": pack_id, "user_id": collaborator_id }
+    })))
+}
+
+fn hash_password(password: String) -> Result<String, AppError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| AppError::InternalServerError(e.to_string()))
+}
+
+fn verify_password(password: String, hash: String) -> Result<bool, AppError> {
+    let hash = PasswordHash::new(&hash).map_err(|e| AppError::InternalServerError(e.to_string()))?;
+    Ok(Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+}
+
+#[derive(Deserialize)]
+struct CreateLink {
+    /// Visitors have to send it in the `X-Share-Password` header.
+    password: Option<String>,
+    /// Never expires when omitted.
+    expires_at: Option<DateTime<Utc>>,
+}
+
+/// Creates a public, read-only link to a pack.
+#[post("/packs/{id}/links")]
+pub async fn create_link(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<CreateLink>,
+) -> Result<HttpResponse, AppError> {
+    let body = body.into_inner();
+    if body.expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+        return Err(AppError::BadRequest("expires_at must be in the future".to_string()));
+    }
+    let password_hash = match body.password {
+        Some(password) if password.is_empty() || password.chars().count() > MAX_PASSWORD_LENGTH => {
+            return Err(AppError::BadRequest(format!(
+                "Passwords are 1 to {} characters",
+                MAX_PASSWORD_LENGTH
+            )));
+        }
+        Some(password) => Some(
+            web::block(move || hash_password(password))
+                .await
+                .map_err(|e| AppError::InternalServerError(e.to_string()))??,
+        ),
+        None => None,
+    };
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let pack = managed_pack(&mut conn, user.id, path.into_inner())?;
+    let link: ShareLink = diesel::insert_into(pack_share_links::table)
+        .values((
+            pack_share_links::pack_id.eq(pack.id),
+            pack_share_links::token.eq(hex::encode(rand::thread_rng().gen::<[u8; 32]>())),
+            pack_share_links::password_hash.eq(password_hash),
+            pack_share_links::expires_at.eq(body.expires_at),
+            pack_share_links::created_by.eq(user.id),
+        ))
+        .get_result(&mut conn)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": link.to_json()
+    })))
+}
+
+/// A pack's links that were not revoked, expired ones included.
+#[get("/packs/{id}/links")]
+pub async fn list_links(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let pack = managed_pack(&mut conn, user.id, path.into_inner())?;
+    let links: Vec<ShareLink> = pack_share_links::table
+        .filter(pack_share_links::pack_id.eq(pack.id))
+        .filter(pack_share_links::revoked_at.is_null())
+        .order(pack_share_links::id.desc())
+        .load(&mut conn)?;
+    let links: Vec<serde_json::Value> = links.iter().map(ShareLink::to_json).collect();
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "links": links }
+    })))
+}
+
+/// Revokes a link, which also stops the signed image URLs handed out through it.
+#[post("/packs/{id}/links/{link_id}/revoke")]
+pub async fn revoke_link(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, AppError> {
+    let (pack_id, link_id) = path.into_inner();
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    managed_pack(&mut conn, user.id, pack_id)?;
+    let link: ShareLink = diesel::update(
+        pack_share_links::table
+            .filter(pack_share_links::id.eq(link_id))
+            .filter(pack_share_links::pack_id.eq(pack_id))
+            .filter(pack_share_links::revoked_at.is_null()),
+    )
+    .set(pack_share_links::revoked_at.eq(Utc::now()))
+    .get_result(&mut conn)
+    .optional()?
+    .ok_or_else(|| AppError::NotFound("Link not found".to_string()))?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": link.to_json()
+    })))
+}
+
+/// Revoked and expired links are reported as missing.
+fn active(link: Option<ShareLink>) -> Result<ShareLink, AppError> {
+    link.filter(ShareLink::is_active)
+        .ok_or_else(|| AppError::NotFound("This link does not exist or has expired".to_string()))
+}
+
+/// The public page of a shared pack, no login needed. Images come as signed URLs that work
+/// for `SIGNED_URL_TTL_SECS`, fetch the page again for fresh ones.
+#[get("/public/packs/{token}")]
+pub async fn public_pack(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let token = path.into_inner();
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let link = active(
+        pack_share_links::table
+            .filter(pack_share_links::token.eq(token))
+            .first(&mut conn)
+            .optional()?,
+    )?;
+
+    if let Some(hash) = link.password_hash.clone() {
+        let password = req
+            .headers()
+            .get(PASSWORD_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+            .ok_or_else(|| AppError::Forbidden("This link is password protected".to_string()))?;
+        let valid = web::block(move || verify_password(password, hash))
+            .await
+            .map_err(|e| AppError::InternalServerError(e.to_string()))??;
+        if !valid {
+            return Err(AppError::Forbidden("Wrong password".to_string()));
+        }
+    }
+
+    let pack: IconPack = icon_packs::table.find(link.pack_id).first(&mut conn)?;
+    let pack_icons: Vec<(i32, Option<String>, Vec<String>)> = icons::table
+        .filter(icons::icon_pack_id.eq(pack.id))
+        .filter(icons::candidate_status.is_distinct_from("candidate"))
+        .filter(icons::image_data.ne(Vec::<u8>::new()))
+        .select((icons::id, icons::metadata, icons::tags))
+        .order(icons::id.asc())
+        .load(&mut conn)?;
+    let pack_icons: Vec<serde_json::Value> = pack_icons
+        .into_iter()
+        .map(|(id, metadata, tags)| {
+            serde_json::json!({
+                "id": id,
+                "metadata": metadata,
+                "tags": tags,
+                "image_url": data.url_signer.image_url(link.id, id),
+            })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": {
+            "pack": { "name": pack.name, "palette": pack.palette },
+            "expires_at": link.expires_at,
+            "icons": pack_icons,
+        }
+    })))
+}
+
+#[derive(Deserialize)]
+struct SignedImageQuery {
+    link: i32,
+    expires: i64,
+    signature: String,
+}
+
+/// An icon image through a signed URL from a public pack page.
+#[get("/public/icons/{id}/image")]
+pub async fn public_icon_image(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+    query: web::Query<SignedImageQuery>,
+) -> Result<HttpResponse, AppError> {
+    let icon_id = path.into_inner();
+    if !data.url_signer.verify(query.link, icon_id, query.expires, &query.signature) {
+        return Err(AppError::Forbidden("Invalid or expired signature".to_string()));
+    }
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let link = active(pack_share_links::table.find(query.link).first(&mut conn).optional()?)?;
+    let (image_data, output_format): (Vec<u8>, Option<String>) = icons::table
+        .filter(icons::id.eq(icon_id))
+        .filter(icons::icon_pack_id.eq(link.pack_id))
+        .filter(icons::candidate_status.is_distinct_from("candidate"))
+        .filter(icons::image_data.ne(Vec::<u8>::new()))
+        .select((icons::image_data, icons::output_format))
+        .first(&mut conn)
+        .optional()?
+        .ok_or(AppError::IconNotFound)?;
+
+    let max_age = (query.expires - Utc::now().timestamp()).max(0);
+    Ok(HttpResponse::Ok()
+        .content_type(
+            output_format
+                .as_deref()
+                .and_then(OutputFormat::parse)
+                .unwrap_or(OutputFormat::Jpeg)
+                .content_type(),
+        )
+        .insert_header(("Cache-Control", format!("private, max-age={}", max_age)))
+        .body(image_data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn signer() -> UrlSigner {
+        UrlSigner { secret: b"test".to_vec(), ttl: Duration::seconds(60) }
+    }
+
+    /// `expires` and `signature` of a signed URL.
+    fn params(url: &str) -> (i64, String) {
+        let query = url.split_once('?').unwrap().1;
+        let param = |name: &str| {
+            query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+                .unwrap()
+                .to_string()
+        };
+        (param("expires").parse().unwrap(), param("signature"))
+    }
+
+    #[test]
+    fn icon_signature_only_opens_its_icon() {
+        let signer = signer();
+        let (expires, signature) = params(&signer.icon_image_url(7));
+        assert!(signer.verify_icon(7, expires, &signature));
+        assert!(!signer.verify_icon(8, expires, &signature));
+        assert!(!signer.verify_icon(7, expires + 1, &signature));
+
+        let expired = Utc::now().timestamp() - 1;
+        let signature = hex::encode(signer.icon_mac(7, expired).finalize().into_bytes());
+        assert!(!signer.verify_icon(7, expired, &signature));
+    }
+
+    #[test]
+    fn link_and_icon_signatures_are_not_interchangeable() {
+        let signer = signer();
+        let (expires, signature) = params(&signer.image_url(3, 7));
+        assert!(signer.verify(3, 7, expires, &signature));
+        assert!(!signer.verify_icon(7, expires, &signature));
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn shares_only_with_verified_addresses() {
+        let mut conn = testing::pool(1).get().unwrap();
+        let user_id = testing::user(&mut conn);
+        let email: String = users::table.find(user_id).select(users::email).first(&mut conn).unwrap();
+        assert_eq!(verified_user(&mut conn, &email).unwrap(), None);
+
+        diesel::update(users::table.find(user_id))
+            .set(users::email_verified.eq(true))
+            .execute(&mut conn)
+            .unwrap();
+        assert_eq!(verified_user(&mut conn, &email).unwrap(), Some(user_id));
+        assert_eq!(verified_user(&mut conn, &format!(" {} ", email.to_uppercase())).unwrap(), Some(user_id));
+        assert_eq!(verified_user(&mut conn, "nobody@example.com").unwrap(), None);
+    }
+}
@@ -0,0 +1,244 @@
+use actix_web::{get, post, web, HttpResponse};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    billing::{self, Wallet},
+    events,
+    handlers::{AppError, AuthenticatedUser},
+    imaging,
+    model::AppState,
+    organizations::{self, Role},
+    packs::find_pack,
+    pricing::{self, Operation},
+    schema::icons,
+    versions::{icon_image, store_version, IconVersion, Rendition, SkippedIcon},
+};
+
+/// Icons already this close to the target are left alone by pack normalization.
+const TOLERANCE: f32 = 0.5;
+const MAX_STROKE_WIDTH: f32 = 64.0;
+
+/// Measures an icon's dominant stroke width.
+async fn stroke_width(image_data: Vec<u8>) -> Result<Option<f32>, AppError> {
+    web::block(move || imaging::decode(&image_data).map(|img| imaging::stroke_width(&img)))
+        .await
+        .map_err(|e| AppError::InternalServerError(e.to_string()))?
+}
+
+/// Measures an icon's dominant stroke width and remembers it on the icon. Only for users
+/// who may edit the icon.
+async fn measure(
+    conn: &mut PgConnection,
+    icon_id: i32,
+    image_data: Vec<u8>,
+) -> Result<Option<f32>, AppError> {
+    let width = stroke_width(image_data).await?;
+    diesel::update(icons::table.filter(icons::id.eq(icon_id)))
+        .set(icons::stroke_width.eq(width))
+        .execute(conn)?;
+    Ok(width)
+}
+
+/// Redraws an icon's strokes from `current` to `target` pixels wide.
+async fn normalized(image_data: Vec<u8>, current: f32, target: f32) -> Result<Rendition, AppError> {
+    let png = web::block(move || {
+        let img = imaging::decode(&image_data)?;
+        imaging::encode_png(&imaging::normalize_stroke(&img, current, target))
+    })
+    .await
+    .map_err(|e| AppError::InternalServerError(e.to_string()))??;
+    Rendition::from_png(png)
+}
+
+/// Charges `cost` for a normalized icon and stores it as a new version. Run it in a
+/// transaction so it is both or neither.
+fn store_normalized(
+    conn: &mut PgConnection,
+    wallet: Wallet,
+    icon_id: i32,
+    rendition: Rendition,
+    current: f32,
+    target: f32,
+    cost: i32,
+) -> Result<IconVersion, AppError> {
+    let parameters = serde_json::json!({ "from": current, "target": target });
+    billing::charge(conn, wallet, Operation::Edit.transaction_type(), cost, Some(icon_id))?;
+    store_version(conn, icon_id, "normalize_stroke", &parameters, rendition)
+}
+
+fn validate_target(target: f32) -> Result<(), AppError> {
+    if !(1.0..=MAX_STROKE_WIDTH).contains(&target) {
+        return Err(AppError::BadRequest(format!(
+            "target must be between 1 and {} pixels",
+            MAX_STROKE_WIDTH
+        )));
+    }
+    Ok(())
+}
+
+#[get("/icons/{id}/stroke")]
+pub async fn measure_stroke(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let icon_id = path.into_inner();
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let (image_data, _) = icon_image(&mut conn, user.id, icon_id, Role::Viewer)?;
+    // Viewers get the measurement, only editors store it on the icon.
+    let can_edit: bool = diesel::select(diesel::dsl::exists(
+        icons::table
+            .filter(icons::id.eq(icon_id))
+            .filter(organizations::icon_access(user.id, Role::Editor)),
+    ))
+    .get_result(&mut conn)?;
+    let stroke_width = if can_edit {
+        measure(&mut conn, icon_id, image_data).await?
+    } else {
+        stroke_width(image_data).await?
+    };
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "id": icon_id, "stroke_width": stroke_width }
+    })))
+}
+
+#[derive(Deserialize)]
+struct NormalizeIcon {
+    target: f32,
+}
+
+#[post("/icons/{id}/normalize-stroke")]
+pub async fn normalize_icon_stroke(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<NormalizeIcon>,
+) -> Result<HttpResponse, AppError> {
+    let icon_id = path.into_inner();
+    validate_target(body.target)?;
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let (image_data, organization_id) = icon_image(&mut conn, user.id, icon_id, Role::Editor)?;
+    let current = measure(&mut conn, icon_id, image_data.clone())
+        .await?
+        .ok_or_else(|| AppError::BadRequest("Icon has no ink to normalize".to_string()))?;
+    let rendition = normalized(image_data, current, body.target).await?;
+    let wallet = Wallet::of(user.id, organization_id);
+    let cost = pricing::cost(&mut conn, wallet, Operation::Edit, None)?;
+    let version = conn.transaction(|conn| {
+        store_normalized(conn, wallet, icon_id, rendition, current, body.target, cost)
+    })?;
+    events::balance_changed(&data, user.id);
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "version": version }
+    })))
+}
+
+#[derive(Deserialize)]
+struct NormalizePack {
+    /// Defaults to the median stroke width of the pack.
+    target: Option<f32>,
+}
+
+#[derive(Serialize)]
+struct NormalizedIcon {
+    id: i32,
+    stroke_width: Option<f32>,
+    version: Option<IconVersion>,
+}
+
+/// Brings every finished icon in a pack to one stroke width, each result stored as a new
+/// version. All of them are charged and stored in one transaction, so a wallet that can't
+/// pay for the whole pack pays for none of it. Icons that fail to measure or render are
+/// left out and listed under `skipped`.
+#[post("/packs/{id}/normalize-strokes")]
+pub async fn normalize_pack_strokes(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<NormalizePack>,
+) -> Result<HttpResponse, AppError> {
+    let pack_id = path.into_inner();
+    if let Some(target) = body.target {
+        validate_target(target)?;
+    }
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let pack = find_pack(&mut conn, user.id, pack_id, Role::Editor)?;
+    let wallet = Wallet::of(user.id, pack.organization_id);
+
+    let pack_icons: Vec<(i32, Vec<u8>)> = icons::table
+        .filter(icons::icon_pack_id.eq(pack_id))
+        .filter(icons::candidate_status.is_distinct_from("candidate"))
+        .filter(icons::image_data.ne(Vec::<u8>::new()))
+        .filter(organizations::icon_access(user.id, Role::Editor))
+        .select((icons::id, icons::image_data))
+        .order(icons::id.asc())
+        .load(&mut conn)?;
+
+    let mut measured = Vec::with_capacity(pack_icons.len());
+    let mut skipped = Vec::new();
+    for (icon_id, image_data) in pack_icons {
+        match measure(&mut conn, icon_id, image_data.clone()).await {
+            Ok(width) => measured.push((icon_id, image_data, width)),
+            Err(e) => skipped.push(SkippedIcon { id: icon_id, error: e.to_string() }),
+        }
+    }
+
+    let target = match body.target {
+        Some(target) => target,
+        None => {
+            let mut widths: Vec<f32> = measured.iter().filter_map(|(_, _, w)| *w).collect();
+            if widths.is_empty() {
+                return Err(AppError::BadRequest("Pack has no icons with ink".to_string()));
+            }
+            widths.sort_by(|a, b| a.total_cmp(b));
+            widths[widths.len() / 2]
+        }
+    };
+
+    let mut results = Vec::with_capacity(measured.len());
+    let mut rendered = Vec::new();
+    for (icon_id, image_data, width) in measured {
+        if let Some(current) = width.filter(|current| (current - target).abs() > TOLERANCE) {
+            match normalized(image_data, current, target).await {
+                Ok(rendition) => rendered.push((results.len(), icon_id, rendition, current)),
+                Err(e) => {
+                    skipped.push(SkippedIcon { id: icon_id, error: e.to_string() });
+                    continue;
+                }
+            }
+        }
+        results.push(NormalizedIcon { id: icon_id, stroke_width: width, version: None });
+    }
+
+    let cost = pricing::cost(&mut conn, wallet, Operation::Edit, None)?;
+    let versions: Vec<(usize, IconVersion)> = conn.transaction(|conn| {
+        rendered
+            .into_iter()
+            .map(|(index, icon_id, rendition, current)| {
+                let version = store_normalized(conn, wallet, icon_id, rendition, current, target, cost)?;
+                Ok((index, version))
+            })
+            .collect::<Result<_, AppError>>()
+    })?;
+    if !versions.is_empty() {
+        events::balance_changed(&data, user.id);
+    }
+    for (index, version) in versions {
+        results[index].version = Some(version);
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "target": target, "icons": results, "skipped": skipped }
+    })))
+}
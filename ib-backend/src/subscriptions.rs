@@ -0,0 +1,427 @@
+use std::collections::HashSet;
+use std::env;
+use std::time::Duration;
+
+use actix_web::{get, post, web, HttpResponse};
+use chrono::{DateTime, Months, Utc};
+use diesel::prelude::*;
+use serde::Serialize;
+
+use crate::{
+    billing, events,
+    handlers::{AppError, AuthenticatedUser},
+    model::{AppState, TransactionType},
+    payments, ratelimit,
+    schema::{ledger_transactions, payments as payments_table, plans, subscriptions},
+};
+
+pub const FREE_PLAN: &str = "free";
+
+/// Entitlement required for `.svg` exports.
+pub const EXPORT_SVG: &str = "export_svg";
+
+/// How long a provider may take to confirm a renewal before the subscription is past due.
+const RENEWAL_GRACE_HOURS: i64 = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubscriptionStatus {
+    Trialing,
+    Active,
+    PastDue,
+    Cancelled,
+}
+
+impl SubscriptionStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SubscriptionStatus::Trialing => "trialing",
+            SubscriptionStatus::Active => "active",
+            SubscriptionStatus::PastDue => "past_due",
+            SubscriptionStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// Statuses in which the plan's entitlements apply. A past due subscription keeps them
+/// while the provider retries the payment.
+const LIVE: [&str; 3] = ["trialing", "active", "past_due"];
+
+#[derive(Debug, Queryable, Serialize)]
+#[diesel(table_name = plans)]
+pub struct Plan {
+    pub id: String,
+    pub name: String,
+    pub monthly_inkbucks: i32,
+    pub price_cents: i32,
+    pub entitlements: Vec<String>,
+    pub daily_generations: Option<i32>,
+}
+
+#[derive(Debug, Queryable, Serialize)]
+#[diesel(table_name = subscriptions)]
+pub struct Subscription {
+    pub id: i32,
+    pub user_id: i32,
+    pub plan_id: String,
+    pub status: String,
+    pub provider: String,
+    pub provider_subscription_id: Option<String>,
+    pub current_period_start: DateTime<Utc>,
+    pub current_period_end: DateTime<Utc>,
+    pub trial_end: Option<DateTime<Utc>>,
+    pub cancel_at_period_end: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Changes reported by the payment provider for one of its subscriptions.
+#[derive(Debug)]
+pub struct ProviderUpdate {
+    pub provider_subscription_id: String,
+    pub status: SubscriptionStatus,
+    pub current_period_start: Option<DateTime<Utc>>,
+    pub current_period_end: Option<DateTime<Utc>>,
+    pub trial_end: Option<DateTime<Utc>>,
+    pub cancel_at_period_end: bool,
+}
+
+fn next_month(time: DateTime<Utc>) -> DateTime<Utc> {
+    time.checked_add_months(Months::new(1)).unwrap_or(time)
+}
+
+pub fn live_subscription(conn: &mut PgConnection, user_id: i32) -> Result<Option<Subscription>, AppError> {
+    Ok(subscriptions::table
+        .filter(subscriptions::user_id.eq(user_id))
+        .filter(subscriptions::status.eq_any(LIVE))
+        .first(conn)
+        .optional()?)
+}
+
+/// Starts a paid subscription with the billing period the provider reports for it, or one
+/// beginning now if the provider doesn't manage it. Taking the provider's period keys the
+/// first grant the same way as the provider's later updates, which would otherwise grant the
+/// first month again. A live subscription the user still has is ended, there is only ever one.
+pub fn activate(
+    conn: &mut PgConnection,
+    user_id: i32,
+    plan_id: &str,
+    provider: &str,
+    provider_subscription: Option<&ProviderUpdate>,
+) -> Result<Subscription, AppError> {
+    let now = Utc::now();
+    let period_start = provider_subscription
+        .and_then(|update| update.current_period_start)
+        .unwrap_or(now);
+    let period_end = provider_subscription
+        .and_then(|update| update.current_period_end)
+        .unwrap_or_else(|| next_month(period_start));
+    diesel::update(
+        subscriptions::table
+            .filter(subscriptions::user_id.eq(user_id))
+            .filter(subscriptions::status.eq_any(LIVE)),
+    )
+    .set((
+        subscriptions::status.eq(SubscriptionStatus::Cancelled.as_str()),
+        subscriptions::updated_at.eq(now),
+    ))
+    .execute(conn)?;
+
+    Ok(diesel::insert_into(subscriptions::table)
+        .values((
+            subscriptions::user_id.eq(user_id),
+            subscriptions::plan_id.eq(plan_id),
+            subscriptions::status.eq(SubscriptionStatus::Active.as_str()),
+            subscriptions::provider.eq(provider),
+            subscriptions::provider_subscription_id
+                .eq(provider_subscription.map(|update| &update.provider_subscription_id)),
+            subscriptions::current_period_start.eq(period_start),
+            subscriptions::current_period_end.eq(period_end),
+        ))
+        .get_result(conn)?)
+}
+
+/// Credits the plan's monthly inkbucks for the subscription's current period, once. The
+/// period start is part of the ledger reference, which is unique, so running this again
+/// for the same period does nothing.
+pub fn grant_current_period(conn: &mut PgConnection, subscription: &Subscription) -> Result<Option<i32>, AppError> {
+    if subscription.status != SubscriptionStatus::Active.as_str() {
+        return Ok(None);
+    }
+    let monthly: i32 = plans::table
+        .filter(plans::id.eq(&subscription.plan_id))
+        .select(plans::monthly_inkbucks)
+        .first(conn)?;
+    if monthly <= 0 {
+        return Ok(None);
+    }
+
+    let reference = format!(
+        "subscription:{}:{}",
+        subscription.id,
+        subscription.current_period_start.timestamp()
+    );
+    let granted: bool = diesel::select(diesel::dsl::exists(
+        ledger_transactions::table.filter(ledger_transactions::reference.eq(&reference)),
+    ))
+    .get_result(conn)?;
+    if granted {
+        return Ok(None);
+    }
+    billing::credit(conn, subscription.user_id, TransactionType::Grant, monthly, None, Some(&reference))
+        .map(Some)
+}
+
+/// Applies a provider's view of a subscription and grants the period it reports if that
+/// has not happened yet.
+pub fn apply_provider_update(conn: &mut PgConnection, update: &ProviderUpdate) -> Result<Option<Subscription>, AppError> {
+    conn.transaction(|conn| {
+        let current: Option<Subscription> = subscriptions::table
+            .filter(subscriptions::provider_subscription_id.eq(&update.provider_subscription_id))
+            .first(conn)
+            .optional()?;
+        let Some(current) = current else {
+            // Subscriptions are created from the checkout, anything else is not ours.
+            return Ok(None);
+        };
+
+        let subscription: Subscription = diesel::update(subscriptions::table.filter(subscriptions::id.eq(current.id)))
+            .set((
+                subscriptions::status.eq(update.status.as_str()),
+                subscriptions::current_period_start.eq(update.current_period_start.unwrap_or(current.current_period_start)),
+                subscriptions::current_period_end.eq(update.current_period_end.unwrap_or(current.current_period_end)),
+                subscriptions::trial_end.eq(update.trial_end.or(current.trial_end)),
+                subscriptions::cancel_at_period_end.eq(update.cancel_at_period_end),
+                subscriptions::updated_at.eq(Utc::now()),
+            ))
+            .get_result(conn)?;
+        grant_current_period(conn, &subscription)?;
+        Ok(Some(subscription))
+    })
+}
+
+/// The plan the user is on right now, `free` without a live subscription.
+pub fn current_plan(conn: &mut PgConnection, user_id: i32) -> Result<String, AppError> {
+    Ok(live_subscription(conn, user_id)?
+        .map(|subscription| subscription.plan_id)
+        .unwrap_or_else(|| FREE_PLAN.to_string()))
+}
+
+/// Feature flags of the user's plan plus those bought with one-time bundles.
+pub fn entitlements(conn: &mut PgConnection, user_id: i32) -> Result<Vec<String>, AppError> {
+    let plan_id = current_plan(conn, user_id)?;
+    let mut entitlements: Vec<String> = plans::table
+        .filter(plans::id.eq(&plan_id))
+        .select(plans::entitlements)
+        .first(conn)?;
+
+    let bought: Vec<String> = payments_table::table
+        .filter(payments_table::user_id.eq(user_id))
+        .filter(payments_table::status.eq("paid"))
+        .select(payments_table::product)
+        .distinct()
+        .load(conn)?;
+    for product in bought.iter().filter_map(|id| payments::find_product(id)) {
+        for entitlement in product.entitlements {
+            if !entitlements.iter().any(|e| e == entitlement) {
+                entitlements.push(entitlement.to_string());
+            }
+        }
+    }
+    Ok(entitlements)
+}
+
+/// Gate for plan features, e.g. `require_entitlement(conn, user.id, EXPORT_SVG)?`.
+pub fn require_entitlement(conn: &mut PgConnection, user_id: i32, entitlement: &str) -> Result<(), AppError> {
+    if entitlements(conn, user_id)?.iter().any(|e| e == entitlement) {
+        Ok(())
+    } else {
+        Err(AppError::Forbidden(format!("Your plan does not include {}", entitlement)))
+    }
+}
+
+/// One pass over all subscriptions: ends those cancelled at period end, renews fake
+/// provider subscriptions on the local clock (there is no provider to tell us), marks
+/// unrenewed ones past due and grants every active one its current period. Returns the
+/// users whose balance changed.
+fn run_due(conn: &mut PgConnection) -> Result<HashSet<i32>, AppError> {
+    let now = Utc::now();
+
+    diesel::update(
+        subscriptions::table
+            .filter(subscriptions::status.eq_any(LIVE))
+            .filter(subscriptions::cancel_at_period_end.eq(true))
+            .filter(subscriptions::current_period_end.le(now)),
+    )
+    .set((
+        subscriptions::status.eq(SubscriptionStatus::Cancelled.as_str()),
+        subscriptions::updated_at.eq(now),
+    ))
+    .execute(conn)?;
+
+    let expired: Vec<Subscription> = subscriptions::table
+        .filter(subscriptions::status.eq_any([SubscriptionStatus::Trialing.as_str(), SubscriptionStatus::Active.as_str()]))
+        .filter(subscriptions::current_period_end.le(now))
+        .load(conn)?;
+    for subscription in expired {
+        let target = subscriptions::table.filter(subscriptions::id.eq(subscription.id));
+        if subscription.provider == "fake" {
+            let mut start = subscription.current_period_end;
+            while next_month(start) <= now {
+                start = next_month(start);
+            }
+            diesel::update(target)
+                .set((
+                    subscriptions::status.eq(SubscriptionStatus::Active.as_str()),
+                    subscriptions::current_period_start.eq(start),
+                    subscriptions::current_period_end.eq(next_month(start)),
+                    subscriptions::updated_at.eq(now),
+                ))
+                .execute(conn)?;
+        } else if subscription.current_period_end + chrono::Duration::hours(RENEWAL_GRACE_HOURS) <= now {
+            diesel::update(target)
+                .set((
+                    subscriptions::status.eq(SubscriptionStatus::PastDue.as_str()),
+                    subscriptions::updated_at.eq(now),
+                ))
+                .execute(conn)?;
+        }
+    }
+
+    let active: Vec<Subscription> = subscriptions::table
+        .filter(subscriptions::status.eq(SubscriptionStatus::Active.as_str()))
+        .load(conn)?;
+    let mut credited = HashSet::new();
+    for subscription in active {
+        if conn.transaction(|conn| grant_current_period(conn, &subscription))?.is_some() {
+            credited.insert(subscription.user_id);
+        }
+    }
+    Ok(credited)
+}
+
+/// Runs the subscription pass every `SUBSCRIPTION_SCHEDULER_SECS` (5 minutes by default).
+/// Grants are idempotent, so overlapping or repeated runs are harmless.
+pub async fn run_scheduler(state: AppState) {
+    let interval = env::var("SUBSCRIPTION_SCHEDULER_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(300);
+    let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+    loop {
+        ticker.tick().await;
+        let pool = state.db_pool.clone();
+        let result = web::block(move || {
+            let mut conn = pool.get().map_err(|e| AppError::DbConnection(e.to_string()))?;
+            run_due(&mut conn)
+        })
+        .await;
+        match result {
+            Ok(Ok(credited)) => {
+                for user_id in credited {
+                    events::balance_changed(&state, user_id);
+                }
+            }
+            Ok(Err(e)) => println!("Subscription scheduler failed: {}", e),
+            Err(e) => println!("Subscription scheduler failed: {}", e),
+        }
+    }
+}
+
+#[get("/plans")]
+pub async fn list_plans(data: web::Data<AppState>) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let plans: Vec<Plan> = plans::table.order(plans::price_cents.asc()).load(&mut conn)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "plans": plans }
+    })))
+}
+
+#[get("/me/subscription")]
+pub async fn get_subscription(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let subscription = live_subscription(&mut conn, user.id)?;
+    let plan: Plan = plans::table
+        .filter(plans::id.eq(subscription.as_ref().map_or(FREE_PLAN, |s| s.plan_id.as_str())))
+        .first(&mut conn)?;
+    let entitlements = entitlements(&mut conn, user.id)?;
+    let generations_today = ratelimit::generations_today(&mut conn, user.id)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": {
+            "subscription": subscription,
+            "plan": plan,
+            "entitlements": entitlements,
+            "generations_today": generations_today,
+        }
+    })))
+}
+
+/// Cancels at the end of the paid period, the plan stays in effect until then.
+#[post("/me/subscription/cancel")]
+pub async fn cancel_subscription(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let subscription = live_subscription(&mut conn, user.id)?
+        .ok_or_else(|| AppError::NotFound("No active subscription".to_string()))?;
+    if let Some(provider_id) = &subscription.provider_subscription_id {
+        data.payments.cancel_subscription(provider_id).await?;
+    }
+
+    let subscription: Subscription = diesel::update(subscriptions::table.filter(subscriptions::id.eq(subscription.id)))
+        .set((
+            subscriptions::cancel_at_period_end.eq(true),
+            subscriptions::updated_at.eq(Utc::now()),
+        ))
+        .get_result(&mut conn)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "subscription": subscription }
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ledger, testing};
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn provider_updates_grant_each_period_once() {
+        let mut conn = testing::pool(1).get().unwrap();
+        let user_id = testing::user(&mut conn);
+        let update = |start: DateTime<Utc>| ProviderUpdate {
+            provider_subscription_id: format!("sub_test_{}", user_id),
+            status: SubscriptionStatus::Active,
+            current_period_start: Some(start),
+            current_period_end: Some(next_month(start)),
+            trial_end: None,
+            cancel_at_period_end: false,
+        };
+        // The provider starts the subscription a moment before the checkout completes.
+        let start = DateTime::from_timestamp(Utc::now().timestamp() - 5, 0).unwrap();
+
+        conn.transaction(|conn| {
+            let subscription = activate(conn, user_id, "pro", "stripe", Some(&update(start)))?;
+            grant_current_period(conn, &subscription)
+        })
+        .unwrap();
+        apply_provider_update(&mut conn, &update(start)).unwrap();
+        assert_eq!(ledger::balance(&mut conn, user_id).unwrap(), 200);
+
+        apply_provider_update(&mut conn, &update(next_month(start))).unwrap();
+        apply_provider_update(&mut conn, &update(next_month(start))).unwrap();
+        assert_eq!(ledger::balance(&mut conn, user_id).unwrap(), 400);
+    }
+}
@@ -0,0 +1,26 @@
+//! Helpers for the tests that run against the database at `DATABASE_URL`, which are
+//! `#[ignore]`d so a plain `cargo test` needs no database.
+
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool};
+
+use crate::schema::users;
+
+pub fn pool(size: u32) -> Pool<ConnectionManager<PgConnection>> {
+    let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    Pool::builder().max_size(size).build(ConnectionManager::new(url)).unwrap()
+}
+
+/// A new user with a unique uid, without a ledger account yet.
+pub fn user(conn: &mut PgConnection) -> i32 {
+    let uid = format!("test-{:016x}", rand::random::<u64>());
+    diesel::insert_into(users::table)
+        .values((
+            users::email.eq(format!("{}@example.com", uid)),
+            users::username.eq(&uid),
+            users::uid.eq(&uid),
+        ))
+        .returning(users::id)
+        .get_result(conn)
+        .unwrap()
+}
@@ -0,0 +1,444 @@
+use actix_web::{
+    get,
+    http::header::{CacheControl, CacheDirective},
+    http::Method,
+    post, web, HttpResponse,
+};
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    handlers::{AppError, AuthenticatedUser},
+    model::AppState,
+    schema::api_tokens,
+};
+
+/// Every API token starts with it, which is how they are told apart from Firebase ID tokens.
+pub const PREFIX: &str = "ib_";
+const MAX_NAME_LENGTH: usize = 100;
+const MAX_ACTIVE_TOKENS: i64 = 50;
+
+/// What an API token may be used for. Anything no scope covers, like managing tokens,
+/// workspaces or sharing, needs a signed-in session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// Searches, lists and downloads icons and packs.
+    IconsRead,
+    /// Generates and edits icons and packs, paid from the token owner's wallet.
+    IconsWrite,
+    /// Exports packs as archives.
+    PacksExport,
+    /// Reads balance history, subscription and prices.
+    BillingRead,
+}
+
+impl Scope {
+    pub fn parse(s: &str) -> Option<Scope> {
+        match s {
+            "icons:read" => Some(Scope::IconsRead),
+            "icons:write" => Some(Scope::IconsWrite),
+            "packs:export" => Some(Scope::PacksExport),
+            "billing:read" => Some(Scope::BillingRead),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::IconsRead => "icons:read",
+            Scope::IconsWrite => "icons:write",
+            Scope::PacksExport => "packs:export",
+            Scope::BillingRead => "billing:read",
+        }
+    }
+
+    /// The scope a route needs, `None` for routes API tokens cannot use at all.
+    pub fn required(method: &Method, path: &str) -> Option<Scope> {
+        let path = path.strip_prefix("/api").unwrap_or(path);
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        if *method == Method::GET || *method == Method::HEAD {
+            return match segments.as_slice() {
+                ["icons", ..] | ["packs"] | ["batches", _] => Some(Scope::IconsRead),
+                ["me", "transactions" | "transactions.csv" | "subscription"]
+                | ["organizations", _, "transactions"]
+                | ["pricing"] => Some(Scope::BillingRead),
+                _ => None,
+            };
+        }
+        if *method == Method::POST || *method == Method::PUT {
+            return match segments.as_slice() {
+                ["packs", _, "export"] => Some(Scope::PacksExport),
+                ["icons", ..]
+                | ["packs"]
+                | ["packs", _, "batch" | "recolor" | "normalize-strokes" | "palette" | "style"] => {
+                    Some(Scope::IconsWrite)
+                }
+                _ => None,
+            };
+        }
+        None
+    }
+}
+
+#[derive(Debug, Queryable, Serialize)]
+#[diesel(table_name = api_tokens)]
+pub struct ApiToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    /// The start of the secret, enough to recognise a token without storing it.
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// Everything but the hash, which never leaves the database.
+const COLUMNS: (
+    api_tokens::id,
+    api_tokens::user_id,
+    api_tokens::name,
+    api_tokens::prefix,
+    api_tokens::scopes,
+    api_tokens::created_at,
+    api_tokens::expires_at,
+    api_tokens::last_used_at,
+    api_tokens::revoked_at,
+) = (
+    api_tokens::id,
+    api_tokens::user_id,
+    api_tokens::name,
+    api_tokens::prefix,
+    api_tokens::scopes,
+    api_tokens::created_at,
+    api_tokens::expires_at,
+    api_tokens::last_used_at,
+    api_tokens::revoked_at,
+);
+
+impl ApiToken {
+    fn allows(&self, scope: Scope) -> bool {
+        self.scopes.iter().any(|s| s == scope.as_str())
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// The user behind an API token, as long as the token is active and carries the scope the
+/// route needs. `last_used_at` is only written once a minute so busy pipelines don't turn
+/// every request into a write.
+pub fn authenticate(
+    conn: &mut PgConnection,
+    token: &str,
+    required: Option<Scope>,
+) -> Result<i32, actix_web::Error> {
+    let now = Utc::now();
+    let token: ApiToken = api_tokens::table
+        .filter(api_tokens::token_hash.eq(hash_token(token)))
+        .filter(api_tokens::revoked_at.is_null())
+        .filter(api_tokens::expires_at.is_null().or(api_tokens::expires_at.gt(now)))
+        .select(COLUMNS)
+        .first(conn)
+        .optional()
+        .map_err(AppError::from)?
+        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Invalid or expired API token"))?;
+
+    match required {
+        Some(scope) if token.allows(scope) => {}
+        Some(scope) => {
+            return Err(AppError::Forbidden(format!("This API token lacks the {} scope", scope.as_str())).into());
+        }
+        None => return Err(AppError::Forbidden("API tokens cannot be used for this request".to_string()).into()),
+    }
+
+    diesel::update(
+        api_tokens::table
+            .filter(api_tokens::id.eq(token.id))
+            .filter(api_tokens::last_used_at.is_null().or(api_tokens::last_used_at.lt(now - Duration::minutes(1)))),
+    )
+    .set(api_tokens::last_used_at.eq(now))
+    .execute(conn)
+    .map_err(AppError::from)?;
+
+    Ok(token.user_id)
+}
+
+#[derive(Deserialize)]
+struct CreateToken {
+    name: String,
+    scopes: Vec<String>,
+    /// Never expires when omitted.
+    expires_at: Option<DateTime<Utc>>,
+}
+
+/// Creates a token. Its secret is only ever shown in this response.
+#[post("/me/tokens")]
+pub async fn create_token(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    body: web::Json<CreateToken>,
+) -> Result<HttpResponse, AppError> {
+    let body = body.into_inner();
+    let name = body.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(AppError::BadRequest(format!("Names are 1 to {} characters", MAX_NAME_LENGTH)));
+    }
+    let mut scopes = Vec::new();
+    for scope in &body.scopes {
+        let scope = Scope::parse(scope)
+            .ok_or_else(|| AppError::BadRequest(format!("Unknown scope {}", scope)))?;
+        if !scopes.contains(&scope.as_str()) {
+            scopes.push(scope.as_str());
+        }
+    }
+    if scopes.is_empty() {
+        return Err(AppError::BadRequest("A token needs at least one scope".to_string()));
+    }
+    if body.expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+        return Err(AppError::BadRequest("expires_at must be in the future".to_string()));
+    }
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let active: i64 = api_tokens::table
+        .filter(api_tokens::user_id.eq(user.id))
+        .filter(api_tokens::revoked_at.is_null())
+        .count()
+        .get_result(&mut conn)?;
+    if active >= MAX_ACTIVE_TOKENS {
+        return Err(AppError::Conflict(format!(
+            "At most {} tokens at a time, revoke one first",
+            MAX_ACTIVE_TOKENS
+        )));
+    }
+
+    let secret = format!("{}{}", PREFIX, hex::encode(rand::thread_rng().gen::<[u8; 32]>()));
+    let token: ApiToken = diesel::insert_into(api_tokens::table)
+        .values((
+            api_tokens::user_id.eq(user.id),
+            api_tokens::name.eq(name),
+            api_tokens::token_hash.eq(hash_token(&secret)),
+            api_tokens::prefix.eq(&secret[..PREFIX.len() + 8]),
+            api_tokens::scopes.eq(&scopes),
+            api_tokens::expires_at.eq(body.expires_at),
+        ))
+        .returning(COLUMNS)
+        .get_result(&mut conn)?;
+
+    // Only the hash is kept, the secret must not end up in caches or idempotency snapshots either.
+    Ok(HttpResponse::Ok()
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .json(serde_json::json!({
+            "status": "success",
+            "data": { "token": token, "secret": secret }
+        })))
+}
+
+/// The user's tokens that were not revoked, expired ones included.
+#[get("/me/tokens")]
+pub async fn list_tokens(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let tokens: Vec<ApiToken> = api_tokens::table
+        .filter(api_tokens::user_id.eq(user.id))
+        .filter(api_tokens::revoked_at.is_null())
+        .order(api_tokens::id.desc())
+        .select(COLUMNS)
+        .load(&mut conn)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "tokens": tokens }
+    })))
+}
+
+#[post("/me/tokens/{id}/revoke")]
+pub async fn revoke_token(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let token: ApiToken = diesel::update(
+        api_tokens::table
+            .filter(api_tokens::id.eq(path.into_inner()))
+            .filter(api_tokens::user_id.eq(user.id))
+            .filter(api_tokens::revoked_at.is_null()),
+    )
+    .set(api_tokens::revoked_at.eq(Utc::now()))
+    .returning(COLUMNS)
+    .get_result(&mut conn)
+    .optional()?
+    .ok_or_else(|| AppError::NotFound("Token not found".to_string()))?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "token": token }
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+
+    use super::*;
+    use crate::testing;
+
+    fn required(method: Method, path: &str) -> Option<Scope> {
+        Scope::required(&method, path)
+    }
+
+    #[test]
+    fn reads_need_read_scopes() {
+        for path in ["/api/icons/search", "/api/icons/5/image", "/api/icons/5/versions/2/image", "/api/packs", "/api/batches/3"] {
+            assert_eq!(required(Method::GET, path), Some(Scope::IconsRead), "{}", path);
+        }
+        assert_eq!(required(Method::HEAD, "/api/icons/5/image"), Some(Scope::IconsRead));
+        for path in [
+            "/api/me/transactions",
+            "/api/me/transactions.csv",
+            "/api/me/subscription",
+            "/api/organizations/2/transactions",
+            "/api/pricing",
+        ] {
+            assert_eq!(required(Method::GET, path), Some(Scope::BillingRead), "{}", path);
+        }
+    }
+
+    #[test]
+    fn writes_need_the_write_scope() {
+        for path in ["/api/icons", "/api/icons/5/recolor", "/api/icons/5/variations", "/api/packs", "/api/packs/3/batch"] {
+            assert_eq!(required(Method::POST, path), Some(Scope::IconsWrite), "{}", path);
+        }
+        for path in ["/api/icons/5/tags", "/api/packs/3/palette", "/api/packs/3/style"] {
+            assert_eq!(required(Method::PUT, path), Some(Scope::IconsWrite), "{}", path);
+        }
+        assert_eq!(required(Method::POST, "/api/packs/3/recolor"), Some(Scope::IconsWrite));
+        assert_eq!(required(Method::POST, "/api/packs/3/normalize-strokes"), Some(Scope::IconsWrite));
+    }
+
+    #[test]
+    fn exports_need_the_export_scope() {
+        assert_eq!(required(Method::POST, "/api/packs/3/export"), Some(Scope::PacksExport));
+        assert_eq!(required(Method::POST, "/packs/3/export/"), Some(Scope::PacksExport));
+        assert_eq!(required(Method::GET, "/api/packs/3/export"), None);
+    }
+
+    #[test]
+    fn management_routes_are_closed_to_tokens() {
+        for (method, path) in [
+            (Method::GET, "/api/me/tokens"),
+            (Method::POST, "/api/me/tokens"),
+            (Method::POST, "/api/me/tokens/1/revoke"),
+            (Method::GET, "/api/packs/3/collaborators"),
+            (Method::POST, "/api/packs/3/collaborators"),
+            (Method::POST, "/api/packs/3/links"),
+            (Method::POST, "/api/packs/3/publish"),
+            (Method::GET, "/api/organizations"),
+            (Method::POST, "/api/organizations"),
+            (Method::PUT, "/api/organizations/1/members/2"),
+            (Method::POST, "/api/organizations/1/wallet/deposit"),
+            (Method::POST, "/api/me/subscription/cancel"),
+            (Method::POST, "/api/me/referral"),
+            (Method::POST, "/api/payments/checkout"),
+            (Method::DELETE, "/api/icons/5"),
+            (Method::PATCH, "/api/icons/5"),
+        ] {
+            assert_eq!(required(method.clone(), path), None, "{} {}", method, path);
+        }
+    }
+
+    #[test]
+    fn scopes_round_trip() {
+        for scope in [Scope::IconsRead, Scope::IconsWrite, Scope::PacksExport, Scope::BillingRead] {
+            assert_eq!(Scope::parse(scope.as_str()), Some(scope));
+        }
+        assert_eq!(Scope::parse("icons:*"), None);
+    }
+
+    /// A token of `user_id` with `scopes`, returns its secret.
+    fn token(
+        conn: &mut PgConnection,
+        user_id: i32,
+        scopes: &[&str],
+        expires_at: Option<DateTime<Utc>>,
+        revoked_at: Option<DateTime<Utc>>,
+    ) -> String {
+        let secret = format!("{}test_{:016x}", PREFIX, rand::random::<u64>());
+        diesel::insert_into(api_tokens::table)
+            .values((
+                api_tokens::user_id.eq(user_id),
+                api_tokens::name.eq("test"),
+                api_tokens::token_hash.eq(hash_token(&secret)),
+                api_tokens::prefix.eq(&secret[..PREFIX.len() + 8]),
+                api_tokens::scopes.eq(scopes),
+                api_tokens::expires_at.eq(expires_at),
+                api_tokens::revoked_at.eq(revoked_at),
+            ))
+            .execute(conn)
+            .unwrap();
+        secret
+    }
+
+    fn status(result: Result<i32, actix_web::Error>) -> StatusCode {
+        match result {
+            Ok(_) => StatusCode::OK,
+            Err(e) => e.as_response_error().status_code(),
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn authenticates_active_tokens_with_the_scope() {
+        let mut conn = testing::pool(1).get().unwrap();
+        let user_id = testing::user(&mut conn);
+        let later = Utc::now() + Duration::hours(1);
+        let secret = token(&mut conn, user_id, &["icons:read", "packs:export"], Some(later), None);
+
+        assert_eq!(authenticate(&mut conn, &secret, Some(Scope::IconsRead)).unwrap(), user_id);
+        assert_eq!(authenticate(&mut conn, &secret, Some(Scope::PacksExport)).unwrap(), user_id);
+        let last_used_at: Option<DateTime<Utc>> = api_tokens::table
+            .filter(api_tokens::token_hash.eq(hash_token(&secret)))
+            .select(api_tokens::last_used_at)
+            .first(&mut conn)
+            .unwrap();
+        assert!(last_used_at.is_some());
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn rejects_revoked_expired_and_under_scoped_tokens() {
+        let mut conn = testing::pool(1).get().unwrap();
+        let user_id = testing::user(&mut conn);
+        let earlier = Utc::now() - Duration::minutes(1);
+
+        let revoked = token(&mut conn, user_id, &["icons:read"], None, Some(earlier));
+        assert_eq!(status(authenticate(&mut conn, &revoked, Some(Scope::IconsRead))), StatusCode::UNAUTHORIZED);
+        let expired = token(&mut conn, user_id, &["icons:read"], Some(earlier), None);
+        assert_eq!(status(authenticate(&mut conn, &expired, Some(Scope::IconsRead))), StatusCode::UNAUTHORIZED);
+        let unknown = format!("{}unknown", PREFIX);
+        assert_eq!(status(authenticate(&mut conn, &unknown, Some(Scope::IconsRead))), StatusCode::UNAUTHORIZED);
+
+        let reader = token(&mut conn, user_id, &["icons:read"], None, None);
+        assert_eq!(status(authenticate(&mut conn, &reader, Some(Scope::IconsWrite))), StatusCode::FORBIDDEN);
+        assert_eq!(status(authenticate(&mut conn, &reader, Some(Scope::BillingRead))), StatusCode::FORBIDDEN);
+        assert_eq!(status(authenticate(&mut conn, &reader, None)), StatusCode::FORBIDDEN);
+        let last_used_at: Option<DateTime<Utc>> = api_tokens::table
+            .filter(api_tokens::token_hash.eq(hash_token(&reader)))
+            .select(api_tokens::last_used_at)
+            .first(&mut conn)
+            .unwrap();
+        assert_eq!(last_used_at, None);
+    }
+}
@@ -0,0 +1,283 @@
+use std::env;
+
+use actix_web::{get, post, web, HttpResponse};
+use diesel::prelude::*;
+use serde::Deserialize;
+
+use crate::{
+    billing::{self, Wallet},
+    events,
+    handlers::{AppError, AuthenticatedUser, FilteredIcon},
+    jobs,
+    model::{AppState, Icon},
+    organizations::{self, Role},
+    pricing::{Operation, Pricing},
+    provider::{GenerationParams, Model, OutputFormat, MAX_SEED},
+    ratelimit,
+    schema::icons,
+};
+
+const DEFAULT_COUNT: u32 = 4;
+const MAX_COUNT: u32 = 8;
+const DEFAULT_STRENGTH: f32 = 0.35;
+
+/// What happens to the inkbucks spent on a candidate the user throws away.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DiscardPolicy {
+    /// Every generated candidate is paid for.
+    Charge,
+    /// Only kept candidates are paid for, discarding one refunds it.
+    Refund,
+}
+
+fn discard_policy() -> DiscardPolicy {
+    match env::var("VARIATION_DISCARD_POLICY").as_deref() {
+        Ok("refund") => DiscardPolicy::Refund,
+        _ => DiscardPolicy::Charge,
+    }
+}
+
+#[derive(Deserialize)]
+struct VariationsQuery {
+    count: Option<u32>,
+    strength: Option<f32>,
+}
+
+fn find_icon(conn: &mut PgConnection, user_id: i32, icon_id: i32, role: Role) -> Result<Icon, AppError> {
+    icons::table
+        .filter(icons::id.eq(icon_id))
+        .filter(organizations::icon_access(user_id, role))
+        .first(conn)
+        .optional()?
+        .ok_or(AppError::IconNotFound)
+}
+
+fn find_candidate(conn: &mut PgConnection, user_id: i32, icon_id: i32) -> Result<Icon, AppError> {
+    let icon = find_icon(conn, user_id, icon_id, Role::Editor)?;
+    if icon.candidate_status.as_deref() != Some("candidate") {
+        return Err(AppError::BadRequest("Icon is not an open variation candidate".to_string()));
+    }
+    Ok(icon)
+}
+
+/// Parameters of the `n`th candidate. Seeds step away from the source seed so the same
+/// request always yields the same candidates, the init image keeps them close to the source.
+fn candidate_params(
+    source: &Icon,
+    base_seed: u32,
+    n: u32,
+    strength: f32,
+    image_to_image: bool,
+) -> Result<GenerationParams, AppError> {
+    let model = source.model.as_deref().and_then(Model::parse).unwrap_or(Model::Sd3);
+    Ok(GenerationParams {
+        prompt: source.prompt.clone()
+            .ok_or_else(|| AppError::BadRequest("Icon has no prompt to vary".to_string()))?,
+        negative_prompt: source.negative_prompt.clone(),
+        seed: Some(((base_seed as u64 + n as u64) % (MAX_SEED as u64 + 1)) as u32),
+        aspect_ratio: source.aspect_ratio.clone().unwrap_or_else(|| "1:1".to_string()),
+        model,
+        output_format: source.output_format.as_deref()
+            .and_then(OutputFormat::parse)
+            .unwrap_or(OutputFormat::Jpeg),
+        extra: serde_json::Map::new(),
+        init_image: image_to_image.then(|| source.image_data.clone()),
+        strength: image_to_image.then_some(strength),
+    })
+}
+
+/// Generates `count` alternatives of an icon as candidates linked to it. Each candidate is
+/// charged like a generation, candidates that fail to generate are refunded and dropped.
+#[post("/icons/{id}/variations")]
+pub async fn create_variations(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    query: web::Query<VariationsQuery>,
+) -> Result<HttpResponse, AppError> {
+    let count = query.count.unwrap_or(DEFAULT_COUNT);
+    if count == 0 || count > MAX_COUNT {
+        return Err(AppError::BadRequest(format!("count must be between 1 and {}", MAX_COUNT)));
+    }
+    let strength = query.strength.unwrap_or(DEFAULT_STRENGTH);
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let source = find_icon(&mut conn, user.id, path.into_inner(), Role::Editor)?;
+    if source.image_data.is_empty() {
+        return Err(AppError::BadRequest("Icon has no image yet".to_string()));
+    }
+
+    // Models without image-to-image still vary through the seed alone.
+    let model = source.model.as_deref().and_then(Model::parse).unwrap_or(Model::Sd3);
+    let image_to_image = data.image_provider
+        .capabilities(model)
+        .is_some_and(|capabilities| capabilities.image_to_image);
+    let base_seed = source.seed.map(|seed| seed as u32).unwrap_or(0);
+
+    let mut planned = Vec::with_capacity(count as usize);
+    for n in 1..=count {
+        let params = candidate_params(&source, base_seed, n, strength, image_to_image)?;
+        data.image_provider.validate(&params)?;
+        planned.push(params);
+    }
+
+    let wallet = Wallet::of(user.id, source.organization_id);
+    let pricing = Pricing::for_wallet(&mut conn, wallet)?;
+    let queued: Vec<(i32, GenerationParams)> = conn.transaction(|conn| {
+        ratelimit::reserve_generations(conn, user.id, planned.len() as i64)?;
+        let mut queued = Vec::with_capacity(planned.len());
+        for params in planned {
+            let icon_id: i32 = diesel::insert_into(icons::table)
+                .values((
+                    icons::user_id.eq(user.id),
+                    icons::icon_pack_id.eq(source.icon_pack_id),
+                    icons::metadata.eq(&source.metadata),
+                    icons::image_data.eq(Vec::<u8>::new()),
+                    icons::prompt.eq(&params.prompt),
+                    icons::tags.eq(&source.tags),
+                    icons::prompt_template_id.eq(source.prompt_template_id),
+                    icons::seed.eq(params.seed.map(i64::from)),
+                    icons::aspect_ratio.eq(&params.aspect_ratio),
+                    icons::negative_prompt.eq(&params.negative_prompt),
+                    icons::model.eq(params.model.as_str()),
+                    icons::output_format.eq(params.output_format.as_str()),
+                    icons::source_icon_id.eq(source.id),
+                    icons::candidate_status.eq("candidate"),
+                    icons::organization_id.eq(source.organization_id),
+                ))
+                .returning(icons::id)
+                .get_result(conn)?;
+            let cost = pricing.cost(Operation::Variations, Some(params.model))?;
+            billing::charge(conn, wallet, Operation::Variations.transaction_type(), cost, Some(icon_id))?;
+            queued.push((icon_id, params));
+        }
+        Ok::<_, AppError>(queued)
+    })?;
+    events::balance_changed(&data, user.id);
+
+    drop(conn);
+    let results = futures::future::join_all(queued.iter().map(|(icon_id, params)| {
+        jobs::generate_icon(&data, user.id, *icon_id, params)
+    }))
+    .await;
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+
+    let mut candidate_ids = Vec::new();
+    let mut failed = 0;
+    for ((icon_id, _), result) in queued.iter().zip(results) {
+        match result {
+            Ok(()) => candidate_ids.push(*icon_id),
+            Err(e) => {
+                println!("Variation {} of icon {} failed: {}", icon_id, source.id, e);
+                failed += 1;
+                conn.transaction(|conn| {
+                    let charged = billing::charged_for_generation(conn, *icon_id)?;
+                    billing::refund(conn, wallet, charged, Some(*icon_id))?;
+                    diesel::delete(icons::table.filter(icons::id.eq(icon_id))).execute(conn)?;
+                    Ok::<_, AppError>(())
+                })?;
+                data.phash_index.remove(*icon_id);
+            }
+        }
+    }
+
+    if failed > 0 {
+        events::balance_changed(&data, user.id);
+    }
+
+    let candidates: Vec<FilteredIcon> = icons::table
+        .filter(icons::id.eq_any(&candidate_ids))
+        .order(icons::id.asc())
+        .load::<Icon>(&mut conn)?
+        .into_iter()
+        .map(FilteredIcon::from)
+        .collect();
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "candidates": candidates, "failed": failed }
+    })))
+}
+
+#[get("/icons/{id}/variations")]
+pub async fn list_variations(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let source = find_icon(&mut conn, user.id, path.into_inner(), Role::Viewer)?;
+
+    let variations: Vec<FilteredIcon> = icons::table
+        .filter(icons::source_icon_id.eq(source.id))
+        .filter(organizations::icon_access(user.id, Role::Viewer))
+        .order(icons::id.asc())
+        .load::<Icon>(&mut conn)?
+        .into_iter()
+        .map(FilteredIcon::from)
+        .collect();
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "variations": variations }
+    })))
+}
+
+#[post("/icons/{id}/keep")]
+pub async fn keep_candidate(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let candidate = find_candidate(&mut conn, user.id, path.into_inner())?;
+
+    let icon: Icon = diesel::update(icons::table.filter(icons::id.eq(candidate.id)))
+        .set(icons::candidate_status.eq("kept"))
+        .get_result(&mut conn)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "icon": FilteredIcon::from(icon) }
+    })))
+}
+
+/// Deletes a candidate, refunding it when `VARIATION_DISCARD_POLICY=refund`.
+#[post("/icons/{id}/discard")]
+pub async fn discard_candidate(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let candidate = find_candidate(&mut conn, user.id, path.into_inner())?;
+    let policy = discard_policy();
+
+    let refunded = conn.transaction(|conn| {
+        let refunded = match policy {
+            DiscardPolicy::Refund => billing::charged_for_generation(conn, candidate.id)?,
+            DiscardPolicy::Charge => 0,
+        };
+        if refunded > 0 {
+            // Back to whoever paid for the candidate, not whoever discards it.
+            let wallet = Wallet::of(candidate.user_id, candidate.organization_id);
+            billing::refund(conn, wallet, refunded, Some(candidate.id))?;
+        }
+        diesel::delete(icons::table.filter(icons::id.eq(candidate.id))).execute(conn)?;
+        Ok::<_, AppError>(refunded)
+    })?;
+    data.phash_index.remove(candidate.id);
+    if refunded > 0 {
+        events::balance_changed(&data, user.id);
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "id": candidate.id, "refunded": refunded }
+    })))
+}
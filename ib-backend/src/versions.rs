@@ -0,0 +1,254 @@
+use actix_web::{get, post, web, HttpResponse};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    billing::{self, Wallet},
+    events,
+    handlers::{AppError, AuthenticatedUser},
+    imaging,
+    model::AppState,
+    organizations::{self, Role},
+    pricing::{self, Operation},
+    schema::{icon_versions, icons},
+};
+
+const DEFAULT_UPSCALE_SIZE: u32 = 2048;
+
+/// Version metadata, the image itself is served by `get_version_image`.
+#[derive(Debug, Queryable, Serialize)]
+pub struct IconVersion {
+    pub id: i32,
+    pub icon_id: i32,
+    pub operation: String,
+    pub parameters: Value,
+    pub width: i32,
+    pub height: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+type VersionColumns = (
+    icon_versions::id,
+    icon_versions::icon_id,
+    icon_versions::operation,
+    icon_versions::parameters,
+    icon_versions::width,
+    icon_versions::height,
+    icon_versions::created_at,
+);
+
+const VERSION_COLUMNS: VersionColumns = (
+    icon_versions::id,
+    icon_versions::icon_id,
+    icon_versions::operation,
+    icon_versions::parameters,
+    icon_versions::width,
+    icon_versions::height,
+    icon_versions::created_at,
+);
+
+/// A derived PNG of an icon with its size, read from the header before any transaction
+/// it is stored in.
+pub struct Rendition {
+    png: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+impl Rendition {
+    pub fn from_png(png: Vec<u8>) -> Result<Rendition, AppError> {
+        let (width, height) = imaging::dimensions(&png)?;
+        Ok(Rendition { png, width, height })
+    }
+}
+
+/// Stores a derived rendition of an icon.
+pub fn store_version(
+    conn: &mut PgConnection,
+    icon_id: i32,
+    operation: &str,
+    parameters: &Value,
+    rendition: Rendition,
+) -> Result<IconVersion, AppError> {
+    let version = diesel::insert_into(icon_versions::table)
+        .values((
+            icon_versions::icon_id.eq(icon_id),
+            icon_versions::operation.eq(operation),
+            icon_versions::parameters.eq(parameters),
+            icon_versions::image_data.eq(rendition.png),
+            icon_versions::width.eq(rendition.width as i32),
+            icon_versions::height.eq(rendition.height as i32),
+        ))
+        .returning(VERSION_COLUMNS)
+        .get_result(conn)?;
+    Ok(version)
+}
+
+/// An icon a pack-wide edit had to leave out, reported next to the versions it stored.
+#[derive(Debug, Serialize)]
+pub struct SkippedIcon {
+    pub id: i32,
+    pub error: String,
+}
+
+/// Returns the stored image of an icon the user can work with at `role`, along with the
+/// workspace the icon belongs to.
+pub fn icon_image(
+    conn: &mut PgConnection,
+    user_id: i32,
+    icon_id: i32,
+    role: Role,
+) -> Result<(Vec<u8>, Option<i32>), AppError> {
+    let (image_data, organization_id): (Vec<u8>, Option<i32>) = icons::table
+        .filter(icons::id.eq(icon_id))
+        .filter(organizations::icon_access(user_id, role))
+        .select((icons::image_data, icons::organization_id))
+        .first(conn)
+        .optional()?
+        .ok_or(AppError::IconNotFound)?;
+    if image_data.is_empty() {
+        return Err(AppError::BadRequest("Icon has no image yet".to_string()));
+    }
+    Ok((image_data, organization_id))
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum UpscaleMode {
+    /// Lanczos resampling with edge sharpening on our own hardware.
+    Local,
+    /// The image provider's upscaler.
+    Provider,
+}
+
+#[derive(Deserialize)]
+struct UpscaleRequest {
+    mode: Option<UpscaleMode>,
+    /// Target length of the longest side for local upscales.
+    size: Option<u32>,
+}
+
+/// Upscales an icon for print and stores the result as a new version.
+#[post("/icons/{id}/upscale")]
+pub async fn upscale_icon(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<UpscaleRequest>,
+) -> Result<HttpResponse, AppError> {
+    let icon_id = path.into_inner();
+    let mode = body.mode.unwrap_or(UpscaleMode::Local);
+    let size = body.size.unwrap_or(DEFAULT_UPSCALE_SIZE);
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let (image_data, organization_id) = icon_image(&mut conn, user.id, icon_id, Role::Editor)?;
+    let wallet = Wallet::of(user.id, organization_id);
+
+    let version = match mode {
+        UpscaleMode::Local => {
+            let img = imaging::decode(&image_data)?;
+            if size <= img.width().max(img.height()) || size > imaging::MAX_DIMENSION {
+                return Err(AppError::BadRequest(format!(
+                    "size must be larger than the icon and at most {}",
+                    imaging::MAX_DIMENSION
+                )));
+            }
+            // Resampling thousands of pixels per side is CPU bound, keep it off the worker.
+            let png = web::block(move || imaging::encode_png(&imaging::upscale(&img, size)))
+                .await
+                .map_err(|e| AppError::InternalServerError(e.to_string()))??;
+            let rendition = Rendition::from_png(png)?;
+            let parameters = serde_json::json!({ "mode": mode, "size": size });
+
+            let version = conn.transaction(|conn| {
+                let cost = pricing::cost(conn, wallet, Operation::Upscale, None)?;
+                billing::charge(conn, wallet, Operation::Upscale.transaction_type(), cost, Some(icon_id))?;
+                store_version(conn, icon_id, "upscale", &parameters, rendition)
+            })?;
+            events::balance_changed(&data, user.id);
+            version
+        }
+        UpscaleMode::Provider => {
+            // Checked up front so a wallet that cannot pay doesn't cost us a provider call,
+            // charged together with storing the result like local upscales.
+            let cost = pricing::cost(&mut conn, wallet, Operation::UpscaleProvider, None)?;
+            billing::ensure_affordable(&mut conn, wallet, cost)?;
+
+            let rendition = Rendition::from_png(data.image_provider.upscale(&image_data).await?)?;
+            let parameters = serde_json::json!({ "mode": mode, "provider": data.image_provider.name() });
+            let version = conn.transaction(|conn| {
+                billing::charge(conn, wallet, Operation::UpscaleProvider.transaction_type(), cost, Some(icon_id))?;
+                store_version(conn, icon_id, "upscale", &parameters, rendition)
+            })?;
+            events::balance_changed(&data, user.id);
+            version
+        }
+    };
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "version": version }
+    })))
+}
+
+/// Fails with `IconNotFound` unless the user can at least view the icon.
+fn ensure_visible(conn: &mut PgConnection, user_id: i32, icon_id: i32) -> Result<(), AppError> {
+    let visible: bool = diesel::select(diesel::dsl::exists(
+        icons::table
+            .filter(icons::id.eq(icon_id))
+            .filter(organizations::icon_access(user_id, Role::Viewer)),
+    ))
+    .get_result(conn)?;
+    if !visible {
+        return Err(AppError::IconNotFound);
+    }
+    Ok(())
+}
+
+#[get("/icons/{id}/versions")]
+pub async fn list_versions(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let icon_id = path.into_inner();
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+
+    ensure_visible(&mut conn, user.id, icon_id)?;
+    let versions: Vec<IconVersion> = icon_versions::table
+        .filter(icon_versions::icon_id.eq(icon_id))
+        .select(VERSION_COLUMNS)
+        .order(icon_versions::id.asc())
+        .load(&mut conn)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "versions": versions }
+    })))
+}
+
+#[get("/icons/{id}/versions/{version_id}/image")]
+pub async fn get_version_image(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, AppError> {
+    let (icon_id, version_id) = path.into_inner();
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+
+    ensure_visible(&mut conn, user.id, icon_id)?;
+    let image_data: Vec<u8> = icon_versions::table
+        .filter(icon_versions::id.eq(version_id))
+        .filter(icon_versions::icon_id.eq(icon_id))
+        .select(icon_versions::image_data)
+        .first(&mut conn)
+        .optional()?
+        .ok_or_else(|| AppError::NotFound("Version not found".to_string()))?;
+
+    Ok(HttpResponse::Ok().content_type("image/png").body(image_data))
+}
@@ -0,0 +1,869 @@
+use std::collections::HashSet;
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::{
+    get,
+    http::header::{CacheControl, CacheDirective},
+    post, put, web, HttpResponse,
+};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use reqwest::dns::{Name, Resolve, Resolving};
+use reqwest::{redirect, Client};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use tokio::sync::Notify;
+
+use crate::{
+    handlers::{AppError, AuthenticatedUser},
+    model::AppState,
+    schema::{webhook_deliveries, webhook_endpoints},
+};
+
+/// Events an endpoint can subscribe to.
+pub const EVENTS: [&str; 4] = ["icon.created", "icon.failed", "pack.exported", "balance.low"];
+
+/// How often the dispatcher looks for due retries when nothing wakes it up.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Deliveries attempted per dispatcher round.
+const DISPATCH_BATCH: i64 = 32;
+/// How long a claimed delivery is left to the instance that claimed it. Well above the
+/// client timeout, if that instance dies the delivery is due again afterwards.
+const CLAIM_LEASE: Duration = Duration::from_secs(60);
+const DEFAULT_DELIVERY_LIMIT: i64 = 50;
+const MAX_DELIVERY_LIMIT: i64 = 200;
+
+#[derive(Debug, Queryable, Serialize)]
+#[diesel(table_name = webhook_endpoints)]
+pub struct WebhookEndpoint {
+    pub id: i32,
+    pub user_id: i32,
+    pub url: String,
+    /// Only returned once, when the endpoint is created.
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub events: Vec<String>,
+    pub active: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Queryable, Serialize)]
+#[diesel(table_name = webhook_deliveries)]
+pub struct WebhookDelivery {
+    pub id: i32,
+    pub endpoint_id: i32,
+    pub event: String,
+    pub payload: Value,
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub response_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+/// Delivery settings plus the handle used to wake the dispatcher when work is queued.
+pub struct Webhooks {
+    client: Client,
+    /// `WEBHOOK_ALLOW_PRIVATE_URLS`, lets local development deliver to localhost.
+    allow_private_urls: bool,
+    wake: Notify,
+    max_attempts: i32,
+    retry_base: Duration,
+    low_balance: i32,
+    /// Users already told their balance is low, so `balance.low` fires once per dip.
+    low_notified: Mutex<HashSet<i32>>,
+}
+
+impl Webhooks {
+    pub fn from_env() -> Webhooks {
+        let var = |name: &str, default: u64| {
+            env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+        };
+        let allow_private_urls = env::var("WEBHOOK_ALLOW_PRIVATE_URLS").is_ok_and(|v| v == "true");
+        Webhooks {
+            client: client(allow_private_urls),
+            allow_private_urls,
+            wake: Notify::new(),
+            max_attempts: var("WEBHOOK_MAX_ATTEMPTS", 8).max(1) as i32,
+            retry_base: Duration::from_secs(var("WEBHOOK_RETRY_BASE_SECS", 30)),
+            low_balance: var("LOW_BALANCE_THRESHOLD", 3) as i32,
+            low_notified: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Delay before the next attempt, doubling with every failed one.
+    fn backoff(&self, attempts: i32) -> Duration {
+        self.retry_base * 2u32.pow((attempts - 1).clamp(0, 10) as u32)
+    }
+}
+
+/// Endpoint urls are user input, so unless private urls are allowed the client only connects
+/// to public addresses and never follows redirects, which could point anywhere.
+fn client(allow_private_urls: bool) -> Client {
+    let builder = Client::builder()
+        .timeout(Duration::from_secs(10))
+        .redirect(redirect::Policy::none());
+    let builder = if allow_private_urls {
+        builder
+    } else {
+        builder.dns_resolver(Arc::new(PublicResolver))
+    };
+    builder.build().expect("Failed to build webhook client")
+}
+
+/// Whether an address is reachable on the public internet, as opposed to loopback, private
+/// networks, link-local (cloud metadata services live there) and other special ranges.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // Carrier-grade NAT, 100.64.0.0/10.
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()),
+        },
+    }
+}
+
+/// Resolves endpoint hosts and refuses those pointing at non-public addresses. Checking
+/// here rather than before sending means the address that was checked is the one
+/// connected to, a second lookup can't be rebound to an internal one.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            if addrs.is_empty() || !addrs.iter().all(|addr| is_public(addr.ip())) {
+                return Err(format!("{} does not resolve to a public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Rejects urls naming a non-public IP address directly, those never reach the resolver.
+fn check_host(url: &reqwest::Url, allow_private_urls: bool) -> Result<(), String> {
+    let host = url.host_str().unwrap_or_default();
+    let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() else {
+        return Ok(());
+    };
+    if allow_private_urls || is_public(ip) {
+        Ok(())
+    } else {
+        Err(format!("{} is not a public address", ip))
+    }
+}
+
+/// Queues `event` for every active endpoint of the user subscribed to it. Failing to queue
+/// is logged rather than returned, a webhook problem must not fail the operation itself.
+pub fn emit(state: &AppState, user_id: i32, event: &'static str, data: Value) {
+    let queued = state.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))
+        .and_then(|mut conn| {
+            let endpoint_ids: Vec<i32> = webhook_endpoints::table
+                .filter(webhook_endpoints::user_id.eq(user_id))
+                .filter(webhook_endpoints::active.eq(true))
+                .filter(webhook_endpoints::events.contains(vec![event]))
+                .select(webhook_endpoints::id)
+                .load(&mut conn)?;
+            let rows: Vec<_> = endpoint_ids
+                .iter()
+                .map(|endpoint_id| (
+                    webhook_deliveries::endpoint_id.eq(*endpoint_id),
+                    webhook_deliveries::event.eq(event),
+                    webhook_deliveries::payload.eq(&data),
+                ))
+                .collect();
+            Ok(diesel::insert_into(webhook_deliveries::table).values(&rows).execute(&mut conn)?)
+        });
+    match queued {
+        Ok(0) => {}
+        Ok(_) => state.webhooks.wake.notify_one(),
+        Err(e) => println!("Failed to queue {} webhook for user {}: {}", event, user_id, e),
+    }
+}
+
+/// Sends `balance.low` when a balance drops below `LOW_BALANCE_THRESHOLD`, and re-arms the
+/// notification once it is topped up again.
+pub fn check_balance(state: &AppState, user_id: i32, inkbucks: i32) {
+    let newly_low = {
+        let mut notified = state.webhooks.low_notified.lock().unwrap();
+        if inkbucks < state.webhooks.low_balance {
+            notified.insert(user_id)
+        } else {
+            notified.remove(&user_id);
+            false
+        }
+    };
+    if newly_low {
+        emit(state, user_id, "balance.low", serde_json::json!({
+            "inkbucks": inkbucks,
+            "threshold": state.webhooks.low_balance,
+        }));
+    }
+}
+
+/// Background loop delivering queued webhooks. Pending rows live in the database, so
+/// retries scheduled before a restart are picked up again.
+pub async fn run_dispatcher(state: AppState) {
+    loop {
+        match deliver_due(&state).await {
+            // A full round may have left more due rows behind, go again right away.
+            Ok(delivered) if delivered as i64 == DISPATCH_BATCH => continue,
+            Ok(_) => {}
+            Err(e) => println!("Webhook dispatch failed: {}", e),
+        }
+        let _ = tokio::time::timeout(POLL_INTERVAL, state.webhooks.wake.notified()).await;
+    }
+}
+
+/// Takes the due deliveries for this instance by moving their next attempt past the lease.
+/// Rows another instance is claiming at the same moment are skipped, not waited for.
+fn claim_due(conn: &mut PgConnection) -> Result<Vec<(WebhookDelivery, WebhookEndpoint)>, AppError> {
+    conn.transaction(|conn| {
+        let now = Utc::now();
+        let ids: Vec<i32> = webhook_deliveries::table
+            .filter(webhook_deliveries::status.eq("pending"))
+            .filter(webhook_deliveries::next_attempt_at.le(now))
+            .filter(webhook_deliveries::endpoint_id.eq_any(
+                webhook_endpoints::table
+                    .filter(webhook_endpoints::active.eq(true))
+                    .select(webhook_endpoints::id),
+            ))
+            .order(webhook_deliveries::next_attempt_at.asc())
+            .limit(DISPATCH_BATCH)
+            .select(webhook_deliveries::id)
+            .for_update()
+            .skip_locked()
+            .load(conn)?;
+        let lease = chrono::Duration::from_std(CLAIM_LEASE).unwrap_or_else(|_| chrono::Duration::minutes(1));
+        diesel::update(webhook_deliveries::table.filter(webhook_deliveries::id.eq_any(&ids)))
+            .set(webhook_deliveries::next_attempt_at.eq(now + lease))
+            .execute(conn)?;
+        Ok(webhook_deliveries::table
+            .inner_join(webhook_endpoints::table)
+            .filter(webhook_deliveries::id.eq_any(&ids))
+            .order(webhook_deliveries::id.asc())
+            .load(conn)?)
+    })
+}
+
+async fn deliver_due(state: &AppState) -> Result<usize, AppError> {
+    let due = {
+        let mut conn = state.db_pool.get()
+            .map_err(|e| AppError::DbConnection(e.to_string()))?;
+        claim_due(&mut conn)?
+    };
+
+    let count = due.len();
+    let results = futures::future::join_all(
+        due.iter().map(|(delivery, endpoint)| attempt(&state.webhooks, delivery, endpoint)),
+    )
+    .await;
+
+    let mut conn = state.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    for ((delivery, _), (response_status, error)) in due.iter().zip(results) {
+        let attempts = delivery.attempts + 1;
+        let now = Utc::now();
+        // Left alone if the lease ran out and another instance attempted it since.
+        let target = webhook_deliveries::table
+            .filter(webhook_deliveries::id.eq(delivery.id))
+            .filter(webhook_deliveries::attempts.eq(delivery.attempts));
+        match error {
+            None => diesel::update(target)
+                .set((
+                    webhook_deliveries::status.eq("succeeded"),
+                    webhook_deliveries::attempts.eq(attempts),
+                    webhook_deliveries::response_status.eq(response_status),
+                    webhook_deliveries::last_error.eq(None::<String>),
+                    webhook_deliveries::delivered_at.eq(now),
+                ))
+                .execute(&mut conn)?,
+            Some(error) => {
+                let status = if attempts >= state.webhooks.max_attempts { "failed" } else { "pending" };
+                let next_attempt_at = now + chrono::Duration::from_std(state.webhooks.backoff(attempts))
+                    .unwrap_or_else(|_| chrono::Duration::hours(1));
+                diesel::update(target)
+                    .set((
+                        webhook_deliveries::status.eq(status),
+                        webhook_deliveries::attempts.eq(attempts),
+                        webhook_deliveries::response_status.eq(response_status),
+                        webhook_deliveries::last_error.eq(error),
+                        webhook_deliveries::next_attempt_at.eq(next_attempt_at),
+                    ))
+                    .execute(&mut conn)?
+            }
+        };
+    }
+    Ok(count)
+}
+
+/// `t=<unix seconds>,v1=<hex HMAC-SHA256 of "<t>.<body>">`, the same scheme receivers may
+/// know from Stripe. The timestamp lets them reject replayed requests.
+pub fn signature(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    format!("t={},v1={}", timestamp, hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Posts one delivery, returning the response status and an error unless it was a 2xx.
+async fn attempt(
+    webhooks: &Webhooks,
+    delivery: &WebhookDelivery,
+    endpoint: &WebhookEndpoint,
+) -> (Option<i32>, Option<String>) {
+    let body = serde_json::json!({
+        "id": delivery.id,
+        "event": delivery.event,
+        "created_at": delivery.created_at,
+        "data": delivery.payload,
+    })
+    .to_string();
+    let timestamp = Utc::now().timestamp();
+
+    let url = match reqwest::Url::parse(&endpoint.url) {
+        Ok(url) => url,
+        Err(e) => return (None, Some(e.to_string())),
+    };
+    if let Err(e) = check_host(&url, webhooks.allow_private_urls) {
+        return (None, Some(e));
+    }
+    let response = webhooks.client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header("InkBlink-Event", &delivery.event)
+        .header("InkBlink-Delivery", delivery.id.to_string())
+        .header("InkBlink-Signature", signature(&endpoint.secret, timestamp, &body))
+        .body(body)
+        .send()
+        .await;
+
+    match response {
+        Ok(response) if response.status().is_success() => (Some(response.status().as_u16() as i32), None),
+        Ok(response) => (
+            Some(response.status().as_u16() as i32),
+            Some(format!("Endpoint responded with {}", response.status())),
+        ),
+        Err(e) => (None, Some(e.to_string())),
+    }
+}
+
+fn find_owned_endpoint(
+    conn: &mut PgConnection,
+    user_id: i32,
+    endpoint_id: i32,
+) -> Result<WebhookEndpoint, AppError> {
+    webhook_endpoints::table
+        .filter(webhook_endpoints::id.eq(endpoint_id))
+        .filter(webhook_endpoints::user_id.eq(user_id))
+        .first(conn)
+        .optional()?
+        .ok_or_else(|| AppError::NotFound("Webhook endpoint not found".to_string()))
+}
+
+/// Catches urls that can never be delivered to early. Host names are only checked when
+/// delivering, since what they resolve to can change.
+fn validate_url(webhooks: &Webhooks, url: &str) -> Result<String, AppError> {
+    let url = url.trim();
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {
+            check_host(&parsed, webhooks.allow_private_urls).map_err(AppError::BadRequest)?;
+            Ok(url.to_string())
+        }
+        _ => Err(AppError::BadRequest("url must be an absolute http(s) URL".to_string())),
+    }
+}
+
+fn validate_events(events: &[String]) -> Result<Vec<String>, AppError> {
+    let mut unique: Vec<String> = Vec::new();
+    for event in events {
+        if !EVENTS.contains(&event.as_str()) {
+            return Err(AppError::BadRequest(format!(
+                "Unknown event '{}', expected one of {}",
+                event,
+                EVENTS.join(", ")
+            )));
+        }
+        if !unique.contains(event) {
+            unique.push(event.clone());
+        }
+    }
+    if unique.is_empty() {
+        return Err(AppError::BadRequest("At least one event is required".to_string()));
+    }
+    Ok(unique)
+}
+
+#[derive(Deserialize)]
+struct CreateEndpoint {
+    url: String,
+    events: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct UpdateEndpoint {
+    url: Option<String>,
+    events: Option<Vec<String>>,
+    active: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct DeliveryQuery {
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+/// Registers an endpoint. The signing secret is part of this response only.
+#[post("/webhooks")]
+pub async fn create_endpoint(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    body: web::Json<CreateEndpoint>,
+) -> Result<HttpResponse, AppError> {
+    let url = validate_url(&data.webhooks, &body.url)?;
+    let events = validate_events(&body.events)?;
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    let secret = format!("whsec_{}", hex::encode(secret));
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let endpoint: WebhookEndpoint = diesel::insert_into(webhook_endpoints::table)
+        .values((
+            webhook_endpoints::user_id.eq(user.id),
+            webhook_endpoints::url.eq(&url),
+            webhook_endpoints::secret.eq(&secret),
+            webhook_endpoints::events.eq(&events),
+        ))
+        .get_result(&mut conn)?;
+
+    // no-store keeps the signing secret out of caches and idempotency snapshots.
+    Ok(HttpResponse::Ok()
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .json(serde_json::json!({
+            "status": "success",
+            "data": { "endpoint": endpoint, "secret": secret }
+        })))
+}
+
+#[get("/webhooks")]
+pub async fn list_endpoints(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let endpoints: Vec<WebhookEndpoint> = webhook_endpoints::table
+        .filter(webhook_endpoints::user_id.eq(user.id))
+        .order(webhook_endpoints::id.asc())
+        .load(&mut conn)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "endpoints": endpoints }
+    })))
+}
+
+/// Changes the url or event filter, or pauses an endpoint with `"active": false`. Paused
+/// endpoints keep their pending deliveries until they are switched back on.
+#[put("/webhooks/{id}")]
+pub async fn update_endpoint(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<UpdateEndpoint>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let current = find_owned_endpoint(&mut conn, user.id, path.into_inner())?;
+    let url = match &body.url {
+        Some(url) => validate_url(&data.webhooks, url)?,
+        None => current.url,
+    };
+    let events = match &body.events {
+        Some(events) => validate_events(events)?,
+        None => current.events,
+    };
+
+    let endpoint: WebhookEndpoint = diesel::update(
+        webhook_endpoints::table.filter(webhook_endpoints::id.eq(current.id)),
+    )
+    .set((
+        webhook_endpoints::url.eq(&url),
+        webhook_endpoints::events.eq(&events),
+        webhook_endpoints::active.eq(body.active.unwrap_or(current.active)),
+    ))
+    .get_result(&mut conn)?;
+    if endpoint.active {
+        data.webhooks.wake.notify_one();
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "endpoint": endpoint }
+    })))
+}
+
+/// Delivery log of an endpoint, newest first.
+#[get("/webhooks/{id}/deliveries")]
+pub async fn list_deliveries(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    query: web::Query<DeliveryQuery>,
+) -> Result<HttpResponse, AppError> {
+    let limit = query.limit.unwrap_or(DEFAULT_DELIVERY_LIMIT).clamp(1, MAX_DELIVERY_LIMIT);
+    let offset = query.offset.unwrap_or(0).max(0);
+
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let endpoint = find_owned_endpoint(&mut conn, user.id, path.into_inner())?;
+    let deliveries: Vec<WebhookDelivery> = webhook_deliveries::table
+        .filter(webhook_deliveries::endpoint_id.eq(endpoint.id))
+        .order(webhook_deliveries::id.desc())
+        .limit(limit)
+        .offset(offset)
+        .load(&mut conn)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "data": { "deliveries": deliveries }
+    })))
+}
+
+fn replay(conn: &mut PgConnection, user_id: i32, delivery_id: i32) -> Result<WebhookDelivery, AppError> {
+    let original: WebhookDelivery = webhook_deliveries::table
+        .inner_join(webhook_endpoints::table)
+        .filter(webhook_deliveries::id.eq(delivery_id))
+        .filter(webhook_endpoints::user_id.eq(user_id))
+        .select(webhook_deliveries::all_columns)
+        .first(conn)
+        .optional()?
+        .ok_or_else(|| AppError::NotFound("Webhook delivery not found".to_string()))?;
+
+    Ok(diesel::insert_into(webhook_deliveries::table)
+        .values((
+            webhook_deliveries::endpoint_id.eq(original.endpoint_id),
+            webhook_deliveries::event.eq(&original.event),
+            webhook_deliveries::payload.eq(&original.payload),
+        ))
+        .get_result(conn)?)
+}
+
+/// Sends a past delivery again as a new delivery, leaving the original in the log.
+#[post("/webhooks/deliveries/{id}/replay")]
+pub async fn replay_delivery(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = data.db_pool.get()
+        .map_err(|e| AppError::DbConnection(e.to_string()))?;
+    let delivery = replay(&mut conn, user.id, path.into_inner())?;
+    data.webhooks.wake.notify_one();
+
+    Ok(HttpResponse::Accepted().json(serde_json::json!({
+        "status": "success",
+        "data": { "delivery": delivery }
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{schema::users, testing};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::task::JoinHandle;
+
+    /// Reads one request, headers and body, from a connection.
+    async fn read_request(socket: &mut TcpStream) -> Option<String> {
+        let mut request = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let text = String::from_utf8_lossy(&request).into_owned();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length = header(head, "content-length").and_then(|v| v.parse().ok()).unwrap_or(0);
+                if body.len() >= length {
+                    return Some(text);
+                }
+            }
+            let n = socket.read(&mut buf).await.ok()?;
+            if n == 0 {
+                return Some(text);
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    /// An HTTP server on localhost answering one request with each of `responses` in turn.
+    /// The handle yields the requests it received, fewer when nothing else connected.
+    async fn receiver_of(responses: Vec<String>) -> (u16, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for response in responses {
+                let Ok(Ok((mut socket, _))) = tokio::time::timeout(Duration::from_secs(1), listener.accept()).await else {
+                    break;
+                };
+                let Some(request) = read_request(&mut socket).await else { break };
+                let _ = socket.write_all(response.as_bytes()).await;
+                requests.push(request);
+            }
+            requests
+        });
+        (port, handle)
+    }
+
+    /// A one-request HTTP server on localhost answering with `response`. The handle yields
+    /// the request it received, or `None` when nothing connected.
+    async fn receiver(response: impl Into<String>) -> (u16, JoinHandle<Option<String>>) {
+        let (port, handle) = receiver_of(vec![response.into()]).await;
+        (port, tokio::spawn(async move { handle.await.unwrap().pop() }))
+    }
+
+    fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+        head.lines().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+
+    /// Checks a request's signature the way a receiver would and returns its JSON body.
+    fn verified_body(request: &str, secret: &str) -> Value {
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        let signature_header = header(head, "inkblink-signature").unwrap();
+        let timestamp: i64 = signature_header
+            .strip_prefix("t=")
+            .and_then(|rest| rest.split(',').next())
+            .and_then(|t| t.parse().ok())
+            .unwrap();
+        assert!((Utc::now().timestamp() - timestamp).abs() < 60);
+        assert_eq!(signature_header, signature(secret, timestamp, body));
+        serde_json::from_str(body).unwrap()
+    }
+
+    fn webhooks(allow_private_urls: bool) -> Webhooks {
+        Webhooks { client: client(allow_private_urls), allow_private_urls, ..Webhooks::from_env() }
+    }
+
+    fn delivery_to(url: String) -> (WebhookDelivery, WebhookEndpoint) {
+        let now = Utc::now();
+        let delivery = WebhookDelivery {
+            id: 1,
+            endpoint_id: 1,
+            event: "icon.created".to_string(),
+            payload: serde_json::json!({ "icon_id": 1 }),
+            status: "pending".to_string(),
+            attempts: 0,
+            next_attempt_at: now,
+            response_status: None,
+            last_error: None,
+            created_at: now,
+            delivered_at: None,
+        };
+        let endpoint = WebhookEndpoint {
+            id: 1,
+            user_id: 1,
+            url,
+            secret: "whsec_test".to_string(),
+            events: vec!["icon.created".to_string()],
+            active: true,
+            created_at: now,
+        };
+        (delivery, endpoint)
+    }
+
+    const OK: &str = "HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+
+    #[tokio::test]
+    async fn refuses_loopback_addresses() {
+        let webhooks = webhooks(false);
+        for host in ["127.0.0.1", "localhost"] {
+            let (port, received) = receiver(OK).await;
+            let (delivery, endpoint) = delivery_to(format!("http://{}:{}/hook", host, port));
+            let (status, error) = attempt(&webhooks, &delivery, &endpoint).await;
+            assert_eq!(status, None);
+            assert!(error.is_some(), "{} must not be delivered to", host);
+            assert_eq!(received.await.unwrap(), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_requests_when_private_urls_are_allowed() {
+        let (port, received) = receiver(OK).await;
+        let (delivery, endpoint) = delivery_to(format!("http://localhost:{}/hook", port));
+        let (status, error) = attempt(&webhooks(true), &delivery, &endpoint).await;
+        assert_eq!((status, error), (Some(200), None));
+        let request = received.await.unwrap().unwrap();
+        assert!(request.starts_with("POST /hook "));
+        assert_eq!(verified_body(&request, "whsec_test")["data"]["icon_id"], 1);
+    }
+
+    #[tokio::test]
+    async fn does_not_follow_redirects() {
+        let (target_port, target) = receiver(OK).await;
+        let redirect = format!(
+            "HTTP/1.1 302 Found\r\nlocation: http://127.0.0.1:{}/internal\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+            target_port
+        );
+        let (port, received) = receiver(redirect).await;
+        let (delivery, endpoint) = delivery_to(format!("http://127.0.0.1:{}/hook", port));
+        let (status, error) = attempt(&webhooks(true), &delivery, &endpoint).await;
+        assert_eq!(status, Some(302));
+        assert!(error.is_some());
+        assert!(received.await.unwrap().is_some());
+        assert_eq!(target.await.unwrap(), None);
+    }
+
+    #[test]
+    fn classifies_addresses() {
+        for ip in ["10.0.0.1", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0", "::1", "fd00::1", "fe80::1", "::ffff:127.0.0.1"] {
+            assert!(!is_public(ip.parse().unwrap()), "{} is not public", ip);
+        }
+        for ip in ["93.184.216.34", "2606:2800:220:1::1"] {
+            assert!(is_public(ip.parse().unwrap()), "{} is public", ip);
+        }
+    }
+
+    #[test]
+    fn rejects_private_ip_urls_up_front() {
+        assert!(validate_url(&webhooks(false), "http://169.254.169.254/latest").is_err());
+        assert!(validate_url(&webhooks(false), "http://[::1]:8080/").is_err());
+        assert!(validate_url(&webhooks(false), "https://hooks.example.com/x").is_ok());
+        assert!(validate_url(&webhooks(true), "http://127.0.0.1:8080/").is_ok());
+    }
+
+    const FAIL: &str = "HTTP/1.1 500 Internal Server Error\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+
+    fn deliveries_of(conn: &mut PgConnection, endpoint_id: i32) -> Vec<WebhookDelivery> {
+        webhook_deliveries::table
+            .filter(webhook_deliveries::endpoint_id.eq(endpoint_id))
+            .order(webhook_deliveries::id.asc())
+            .load(conn)
+            .unwrap()
+    }
+
+    /// Makes every pending delivery of the endpoint due now, as if its backoff had passed.
+    fn make_due(conn: &mut PgConnection, endpoint_id: i32) {
+        diesel::update(webhook_deliveries::table.filter(webhook_deliveries::endpoint_id.eq(endpoint_id)))
+            .set(webhook_deliveries::next_attempt_at.eq(Utc::now()))
+            .execute(conn)
+            .unwrap();
+    }
+
+    /// State delivering to private urls, and a new user with an endpoint at `url`.
+    async fn state_with_endpoint(url: String) -> (AppState, i32, i32) {
+        let state = AppState {
+            webhooks: Arc::new(Webhooks { retry_base: Duration::from_secs(30), ..webhooks(true) }),
+            ..AppState::init().await
+        };
+        let mut conn = state.db_pool.get().unwrap();
+        let user_id = testing::user(&mut conn);
+        let endpoint_id = diesel::insert_into(webhook_endpoints::table)
+            .values((
+                webhook_endpoints::user_id.eq(user_id),
+                webhook_endpoints::url.eq(url),
+                webhook_endpoints::secret.eq("whsec_test"),
+                webhook_endpoints::events.eq(vec!["icon.created"]),
+            ))
+            .returning(webhook_endpoints::id)
+            .get_result(&mut conn)
+            .unwrap();
+        (state, user_id, endpoint_id)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    async fn emits_retries_and_replays_signed_deliveries() {
+        let (port, received) = receiver_of(vec![FAIL.to_string(), OK.to_string(), OK.to_string()]).await;
+        let (state, user_id, endpoint_id) = state_with_endpoint(format!("http://127.0.0.1:{}/hook", port)).await;
+        let mut conn = state.db_pool.get().unwrap();
+
+        emit(&state, user_id, "icon.created", serde_json::json!({ "icon_id": 42 }));
+        // Not subscribed, never queued.
+        emit(&state, user_id, "pack.exported", serde_json::json!({ "pack_id": 1 }));
+        let queued = deliveries_of(&mut conn, endpoint_id);
+        assert_eq!(queued.len(), 1);
+        assert_eq!((queued[0].status.as_str(), queued[0].attempts), ("pending", 0));
+
+        // The receiver fails the first attempt, which is retried after the backoff.
+        deliver_due(&state).await.unwrap();
+        let failed = deliveries_of(&mut conn, endpoint_id).remove(0);
+        assert_eq!((failed.status.as_str(), failed.attempts, failed.response_status), ("pending", 1, Some(500)));
+        assert!(failed.last_error.is_some());
+        let backoff = (failed.next_attempt_at - Utc::now()).num_seconds();
+        assert!((25..=30).contains(&backoff), "next attempt in {}s", backoff);
+        deliver_due(&state).await.unwrap();
+        assert_eq!(deliveries_of(&mut conn, endpoint_id)[0].attempts, 1, "not due before the backoff");
+
+        make_due(&mut conn, endpoint_id);
+        deliver_due(&state).await.unwrap();
+        let delivered = deliveries_of(&mut conn, endpoint_id).remove(0);
+        assert_eq!((delivered.status.as_str(), delivered.attempts, delivered.response_status), ("succeeded", 2, Some(200)));
+        assert!(delivered.delivered_at.is_some() && delivered.last_error.is_none());
+
+        let replayed = replay(&mut conn, user_id, delivered.id).unwrap();
+        assert_ne!(replayed.id, delivered.id);
+        deliver_due(&state).await.unwrap();
+        let log = deliveries_of(&mut conn, endpoint_id);
+        let statuses: Vec<(&str, i32)> = log.iter().map(|d| (d.status.as_str(), d.attempts)).collect();
+        assert_eq!(statuses, [("succeeded", 2), ("succeeded", 1)]);
+        let stranger = testing::user(&mut conn);
+        assert!(replay(&mut conn, stranger, delivered.id).is_err(), "only the owner may replay");
+
+        let requests = received.await.unwrap();
+        diesel::delete(users::table.filter(users::id.eq_any([user_id, stranger]))).execute(&mut conn).unwrap();
+        assert_eq!(requests.len(), 3);
+        let bodies: Vec<Value> = requests.iter().map(|request| verified_body(request, "whsec_test")).collect();
+        for body in &bodies {
+            assert_eq!(body["event"], "icon.created");
+            assert_eq!(body["data"]["icon_id"], 42);
+        }
+        assert_eq!(bodies[0]["id"], delivered.id);
+        assert_eq!(bodies[1]["id"], delivered.id);
+        assert_eq!(bodies[2]["id"], replayed.id);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    async fn claimed_deliveries_are_not_claimed_again() {
+        let (state, user_id, endpoint_id) = state_with_endpoint("http://127.0.0.1:9/hook".to_string()).await;
+        let mut conn = state.db_pool.get().unwrap();
+        emit(&state, user_id, "icon.created", serde_json::json!({ "icon_id": 1 }));
+        let id = deliveries_of(&mut conn, endpoint_id)[0].id;
+
+        let first = claim_due(&mut conn).unwrap();
+        let second = claim_due(&mut state.db_pool.get().unwrap()).unwrap();
+        diesel::delete(users::table.filter(users::id.eq(user_id))).execute(&mut conn).unwrap();
+
+        assert!(first.iter().any(|(delivery, _)| delivery.id == id));
+        assert!(!second.iter().any(|(delivery, _)| delivery.id == id));
+    }
+}